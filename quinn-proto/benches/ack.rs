@@ -0,0 +1,146 @@
+#[macro_use]
+extern crate criterion;
+extern crate quinn_proto;
+extern crate rand;
+extern crate rustls;
+#[macro_use]
+extern crate slog;
+extern crate untrusted;
+extern crate webpki;
+
+use std::fs;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+
+use criterion::Criterion;
+use quinn_proto::{
+    ClientConfig, Config, ConnectionHandle, Endpoint, Io, ListenKeys, ALPN_QUIC_HTTP,
+};
+use rustls::{internal::pemfile, ProtocolVersion};
+use slog::{Discard, Logger};
+use untrusted::Input;
+
+/// Packets the client sends after the handshake, every other one of which reaches the server
+const PACKETS: usize = 4000;
+
+/// Send a packet from a server awaiting acknowledgement of many fragmented ranges
+///
+/// An ACK frame of all of them doesn't fit in one packet, so it must be truncated.
+fn ack_many_ranges(c: &mut Criterion) {
+    let (mut server, conn) = fragmented_server();
+    c.bench_function("ACK of fragmented ranges", move |b| {
+        b.iter(|| {
+            server.ping(conn);
+            while let Some(io) = server.poll_io(0) {
+                if let Io::Transmit { packet, .. } = io {
+                    criterion::black_box(packet);
+                }
+            }
+        })
+    });
+}
+
+fn fragmented_server() -> (Endpoint, ConnectionHandle) {
+    let log = Logger::root(Discard, o!());
+    let server_addr: SocketAddr = "[::1]:4433".parse().unwrap();
+    let client_addr: SocketAddr = "[::1]:44433".parse().unwrap();
+    let mut server = Endpoint::new(
+        log.clone(),
+        Config {
+            // Remember every range, and never stop sending for lack of acknowledgements
+            max_ack_blocks: PACKETS,
+            initial_window: u64::max_value() / 2,
+            ..server_config()
+        },
+        Some(ListenKeys::new(&mut rand::thread_rng())),
+    ).unwrap();
+    let mut client = Endpoint::new(
+        log,
+        Config {
+            initial_window: u64::max_value() / 2,
+            ..Config::default()
+        },
+        None,
+    ).unwrap();
+
+    let client_conn = client
+        .connect(0, server_addr, &client_config(), "localhost")
+        .unwrap();
+    while deliver(&mut client, client_addr, &mut server)
+        | deliver(&mut server, server_addr, &mut client)
+    {}
+    let server_conn = server.accept().expect("server didn't connect");
+
+    for i in 0..PACKETS {
+        client.ping(client_conn);
+        while let Some(io) = client.poll_io(0) {
+            if let Io::Transmit { packet, .. } = io {
+                if i % 2 == 0 {
+                    server.handle(0, client_addr, packet[..].into());
+                }
+            }
+        }
+    }
+    // Discard the server's acknowledgements so they never get acknowledged in turn
+    while server.poll_io(0).is_some() {}
+    (server, server_conn)
+}
+
+/// Pass every packet `from` has to send to `to`, returning whether there were any
+fn deliver(from: &mut Endpoint, from_addr: SocketAddr, to: &mut Endpoint) -> bool {
+    let mut sent = false;
+    while let Some(io) = from.poll_io(0) {
+        if let Io::Transmit { packet, .. } = io {
+            to.handle(0, from_addr, packet[..].into());
+            sent = true;
+        }
+    }
+    sent
+}
+
+fn server_config() -> Config {
+    let certs = {
+        let f =
+            fs::File::open("../certs/server.chain").expect("cannot open '../certs/server.chain'");
+        let mut reader = io::BufReader::new(f);
+        pemfile::certs(&mut reader).expect("cannot read certificates")
+    };
+
+    let keys = {
+        let f = fs::File::open("../certs/server.rsa").expect("cannot open '../certs/server.rsa'");
+        let mut reader = io::BufReader::new(f);
+        pemfile::rsa_private_keys(&mut reader).expect("cannot read private keys")
+    };
+
+    let mut tls_server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    tls_server_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+    tls_server_config
+        .set_single_cert(certs, keys[0].clone())
+        .unwrap();
+    Config {
+        tls_server_config: Arc::new(tls_server_config),
+        ..Default::default()
+    }
+}
+
+fn client_config() -> Arc<ClientConfig> {
+    let mut f = fs::File::open("../certs/ca.der").expect("cannot open '../certs/ca.der'");
+    let mut bytes = Vec::new();
+    f.read_to_end(&mut bytes).expect("error while reading");
+
+    let anchor = webpki::trust_anchor_util::cert_der_as_trust_anchor(Input::from(&bytes)).unwrap();
+    let anchor_vec = vec![anchor];
+
+    let mut tls_client_config = ClientConfig::new();
+    tls_client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    tls_client_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+    tls_client_config
+        .root_store
+        .add_server_trust_anchors(&webpki::TLSServerTrustAnchors(&anchor_vec));
+    Arc::new(tls_client_config)
+}
+
+criterion_group!(benches, ack_many_ranges);
+criterion_main!(benches);
@@ -0,0 +1,53 @@
+#[macro_use]
+extern crate criterion;
+extern crate quinn_proto;
+extern crate ring;
+
+use criterion::Criterion;
+use quinn_proto::{
+    Cipher, PacketOpeningKey, PacketProtection, PacketSealingKey, SoftwareProtection,
+};
+use ring::aead;
+
+/// Typical size of a full packet's payload
+const PAYLOAD: usize = 1200;
+const HEADER: [u8; 20] = [0xab; 20];
+const KEY: [u8; 16] = [0x42; 16];
+const NONCE: [u8; 12] = [0x07; 12];
+
+/// Seal and open a packet through the keys `SoftwareProtection` constructs
+///
+/// Compare with `ring_direct` to see what the indirection through `PacketProtection` costs.
+fn pluggable(c: &mut Criterion) {
+    let sealing = SoftwareProtection.sealing_key(Cipher::Aes128Gcm, &KEY);
+    let opening = SoftwareProtection.opening_key(Cipher::Aes128Gcm, &KEY);
+    let tag_len = aead::AES_128_GCM.tag_len();
+    c.bench_function("AES-128-GCM packet via PacketProtection", move |b| {
+        let mut buf = vec![0; PAYLOAD + tag_len];
+        b.iter(|| {
+            sealing.seal(&NONCE, &HEADER, &mut buf).unwrap();
+            let len = opening.open(&NONCE, &HEADER, &mut buf).unwrap();
+            criterion::black_box(&buf[..len]);
+        })
+    });
+}
+
+/// Seal and open a packet by calling *ring* directly, as was done before protection was pluggable
+fn ring_direct(c: &mut Criterion) {
+    let sealing = aead::SealingKey::new(&aead::AES_128_GCM, &KEY).unwrap();
+    let opening = aead::OpeningKey::new(&aead::AES_128_GCM, &KEY).unwrap();
+    let tag_len = aead::AES_128_GCM.tag_len();
+    c.bench_function("AES-128-GCM packet via ring", move |b| {
+        let mut buf = vec![0; PAYLOAD + tag_len];
+        b.iter(|| {
+            aead::seal_in_place(&sealing, &NONCE, &HEADER, &mut buf, tag_len).unwrap();
+            let len = aead::open_in_place(&opening, &NONCE, &HEADER, 0, &mut buf)
+                .unwrap()
+                .len();
+            criterion::black_box(&buf[..len]);
+        })
+    });
+}
+
+criterion_group!(benches, pluggable, ring_direct);
+criterion_main!(benches);
@@ -0,0 +1,217 @@
+#[macro_use]
+extern crate criterion;
+extern crate quinn_proto;
+extern crate rand;
+extern crate rustls;
+#[macro_use]
+extern crate slog;
+extern crate untrusted;
+extern crate webpki;
+
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Read};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::Arc;
+
+use criterion::Criterion;
+use quinn_proto::{
+    ClientConfig, Config, ConnectionHandle, Directionality, Endpoint, Io, ListenKeys, StreamId,
+    ALPN_QUIC_HTTP,
+};
+use rustls::{internal::pemfile, ProtocolVersion};
+use slog::{Discard, Logger};
+use untrusted::Input;
+
+/// Bytes read from the stream in each iteration
+const LEN: usize = 1024 * 1024;
+
+/// Read 1 MiB from a stream into a zeroed buffer, as `read` requires
+fn read_zeroed(c: &mut Criterion) {
+    let pair = RefCell::new(Pair::new());
+    c.bench_function("read 1 MiB into a zeroed buffer", move |b| {
+        b.iter_with_setup(
+            || pair.borrow_mut().transfer(),
+            |()| {
+                let mut pair = pair.borrow_mut();
+                let pair = &mut *pair;
+                let mut buf = vec![0; LEN];
+                let mut n = 0;
+                while n < LEN {
+                    n += pair
+                        .server
+                        .read(pair.server_conn, pair.stream, &mut buf[n..])
+                        .unwrap();
+                }
+                criterion::black_box(buf);
+            },
+        )
+    });
+}
+
+/// Read 1 MiB from a stream with `read_bytes`, which needs no buffer up front
+fn read_bytes(c: &mut Criterion) {
+    let pair = RefCell::new(Pair::new());
+    c.bench_function("read 1 MiB with read_bytes", move |b| {
+        b.iter_with_setup(
+            || pair.borrow_mut().transfer(),
+            |()| {
+                let mut pair = pair.borrow_mut();
+                let pair = &mut *pair;
+                let mut n = 0;
+                while n < LEN {
+                    let data = pair
+                        .server
+                        .read_bytes(pair.server_conn, pair.stream, LEN - n)
+                        .unwrap();
+                    n += data.len();
+                    criterion::black_box(data);
+                }
+            },
+        )
+    });
+}
+
+/// A connected client and server, and a stream from the former to the latter
+struct Pair {
+    client: Endpoint,
+    server: Endpoint,
+    client_conn: ConnectionHandle,
+    server_conn: ConnectionHandle,
+    stream: StreamId,
+    data: Vec<u8>,
+}
+
+impl Pair {
+    fn new() -> Self {
+        let log = Logger::root(Discard, o!());
+        let mut server = Endpoint::new(
+            log.clone(),
+            Config {
+                // Leave room for a whole iteration's data to arrive before it's read
+                stream_receive_window: 4 * LEN as u32,
+                receive_window: 4 * LEN as u32,
+                initial_window: u64::max_value() / 2,
+                max_remote_uni_streams: 1,
+                ..server_config()
+            },
+            Some(ListenKeys::new(&mut rand::thread_rng())),
+        ).unwrap();
+        let mut client = Endpoint::new(
+            log,
+            Config {
+                initial_window: u64::max_value() / 2,
+                ..Config::default()
+            },
+            None,
+        ).unwrap();
+
+        let client_conn = client
+            .connect(0, server_addr(), &client_config(), "localhost")
+            .unwrap();
+        drive(&mut client, &mut server);
+        let server_conn = server.accept().expect("server didn't connect");
+
+        let stream = client.open(client_conn, Directionality::Uni).unwrap();
+        client.write(client_conn, stream, &[0]).unwrap();
+        drive(&mut client, &mut server);
+        while server.poll().is_some() {}
+        server.read(server_conn, stream, &mut [0]).unwrap();
+
+        Pair {
+            client,
+            server,
+            client_conn,
+            server_conn,
+            stream,
+            data: vec![0xab; LEN],
+        }
+    }
+
+    /// Send `LEN` bytes from the client, leaving them buffered at the server
+    fn transfer(&mut self) {
+        let mut n = 0;
+        while n < LEN {
+            // Blocked writes are retried once the server has caught up
+            if let Ok(written) = self
+                .client
+                .write(self.client_conn, self.stream, &self.data[n..])
+            {
+                n += written;
+            }
+            drive(&mut self.client, &mut self.server);
+        }
+        while self.server.poll().is_some() {}
+    }
+}
+
+/// Exchange packets until neither endpoint has anything more to send
+fn drive(client: &mut Endpoint, server: &mut Endpoint) {
+    while deliver(client, client_addr(), server) | deliver(server, server_addr(), client) {}
+}
+
+fn server_addr() -> SocketAddr {
+    "[::1]:4433".parse().unwrap()
+}
+
+fn client_addr() -> SocketAddr {
+    "[::1]:44433".parse().unwrap()
+}
+
+/// Pass every packet `from` has to send to `to`, returning whether there were any
+fn deliver(from: &mut Endpoint, from_addr: SocketAddr, to: &mut Endpoint) -> bool {
+    let mut sent = false;
+    while let Some(io) = from.poll_io(0) {
+        if let Io::Transmit { packet, .. } = io {
+            to.handle(0, from_addr, packet[..].into());
+            sent = true;
+        }
+    }
+    sent
+}
+
+fn server_config() -> Config {
+    let certs = {
+        let f =
+            fs::File::open("../certs/server.chain").expect("cannot open '../certs/server.chain'");
+        let mut reader = io::BufReader::new(f);
+        pemfile::certs(&mut reader).expect("cannot read certificates")
+    };
+
+    let keys = {
+        let f = fs::File::open("../certs/server.rsa").expect("cannot open '../certs/server.rsa'");
+        let mut reader = io::BufReader::new(f);
+        pemfile::rsa_private_keys(&mut reader).expect("cannot read private keys")
+    };
+
+    let mut tls_server_config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    tls_server_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+    tls_server_config
+        .set_single_cert(certs, keys[0].clone())
+        .unwrap();
+    Config {
+        tls_server_config: Arc::new(tls_server_config),
+        ..Default::default()
+    }
+}
+
+fn client_config() -> Arc<ClientConfig> {
+    let mut f = fs::File::open("../certs/ca.der").expect("cannot open '../certs/ca.der'");
+    let mut bytes = Vec::new();
+    f.read_to_end(&mut bytes).expect("error while reading");
+
+    let anchor = webpki::trust_anchor_util::cert_der_as_trust_anchor(Input::from(&bytes)).unwrap();
+    let anchor_vec = vec![anchor];
+
+    let mut tls_client_config = ClientConfig::new();
+    tls_client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    tls_client_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+    tls_client_config
+        .root_store
+        .add_server_trust_anchors(&webpki::TLSServerTrustAnchors(&anchor_vec));
+    Arc::new(tls_client_config)
+}
+
+criterion_group!(benches, read_zeroed, read_bytes);
+criterion_main!(benches);
@@ -9,7 +9,7 @@ extern crate slog_term;
 extern crate webpki_roots;
 
 use std::io::{self, Write};
-use std::net::{SocketAddr, SocketAddrV6, ToSocketAddrs, UdpSocket};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
@@ -35,13 +35,6 @@ fn main() {
     ::std::process::exit(code);
 }
 
-fn normalize(x: SocketAddr) -> SocketAddrV6 {
-    match x {
-        SocketAddr::V6(x) => x,
-        SocketAddr::V4(x) => SocketAddrV6::new(x.ip().to_ipv6_mapped(), x.port(), 0, 0),
-    }
-}
-
 type Result<T> = ::std::result::Result<T, Error>;
 
 fn run(log: Logger) -> Result<()> {
@@ -58,7 +51,7 @@ struct Context {
     socket: UdpSocket,
     client: Endpoint,
     remote_host: String,
-    remote: SocketAddrV6,
+    remote: SocketAddr,
     loss_timer: Option<u64>,
     close_timer: Option<u64>,
     idle_timer: Option<u64>,
@@ -66,13 +59,15 @@ struct Context {
 
 impl Context {
     fn new(log: Logger, mut remote_host: String) -> Result<Self> {
-        let socket = UdpSocket::bind("[::]:0")?;
-        let remote = normalize(
-            remote_host
-                .to_socket_addrs()?
-                .next()
-                .ok_or(format_err!("couldn't resolve to an address"))?,
-        );
+        let remote = remote_host
+            .to_socket_addrs()?
+            .next()
+            .ok_or(format_err!("couldn't resolve to an address"))?;
+        let socket = UdpSocket::bind(if remote.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        })?;
         if let Some(x) = remote_host.rfind(':') {
             remote_host.truncate(x);
         }
@@ -100,7 +95,7 @@ impl Context {
         let config = Arc::new(config);
         let c = self
             .client
-            .connect(self.remote, &config, &self.remote_host)?;
+            .connect(0, self.remote, &config, &self.remote_host)?;
         let mut time = 0;
         let mut buf = Vec::new();
         let mut sent = 0;
@@ -110,14 +105,11 @@ impl Context {
                 match e {
                     Event::Connected { protocol, .. } => {
                         info!(self.log, "connected, submitting request"; "protocol" => protocol);
-                        let s = self
-                            .client
-                            .open(c, Directionality::Bi)
-                            .ok_or(format_err!("no streams available"))?;
+                        let s = self.client.open(c, Directionality::Bi)?;
                         self.client
                             .write(c, s, b"GET /index.html\r\n"[..].into())
                             .unwrap();
-                        self.client.finish(c, s);
+                        self.client.finish(c, s)?;
                     }
                     Event::ConnectionLost { reason, .. } => {
                         self.client.close(time, c, 0, b""[..].into());
@@ -225,8 +217,7 @@ impl Context {
             match r {
                 Ok((n, addr)) => {
                     recvd += 1;
-                    self.client
-                        .handle(time, normalize(addr), (&buf[0..n]).into());
+                    self.client.handle(time, addr, (&buf[0..n]).into());
                 }
                 Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
                     trace!(self.log, "timeout"; "type" => ?timer);
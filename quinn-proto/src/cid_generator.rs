@@ -0,0 +1,18 @@
+use packet::ConnectionId;
+
+/// Chooses the connection IDs an endpoint issues to its peers
+///
+/// Load balancers can route QUIC traffic by information a server embeds in the connection IDs it
+/// chooses, such as a server identifier. Generated IDs must be exactly `Config::local_cid_len` bytes
+/// long, and should otherwise be unpredictable so they can't be linked across connection migration.
+pub trait ConnectionIdGenerator {
+    /// Produce a connection ID. IDs that are already in use are discarded and generated again, a
+    /// limited number of times before the connection attempt is abandoned. An ID of the wrong
+    /// length abandons the attempt immediately.
+    fn generate(&mut self) -> ConnectionId;
+    /// Whether `cid` could have been produced by this generator
+    ///
+    /// Short-header packets for unknown connections whose IDs fail this check are assumed to have
+    /// been misrouted, and are dropped without sending a stateless reset.
+    fn validate(&self, cid: &ConnectionId) -> bool;
+}
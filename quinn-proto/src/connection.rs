@@ -1,27 +1,50 @@
+use std::any::Any;
 use std::collections::{hash_map, BTreeMap, VecDeque};
-use std::net::SocketAddrV6;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::{cmp, io, mem};
 
 use bytes::{Buf, Bytes, BytesMut};
+use constant_time_eq::constant_time_eq;
 use fnv::{FnvHashMap, FnvHashSet};
+use rand::Rng;
 use slog::Logger;
 
 use coding::{BufExt, BufMutExt};
-use crypto::{self, reset_token_for, Crypto, TLSError, TlsSession, ACK_DELAY_EXPONENT};
+use crypto::{self, reset_token_for, Crypto, TLSError, TlsSession};
 use endpoint::{Config, Context, Event, Io, Timer};
 use packet::{
     set_payload_length, ConnectionId, Header, LongType, Packet, PacketNumber, PartialDecode,
     AEAD_TAG_SIZE,
 };
+#[cfg(feature = "qlog")]
+use qlog;
 use range_set::RangeSet;
-use stream::{self, ReadError, Stream, WriteError};
+use stream::{self, FinishError, InvalidStreamError, OpenError, ReadError, Stream, WriteError};
 use transport_parameters::{self, TransportParameters};
 use {
-    frame, Directionality, Frame, Side, StreamId, TransportError, MIN_INITIAL_SIZE, MIN_MTU,
-    VERSION,
+    frame, grease_version, Directionality, Frame, Side, StreamId, TransportError, MAX_IDLE_TIMEOUT,
+    MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE,
 };
 
+/// Record an event in the qlog output, if configured
+///
+/// The event's data is given in `json!` syntax, and is only evaluated when qlog support is enabled
+/// and a sink is configured.
+#[cfg(feature = "qlog")]
+macro_rules! qlog {
+    ($conn:expr, $config:expr, $now:expr, $category:expr, $event:expr, $($data:tt)+) => {
+        if let Some(ref sink) = $config.qlog {
+            sink.emit($now, $conn.handle, $category, $event, json!($($data)+));
+        }
+    };
+}
+
+#[cfg(not(feature = "qlog"))]
+macro_rules! qlog {
+    ($($x:tt)*) => {};
+}
+
 pub struct Connection {
     log: Logger,
     pub tls: TlsSession,
@@ -30,30 +53,68 @@ pub struct Connection {
     pub init_cid: ConnectionId,
     pub loc_cid: ConnectionId,
     pub rem_cid: ConnectionId,
-    pub remote: SocketAddrV6,
+    pub remote: SocketAddr,
     pub state: Option<State>,
     pub side: Side,
+    /// QUIC version in use
+    pub version: u32,
     pub handle: ConnectionHandle,
     pub mtu: u16,
-    pub rx_packet: u64,
-    pub rx_packet_time: u64,
     pub crypto: Option<Crypto>,
     pub prev_crypto: Option<(u64, Crypto)>,
+    /// Time after which `prev_crypto` is discarded, once the peer has confirmed the update
+    prev_crypto_expiry: Option<u64>,
+    /// Largest 1-RTT packet number we sent under `prev_crypto`
+    prev_crypto_sent: u64,
     //pub zero_rtt_crypto: Option<Crypto>,
     pub key_phase: bool,
+    /// Whether this connection takes part in the latency spin bit
+    pub spin_enabled: bool,
+    /// Latency spin bit to send in short-header packets
+    pub spin: bool,
     pub params: TransportParameters,
+    /// Stateless reset tokens for the alternate CIDs the peer issued via NEW_CONNECTION_ID
+    rem_reset_tokens: Vec<[u8; RESET_TOKEN_SIZE]>,
     /// Streams with data buffered for reading by the application
     readable_streams: FnvHashSet<StreamId>,
     /// Streams on which writing was blocked on *connection-level* flow or congestion control
     pub blocked_streams: FnvHashSet<StreamId>,
+    /// Streams with gaps in their received data, and when each gap was first observed. `None`
+    /// once the stall has been reported.
+    stalled_streams: FnvHashMap<StreamId, Option<u64>>,
     /// Limit on outgoing data, dictated by peer
     pub max_data: u64,
+    /// Number of the packet whose MAX_DATA set `max_data`, or `None` if the transport parameters
+    /// did
+    max_data_pn: Option<u64>,
     pub data_sent: u64,
+    /// Connection-level limit at which we last told the peer we were blocked
+    data_blocked_at: Option<u64>,
     /// Sum of end offsets of all streams. Includes gaps, so it's an upper bound.
     pub data_recvd: u64,
     /// Limit on incoming data
     pub local_max_data: u64,
+    /// Value of `local_max_data` most recently sent to the peer
+    pub advertised_max_data: u64,
+    /// Total size of all UDP payloads sent, including headers, padding, and retransmissions
+    pub wire_bytes_sent: u64,
+    /// Total size of all UDP payloads received
+    pub wire_bytes_recvd: u64,
+    /// Whether the peer is known to receive packets at the address it claims
+    ///
+    /// Until then, a server sends no more than three times as much as it has received.
+    pub address_validated: bool,
+    /// Number of writes rejected due to connection-level flow control
+    pub flow_control_blocked_writes: u64,
+    /// Number of packets declared lost
+    pub lost_packets: u64,
+    /// Time at which the connection was created
+    pub created_at: u64,
+    /// Time at which the handshake completed, if it has
+    pub established_at: Option<u64>,
     client_config: Option<ClientConfig>,
+    /// Arbitrary data associated with the connection by the application
+    pub user_data: Option<Box<Any + Send>>,
 
     //
     // Loss Detection
@@ -67,9 +128,6 @@ pub struct Connection {
     /// The largest packet number gap between the largest acked retransmittable packet and an
     /// unacknowledged retransmittable packet before it is declared lost.
     pub reordering_threshold: u32,
-    /// The time at which the next packet will be considered lost based on early transmit or
-    /// exceeding the reordering window in time.
-    pub loss_time: u64,
     /// The most recent RTT measurement made when receiving an ack for a previously unacked packet.
     /// μs
     pub latest_rtt: u64,
@@ -84,18 +142,14 @@ pub struct Connection {
     /// Excludes ack delays for ack only packets and those that create an RTT sample less than
     /// min_rtt.
     pub max_ack_delay: u64,
-    /// The last packet number sent prior to the first retransmission timeout.
+    /// The last 1-RTT packet number sent prior to the first retransmission timeout.
     pub largest_sent_before_rto: u64,
     /// The time the most recently sent retransmittable packet was sent.
     pub time_of_last_sent_retransmittable_packet: u64,
     /// The time the most recently sent handshake packet was sent.
     pub time_of_last_sent_handshake_packet: u64,
-    /// The packet number of the most recently sent packet.
-    pub largest_sent_packet: u64,
-    /// The largest packet number the remote peer acknowledged in an ACK frame.
-    pub largest_acked_packet: u64,
-    /// Transmitted but not acked
-    pub sent_packets: BTreeMap<u64, SentPacket>,
+    /// Per-space packet numbering, acknowledgement, and loss detection state, indexed by `SpaceId`
+    pub spaces: [PacketSpace; 3],
 
     //
     // Congestion Control
@@ -109,9 +163,10 @@ pub struct Connection {
     pub bytes_in_flight: u64,
     /// Maximum number of bytes in flight that may be sent.
     pub congestion_window: u64,
-    /// The largest packet number sent when QUIC detects a loss. When a larger packet is
-    /// acknowledged, QUIC exits recovery.
-    pub end_of_recovery: u64,
+    /// The time at which QUIC last detected a loss. When a packet sent after this is
+    /// acknowledged, QUIC exits recovery. Times are comparable across packet number spaces, unlike
+    /// packet numbers.
+    pub end_of_recovery: Option<u64>,
     /// Slow start threshold in bytes. When the congestion window is below ssthresh, the mode is
     /// slow start and the window grows by the number of bytes acknowledged.
     pub ssthresh: u64,
@@ -130,18 +185,44 @@ pub struct Connection {
     // Transmit queue
     //
     pub pending: Retransmits,
-    pub pending_acks: RangeSet,
-    /// Set iff we have received a non-ack frame since the last ack-only packet we sent
-    pub permit_ack_only: bool,
+    /// Number of ack-eliciting protected packets received since we last sent an ACK
+    pub ack_eliciting_since_ack: u32,
+    /// Set iff an ACK should be sent even if there's nothing else to send
+    pub ack_due: bool,
+    /// Start of the current period, one RTT long, over which PATH_RESPONSEs are rate limited
+    pub path_response_period: u64,
+    /// Number of PATH_RESPONSEs queued since `path_response_period`
+    pub path_responses_sent: u32,
+    /// The address we're probing before migrating to it, if any
+    pub path_challenge: Option<PathChallenge>,
+    /// Tokens of the most recent challenges answered or abandoned, to which late responses are
+    /// tolerated
+    pub past_path_tokens: VecDeque<u64>,
+    /// Short-header packets that arrived before the 1-RTT keys, to be processed once we have them
+    early_packets: VecDeque<(SocketAddr, PartialDecode)>,
 
     // Timer updates: None if no change, Some(None) to stop, Some(Some(_)) to reset
     pub set_idle: Option<Option<u64>>,
     pub set_loss_detection: Option<Option<u64>>,
+    pub set_delayed_ack: Option<Option<u64>>,
+    pub set_keep_alive: Option<Option<u64>>,
+    /// Time at which the loss detection timer is currently armed to fire, if at all
+    pub loss_detection_time: Option<u64>,
+    /// Time at which the idle timer is currently armed to fire, if at all
+    pub idle_time: Option<u64>,
+    /// Time at which the delayed ACK timer is currently armed to fire, if at all
+    pub delayed_ack_time: Option<u64>,
+    /// Time at which the close timer is currently armed to fire, if at all
+    pub close_time: Option<u64>,
+    /// Time at which the keep-alive timer is currently armed to fire, if at all
+    pub keep_alive_time: Option<u64>,
 
     //
     // Stream states
     //
     streams: Streams,
+    /// The stream most recently included in an outgoing packet, for round-robin scheduling
+    stream_cursor: StreamId,
 }
 
 impl Connection {
@@ -150,18 +231,21 @@ impl Connection {
         init_cid: ConnectionId,
         loc_cid: ConnectionId,
         rem_cid: ConnectionId,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
+        version: u32,
         client_config: Option<ClientConfig>,
         tls: TlsSession,
         ctx: &mut Context,
         handle: ConnectionHandle,
+        now: u64,
     ) -> Self {
         let side = if client_config.is_some() {
             Side::Client
         } else {
             Side::Server
         };
-        let handshake_crypto = Crypto::new_initial(&init_cid, side);
+        let handshake_crypto =
+            Crypto::new_initial(&ctx.config.packet_protection, version, &init_cid, side);
         let mut streams = FnvHashMap::default();
         for i in 0..ctx.config.max_remote_uni_streams {
             streams.insert(
@@ -196,23 +280,39 @@ impl Connection {
             rem_cid,
             remote,
             side,
+            version,
             handle,
             state: None,
             mtu: MIN_MTU,
-            rx_packet: 0,
-            rx_packet_time: 0,
             crypto: None,
             prev_crypto: None,
+            prev_crypto_expiry: None,
+            prev_crypto_sent: 0,
             //zero_rtt_crypto: None,
             key_phase: false,
+            spin_enabled: ctx.rng.gen_range(0, SPIN_DISABLED_ONE_IN) != 0,
+            spin: false,
             params: TransportParameters::new(&ctx.config),
+            rem_reset_tokens: Vec::new(),
             readable_streams: FnvHashSet::default(),
             blocked_streams: FnvHashSet::default(),
+            stalled_streams: FnvHashMap::default(),
             max_data: 0,
+            max_data_pn: None,
             data_sent: 0,
+            data_blocked_at: None,
             data_recvd: 0,
             local_max_data: ctx.config.receive_window as u64,
+            advertised_max_data: ctx.config.receive_window as u64,
+            wire_bytes_sent: 0,
+            wire_bytes_recvd: 0,
+            address_validated: side == Side::Client,
+            flow_control_blocked_writes: 0,
+            lost_packets: 0,
+            created_at: now,
+            established_at: None,
             client_config,
+            user_data: None,
 
             handshake_count: 0,
             tlp_count: 0,
@@ -222,7 +322,6 @@ impl Connection {
             } else {
                 ctx.config.reordering_threshold
             },
-            loss_time: 0,
             latest_rtt: 0,
             smoothed_rtt: 0,
             rttvar: 0,
@@ -231,13 +330,11 @@ impl Connection {
             largest_sent_before_rto: 0,
             time_of_last_sent_retransmittable_packet: 0,
             time_of_last_sent_handshake_packet: 0,
-            largest_sent_packet: 0,
-            largest_acked_packet: 0,
-            sent_packets: BTreeMap::new(),
+            spaces: [PacketSpace::new(), PacketSpace::new(), PacketSpace::new()],
 
             bytes_in_flight: 0,
             congestion_window: ctx.config.initial_window,
-            end_of_recovery: 0,
+            end_of_recovery: None,
             ssthresh: u64::max_value(),
 
             awaiting_handshake: false,
@@ -245,11 +342,23 @@ impl Connection {
             handshake_crypto,
 
             pending: Retransmits::default(),
-            pending_acks: RangeSet::new(),
-            permit_ack_only: false,
+            ack_eliciting_since_ack: 0,
+            ack_due: false,
+            path_response_period: 0,
+            path_responses_sent: 0,
+            path_challenge: None,
+            past_path_tokens: VecDeque::new(),
+            early_packets: VecDeque::new(),
 
             set_idle: None,
             set_loss_detection: None,
+            set_delayed_ack: None,
+            set_keep_alive: None,
+            loss_detection_time: None,
+            idle_time: None,
+            delayed_ack_time: None,
+            close_time: None,
+            keep_alive_time: None,
 
             streams: Streams {
                 streams,
@@ -262,8 +371,15 @@ impl Connection {
                 max_bi: 0,
                 max_remote_uni: ctx.config.max_remote_uni_streams as u64,
                 max_remote_bi: max_remote_bi_streams,
+                next_remote_uni: 0,
+                next_remote_bi: 0,
+                extra_remote_uni: 0,
+                extra_remote_bi: 0,
+                uni_blocked_at: None,
+                bi_blocked_at: None,
                 finished: Vec::new(),
             },
+            stream_cursor: StreamId(0),
         };
         match side {
             Side::Client => {
@@ -295,7 +411,8 @@ impl Connection {
         packet_number: u64,
     ) {
         //self.zero_rtt_crypto = zero_rtt_crypto;
-        self.on_packet_authenticated(ctx, now, packet_number);
+        self.on_packet_authenticated(ctx, now, SpaceId::Initial, packet_number);
+        self.spaces[SpaceId::Initial as usize].permit_ack_only = true;
         let mut outgoing = Vec::new();
         self.tls.write_tls(&mut outgoing).unwrap();
         self.transmit_handshake(&outgoing);
@@ -304,32 +421,45 @@ impl Connection {
             rem_cid_set: true,
             token: None,
         }));
-        self.set_params(params);
+        self.set_params(&ctx.config, params);
         ctx.dirty_conns.insert(self.handle);
         ctx.incoming_handshakes += 1;
     }
 
-    fn get_tx_number(&mut self) -> u64 {
-        self.largest_sent_packet = self.largest_sent_packet.overflowing_add(1).0;
+    fn get_tx_number(&mut self, space: SpaceId) -> u64 {
+        let space = &mut self.spaces[space as usize];
+        space.largest_sent_packet = space.largest_sent_packet.overflowing_add(1).0;
         // TODO: Handle packet number overflow gracefully
-        assert!(self.largest_sent_packet < 2u64.pow(62));
-        self.largest_sent_packet
+        assert!(space.largest_sent_packet < 2u64.pow(62));
+        space.largest_sent_packet
     }
 
     fn on_packet_sent(
         &mut self,
         config: &Config,
         now: u64,
+        space: SpaceId,
         packet_number: u64,
         packet: SentPacket,
     ) {
-        self.largest_sent_packet = packet_number;
+        let handshake = space != SpaceId::Data;
+        qlog!(self, config, now, "transport", "packet_sent", {
+            "packet_number": packet_number,
+            "packet_type": qlog::packet_type(space),
+            "size": packet.bytes
+        });
+        if let Some(ref observer) = config.observer {
+            observer.packet_sent(self.handle, packet_number, packet.bytes);
+        }
         let bytes = packet.bytes;
-        let handshake = packet.handshake;
-        if handshake {
+        if handshake && bytes != 0 {
             self.awaiting_handshake = true;
         }
-        self.sent_packets.insert(packet_number, packet);
+        {
+            let space = &mut self.spaces[space as usize];
+            space.largest_sent_packet = packet_number;
+            space.sent_packets.insert(packet_number, packet);
+        }
         if bytes != 0 {
             self.time_of_last_sent_retransmittable_packet = now;
             if handshake {
@@ -337,43 +467,98 @@ impl Connection {
             }
             self.bytes_in_flight += bytes as u64;
             self.set_loss_detection_alarm(config);
+            if config.keep_alive_interval != 0 {
+                let interval = u64::from(config.keep_alive_interval) * 1_000_000;
+                self.set_keep_alive = Some(Some(now + interval));
+            }
         }
     }
 
-    fn on_ack_received(&mut self, ctx: &mut Context, now: u64, ack: frame::Ack) {
-        trace!(self.log, "got ack"; "ranges" => ?ack.iter().collect::<Vec<_>>());
+    fn on_ack_received(
+        &mut self,
+        ctx: &mut Context,
+        now: u64,
+        space: SpaceId,
+        ack: frame::Ack,
+    ) -> Result<(), TransportError> {
+        trace!(self.log, "got ack"; "space" => ?space, "ranges" => ?ack.iter().collect::<Vec<_>>());
+        if ack.largest > self.spaces[space as usize].largest_sent_packet {
+            debug!(self.log, "peer acknowledged an unsent packet"; "largest" => ack.largest);
+            return Err(TransportError::PROTOCOL_VIOLATION);
+        }
         let was_blocked = self.blocked();
-        // TODO: Validate
-        self.largest_acked_packet = cmp::max(self.largest_acked_packet, ack.largest);
-        if let Some(info) = self.sent_packets.get(&ack.largest).cloned() {
-            self.latest_rtt = now - info.time;
+        let sample = {
+            let space = &mut self.spaces[space as usize];
+            space.largest_acked_packet = cmp::max(space.largest_acked_packet, ack.largest);
+            space
+                .sent_packets
+                .get(&ack.largest)
+                .map(|info| (info.time, info.ack_only()))
+        };
+        if let Some((time, ack_only)) = sample {
+            // Saturate rather than underflow if the clock went backwards
+            self.latest_rtt = now.saturating_sub(time);
             let delay = ack.delay << self.params.ack_delay_exponent;
-            self.update_rtt(delay, info.ack_only());
+            self.update_rtt(&ctx.config, delay, ack_only);
+            if ctx.config.rtt_events {
+                ctx.events.push_back((
+                    self.handle,
+                    Event::RttUpdated {
+                        latest: self.latest_rtt,
+                        smoothed: self.smoothed_rtt,
+                    },
+                ));
+            }
         }
         for range in &ack {
             // Avoid DoS from unreasonably huge ack ranges
-            let packets = self
+            let packets = self.spaces[space as usize]
                 .sent_packets
                 .range(range)
                 .map(|(&n, _)| n)
                 .collect::<Vec<_>>();
             for packet in packets {
-                self.on_packet_acked(&ctx.config, packet);
+                self.on_packet_acked(&ctx.config, space, packet);
             }
         }
-        self.detect_lost_packets(&ctx.config, now, ack.largest);
+        self.detect_lost_packets(&ctx.config, now, space, ack.largest);
         self.set_loss_detection_alarm(&ctx.config);
+        qlog!(self, ctx.config, now, "recovery", "metrics_updated", {
+            "latest_rtt": self.latest_rtt,
+            "smoothed_rtt": self.smoothed_rtt,
+            "min_rtt": self.min_rtt,
+            "congestion_window": self.congestion_window,
+            "bytes_in_flight": self.bytes_in_flight
+        });
         if was_blocked && !self.blocked() {
-            for stream in self.blocked_streams.drain() {
+            self.wake_blocked_streams(ctx);
+        }
+        Ok(())
+    }
+
+    /// Notify the application that streams blocked at the connection level may be written to
+    ///
+    /// Streams that are also out of stream-level credit are left for MAX_STREAM_DATA to wake.
+    fn wake_blocked_streams(&mut self, ctx: &mut Context) {
+        for stream in self.blocked_streams.drain() {
+            let writable = self
+                .streams
+                .streams
+                .get(&stream)
+                .and_then(|s| s.send())
+                .map_or(false, |ss| ss.offset < ss.max_data);
+            if writable {
                 ctx.events
                     .push_back((self.handle, Event::StreamWritable { stream }));
             }
         }
     }
 
-    fn update_rtt(&mut self, ack_delay: u64, ack_only: bool) {
+    fn update_rtt(&mut self, config: &Config, ack_delay: u64, ack_only: bool) {
         self.min_rtt = cmp::min(self.min_rtt, self.latest_rtt);
-        if self.latest_rtt - self.min_rtt > ack_delay {
+        // Only subtract ack delay when doing so can't produce a sample below min_rtt, so that an
+        // inflated delay from the peer can't drive latest_rtt towards zero or underflow it
+        if self.latest_rtt.saturating_sub(self.min_rtt) > ack_delay {
             self.latest_rtt -= ack_delay;
             if !ack_only {
                 self.max_ack_delay = cmp::max(self.max_ack_delay, ack_delay);
@@ -387,21 +572,27 @@ impl Connection {
             self.rttvar = (3 * self.rttvar + rttvar_sample) / 4;
             self.smoothed_rtt = (7 * self.smoothed_rtt + self.latest_rtt) / 8;
         }
+        if let Some(ref observer) = config.observer {
+            observer.rtt_sampled(self.handle, self.latest_rtt, self.smoothed_rtt);
+        }
     }
 
     // Not timing-aware, so it's safe to call this for inferred acks, such as arise from
     // high-latency handshakes
-    fn on_packet_acked(&mut self, config: &Config, packet: u64) {
-        let info = if let Some(x) = self.sent_packets.remove(&packet) {
+    fn on_packet_acked(&mut self, config: &Config, space: SpaceId, packet: u64) {
+        let info = if let Some(x) = self.spaces[space as usize].sent_packets.remove(&packet) {
             x
         } else {
             return;
         };
+        if let Some(ref observer) = config.observer {
+            observer.packet_acked(self.handle, packet);
+        }
         if info.bytes != 0 {
             // Congestion control
             self.bytes_in_flight -= info.bytes as u64;
             // Do not increase congestion window in recovery period.
-            if !self.in_recovery(packet) {
+            if !self.in_recovery(info.time) {
                 if self.congestion_window < self.ssthresh {
                     // Slow start.
                     self.congestion_window += info.bytes as u64;
@@ -417,7 +608,7 @@ impl Connection {
 
         // If a packet sent prior to RTO was acked, then the RTO was spurious. Otherwise, inform
         // congestion control.
-        if self.rto_count > 0 && packet > self.largest_sent_before_rto {
+        if space == SpaceId::Data && self.rto_count > 0 && packet > self.largest_sent_before_rto {
             // Retransmission timeout verified
             self.congestion_window = config.minimum_window;
         }
@@ -434,7 +625,7 @@ impl Connection {
                 self.streams.get_send_mut(&id).unwrap().state =
                     stream::SendState::ResetRecvd { stop_reason };
                 if stop_reason.is_none() {
-                    self.maybe_cleanup(config, id);
+                    self.maybe_cleanup(id);
                 }
             }
         }
@@ -454,57 +645,89 @@ impl Connection {
                 }
             };
             if recvd {
-                self.maybe_cleanup(config, frame.id);
+                self.maybe_cleanup(frame.id);
                 self.streams.finished.push(frame.id);
             }
         }
-        self.pending_acks.subtract(&info.acks);
+        // The peer has seen our acknowledgement of these. Ranges left out of a truncated ACK, and
+        // packets that arrived out of order since, still need to be acknowledged.
+        self.spaces[space as usize]
+            .pending_acks
+            .subtract(&info.acks);
     }
 
     pub fn check_packet_loss(&mut self, ctx: &mut Context, now: u64) {
+        if self.awaiting_handshake && self.handshake_unconfirmed(&ctx.config) {
+            debug!(self.log, "server never confirmed handshake completion");
+            self.close_common(ctx, now);
+            self.state = Some(State::Draining);
+            ctx.events.push_back((
+                self.handle,
+                Event::ConnectionLost {
+                    reason: ConnectionError::HandshakeUnconfirmed,
+                },
+            ));
+            ctx.dirty_conns.insert(self.handle);
+            return;
+        }
+        if self.handshake_timed_out(&ctx.config) {
+            debug!(self.log, "handshake timed out");
+            if self.side == Side::Server {
+                ctx.incoming_handshakes -= 1;
+            }
+            self.close_common(ctx, now);
+            self.state = Some(State::Draining);
+            ctx.events.push_back((
+                self.handle,
+                Event::ConnectionLost {
+                    reason: ConnectionError::TimedOut,
+                },
+            ));
+            ctx.dirty_conns.insert(self.handle);
+            return;
+        }
         if self.awaiting_handshake {
             trace!(self.log, "retransmitting handshake packets");
-            let packets = self
-                .sent_packets
-                .iter()
-                .filter_map(|(&packet, info)| if info.handshake { Some(packet) } else { None })
-                .collect::<Vec<_>>();
-            for number in packets {
-                let mut info = self.sent_packets.remove(&number).unwrap();
-                self.handshake_pending += info.retransmits;
-                self.bytes_in_flight -= info.bytes as u64;
+            for &space in &[SpaceId::Initial, SpaceId::Handshake] {
+                let packets = mem::replace(
+                    &mut self.spaces[space as usize].sent_packets,
+                    BTreeMap::new(),
+                );
+                for (_, info) in packets {
+                    self.handshake_pending += info.retransmits;
+                    self.bytes_in_flight -= info.bytes as u64;
+                }
             }
             self.handshake_count += 1;
-        } else if self.loss_time != 0 {
+        } else if let Some((space, _)) = self.earliest_loss_time() {
             // Early retransmit or Time Loss Detection
-            let largest = self.largest_acked_packet;
-            self.detect_lost_packets(&ctx.config, now, largest);
+            let largest = self.spaces[space as usize].largest_acked_packet;
+            self.detect_lost_packets(&ctx.config, now, space, largest);
         } else if self.tlp_count < ctx.config.max_tlps {
             trace!(self.log, "sending TLP {number} in {pn}",
                            number=self.tlp_count,
-                           pn=self.largest_sent_packet + 1;
-                           "outstanding" => ?self.sent_packets.keys().collect::<Vec<_>>(),
+                           pn=self.spaces[SpaceId::Data as usize].largest_sent_packet + 1;
+                           "outstanding" => ?self.spaces[SpaceId::Data as usize].sent_packets.keys().collect::<Vec<_>>(),
                            "in flight" => self.bytes_in_flight);
             // Tail Loss Probe.
-            ctx.io.push_back(Io::Transmit {
-                destination: self.remote,
-                packet: self.force_transmit(&ctx.config, now),
-            });
+            let packet = self.force_transmit(&ctx.config, now);
+            let remote = self.remote;
+            self.queue_datagram(ctx, remote, packet);
             self.reset_idle_timeout(&ctx.config, now);
             self.tlp_count += 1;
         } else {
-            trace!(self.log, "RTO fired, retransmitting"; "pn" => self.largest_sent_packet + 1,
-                           "outstanding" => ?self.sent_packets.keys().collect::<Vec<_>>(),
+            trace!(self.log, "RTO fired, retransmitting"; "pn" => self.spaces[SpaceId::Data as usize].largest_sent_packet + 1,
+                           "outstanding" => ?self.spaces[SpaceId::Data as usize].sent_packets.keys().collect::<Vec<_>>(),
                            "in flight" => self.bytes_in_flight);
             // RTO
             if self.rto_count == 0 {
-                self.largest_sent_before_rto = self.largest_sent_packet;
+                self.largest_sent_before_rto =
+                    self.spaces[SpaceId::Data as usize].largest_sent_packet;
             }
             for _ in 0..2 {
-                ctx.io.push_back(Io::Transmit {
-                    destination: self.remote,
-                    packet: self.force_transmit(&ctx.config, now),
-                });
+                let packet = self.force_transmit(&ctx.config, now);
+                let remote = self.remote;
+                self.queue_datagram(ctx, remote, packet);
             }
             self.reset_idle_timeout(&ctx.config, now);
             self.rto_count += 1;
@@ -513,60 +736,140 @@ impl Connection {
         ctx.dirty_conns.insert(self.handle);
     }
 
-    fn detect_lost_packets(&mut self, config: &Config, now: u64, largest_acked: u64) {
-        self.loss_time = 0;
+    /// Whether we've completed the handshake but given up waiting for the server to confirm it
+    fn handshake_unconfirmed(&self, config: &Config) -> bool {
+        let established = match *self.state.as_ref().unwrap() {
+            State::Established => true,
+            _ => false,
+        };
+        established
+            && config.max_handshake_confirmation_retransmits != 0
+            && self.handshake_count >= config.max_handshake_confirmation_retransmits
+    }
+
+    /// Whether we've given up on a handshake the peer hasn't responded to
+    fn handshake_timed_out(&self, config: &Config) -> bool {
+        self.state.as_ref().unwrap().is_handshake()
+            && config.max_handshake_retransmits != 0
+            && self.handshake_count >= config.max_handshake_retransmits
+    }
+
+    fn detect_lost_packets(
+        &mut self,
+        config: &Config,
+        now: u64,
+        space_id: SpaceId,
+        largest_acked: u64,
+    ) {
         let mut lost_packets = Vec::<u64>::new();
         let delay_until_lost;
         let rtt = cmp::max(self.latest_rtt, self.smoothed_rtt);
-        if config.using_time_loss_detection {
-            // factor * (1 + fraction)
-            delay_until_lost = (rtt + (rtt * config.time_reordering_fraction as u64)) >> 16;
-        } else if largest_acked == self.largest_sent_packet {
-            // Early retransmit alarm.
-            delay_until_lost = (5 * rtt) / 4;
-        } else {
-            delay_until_lost = u64::max_value();
-        }
-        for (&packet, info) in self.sent_packets.range(0..largest_acked) {
-            let time_since_sent = now - info.time;
-            let delta = largest_acked - packet;
-            // Use of >= for time comparison here is critical so that we successfully detect lost
-            // packets in testing when rtt = 0
-            if time_since_sent >= delay_until_lost || delta > self.reordering_threshold as u64 {
-                lost_packets.push(packet);
-            } else if self.loss_time == 0 && delay_until_lost != u64::max_value() {
-                self.loss_time = now + delay_until_lost - time_since_sent;
+        let reordering_threshold = self.reordering_threshold as u64;
+        {
+            let space = &mut self.spaces[space_id as usize];
+            space.loss_time = 0;
+            if config.using_time_loss_detection {
+                // factor * (1 + fraction)
+                delay_until_lost = (rtt + (rtt * config.time_reordering_fraction as u64)) >> 16;
+            } else if largest_acked == space.largest_sent_packet {
+                // Early retransmit alarm.
+                delay_until_lost = (5 * rtt) / 4;
+            } else {
+                delay_until_lost = u64::max_value();
+            }
+            for (&packet, info) in space.sent_packets.range(0..largest_acked) {
+                // Coarse timers can report a time slightly before a packet was sent. Treat that as
+                // no time having passed rather than letting the subtraction wrap and declare the
+                // packet lost.
+                debug_assert!(
+                    now + MAX_CLOCK_LAG >= info.time,
+                    "clock lags packet send time by more than timer coarseness explains"
+                );
+                let time_since_sent = now.saturating_sub(info.time);
+                let delta = largest_acked - packet;
+                // Use of >= for time comparison here is critical so that we successfully detect
+                // lost packets in testing when rtt = 0
+                if time_since_sent >= delay_until_lost || delta > reordering_threshold {
+                    lost_packets.push(packet);
+                } else if space.loss_time == 0 && delay_until_lost != u64::max_value() {
+                    space.loss_time = now + delay_until_lost - time_since_sent;
+                }
             }
         }
 
         if let Some(largest_lost) = lost_packets.last().cloned() {
+            self.lost_packets += lost_packets.len() as u64;
+            let largest_lost_time = self.spaces[space_id as usize].sent_packets[&largest_lost].time;
             let old_bytes_in_flight = self.bytes_in_flight;
             for packet in lost_packets {
-                let mut info = self.sent_packets.remove(&packet).unwrap();
-                if info.handshake {
-                    self.handshake_pending += info.retransmits;
-                } else {
+                qlog!(self, config, now, "recovery", "packet_lost", {
+                    "packet_type": qlog::packet_type(space_id),
+                    "packet_number": packet
+                });
+                if let Some(ref observer) = config.observer {
+                    observer.packet_lost(self.handle, packet);
+                }
+                let info = self.spaces[space_id as usize]
+                    .sent_packets
+                    .remove(&packet)
+                    .unwrap();
+                if space_id == SpaceId::Data {
                     self.pending += info.retransmits;
+                } else {
+                    self.handshake_pending += info.retransmits;
                 }
                 self.bytes_in_flight -= info.bytes as u64;
             }
             // Don't apply congestion penalty for lost ack-only packets
             let lost_nonack = old_bytes_in_flight != self.bytes_in_flight;
-            // Start a new recovery epoch if the lost packet is larger than the end of the
-            // previous recovery epoch.
-            if lost_nonack && !self.in_recovery(largest_lost) {
-                self.end_of_recovery = self.largest_sent_packet;
+            // Start a new recovery epoch if the lost packet was sent after the previous recovery
+            // epoch began.
+            if lost_nonack && !self.in_recovery(largest_lost_time) {
+                self.end_of_recovery = Some(now);
                 // *= factor
                 self.congestion_window =
                     (self.congestion_window * config.loss_reduction_factor as u64) >> 16;
                 self.congestion_window = cmp::max(self.congestion_window, config.minimum_window);
                 self.ssthresh = self.congestion_window;
+                if let Some(ref observer) = config.observer {
+                    observer.congestion_event(self.handle, self.congestion_window);
+                }
             }
         }
+        self.retire_prev_crypto(now);
     }
 
-    fn in_recovery(&self, packet: u64) -> bool {
-        packet <= self.end_of_recovery
+    /// Discard the previous generation of 1-RTT keys once their retention period has elapsed and
+    /// every packet we sent under them has been acknowledged or declared lost
+    fn retire_prev_crypto(&mut self, now: u64) {
+        match self.prev_crypto_expiry {
+            Some(expiry) if now >= expiry => {}
+            _ => return,
+        }
+        let in_flight = self.spaces[SpaceId::Data as usize]
+            .sent_packets
+            .range(..=self.prev_crypto_sent)
+            .any(|(_, info)| !info.ack_only());
+        if !in_flight {
+            trace!(self.log, "discarding previous 1-RTT keys");
+            self.prev_crypto = None;
+            self.prev_crypto_expiry = None;
+        }
+    }
+
+    /// Whether a packet sent at `sent_time`, in any space, belongs to the current recovery epoch
+    fn in_recovery(&self, sent_time: u64) -> bool {
+        self.end_of_recovery.map_or(false, |t| sent_time <= t)
+    }
+
+    /// The space whose time-based loss detection is due soonest, and when
+    fn earliest_loss_time(&self) -> Option<(SpaceId, u64)> {
+        SpaceId::VALUES
+            .iter()
+            .filter_map(|&space| match self.spaces[space as usize].loss_time {
+                0 => None,
+                time => Some((space, time)),
+            }).min_by_key(|&(_, time)| time)
     }
 
     fn set_loss_detection_alarm(&mut self, config: &Config) {
@@ -591,20 +894,22 @@ impl Connection {
             return;
         }
 
-        if self.loss_time != 0 {
-            // Early retransmit timer or time loss detection.
-            alarm_duration = self.loss_time - self.time_of_last_sent_retransmittable_packet;
-        } else {
-            // TLP or RTO alarm
-            alarm_duration = self.rto(config);
-            if self.tlp_count < config.max_tlps {
-                // Tail Loss Probe
-                let tlp_duration = cmp::max(
-                    (3 * self.smoothed_rtt) / 2 + self.max_ack_delay,
-                    config.min_tlp_timeout,
-                );
-                alarm_duration = cmp::min(alarm_duration, tlp_duration);
-            }
+        if let Some((_, loss_time)) = self.earliest_loss_time() {
+            // Early retransmit timer or time loss detection. loss_time is derived from the send
+            // time of an older packet, so it may precede the last retransmittable packet.
+            self.set_loss_detection = Some(Some(loss_time));
+            return;
+        }
+
+        // TLP or RTO alarm
+        alarm_duration = self.rto(config);
+        if self.tlp_count < config.max_tlps {
+            // Tail Loss Probe
+            let tlp_duration = cmp::max(
+                (3 * self.smoothed_rtt) / 2 + self.max_ack_delay(),
+                config.min_tlp_timeout,
+            );
+            alarm_duration = cmp::min(alarm_duration, tlp_duration);
         }
         self.set_loss_detection = Some(Some(
             self.time_of_last_sent_retransmittable_packet + alarm_duration,
@@ -613,30 +918,70 @@ impl Connection {
 
     /// Retransmit time-out
     fn rto(&self, config: &Config) -> u64 {
-        let computed = self.smoothed_rtt + 4 * self.rttvar + self.max_ack_delay;
+        let computed = self.smoothed_rtt + 4 * self.rttvar + self.max_ack_delay();
         cmp::max(computed, config.min_rto_timeout) * 2u64.pow(self.rto_count)
     }
 
-    fn on_packet_authenticated(&mut self, ctx: &mut Context, now: u64, packet: u64) {
-        trace!(self.log, "packet authenticated"; "pn" => packet);
+    /// Longest the peer may delay an ACK, whether observed or advertised (μs)
+    fn max_ack_delay(&self) -> u64 {
+        cmp::max(self.max_ack_delay, self.params.max_ack_delay as u64 * 1000)
+    }
+
+    fn on_packet_authenticated(
+        &mut self,
+        ctx: &mut Context,
+        now: u64,
+        space: SpaceId,
+        packet: u64,
+    ) {
+        trace!(self.log, "packet authenticated"; "space" => ?space, "pn" => packet);
         self.reset_idle_timeout(&ctx.config, now);
-        self.pending_acks.insert_one(packet);
-        if self.pending_acks.len() > MAX_ACK_BLOCKS {
-            self.pending_acks.pop_min();
+        let space = &mut self.spaces[space as usize];
+        space.pending_acks.insert_one(packet);
+        if space.pending_acks.len() > ctx.config.max_ack_blocks {
+            space.pending_acks.pop_min();
         }
-        if packet > self.rx_packet {
-            self.rx_packet = packet;
-            self.rx_packet_time = now;
+        if packet > space.rx_packet {
+            space.rx_packet = packet;
+            space.rx_packet_time = now;
         }
     }
 
     pub fn reset_idle_timeout(&mut self, config: &Config, now: u64) {
-        let dt = if config.idle_timeout == 0 || self.params.idle_timeout == 0 {
-            cmp::max(config.idle_timeout, self.params.idle_timeout)
+        // 0 means no timeout, so a limit set by only one side applies on its own
+        let dt = match (config.idle_timeout, self.params.idle_timeout) {
+            (0, 0) => {
+                self.set_idle = Some(None);
+                return;
+            }
+            (0, x) | (x, 0) => x,
+            (x, y) => cmp::min(x, y),
+        };
+        // The peer's value isn't clamped by anyone else
+        let dt = u64::from(cmp::min(dt, MAX_IDLE_TIMEOUT));
+        self.set_idle = Some(Some(now.saturating_add(dt * 1_000_000)));
+    }
+
+    /// Whether a PATH_CHALLENGE received now may be responded to
+    ///
+    /// Challenges in excess of the configured rate are dropped, so that a peer can't use them to
+    /// provoke an unbounded amount of response traffic.
+    fn permit_path_response(&mut self, config: &Config, now: u64) -> bool {
+        let rtt = if self.smoothed_rtt == 0 {
+            config.default_initial_rtt
         } else {
-            cmp::min(config.idle_timeout, self.params.idle_timeout)
+            self.smoothed_rtt
         };
-        self.set_idle = Some(Some(now + dt as u64 * 1_000_000));
+        if now >= self.path_response_period + rtt {
+            self.path_response_period = now;
+            self.path_responses_sent = 0;
+        }
+        if self.path_responses_sent >= config.max_path_responses_per_rtt {
+            debug!(self.log, "dropping PATH_CHALLENGE in excess of rate limit");
+            return false;
+        }
+        self.path_responses_sent += 1;
+        true
     }
 
     /// Consider all previously transmitted handshake packets to be delivered. Called when we
@@ -647,15 +992,16 @@ impl Connection {
         }
         self.awaiting_handshake = false;
         self.handshake_pending = Retransmits::default();
-        let mut packets = Vec::new();
-        for (&packet, info) in &self.sent_packets {
-            if info.handshake {
-                packets.push(packet);
+        for &space in &[SpaceId::Initial, SpaceId::Handshake] {
+            let packets = self.spaces[space as usize]
+                .sent_packets
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>();
+            for packet in packets {
+                self.on_packet_acked(config, space, packet);
             }
         }
-        for packet in packets {
-            self.on_packet_acked(config, packet);
-        }
         self.set_loss_detection_alarm(config);
     }
 
@@ -695,25 +1041,30 @@ impl Connection {
 
     /// Abandon transmitting data on a stream
     ///
-    /// # Panics
-    /// - when applied to a receive stream or an unopened send stream
-    pub fn reset(&mut self, ctx: &mut Context, stream: StreamId, error_code: u16) {
-        assert!(
-            stream.directionality() == Directionality::Bi || stream.initiator() == self.side,
-            "only streams supporting outgoing data may be reset"
-        );
+    /// # Errors
+    /// - when applied to a receive-only stream
+    pub fn reset(
+        &mut self,
+        ctx: &mut Context,
+        stream: StreamId,
+        error_code: u16,
+    ) -> Result<(), InvalidStreamError> {
+        // Only streams supporting outgoing data may be reset
+        if stream.directionality() == Directionality::Uni && stream.initiator() != self.side {
+            return Err(InvalidStreamError);
+        }
         {
             // reset is a noop on a closed stream
             let stream = if let Some(x) = self.streams.get_send_mut(&stream) {
                 x
             } else {
-                return;
+                return Ok(());
             };
             match stream.state {
                 stream::SendState::DataRecvd
                 | stream::SendState::ResetSent { .. }
                 | stream::SendState::ResetRecvd { .. } => {
-                    return;
+                    return Ok(());
                 } // Nothing to do
                 _ => {}
             }
@@ -721,6 +1072,7 @@ impl Connection {
         }
         self.pending.rst_stream.push((stream, error_code));
         ctx.dirty_conns.insert(self.handle);
+        Ok(())
     }
 
     fn drive_tls(&mut self) -> Result<(), TransportError> {
@@ -798,6 +1150,8 @@ impl Connection {
         }
         let params = TransportParameters::read(
             Side::Server,
+            self.version,
+            &ctx.config.supported_versions,
             &mut io::Cursor::new(self.tls.get_quic_transport_parameters().unwrap()),
         )?;
         self.handshake_complete(ctx, params, now, packet_number);
@@ -807,13 +1161,7 @@ impl Connection {
     fn read_tls(&mut self, frame: &frame::Stream) {
         let mut buf = [0; 8192];
         let n = {
-            let rs = self
-                .streams
-                .get_recv_stream(self.side, StreamId(0))
-                .unwrap()
-                .unwrap()
-                .recv_mut()
-                .unwrap();
+            let rs = self.streams.get_recv_mut(&StreamId(0)).unwrap();
             rs.assembler.insert(frame.offset, &frame.data);
             rs.assembler.read(&mut buf)
         };
@@ -824,21 +1172,45 @@ impl Connection {
         &mut self,
         ctx: &mut Context,
         now: u64,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
         partial_decode: PartialDecode,
     ) -> Option<BytesMut> {
-        let result = {
-            let crypto = if partial_decode.is_handshake() {
-                &self.handshake_crypto
+        if !partial_decode.has_long_header() && self.crypto.is_none() {
+            // Reordering can deliver 1-RTT packets just ahead of the handshake packet that would
+            // let us decrypt them. Short-header packets run to the end of the datagram, so there's
+            // never anything left to return.
+            let handshaking = self.state.as_ref().unwrap().is_handshake();
+            if handshaking && self.early_packets.len() < MAX_EARLY_PACKETS {
+                trace!(self.log, "buffering short packet received during handshake");
+                self.early_packets.push_back((remote, partial_decode));
             } else {
-                &self.crypto.as_ref().unwrap()
+                debug!(self.log, "dropping undecryptable short packet");
+            }
+            return None;
+        }
+
+        let len = partial_decode.len();
+        let result = {
+            // Long-header packets we can't yet decrypt, like version negotiation or 0-RTT, are
+            // discarded after decoding regardless, so any key will do
+            let crypto = match self.crypto {
+                Some(ref crypto) if !partial_decode.is_handshake() => crypto,
+                _ => &self.handshake_crypto,
             };
-            partial_decode.finish(crypto.pn_decrypt_key())
+            partial_decode.finish(crypto.pn_decrypt_key(), ctx.config.max_token_length)
         };
 
         match result {
             Ok((packet, rest)) => {
+                let len = len - rest.as_ref().map_or(0, |x| x.len());
                 self.handle_packet(ctx, now, remote, packet);
+                self.probe_path(ctx, now, remote, len);
+                if self.crypto.is_some() && !self.early_packets.is_empty() {
+                    trace!(self.log, "processing buffered short packets");
+                    for (remote, packet) in mem::replace(&mut self.early_packets, VecDeque::new()) {
+                        self.handle_decode(ctx, now, remote, packet);
+                    }
+                }
                 rest
             }
             Err(e) => {
@@ -852,11 +1224,19 @@ impl Connection {
         &mut self,
         ctx: &mut Context,
         now: u64,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
         mut packet: Packet,
     ) {
-        if let Some(token) = self.params.stateless_reset_token {
-            if packet.payload.len() >= 16 && packet.payload[packet.payload.len() - 16..] == token {
+        if packet.payload.len() >= RESET_TOKEN_SIZE {
+            let tail = &packet.payload[packet.payload.len() - RESET_TOKEN_SIZE..];
+            // The tokens are secret, so don't leak how much of any of them matched, or which
+            let is_reset = self
+                .params
+                .stateless_reset_token
+                .iter()
+                .chain(&self.rem_reset_tokens)
+                .fold(false, |acc, token| constant_time_eq(tail, token) | acc);
+            if is_reset {
                 if !self.state.as_ref().unwrap().is_drained() {
                     debug!(self.log, "got stateless reset");
                     ctx.io.push_back(Io::TimerStop {
@@ -871,6 +1251,14 @@ impl Connection {
                         connection: self.handle,
                         timer: Timer::Idle,
                     });
+                    ctx.io.push_back(Io::TimerStop {
+                        connection: self.handle,
+                        timer: Timer::KeepAlive,
+                    });
+                    self.loss_detection_time = None;
+                    self.close_time = None;
+                    self.idle_time = None;
+                    self.keep_alive_time = None;
                     ctx.events.push_back((
                         self.handle,
                         Event::ConnectionLost {
@@ -888,7 +1276,10 @@ impl Connection {
             State::Handshake(mut state) => {
                 if !state.rem_cid_set {
                     match packet.header {
-                        Header::Long {
+                        Header::Initial {
+                            src_cid: rem_cid, ..
+                        }
+                        | Header::Long {
                             ty: LongType::Handshake,
                             src_cid: rem_cid,
                             ..
@@ -906,20 +1297,55 @@ impl Connection {
         };
         let was_closed = prev_state.is_closed();
 
-        let result = match self.decrypt_packet(was_handshake, &mut packet) {
-            Ok(number) => {
-                if !was_closed {
-                    self.on_packet_authenticated(ctx, now, number);
-                }
-                self.handle_connected_inner(ctx, now, remote, number, packet, prev_state)
-            }
-            Err(Some(e)) => {
-                warn!(self.log, "got illegal packet"; "reason" => %e);
-                Err(e.into())
+        let unprotected = match packet.header {
+            Header::VersionNegotiate { .. } | Header::Retry { .. } => true,
+            _ => false,
+        };
+        let result = if unprotected {
+            // Unprotected and unnumbered, so only meaningful before the handshake completes
+            if was_handshake {
+                self.handle_connected_inner(ctx, now, remote, 0, packet, prev_state)
+            } else {
+                trace!(self.log, "dropping late unprotected packet");
+                Ok(prev_state)
             }
-            Err(None) => {
-                debug!(self.log, "failed to authenticate packet");
-                Ok(State::Established)
+        } else {
+            let space = SpaceId::of(&packet.header);
+            match self.decrypt_packet(&ctx.config, space, &mut packet) {
+                Ok(_) if packet.reserved_bits_set() => {
+                    debug!(self.log, "reserved bits set in packet header");
+                    Err(TransportError::PROTOCOL_VIOLATION.into())
+                }
+                Ok(number) => {
+                    // Only packets in a numbered space decrypt successfully
+                    let space = space.unwrap();
+                    qlog!(self, ctx.config, now, "transport", "packet_received", {
+                        "packet_number": number,
+                        "packet_type": qlog::packet_type(space),
+                        "size": packet.payload.len()
+                    });
+                    if !was_closed {
+                        if let Header::Short { spin, .. } = packet.header {
+                            let rx_packet = self.spaces[SpaceId::Data as usize].rx_packet;
+                            if self.spin_enabled && number > rx_packet {
+                                // The server reflects the spin bit and the client inverts it, so
+                                // the value seen on the wire flips once per round trip
+                                self.spin = spin ^ (self.side == Side::Client);
+                            }
+                        }
+                        self.on_packet_authenticated(ctx, now, space, number);
+                    }
+                    self.expire_prev_crypto(&ctx.config, now);
+                    self.handle_connected_inner(ctx, now, remote, number, packet, prev_state)
+                }
+                Err(Some(e)) => {
+                    warn!(self.log, "got illegal packet"; "reason" => %e);
+                    Err(e.into())
+                }
+                Err(None) => {
+                    debug!(self.log, "failed to authenticate packet");
+                    Ok(State::Established)
+                }
             }
         };
 
@@ -969,6 +1395,13 @@ impl Connection {
             }
         };
 
+        if let State::Established = state {
+            if self.established_at.is_none() {
+                self.established_at = Some(now);
+            }
+            self.address_validated = true;
+        }
+
         if !was_closed && state.is_closed() {
             self.close_common(ctx, now);
         }
@@ -979,29 +1412,26 @@ impl Connection {
                 if !was_closed && self.side == Side::Server {
                     ctx.incoming_handshakes -= 1;
                 }
-                let n = self.get_tx_number();
+                let n = self.get_tx_number(SpaceId::Handshake);
                 debug_assert!(n < 64); // handshake_close doesn't have the connection state
                                        // to decide on packet number encoding length; since this
                                        // is about closing the handshake, it seems reasonable to
                                        // assume that the packet number will fit in one byte.
-                ctx.io.push_back(Io::Transmit {
-                    destination: remote,
-                    packet: handshake_close(
-                        &self.handshake_crypto,
-                        &self.rem_cid,
-                        &self.loc_cid,
-                        n as u8,
-                        state.reason.clone(),
-                        state.alert.as_ref().map(|x| &x[..]),
-                    ),
-                });
+                let packet = handshake_close(
+                    &self.handshake_crypto,
+                    self.version,
+                    &self.rem_cid,
+                    &self.loc_cid,
+                    n as u8,
+                    state.reason.clone(),
+                    state.alert.as_ref().map(|x| &x[..]),
+                );
+                self.queue_datagram(ctx, remote, packet);
                 self.reset_idle_timeout(&ctx.config, now);
             }
             State::Closed(ref state) => {
-                ctx.io.push_back(Io::Transmit {
-                    destination: remote,
-                    packet: self.make_close(&state.reason),
-                });
+                let packet = self.make_close(&state.reason);
+                self.queue_datagram(ctx, remote, packet);
                 self.reset_idle_timeout(&ctx.config, now);
             }
             _ => {}
@@ -1014,7 +1444,7 @@ impl Connection {
         &mut self,
         ctx: &mut Context,
         now: u64,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
         number: u64,
         packet: Packet,
         state: State,
@@ -1023,24 +1453,31 @@ impl Connection {
             State::Handshake(state) => {
                 match packet.header {
                     Header::Retry {
-                        src_cid: rem_cid, ..
+                        src_cid: rem_cid,
+                        orig_dst_cid,
+                        ..
                     } => {
-                        // FIXME: the below guards fail to handle repeated retries resulting from
-                        // retransmitted initials
                         if state.clienthello_packet.is_none() {
                             // Received Retry as a server
                             debug!(self.log, "received retry from client");
                             Err(TransportError::PROTOCOL_VIOLATION.into())
-                        } else if state.clienthello_packet.unwrap() > number {
-                            // Retry corresponds to an outdated Initial; must be a duplicate, so
-                            // ignore it
+                        } else if state.token.is_some() || orig_dst_cid != self.rem_cid {
+                            // A duplicate, or a response to an Initial we didn't send; a client
+                            // only follows one Retry per connection attempt
+                            trace!(self.log, "ignoring retry"; "orig_dst_cid" => %orig_dst_cid);
                             Ok(State::Handshake(state))
                         } else {
                             trace!(self.log, "resending ClientHello"; "rem_cid" => %rem_cid);
                             // Send updated ClientHello
                             let mut outgoing = Vec::new();
                             self.tls.write_tls(&mut outgoing).unwrap();
-                            let tls = make_tls(&ctx, &self.loc_cid, self.client_config.as_ref());
+                            let initial_version = ctx.config.supported_versions[0];
+                            let tls = make_tls(
+                                ctx,
+                                &self.loc_cid,
+                                initial_version,
+                                self.client_config.as_ref(),
+                            );
 
                             // Discard transport state
                             let mut new = Connection::new(
@@ -1049,11 +1486,14 @@ impl Connection {
                                 self.loc_cid,
                                 rem_cid,
                                 remote,
+                                self.version,
                                 self.client_config.clone(),
                                 tls,
                                 ctx,
                                 self.handle,
+                                self.created_at,
                             );
+                            new.user_data = self.user_data.take();
                             mem::replace(self, new);
                             self.transmit_handshake(&outgoing);
                             // Prepare to receive Handshake packets that start stream 0
@@ -1072,25 +1512,25 @@ impl Connection {
                     } => {
                         // Complete handshake (and ultimately send Finished)
                         for frame in frame::Iter::new(packet.payload.into()) {
+                            qlog!(self, ctx.config, now, "transport", "frame_received", {
+                                "packet_number": number,
+                                "frame_type": frame.ty().to_string()
+                            });
+                            if !CryptoLevel::Initial.permits(&frame) {
+                                debug!(self.log, "unexpected frame type in handshake"; "type" => %frame.ty());
+                                return Err(TransportError::PROTOCOL_VIOLATION.into());
+                            }
                             match frame {
                                 Frame::Ack(_) => {}
                                 _ => {
-                                    self.permit_ack_only = true;
+                                    self.spaces[SpaceId::Handshake as usize].permit_ack_only = true;
                                 }
                             }
                             match frame {
                                 Frame::Padding => {}
-                                Frame::Stream(
-                                    frame @ frame::Stream {
-                                        id: StreamId(0), ..
-                                    },
-                                ) => self.read_tls(&frame),
-                                Frame::Stream(frame::Stream { .. }) => {
-                                    debug!(self.log, "non-stream-0 stream frame in handshake");
-                                    return Err(TransportError::PROTOCOL_VIOLATION.into());
-                                }
+                                Frame::Stream(frame) => self.read_tls(&frame),
                                 Frame::Ack(ack) => {
-                                    self.on_ack_received(ctx, now, ack);
+                                    self.on_ack_received(ctx, now, SpaceId::Handshake, ack)?;
                                 }
                                 Frame::ConnectionClose(reason) => {
                                     ctx.events.push_back((
@@ -1111,10 +1551,16 @@ impl Connection {
                                     return Ok(State::Draining);
                                 }
                                 Frame::PathChallenge(value) => {
-                                    self.handshake_pending.path_challenge(number as u64, value);
+                                    if self.permit_path_response(&ctx.config, now) {
+                                        self.handshake_pending.path_challenge(number as u64, value);
+                                    }
+                                }
+                                Frame::PathResponse(_) => {
+                                    debug!(self.log, "unsolicited PATH_RESPONSE");
+                                    return Err(TransportError::UNSOLICITED_PATH_RESPONSE.into());
                                 }
                                 _ => {
-                                    debug!(self.log, "unexpected frame type in handshake"; "type" => %frame.ty());
+                                    debug!(self.log, "unhandled frame type in handshake");
                                     return Err(TransportError::PROTOCOL_VIOLATION.into());
                                 }
                             }
@@ -1132,10 +1578,20 @@ impl Connection {
                                     }).and_then(|x| {
                                         TransportParameters::read(
                                             self.side,
+                                            self.version,
+                                            &ctx.config.supported_versions,
                                             &mut io::Cursor::new(x),
                                         ).map_err(Into::into)
                                     })?;
-                                self.set_params(params);
+                                let crypto = Crypto::new_1rtt(
+                                    &ctx.config.packet_protection,
+                                    &self.tls,
+                                    self.side,
+                                ).ok_or_else(|| {
+                                    debug!(self.log, "negotiated an unsupported cipher");
+                                    ConnectionError::from(TransportError::TLS_HANDSHAKE_FAILED)
+                                })?;
+                                self.set_params(&ctx.config, params);
                                 trace!(self.log, "{connection} established", connection = id);
                                 self.handshake_cleanup(&ctx.config);
                                 let mut msgs = Vec::new();
@@ -1162,7 +1618,7 @@ impl Connection {
                                         ctx.incoming.push_back(self.handle);
                                     }
                                 }
-                                self.crypto = Some(Crypto::new_1rtt(&self.tls, self.side));
+                                self.crypto = Some(crypto);
                                 Ok(State::Established)
                             }
                             Ok(()) => {
@@ -1187,9 +1643,23 @@ impl Connection {
                     }
                     Header::Initial { .. } => {
                         if self.side == Side::Server {
-                            trace!(self.log, "dropping duplicate Initial");
-                        } else {
-                            trace!(self.log, "dropping Initial for initiated connection");
+                            // Our ACK of the original must have been lost
+                            trace!(self.log, "acknowledging duplicate Initial");
+                            self.spaces[SpaceId::Initial as usize].permit_ack_only = true;
+                            return Ok(State::Handshake(state));
+                        }
+                        // The server's Initial packets only acknowledge our ClientHello
+                        for frame in frame::Iter::new(packet.payload.into()) {
+                            match frame {
+                                Frame::Padding => {}
+                                Frame::Ack(ack) => {
+                                    self.on_ack_received(ctx, now, SpaceId::Initial, ack)?;
+                                }
+                                frame => {
+                                    debug!(self.log, "unexpected frame type in Initial"; "type" => %frame.ty());
+                                    return Err(TransportError::PROTOCOL_VIOLATION.into());
+                                }
+                            }
                         }
                         Ok(State::Handshake(state))
                     }
@@ -1246,22 +1716,69 @@ impl Connection {
                         Ok(State::Handshake(state))
                     }
                     Header::VersionNegotiate { .. } => {
+                        if self.side == Side::Server {
+                            debug!(self.log, "dropping version negotiation sent by client");
+                            return Ok(State::Handshake(state));
+                        }
                         let mut payload = io::Cursor::new(&packet.payload[..]);
                         if packet.payload.len() % 4 != 0 {
                             debug!(self.log, "malformed version negotiation");
                             return Err(TransportError::PROTOCOL_VIOLATION.into());
                         }
+                        let mut offered = Vec::new();
                         while payload.has_remaining() {
                             let version = payload.get::<u32>().unwrap();
-                            if version == VERSION {
+                            if version == self.version {
                                 // Our version is supported, so this packet is spurious
                                 return Ok(State::Handshake(state));
                             }
+                            offered.push(version);
                         }
-                        debug!(self.log, "remote doesn't support our version");
-                        Err(ConnectionError::VersionMismatch)
+                        // Only the initial attempt is renegotiated, so a forged packet can't keep
+                        // bouncing us between versions
+                        let version = if self.version == ctx.config.supported_versions[0] {
+                            ctx.config
+                                .supported_versions
+                                .iter()
+                                .cloned()
+                                .find(|x| offered.contains(x))
+                        } else {
+                            None
+                        };
+                        let version = match version {
+                            Some(x) => x,
+                            None => {
+                                debug!(self.log, "remote doesn't support our version");
+                                return Err(ConnectionError::VersionMismatch);
+                            }
+                        };
+                        trace!(self.log, "renegotiating version"; "version" => version);
+                        // The server keeps no state, so start over with a fresh ClientHello
+                        let initial_version = ctx.config.supported_versions[0];
+                        let tls = make_tls(
+                            ctx,
+                            &self.loc_cid,
+                            initial_version,
+                            self.client_config.as_ref(),
+                        );
+                        let mut new = Connection::new(
+                            self.log.clone(),
+                            self.init_cid,
+                            self.loc_cid,
+                            self.init_cid,
+                            self.remote,
+                            version,
+                            self.client_config.clone(),
+                            tls,
+                            ctx,
+                            self.handle,
+                            self.created_at,
+                        );
+                        new.user_data = self.user_data.take();
+                        mem::replace(self, new);
+                        Ok(self.state.take().unwrap())
                     }
-                    // TODO: SHOULD buffer these to improve reordering tolerance.
+                    // Normally buffered by `handle_decode` until we have 1-RTT keys
                     Header::Short { .. } => {
                         trace!(self.log, "dropping short packet during handshake");
                         Ok(State::Handshake(state))
@@ -1269,9 +1786,48 @@ impl Connection {
                 }
             }
             State::Established => {
-                if let Header::Long { .. } = packet.header {
-                    trace!(self.log, "discarding unprotected packet");
-                    return Ok(State::Established);
+                match packet.header {
+                    Header::Short { .. } => {}
+                    Header::Long {
+                        ty: LongType::Handshake,
+                        ..
+                    } => {
+                        // Acknowledges our final handshake packets, or retransmits the peer's
+                        for frame in frame::Iter::new(packet.payload.into()) {
+                            qlog!(self, ctx.config, now, "transport", "frame_received", {
+                                "packet_number": number,
+                                "frame_type": frame.ty().to_string()
+                            });
+                            if !CryptoLevel::Initial.permits(&frame) {
+                                debug!(self.log, "unexpected frame type in handshake"; "type" => %frame.ty());
+                                return Err(TransportError::PROTOCOL_VIOLATION.into());
+                            }
+                            match frame {
+                                Frame::Padding => {}
+                                Frame::Ack(ack) => {
+                                    self.on_ack_received(ctx, now, SpaceId::Handshake, ack)?;
+                                }
+                                // Already processed, but the peer needs to hear that
+                                _ => {
+                                    self.spaces[SpaceId::Handshake as usize].permit_ack_only = true;
+                                }
+                            }
+                        }
+                        if self.awaiting_handshake
+                            && self.handshake_pending.is_empty()
+                            && self.spaces[SpaceId::Handshake as usize]
+                                .sent_packets
+                                .is_empty()
+                        {
+                            // The peer has acknowledged our final handshake flight
+                            self.handshake_cleanup(&ctx.config);
+                        }
+                        return Ok(State::Established);
+                    }
+                    _ => {
+                        trace!(self.log, "discarding late long-header packet");
+                        return Ok(State::Established);
+                    }
                 }
 
                 if self.awaiting_handshake {
@@ -1283,8 +1839,16 @@ impl Connection {
                     // Forget about unacknowledged handshake packets
                     self.handshake_cleanup(&ctx.config);
                 }
+                if remote != self.remote && ctx.config.disable_migration {
+                    debug!(self.log, "peer migrated despite our disabling migration"; "remote" => %remote);
+                    return Err(TransportError::PROTOCOL_VIOLATION.into());
+                }
                 let closed = self.process_payload(ctx, now, number, packet.payload.into())?;
+                if !closed && remote != self.remote {
+                    self.validate_path(ctx, remote);
+                }
                 self.drive_tls()?;
+                self.check_stalled_streams(ctx, now);
                 Ok(if closed {
                     State::Draining
                 } else {
@@ -1296,6 +1860,7 @@ impl Connection {
                     match frame {
                         Frame::ConnectionClose(_) | Frame::ApplicationClose(_) => {
                             trace!(self.log, "draining");
+                            self.drain_now(ctx, now);
                             return Ok(State::Draining);
                         }
                         _ => {}
@@ -1308,6 +1873,7 @@ impl Connection {
                     match frame {
                         Frame::ConnectionClose(_) | Frame::ApplicationClose(_) => {
                             trace!(self.log, "draining");
+                            self.drain_now(ctx, now);
                             return Ok(State::Draining);
                         }
                         _ => {}
@@ -1327,17 +1893,37 @@ impl Connection {
         number: u64,
         payload: Bytes,
     ) -> Result<bool, TransportError> {
+        let mut ack_eliciting = false;
+        let max_frames = match ctx.config.min_average_frame_size {
+            0 => usize::max_value(),
+            x => cmp::max(1, payload.len() / x),
+        };
+        let mut frames = 0;
         for frame in frame::Iter::new(payload) {
             match frame {
                 Frame::Padding => {}
                 _ => {
                     trace!(self.log, "got frame"; "type" => %frame.ty());
+                    qlog!(self, ctx.config, now, "transport", "frame_received", {
+                        "packet_number": number,
+                        "frame_type": frame.ty().to_string()
+                    });
+                    frames += 1;
+                    if frames > max_frames {
+                        debug!(self.log, "too many frames in packet"; "limit" => max_frames);
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
                 }
             }
+            if !CryptoLevel::OneRtt.permits(&frame) {
+                debug!(self.log, "unexpected frame type in 1-RTT packet"; "type" => %frame.ty());
+                return Err(TransportError::PROTOCOL_VIOLATION);
+            }
             match frame {
                 Frame::Ack(_) => {}
                 _ => {
-                    self.permit_ack_only = true;
+                    self.spaces[SpaceId::Data as usize].permit_ack_only = true;
+                    ack_eliciting = true;
                 }
             }
             match frame {
@@ -1346,7 +1932,12 @@ impl Connection {
                     let data_recvd = self.data_recvd;
                     let max_data = self.local_max_data;
                     let rs = {
-                        match self.streams.get_recv_stream(self.side, frame.id) {
+                        match self.streams.get_recv_stream(
+                            self.side,
+                            frame.id,
+                            &ctx.config,
+                            &self.params,
+                        ) {
                             Err(e) => {
                                 debug!(self.log, "received illegal stream frame"; "stream" => frame.id.0);
                                 return Err(e);
@@ -1360,8 +1951,17 @@ impl Connection {
                         self.streams.get_recv_mut(&frame.id).unwrap()
                     };
 
+                    let mut recv_finished = false;
                     let new_bytes = {
-                        let end = frame.offset + frame.data.len() as u64;
+                        // Stream offsets are limited to 2^62 - 1, so a frame extending beyond that
+                        // is malformed. Checking here also keeps `end` from wrapping around below.
+                        let end = match frame.offset.checked_add(frame.data.len() as u64) {
+                            Some(end) if end < 2u64.pow(62) => end,
+                            _ => {
+                                debug!(self.log, "stream frame exceeds maximum offset"; "stream" => frame.id.0, "offset" => frame.offset);
+                                return Err(TransportError::PROTOCOL_VIOLATION);
+                            }
+                        };
                         if let Some(final_offset) = rs.final_offset() {
                             if end > final_offset || (frame.fin && end != final_offset) {
                                 debug!(self.log, "final offset error"; "frame end" => end, "final offset" => final_offset);
@@ -1382,6 +1982,13 @@ impl Connection {
                             }
                         }
                         rs.recvd.insert(frame.offset..end);
+                        if frame.id != StreamId(0) {
+                            if rs.recvd.len() > 1 || rs.recvd.min() != Some(0) {
+                                self.stalled_streams.entry(frame.id).or_insert(Some(now));
+                            } else {
+                                self.stalled_streams.remove(&frame.id);
+                            }
+                        }
                         if frame.id == StreamId(0) && frame.fin {
                             debug!(self.log, "got fin on stream 0");
                             return Err(TransportError::PROTOCOL_VIOLATION);
@@ -1390,6 +1997,7 @@ impl Connection {
                         if let stream::RecvState::Recv { size: Some(size) } = rs.state {
                             if rs.recvd.len() == 1 && rs.recvd.iter().next().unwrap() == (0..size) {
                                 rs.state = stream::RecvState::DataRecvd { size };
+                                recv_finished = true;
                             }
                         }
 
@@ -1413,10 +2021,16 @@ impl Connection {
                         self.readable_streams.insert(frame.id);
                         ctx.readable_conns.insert(self.handle);
                     }
+                    if recv_finished && frame.id.directionality() == Directionality::Bi {
+                        ctx.events.push_back((
+                            self.handle,
+                            Event::StreamRecvFinished { stream: frame.id },
+                        ));
+                    }
                     self.data_recvd += new_bytes;
                 }
                 Frame::Ack(ack) => {
-                    self.on_ack_received(ctx, now, ack);
+                    self.on_ack_received(ctx, now, SpaceId::Data, ack)?;
                     for stream in self.streams.finished.drain(..) {
                         ctx.events
                             .push_back((self.handle, Event::StreamFinished { stream }));
@@ -1446,20 +2060,43 @@ impl Connection {
                     return Err(TransportError::frame(ty));
                 }
                 Frame::PathChallenge(x) => {
-                    self.pending.path_challenge(number, x);
-                }
-                Frame::PathResponse(_) => {
-                    debug!(self.log, "unsolicited PATH_RESPONSE");
-                    return Err(TransportError::UNSOLICITED_PATH_RESPONSE);
+                    if self.permit_path_response(&ctx.config, now) {
+                        self.pending.path_challenge(number, x);
+                    }
                 }
+                Frame::PathResponse(token) => match self.path_challenge {
+                    Some(x) if x.token == token => {
+                        debug!(self.log, "migrating to {remote}", remote = x.remote);
+                        self.remote = x.remote;
+                        self.path_challenge = None;
+                        self.retire_path_token(token);
+                    }
+                    // A challenge repeated after a PTO may be answered more than once, and one of a
+                    // path since abandoned for another may still be answered
+                    _ if self.past_path_tokens.contains(&token) => {}
+                    None => {
+                        debug!(self.log, "unsolicited PATH_RESPONSE");
+                        return Err(TransportError::UNSOLICITED_PATH_RESPONSE);
+                    }
+                    Some(_) => {
+                        debug!(self.log, "PATH_RESPONSE matches no challenge we sent");
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
+                },
                 Frame::MaxData(bytes) => {
+                    if ctx.config.strict
+                        && limit_lowered(self.max_data, self.max_data_pn, bytes, number)
+                    {
+                        debug!(self.log, "peer lowered MAX_DATA"; "old" => self.max_data, "new" => bytes);
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
                     let was_blocked = self.blocked();
-                    self.max_data = cmp::max(bytes, self.max_data);
+                    if bytes > self.max_data {
+                        self.max_data = bytes;
+                        self.max_data_pn = Some(number);
+                    }
                     if was_blocked && !self.blocked() {
-                        for stream in self.blocked_streams.drain() {
-                            ctx.events
-                                .push_back((self.handle, Event::StreamWritable { stream }));
-                        }
+                        self.wake_blocked_streams(ctx);
                     }
                 }
                 Frame::MaxStreamData { id, offset } => {
@@ -1467,15 +2104,37 @@ impl Connection {
                         debug!(self.log, "got MAX_STREAM_DATA on recv-only stream");
                         return Err(TransportError::PROTOCOL_VIOLATION);
                     }
+                    if id.initiator() != self.side {
+                        // May arrive before any STREAM frame opening the stream
+                        self.streams
+                            .get_recv_stream(self.side, id, &ctx.config, &self.params)?;
+                    }
+                    let conn_blocked = self.blocked();
                     if let Some(ss) = self.streams.get_send_mut(&id) {
+                        if ctx.config.strict
+                            && limit_lowered(ss.max_data, ss.max_data_pn, offset, number)
+                        {
+                            debug!(self.log, "peer lowered MAX_STREAM_DATA"; "stream" => id.0,
+                                   "old" => ss.max_data, "new" => offset);
+                            return Err(TransportError::PROTOCOL_VIOLATION);
+                        }
                         if offset > ss.max_data {
                             trace!(self.log, "stream limit increased"; "stream" => id.0,
                                    "old" => ss.max_data, "new" => offset, "current offset" => ss.offset);
-                            if ss.offset == ss.max_data {
-                                ctx.events
-                                    .push_back((self.handle, Event::StreamWritable { stream: id }));
+                            // Finished streams have no further use for credit
+                            if ss.offset == ss.max_data && !ss.state.is_finished() {
+                                if conn_blocked {
+                                    // Still blocked at the connection level; wake on MAX_DATA
+                                    self.blocked_streams.insert(id);
+                                } else {
+                                    ctx.events.push_back((
+                                        self.handle,
+                                        Event::StreamWritable { stream: id },
+                                    ));
+                                }
                             }
                             ss.max_data = offset;
+                            ss.max_data_pn = Some(number);
                         }
                     } else {
                         debug!(self.log, "got MAX_STREAM_DATA on unopened stream");
@@ -1483,13 +2142,25 @@ impl Connection {
                     }
                 }
                 Frame::MaxStreamId(id) => {
-                    let limit = match id.directionality() {
-                        Directionality::Uni => &mut self.streams.max_uni,
-                        Directionality::Bi => &mut self.streams.max_bi,
+                    if ctx.config.strict && id.initiator() != self.side {
+                        debug!(self.log, "got MAX_STREAM_ID for peer-initiated stream"; "stream" => id.0);
+                        return Err(TransportError::STREAM_ID_ERROR);
+                    }
+                    let (limit, blocked) = match id.directionality() {
+                        Directionality::Uni => (
+                            &mut self.streams.max_uni,
+                            &mut self.pending.uni_stream_id_blocked,
+                        ),
+                        Directionality::Bi => (
+                            &mut self.streams.max_bi,
+                            &mut self.pending.bi_stream_id_blocked,
+                        ),
                     };
                     let update = id.index() + 1;
                     if update > *limit {
                         *limit = update;
+                        // No longer blocked at the old limit, so don't tell the peer we are
+                        *blocked = false;
                         ctx.events.push_back((
                             self.handle,
                             Event::StreamAvailable {
@@ -1503,11 +2174,12 @@ impl Connection {
                     error_code,
                     final_offset,
                 }) => {
-                    if id == StreamId(0) {
-                        debug!(self.log, "got RST_STREAM on stream 0");
-                        return Err(TransportError::PROTOCOL_VIOLATION);
-                    }
-                    let offset = match self.streams.get_recv_stream(self.side, id) {
+                    let offset = match self.streams.get_recv_stream(
+                        self.side,
+                        id,
+                        &ctx.config,
+                        &self.params,
+                    ) {
                         Err(e) => {
                             debug!(self.log, "received illegal RST_STREAM");
                             return Err(e);
@@ -1522,6 +2194,10 @@ impl Connection {
                                 if offset != final_offset {
                                     return Err(TransportError::FINAL_OFFSET_ERROR);
                                 }
+                            } else if ctx.config.strict && final_offset < rs.limit() {
+                                debug!(self.log, "RST_STREAM final offset below data received";
+                                       "stream" => id.0, "final offset" => final_offset, "received" => rs.limit());
+                                return Err(TransportError::FINAL_OFFSET_ERROR);
                             }
                             if !rs.is_closed() {
                                 rs.state = stream::RecvState::ResetRecvd {
@@ -1529,6 +2205,7 @@ impl Connection {
                                     error_code,
                                 };
                             }
+                            self.stalled_streams.remove(&id);
                             rs.limit()
                         }
                     };
@@ -1538,9 +2215,20 @@ impl Connection {
                 }
                 Frame::Blocked { offset } => {
                     debug!(self.log, "peer claims to be blocked at connection level"; "offset" => offset);
+                    if ctx.config.strict && offset > self.advertised_max_data {
+                        debug!(self.log, "BLOCKED beyond advertised limit"; "limit" => self.advertised_max_data);
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
                 }
                 Frame::StreamBlocked { id, offset } => {
                     debug!(self.log, "peer claims to be blocked at stream level"; "stream" => id, "offset" => offset);
+                    if ctx.config.strict
+                        && id.initiator() == self.side
+                        && id.directionality() == Directionality::Uni
+                    {
+                        debug!(self.log, "got STREAM_BLOCKED on send-only stream");
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
                 }
                 Frame::StreamIdBlocked { id } => {
                     debug!(self.log, "peer claims to be blocked at stream ID level"; "stream" => id);
@@ -1555,25 +2243,107 @@ impl Connection {
                         debug!(self.log, "got STOP_SENDING on invalid stream");
                         return Err(TransportError::PROTOCOL_VIOLATION);
                     }
-                    self.reset(ctx, id, error_code);
+                    match self.streams.get_send_mut(&id).unwrap().state {
+                        stream::SendState::Ready | stream::SendState::DataSent => {}
+                        _ => {
+                            // Already finished or reset, e.g. by an earlier copy of this frame
+                            trace!(self.log, "ignoring STOP_SENDING on finished stream"; "stream" => id.0);
+                            continue;
+                        }
+                    }
+                    self.reset(ctx, id, error_code).unwrap();
                     self.streams.get_send_mut(&id).unwrap().state = stream::SendState::ResetSent {
                         stop_reason: Some(error_code),
                     };
+                    ctx.events.push_back((
+                        self.handle,
+                        Event::StreamStopped {
+                            stream: id,
+                            error_code,
+                        },
+                    ));
                 }
-                Frame::NewConnectionId { .. } => {
+                Frame::NewConnectionId { reset_token, .. } => {
                     if self.rem_cid.is_empty() {
                         debug!(self.log, "got NEW_CONNECTION_ID for connection {connection} with empty remote ID",
                                connection=self.loc_cid);
                         return Err(TransportError::PROTOCOL_VIOLATION);
                     }
-                    trace!(self.log, "ignoring NEW_CONNECTION_ID (unimplemented)");
+                    // Migration to the new CID is unimplemented, but a reset may still be sent
+                    // under it
+                    if self.rem_reset_tokens.len() >= MAX_REM_RESET_TOKENS {
+                        debug!(self.log, "ignoring excess NEW_CONNECTION_ID");
+                    } else if !self.rem_reset_tokens.contains(&reset_token) {
+                        self.rem_reset_tokens.push(reset_token);
+                    }
+                }
+                Frame::Datagram(datagram) => {
+                    let max = ctx.config.max_datagram_frame_size as usize;
+                    if frame::Datagram::size(datagram.data.len()) > max {
+                        debug!(self.log, "got DATAGRAM exceeding our limit"; "len" => datagram.data.len(), "max" => max);
+                        return Err(TransportError::PROTOCOL_VIOLATION);
+                    }
+                    ctx.events.push_back((
+                        self.handle,
+                        Event::DatagramReceived {
+                            data: datagram.data,
+                        },
+                    ));
                 }
             }
         }
+        self.extend_remote_streams(&ctx.config);
+        if ack_eliciting {
+            self.on_ack_eliciting(&ctx.config, now);
+            self.maybe_probe_rtt(&ctx.config, now);
+        }
         Ok(false)
     }
 
-    pub fn next_packet(&mut self, log: &Logger, config: &Config, now: u64) -> Option<Vec<u8>> {
+    /// Report streams whose received data has had a gap for too long
+    ///
+    /// Checked whenever a packet arrives; if the peer sends nothing at all, the idle timeout will
+    /// take care of things.
+    fn check_stalled_streams(&mut self, ctx: &mut Context, now: u64) {
+        if ctx.config.stream_stall_timeout == 0 {
+            return;
+        }
+        let limit = self.rto(&ctx.config) * u64::from(ctx.config.stream_stall_timeout);
+        for (&stream, since) in &mut self.stalled_streams {
+            if since.map_or(false, |x| now - x > limit) {
+                *since = None;
+                ctx.events.push_back((self.handle, Event::StreamStalled { stream }));
+            }
+        }
+    }
+
+    /// Schedule acknowledgement of an ack-eliciting packet
+    ///
+    /// The ACK is delayed by up to `delayed_ack_timeout` in the hope that it can be bundled with
+    /// other data, but every second ack-eliciting packet is acknowledged immediately.
+    fn on_ack_eliciting(&mut self, config: &Config, now: u64) {
+        self.ack_eliciting_since_ack += 1;
+        if self.ack_eliciting_since_ack == 1 {
+            self.set_delayed_ack = Some(Some(now + config.delayed_ack_timeout));
+        } else {
+            self.ack_due = true;
+            self.set_delayed_ack = Some(None);
+        }
+    }
+
+    /// Elicit an ACK from a peer we've long been receiving from without sending anything of our own
+    fn maybe_probe_rtt(&mut self, config: &Config, now: u64) {
+        if config.rtt_probe_interval == 0 || self.bytes_in_flight != 0 {
+            return;
+        }
+        let idle = now.saturating_sub(self.time_of_last_sent_retransmittable_packet);
+        if idle >= config.rtt_probe_interval {
+            trace!(self.log, "probing RTT"; "idle" => idle);
+            self.pending.ping = true;
+        }
+    }
+
+    pub fn next_packet(&mut self, log: &Logger, config: &Config, now: u64) -> Option<Box<[u8]>> {
         let established = match *self.state.as_ref().unwrap() {
             State::Handshake(_) => false,
             State::Established => true,
@@ -1583,68 +2353,110 @@ impl Connection {
             }
         };
 
+        // Don't let a spoofed client address turn us into an amplifier
+        let mtu = match self.amplification_budget() {
+            Some(x) if x < MIN_AMPLIFICATION_BUDGET => {
+                trace!(log, "blocked by anti-amplification limit");
+                return None;
+            }
+            Some(x) => cmp::min(x, u64::from(self.mtu)) as usize,
+            None => self.mtu as usize,
+        };
+
         let mut buf = Vec::new();
         let mut sent = Retransmits::default();
 
-        let (number, acks, ack_only, handshake) = {
-            let (number, header, crypto, pending, crypto_level) = if (!established
-                || self.awaiting_handshake)
-                && (!self.handshake_pending.is_empty()
-                    || (!self.pending_acks.is_empty() && self.permit_ack_only))
+        // The client's first flight goes in Initial packets; everything else before 1-RTT keys are
+        // in use goes in Handshake packets, except that Initial packets are acknowledged in their
+        // own space.
+        let initial_acks_due = {
+            let x = &self.spaces[SpaceId::Initial as usize];
+            x.permit_ack_only && !x.pending_acks.is_empty()
+        };
+        let client_hello = self.side == Side::Client && self
+            .handshake_pending
+            .lost_stream
+            .front()
+            .or(self.handshake_pending.stream.front())
+            .map_or(false, |x| x.offset == 0);
+        let handshake_space = if client_hello || initial_acks_due {
+            SpaceId::Initial
+        } else {
+            SpaceId::Handshake
+        };
+        // Handshake data past the ClientHello never goes in an Initial packet
+        let mut initial_ack_only = Retransmits::default();
+
+        let (number, acks, ack_only, space) = {
+            let acks_due = {
+                let x = &self.spaces[handshake_space as usize];
+                x.permit_ack_only && !x.pending_acks.is_empty()
+            };
+            let (number, header, crypto, pending, space) = if acks_due
+                || ((!established || self.awaiting_handshake) && !self.handshake_pending.is_empty())
             {
                 // (re)transmit handshake data in long-header packets
-                buf.reserve_exact(self.mtu as usize);
-                let number = self.get_tx_number();
-                trace!(log, "sending handshake packet"; "pn" => number);
-                let header = if self.side == Side::Client && self
-                    .handshake_pending
-                    .stream
-                    .front()
-                    .map_or(false, |x| x.offset == 0)
-                {
+                let number = self.get_tx_number(handshake_space);
+                trace!(log, "sending handshake packet"; "space" => ?handshake_space, "pn" => number);
+                let largest_acked_packet =
+                    self.spaces[handshake_space as usize].largest_acked_packet;
+                let header = if handshake_space == SpaceId::Initial {
+                    let mut token = Vec::new();
                     if let State::Handshake(ref mut state) = self.state.as_mut().unwrap() {
-                        if state.clienthello_packet.is_none() {
+                        if client_hello && state.clienthello_packet.is_none() {
                             state.clienthello_packet = Some(number);
                         }
+                        // Echo the token from a Retry to prove we can receive at our address
+                        if let Some(ref x) = state.token {
+                            token.extend_from_slice(x);
+                        }
                     }
                     Header::Initial {
+                        version: self.version,
                         src_cid: self.loc_cid,
                         dst_cid: self.rem_cid,
-                        token: vec![], // TODO: determine what's needed here
-                        number: PacketNumber::new(number, self.largest_acked_packet),
+                        token,
+                        number: PacketNumber::new(number, largest_acked_packet),
                     }
                 } else {
                     Header::Long {
                         ty: LongType::Handshake,
+                        version: self.version,
                         src_cid: self.loc_cid,
                         dst_cid: self.rem_cid,
-                        number: PacketNumber::new(number, self.largest_acked_packet),
+                        number: PacketNumber::new(number, largest_acked_packet),
                     }
                 };
+                let pending = if handshake_space == SpaceId::Initial && !client_hello {
+                    &mut initial_ack_only
+                } else {
+                    &mut self.handshake_pending
+                };
                 (
                     number,
                     header,
                     &self.handshake_crypto,
-                    &mut self.handshake_pending,
-                    CryptoLevel::Initial,
+                    pending,
+                    handshake_space,
                 )
             } else if established {
                 //|| (self.zero_rtt_crypto.is_some() && self.side == Side::Client) {
                 // Send 0RTT or 1RTT data
-                if self.congestion_blocked()
-                    || self.pending.is_empty()
-                        && (!self.permit_ack_only || self.pending_acks.is_empty())
-                {
+                let acks_due = {
+                    let x = &self.spaces[SpaceId::Data as usize];
+                    x.permit_ack_only && self.ack_due && !x.pending_acks.is_empty()
+                };
+                if self.congestion_blocked() || self.pending.is_empty() && !acks_due {
                     return None;
                 }
-                let number = self.get_tx_number();
-                buf.reserve_exact(self.mtu as usize);
+                let number = self.get_tx_number(SpaceId::Data);
                 trace!(log, "sending protected packet"; "pn" => number);
 
                 /*if !established {
                     crypto = self.zero_rtt_crypto.as_ref().unwrap();
                     Header::Long {
                         ty: types::ZERO_RTT,
+                        version: self.version,
                         number: number as u32,
                         src_cid: self.loc_cid.clone(),
                         dst_cid: self.init_cid.clone(),
@@ -1652,8 +2464,12 @@ impl Connection {
                 } else {*/
                 let header = Header::Short {
                     dst_cid: self.rem_cid,
-                    number: PacketNumber::new(number, self.largest_acked_packet),
+                    number: PacketNumber::new(
+                        number,
+                        self.spaces[SpaceId::Data as usize].largest_acked_packet,
+                    ),
                     key_phase: self.key_phase,
+                    spin: self.spin,
                 };
                 //}
                 (
@@ -1661,16 +2477,17 @@ impl Connection {
                     header,
                     self.crypto.as_ref().unwrap(),
                     &mut self.pending,
-                    CryptoLevel::OneRtt,
+                    SpaceId::Data,
                 )
             } else {
                 return None;
             };
 
+            buf.reserve_exact(self.mtu as usize);
             let partial_encode = header.encode(&mut buf);
             let ack_only = pending.is_empty();
             let header_len = buf.len() as u16;
-            let max_size = self.mtu as usize - AEAD_TAG_SIZE;
+            let max_size = mtu - AEAD_TAG_SIZE;
 
             // PING
             if pending.ping {
@@ -1680,18 +2497,54 @@ impl Connection {
                 buf.write(frame::Type::PING);
             }
 
+            // DATAGRAM
+            // Ahead of the ACK, which shrinks to fit what's left, so that the largest datagram
+            // send_datagram accepts isn't kept waiting behind acknowledgements indefinitely
+            loop {
+                let size = match pending.datagrams.front() {
+                    Some(x) => frame::Datagram::size(x.len()),
+                    None => break,
+                };
+                if header_len as usize + size > max_size {
+                    // Could never fit, and would otherwise hold up every datagram behind it
+                    debug!(log, "dropping datagram too large for the path"; "size" => size);
+                    pending.datagrams.pop_front();
+                    continue;
+                }
+                if buf.len() + size > max_size {
+                    break;
+                }
+                // No need to retransmit these, so we don't save them after encoding.
+                let data = pending.datagrams.pop_front().unwrap();
+                trace!(log, "DATAGRAM"; "len" => data.len());
+                let frame = frame::Datagram { data };
+                frame.encode(&mut buf);
+            }
+
             // ACK
-            // We will never ack protected packets in handshake packets because handshake_cleanup
-            // ensures we never send handshake packets after receiving protected packets.
+            // Only packets in the same space may be acknowledged.
             // 0-RTT packets must never carry acks (which would have to be of handshake packets)
-            let acks = if !self.pending_acks.is_empty() {
-                //&& !crypto.is_0rtt() {
-                let delay = (now - self.rx_packet_time) >> ACK_DELAY_EXPONENT;
-                trace!(log, "ACK"; "ranges" => ?self.pending_acks.iter().collect::<Vec<_>>(), "delay" => delay);
-                frame::Ack::encode(delay, &self.pending_acks, &mut buf);
-                self.pending_acks.clone()
-            } else {
-                RangeSet::new()
+            let acks = {
+                let acks = &self.spaces[space as usize];
+                // Room for at least the newest range, which is never left out
+                if !acks.pending_acks.is_empty() && buf.len() + 26 < max_size {
+                    //&& !crypto.is_0rtt() {
+                    let delay = (now - acks.rx_packet_time) >> config.ack_delay_exponent;
+                    // Leave out the oldest ranges if they don't all fit; older packets are more
+                    // likely to have been acknowledged already, and the peer will give up on them
+                    // eventually
+                    let start = buf.len();
+                    let encoded = frame::Ack::encode(
+                        delay,
+                        &acks.pending_acks,
+                        max_size.saturating_sub(start),
+                        &mut buf,
+                    );
+                    trace!(log, "ACK"; "largest" => ?encoded.max(), "ranges" => encoded.len(), "len" => buf.len() - start, "delay" => delay);
+                    encoded
+                } else {
+                    RangeSet::new()
+                }
             };
 
             // PATH_RESPONSE
@@ -1754,6 +2607,7 @@ impl Connection {
                 sent.max_data = true;
                 buf.write(frame::Type::MAX_DATA);
                 buf.write_var(self.local_max_data);
+                self.advertised_max_data = self.local_max_data;
             }
 
             // MAX_STREAM_DATA
@@ -1764,8 +2618,8 @@ impl Connection {
                     break;
                 };
                 pending.max_stream_data.remove(&id);
-                let rs = if let Some(x) = self.streams.streams.get(&id) {
-                    x.recv().unwrap()
+                let rs = if let Some(x) = self.streams.streams.get_mut(&id) {
+                    x.recv_mut().unwrap()
                 } else {
                     continue;
                 };
@@ -1777,6 +2631,7 @@ impl Connection {
                 buf.write(frame::Type::MAX_STREAM_DATA);
                 buf.write(id);
                 buf.write_var(rs.max_data);
+                rs.advertised_max_data = rs.max_data;
             }
 
             // MAX_STREAM_ID uni
@@ -1805,13 +2660,113 @@ impl Connection {
                 ));
             }
 
-            // STREAM
-            while buf.len() + 25 < max_size {
-                let mut stream = if let Some(x) = pending.stream.pop_front() {
-                    x
+            // STREAM_ID_BLOCKED uni
+            if pending.uni_stream_id_blocked && buf.len() + 9 < max_size {
+                pending.uni_stream_id_blocked = false;
+                // Skip if the peer has raised the limit since this was queued
+                if self.streams.next_uni >= self.streams.max_uni {
+                    sent.uni_stream_id_blocked = true;
+                    let limit = self.streams.max_uni.saturating_sub(1);
+                    trace!(log, "STREAM_ID_BLOCKED (unidirectional)"; "value" => limit);
+                    buf.write(frame::Type::STREAM_ID_BLOCKED);
+                    buf.write(StreamId::new(self.side, Directionality::Uni, limit));
+                }
+            }
+
+            // STREAM_ID_BLOCKED bi
+            if pending.bi_stream_id_blocked && buf.len() + 9 < max_size {
+                pending.bi_stream_id_blocked = false;
+                if self.streams.next_bi >= self.streams.max_bi {
+                    sent.bi_stream_id_blocked = true;
+                    let limit = self.streams.max_bi.saturating_sub(1);
+                    trace!(log, "STREAM_ID_BLOCKED (bidirectional)"; "value" => limit);
+                    buf.write(frame::Type::STREAM_ID_BLOCKED);
+                    buf.write(StreamId::new(self.side, Directionality::Bi, limit));
+                }
+            }
+
+            // BLOCKED
+            if pending.blocked && buf.len() + 9 < max_size {
+                pending.blocked = false;
+                // Skip if the peer has raised the limit since this was queued
+                if self.data_sent >= self.max_data {
+                    sent.blocked = true;
+                    trace!(log, "BLOCKED"; "offset" => self.max_data);
+                    buf.write(frame::Type::BLOCKED);
+                    buf.write_var(self.max_data);
+                }
+            }
+
+            // STREAM_BLOCKED
+            while buf.len() + 17 < max_size {
+                let id = if let Some(x) = pending.stream_blocked.iter().next() {
+                    *x
                 } else {
                     break;
                 };
+                pending.stream_blocked.remove(&id);
+                let ss = if let Some(x) = self.streams.streams.get(&id) {
+                    x.send().unwrap()
+                } else {
+                    continue;
+                };
+                if ss.offset < ss.max_data {
+                    continue;
+                }
+                sent.stream_blocked.insert(id);
+                trace!(log, "STREAM_BLOCKED"; "stream" => id.0, "offset" => ss.max_data);
+                buf.write(frame::Type::STREAM_BLOCKED);
+                buf.write(id);
+                buf.write_var(ss.max_data);
+            }
+
+            // STREAM
+            while buf.len() + 25 < max_size {
+                // Serve the highest priority streams with data queued, in round-robin order of ID
+                // resuming after the one most recently sent on, so that a single backlogged stream
+                // can't starve others of equal priority. Lost data is scheduled alongside fresh
+                // data, so recovering one stream doesn't hold up the rest either.
+                let (id, contended) = {
+                    let streams = &self.streams.streams;
+                    let priority = |id: &StreamId| {
+                        streams
+                            .get(id)
+                            .and_then(|s| s.send())
+                            .map_or(0, |ss| ss.priority)
+                    };
+                    let queued = pending.lost_stream.iter().chain(&pending.stream);
+                    let top = match queued.clone().map(|x| priority(&x.id)).max() {
+                        Some(x) => x,
+                        None => break,
+                    };
+                    let cursor = self.stream_cursor;
+                    let mut next: Option<StreamId> = None;
+                    let mut first: Option<StreamId> = None;
+                    for frame in queued.clone() {
+                        if priority(&frame.id) != top {
+                            continue;
+                        }
+                        if frame.id > cursor && next.map_or(true, |id| frame.id < id) {
+                            next = Some(frame.id);
+                        }
+                        if first.map_or(true, |id| frame.id < id) {
+                            first = Some(frame.id);
+                        }
+                    }
+                    let id = next.or(first).unwrap();
+                    let contended = queued.clone().any(|x| x.id != id && priority(&x.id) == top);
+                    (id, contended)
+                };
+                // Within a stream, lost data goes first, and frames otherwise remain in order since
+                // we always take the first one queued
+                let lost = pending.lost_stream.iter().position(|x| x.id == id);
+                let mut stream = match lost {
+                    Some(i) => pending.lost_stream.remove(i).unwrap(),
+                    None => {
+                        let i = pending.stream.iter().position(|x| x.id == id).unwrap();
+                        pending.stream.remove(i).unwrap()
+                    }
+                };
                 if stream.id != StreamId(0) && self
                     .streams
                     .streams
@@ -1820,7 +2775,11 @@ impl Connection {
                 {
                     continue;
                 }
-                let len = cmp::min(stream.data.len(), max_size as usize - buf.len() - 25);
+                let mut len = cmp::min(stream.data.len(), max_size as usize - buf.len() - 25);
+                if contended {
+                    len = cmp::min(len, STREAM_CHUNK_SIZE);
+                }
+                self.stream_cursor = stream.id;
                 let data = stream.data.split_to(len);
                 let fin = stream.fin && stream.data.is_empty();
                 trace!(log, "STREAM"; "id" => stream.id.0, "off" => stream.offset, "len" => len, "fin" => fin);
@@ -1837,76 +2796,118 @@ impl Connection {
                         offset: stream.offset + len as u64,
                         ..stream
                     };
-                    pending.stream.push_front(stream);
+                    if lost.is_some() {
+                        pending.lost_stream.push_front(stream);
+                    } else {
+                        pending.stream.push_front(stream);
+                    }
                 }
             }
 
+            if space == SpaceId::Data && config.short_packet_padding != 0 {
+                let mut target = cmp::min(config.short_packet_padding, self.mtu) as usize;
+                if !ack_only {
+                    let window = self.congestion_window.saturating_sub(self.bytes_in_flight);
+                    target = cmp::min(target as u64, window) as usize;
+                }
+                let target = target.saturating_sub(AEAD_TAG_SIZE);
+                if buf.len() < target {
+                    buf.resize(target, frame::Type::PADDING.into());
+                }
+            }
+
+            // Every frame above is truncated or split to fit, or guarded by a space check
+            debug_assert!(buf.len() <= max_size, "packet exceeds MTU");
+
             if let Header::Initial { .. } = header {
-                if buf.len() < MIN_INITIAL_SIZE - AEAD_TAG_SIZE {
+                // Only the client must pad, to limit the amplification a spoofed Initial can buy
+                if self.side == Side::Client && buf.len() < MIN_INITIAL_SIZE - AEAD_TAG_SIZE {
                     buf.resize(
                         MIN_INITIAL_SIZE - AEAD_TAG_SIZE,
                         frame::Type::PADDING.into(),
                     );
                 }
             }
-            if crypto_level != CryptoLevel::OneRtt {
+            if space != SpaceId::Data {
                 let pn_len = match header {
                     Header::Initial { number, .. } | Header::Long { number, .. } => number.len(),
                     _ => panic!("invalid header for packet payload length"),
                 };
                 set_payload_length(&mut buf, header_len as usize, pn_len);
             }
-            crypto.encrypt(number, &mut buf, header_len as usize);
+            if crypto
+                .encrypt(number, &mut buf, header_len as usize)
+                .is_err()
+            {
+                // The peer will discard the packet, and loss detection will retransmit its frames
+                warn!(log, "failed to seal packet"; "pn" => number);
+            }
             partial_encode.finish(&mut buf, crypto.pn_encrypt_key(), header_len as usize);
-            (number, acks, ack_only, crypto_level == CryptoLevel::Initial)
+            (number, acks, ack_only, space)
         };
 
         // If we sent any acks, don't immediately resend them. Setting this even if ack_only is
         // false needlessly prevents us from ACKing the next packet if it's ACK-only, but saves
         // the need for subtler logic to avoid double-transmitting acks all the time.
-        self.permit_ack_only &= acks.is_empty();
+        self.spaces[space as usize].permit_ack_only &= acks.is_empty();
+        if space == SpaceId::Data && !acks.is_empty() {
+            self.ack_due = false;
+            if self.ack_eliciting_since_ack != 0 {
+                self.ack_eliciting_since_ack = 0;
+                self.set_delayed_ack = Some(None);
+            }
+        }
 
         self.on_packet_sent(
             config,
             now,
+            space,
             number,
             SentPacket {
                 acks,
                 time: now,
                 bytes: if ack_only { 0 } else { buf.len() as u16 },
-                handshake,
                 retransmits: sent,
             },
         );
 
-        Some(buf)
+        Some(buf.into())
     }
 
     // TLP/RTO transmit
     fn force_transmit(&mut self, config: &Config, now: u64) -> Box<[u8]> {
-        let number = self.get_tx_number();
+        let number = self.get_tx_number(SpaceId::Data);
         let mut buf = Vec::new();
         let header = Header::Short {
             dst_cid: self.rem_cid,
-            number: PacketNumber::new(number, self.largest_acked_packet),
+            number: PacketNumber::new(
+                number,
+                self.spaces[SpaceId::Data as usize].largest_acked_packet,
+            ),
             key_phase: self.key_phase,
+            spin: self.spin,
         };
         let partial_encode = header.encode(&mut buf);
         let header_len = buf.len() as u16;
         buf.push(frame::Type::PING.into());
         {
             let crypto = self.crypto.as_ref().unwrap();
-            crypto.encrypt(number, &mut buf, header_len as usize);
+            if crypto
+                .encrypt(number, &mut buf, header_len as usize)
+                .is_err()
+            {
+                warn!(self.log, "failed to seal packet"; "pn" => number);
+            }
             partial_encode.finish(&mut buf, crypto.pn_encrypt_key(), header_len as usize);
         }
         self.on_packet_sent(
             config,
             now,
+            SpaceId::Data,
             number,
             SentPacket {
                 time: now,
                 bytes: buf.len() as u16,
-                handshake: false,
                 acks: RangeSet::new(),
                 retransmits: Retransmits::default(),
             },
@@ -1915,12 +2916,16 @@ impl Connection {
     }
 
     fn make_close(&mut self, reason: &state::CloseReason) -> Box<[u8]> {
-        let number = self.get_tx_number();
+        let number = self.get_tx_number(SpaceId::Data);
         let mut buf = Vec::new();
         let header = Header::Short {
             dst_cid: self.rem_cid,
-            number: PacketNumber::new(number, self.largest_acked_packet),
+            number: PacketNumber::new(
+                number,
+                self.spaces[SpaceId::Data as usize].largest_acked_packet,
+            ),
             key_phase: self.key_phase,
+            spin: self.spin,
         };
         let partial_encode = header.encode(&mut buf);
         let header_len = buf.len() as u16;
@@ -1931,15 +2936,139 @@ impl Connection {
             state::CloseReason::Connection(ref x) => x.encode(&mut buf, max_len),
         }
 
-        let crypto = self
-            .crypto
-            .as_ref()
-            .unwrap_or_else(|| &self.handshake_crypto);
-        crypto.encrypt(number, &mut buf, header_len as usize);
-        partial_encode.finish(&mut buf, crypto.pn_encrypt_key(), header_len as usize);
+        {
+            let crypto = self
+                .crypto
+                .as_ref()
+                .unwrap_or_else(|| &self.handshake_crypto);
+            if crypto
+                .encrypt(number, &mut buf, header_len as usize)
+                .is_err()
+            {
+                warn!(self.log, "failed to seal packet"; "pn" => number);
+            }
+            partial_encode.finish(&mut buf, crypto.pn_encrypt_key(), header_len as usize);
+        }
+        buf.into()
+    }
+
+    /// Begin probing a new address the peer is sending from, so we can migrate to it once it
+    /// responds
+    fn validate_path(&mut self, ctx: &mut Context, remote: SocketAddr) {
+        if let Some(x) = self.path_challenge {
+            if x.remote == remote {
+                return;
+            }
+            if x.sent.is_some() {
+                // The peer may yet answer the challenge we're abandoning
+                self.retire_path_token(x.token);
+            }
+        }
+        trace!(self.log, "validating path to {remote}", remote = remote);
+        self.path_challenge = Some(PathChallenge::new(remote, ctx.rng.gen()));
+    }
+
+    /// Remember the token of a challenge that's no longer outstanding, so late responses to it
+    /// aren't mistaken for protocol violations
+    fn retire_path_token(&mut self, token: u64) {
+        if self.past_path_tokens.len() >= MAX_PAST_PATH_TOKENS {
+            self.past_path_tokens.pop_front();
+        }
+        self.past_path_tokens.push_back(token);
+    }
+
+    /// Account for a datagram of `len` bytes from `remote`, challenging it if it's the path being
+    /// validated and no challenge has been sent within the last PTO
+    fn probe_path(&mut self, ctx: &mut Context, now: u64, remote: SocketAddr, len: usize) {
+        let pto = self.rto(&ctx.config);
+        let token = match self.path_challenge {
+            Some(ref mut x) => {
+                if x.remote != remote {
+                    return;
+                }
+                x.bytes_recvd += len as u64;
+                if x.sent.map_or(false, |sent| now < sent + pto) {
+                    return;
+                }
+                x.token
+            }
+            None => return,
+        };
+        if self.state.as_ref().unwrap().is_closed() {
+            return;
+        }
+        let packet = self.make_path_challenge(token);
+        {
+            let x = self.path_challenge.as_mut().unwrap();
+            // Until the peer responds, the address may be spoofed
+            if x.bytes_sent + packet.len() as u64 > 3 * x.bytes_recvd {
+                trace!(self.log, "PATH_CHALLENGE blocked by anti-amplification limit");
+                return;
+            }
+            x.sent = Some(now);
+            x.bytes_sent += packet.len() as u64;
+        }
+        self.queue_datagram(ctx, remote, packet);
+    }
+
+    fn make_path_challenge(&mut self, token: u64) -> Box<[u8]> {
+        let number = self.get_tx_number(SpaceId::Data);
+        let mut buf = Vec::new();
+        let header = Header::Short {
+            dst_cid: self.rem_cid,
+            number: PacketNumber::new(
+                number,
+                self.spaces[SpaceId::Data as usize].largest_acked_packet,
+            ),
+            key_phase: self.key_phase,
+            spin: self.spin,
+        };
+        let partial_encode = header.encode(&mut buf);
+        let header_len = buf.len();
+        trace!(self.log, "PATH_CHALLENGE"; "value" => format!("{:08x}", token));
+        buf.write(frame::Type::PATH_CHALLENGE);
+        buf.write(token);
+        {
+            let crypto = self.crypto.as_ref().unwrap();
+            if crypto.encrypt(number, &mut buf, header_len).is_err() {
+                warn!(self.log, "failed to seal packet"; "pn" => number);
+            }
+            partial_encode.finish(&mut buf, crypto.pn_encrypt_key(), header_len);
+        }
         buf.into()
     }
 
+    /// Bytes that may be sent before the peer's address is validated, if it isn't yet
+    fn amplification_budget(&self) -> Option<u64> {
+        if self.address_validated {
+            return None;
+        }
+        Some((3 * self.wire_bytes_recvd).saturating_sub(self.wire_bytes_sent))
+    }
+
+    /// Hand a datagram off to the application for transmission
+    ///
+    /// Every datagram a connection sends passes through here, so this is where the
+    /// anti-amplification limit is enforced; a datagram that would exceed it is dropped as if lost.
+    pub fn queue_datagram(
+        &mut self,
+        ctx: &mut Context,
+        destination: SocketAddr,
+        packet: Box<[u8]>,
+    ) {
+        if let Some(budget) = self.amplification_budget() {
+            if packet.len() as u64 > budget {
+                trace!(self.log, "dropping datagram blocked by anti-amplification limit"; "len" => packet.len());
+                return;
+            }
+        }
+        self.wire_bytes_sent += packet.len() as u64;
+        ctx.io.push_back(Io::Transmit {
+            destination,
+            packet,
+        });
+    }
+
     /// Close a connection immediately
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility
@@ -1950,10 +3079,9 @@ impl Connection {
             state::CloseReason::Application(frame::ApplicationClose { error_code, reason });
         if !was_closed {
             self.close_common(ctx, now);
-            ctx.io.push_back(Io::Transmit {
-                destination: self.remote,
-                packet: self.make_close(&reason),
-            });
+            let packet = self.make_close(&reason);
+            let remote = self.remote;
+            self.queue_datagram(ctx, remote, packet);
             self.reset_idle_timeout(&ctx.config, now);
             ctx.dirty_conns.insert(self.handle);
         }
@@ -1975,29 +3103,65 @@ impl Connection {
     pub fn close_common(&mut self, ctx: &mut Context, now: u64) {
         trace!(self.log, "connection closed");
         self.set_loss_detection = Some(None);
+        self.set_delayed_ack = Some(None);
+        self.set_keep_alive = Some(None);
+        let time = now + 3 * self.rto(&ctx.config);
+        self.close_time = Some(time);
         ctx.io.push_back(Io::TimerStart {
             connection: self.handle,
             timer: Timer::Close,
-            time: now + 3 * self.rto(&ctx.config),
+            time,
         });
     }
 
-    fn set_params(&mut self, params: TransportParameters) {
+    /// Finish draining without waiting out the close timer
+    ///
+    /// Used once the peer has acknowledged our close by closing in turn, since it then won't be
+    /// sending anything more for us to absorb. Only ever brings a pending close timer forward.
+    fn drain_now(&mut self, ctx: &mut Context, now: u64) {
+        match self.close_time {
+            Some(time) if now < time => {}
+            _ => return,
+        }
+        self.close_time = Some(now);
+        ctx.io.push_back(Io::TimerStart {
+            connection: self.handle,
+            timer: Timer::Close,
+            time: now,
+        });
+    }
+
+    fn set_params(&mut self, config: &Config, params: TransportParameters) {
         self.streams.max_bi = params.initial_max_bidi_streams as u64;
         if self.side == Side::Client {
             self.streams.max_bi += 1;
         } // Account for TLS stream
         self.streams.max_uni = params.initial_max_uni_streams as u64;
         self.max_data = params.initial_max_data as u64;
-        for i in 0..self.streams.max_remote_bi {
-            let id = StreamId::new(!self.side, Directionality::Bi, i as u64);
-            self.streams.get_send_mut(&id).unwrap().max_data =
-                params.initial_max_stream_data_bidi_local as u64;
+        let side = self.side;
+        for (_, stream) in self
+            .streams
+            .streams
+            .iter_mut()
+            .filter(|&(id, _)| id.initiator() != side && id.directionality() == Directionality::Bi)
+        {
+            stream.send_mut().unwrap().max_data = params.initial_max_stream_data_bidi_local as u64;
         }
+        self.mtu = cmp::max(
+            MIN_MTU,
+            cmp::min(config.max_packet_size, params.max_packet_size),
+        );
         self.params = params;
     }
 
-    pub fn open(&mut self, config: &Config, direction: Directionality) -> Option<StreamId> {
+    pub fn open(
+        &mut self,
+        config: &Config,
+        direction: Directionality,
+    ) -> Result<StreamId, OpenError> {
+        if self.state.as_ref().unwrap().is_closed() {
+            return Err(OpenError::ConnectionClosed);
+        }
         let (id, mut stream) = match direction {
             Directionality::Uni if self.streams.next_uni < self.streams.max_uni => {
                 self.streams.next_uni += 1;
@@ -2014,8 +3178,9 @@ impl Connection {
                 )
             }
             _ => {
-                return None;
-            } // TODO: Queue STREAM_ID_BLOCKED
+                self.stream_id_blocked(direction);
+                return Err(OpenError::LimitReached);
+            }
         };
         stream.send_mut().unwrap().max_data = match direction {
             Directionality::Uni => self.params.initial_max_stream_data_uni,
@@ -2023,71 +3188,130 @@ impl Connection {
         } as u64;
         let old = self.streams.streams.insert(id, stream);
         assert!(old.is_none());
-        Some(id)
+        Ok(id)
+    }
+
+    /// Ask the peer for more streams, unless we've already done so at the current limit
+    fn stream_id_blocked(&mut self, direction: Directionality) {
+        let (max, blocked_at, pending) = match direction {
+            Directionality::Uni => (
+                self.streams.max_uni,
+                &mut self.streams.uni_blocked_at,
+                &mut self.pending.uni_stream_id_blocked,
+            ),
+            Directionality::Bi => (
+                self.streams.max_bi,
+                &mut self.streams.bi_blocked_at,
+                &mut self.pending.bi_stream_id_blocked,
+            ),
+        };
+        if *blocked_at != Some(max) {
+            *blocked_at = Some(max);
+            *pending = true;
+        }
     }
 
     /// Discard state for a stream if it's fully closed.
     ///
     /// Called when one side of a stream transitions to a closed state
-    pub fn maybe_cleanup(&mut self, config: &Config, id: StreamId) {
-        let new = match self.streams.streams.entry(id) {
+    pub fn maybe_cleanup(&mut self, id: StreamId) {
+        let replace = match self.streams.streams.entry(id) {
             hash_map::Entry::Vacant(_) => unreachable!(),
             hash_map::Entry::Occupied(e) => {
                 if e.get().is_closed() {
                     e.remove_entry();
-                    if id.initiator() != self.side {
-                        Some(match id.directionality() {
-                            Directionality::Uni => {
-                                self.streams.max_remote_uni += 1;
-                                self.pending.max_uni_stream_id = true;
-                                (
-                                    StreamId::new(
-                                        !self.side,
-                                        Directionality::Uni,
-                                        self.streams.max_remote_uni - 1,
-                                    ),
-                                    stream::Recv::new(u64::from(
-                                        config.stream_receive_window as u64,
-                                    )).into(),
-                                )
-                            }
-                            Directionality::Bi => {
-                                self.streams.max_remote_bi += 1;
-                                self.pending.max_bi_stream_id = true;
-                                (
-                                    StreamId::new(
-                                        !self.side,
-                                        Directionality::Bi,
-                                        self.streams.max_remote_bi - 1,
-                                    ),
-                                    Stream::new_bi(config.stream_receive_window as u64),
-                                )
-                            }
-                        })
-                    } else {
-                        None
-                    }
+                    self.stalled_streams.remove(&id);
+                    id.initiator() != self.side
                 } else {
-                    None
+                    false
                 }
             }
         };
-        if let Some((id, stream)) = new {
-            self.streams.streams.insert(id, stream);
+        if replace {
+            self.grant_streams(id.directionality(), 1);
         }
     }
 
-    pub fn finish(&mut self, id: StreamId) {
-        let ss = self
-            .streams
-            .get_send_mut(&id)
-            .expect("unknown or recv-only stream");
-        assert_eq!(ss.state, stream::SendState::Ready);
+    /// Permit the peer to open `additional` more streams of the given directionality
+    ///
+    /// Streams are allocated as the peer opens them, so only the limit is raised here.
+    pub fn grant_streams(&mut self, directionality: Directionality, additional: u64) {
+        let (max, pending) = match directionality {
+            Directionality::Uni => (
+                &mut self.streams.max_remote_uni,
+                &mut self.pending.max_uni_stream_id,
+            ),
+            Directionality::Bi => (
+                &mut self.streams.max_remote_bi,
+                &mut self.pending.max_bi_stream_id,
+            ),
+        };
+        let new = cmp::min(max.saturating_add(additional), MAX_STREAM_COUNT);
+        if new > *max {
+            *max = new;
+            *pending = true;
+        }
+    }
+
+    /// Top up the peer's unopened stream credit to `Config::remote_stream_headroom`
+    fn extend_remote_streams(&mut self, config: &Config) {
+        let headroom = u64::from(config.remote_stream_headroom);
+        if headroom == 0 {
+            return;
+        }
+        for &dir in &[Directionality::Uni, Directionality::Bi] {
+            let limit = match dir {
+                Directionality::Uni => config.max_remote_uni_streams,
+                Directionality::Bi => config.max_remote_bi_streams,
+            };
+            if limit == 0 {
+                // The peer may not open streams of this directionality at all
+                continue;
+            }
+            let n = {
+                let (max, next, extra) = match dir {
+                    Directionality::Uni => (
+                        self.streams.max_remote_uni,
+                        self.streams.next_remote_uni,
+                        &mut self.streams.extra_remote_uni,
+                    ),
+                    Directionality::Bi => (
+                        self.streams.max_remote_bi,
+                        self.streams.next_remote_bi,
+                        &mut self.streams.extra_remote_bi,
+                    ),
+                };
+                let n = cmp::min(headroom.saturating_sub(max - next), headroom - *extra);
+                *extra += n;
+                n
+            };
+            self.grant_streams(dir, n);
+        }
+    }
+
+    pub fn finish(&mut self, id: StreamId) -> Result<(), FinishError> {
+        if id.directionality() == Directionality::Uni && id.initiator() != self.side {
+            return Err(FinishError::InvalidStream);
+        }
+        let ss = match self.streams.get_send_mut(&id) {
+            Some(ss) => ss,
+            // Already finished or reset, and since closed
+            None => return Err(FinishError::Finished),
+        };
+        if ss.state.is_finished() {
+            trace!(self.log, "ignoring redundant finish"; "stream" => id.0);
+            return Err(FinishError::Finished);
+        }
         ss.state = stream::SendState::DataSent;
-        for frame in &mut self.pending.stream {
+        for frame in self
+            .pending
+            .lost_stream
+            .iter_mut()
+            .chain(&mut self.pending.stream)
+        {
             if frame.id == id && frame.offset + frame.data.len() as u64 == ss.offset {
                 frame.fin = true;
-                return;
+                return Ok(());
             }
         }
         self.pending.stream.push_back(frame::Stream {
@@ -2096,53 +3320,166 @@ impl Connection {
             offset: ss.offset,
             fin: true,
         });
+        Ok(())
     }
 
-    pub fn read_unordered(&mut self, id: StreamId) -> Result<(Bytes, u64), ReadError> {
+    pub fn read_unordered(
+        &mut self,
+        config: &Config,
+        id: StreamId,
+    ) -> Result<(Bytes, u64), ReadError> {
         assert_ne!(id, StreamId(0), "cannot read an internal stream");
-        let rs = self.streams.get_recv_mut(&id).unwrap();
-        let (buf, len) = rs.read_unordered()?;
-        // TODO: Reduce granularity of flow control credit, while still avoiding stalls, to
-        // reduce overhead
-        self.local_max_data += buf.len() as u64; // BUG: Don't issue credit for
-                                                 // already-received data!
-        self.pending.max_data = true;
-        if rs.receiving_unknown_size() {
-            self.pending.max_stream_data.insert(id);
+        let (result, unconsumed) = {
+            let rs = self.streams.get_recv_mut(&id).unwrap();
+            let unconsumed = rs.unconsumed();
+            let prev = rs.delivered_offset();
+            let result = rs.read_unordered();
+            // Segments may be delivered more than once, so only count newly consumed bytes
+            self.local_max_data += rs.delivered_offset() - prev;
+            (result, unconsumed)
+        };
+        if let Err(ReadError::Reset { .. }) = result {
+            self.reclaim_credit(id, unconsumed);
+        }
+        self.maybe_issue_credit(config, id);
+        result
+    }
+
+    pub fn read(
+        &mut self,
+        config: &Config,
+        id: StreamId,
+        buf: &mut [u8],
+    ) -> Result<usize, ReadError> {
+        self.read_ordered(config, id, |rs| rs.read(buf).map(|n| (n, n)))
+    }
+
+    pub fn read_bytes(
+        &mut self,
+        config: &Config,
+        id: StreamId,
+        max_len: usize,
+    ) -> Result<Bytes, ReadError> {
+        self.read_ordered(config, id, |rs| {
+            rs.read_bytes(max_len).map(|data| {
+                let n = data.len();
+                (data, n)
+            })
+        })
+    }
+
+    /// Consume data from a stream with `f`, which returns its result and the number of bytes read
+    fn read_ordered<T, F>(&mut self, config: &Config, id: StreamId, f: F) -> Result<T, ReadError>
+    where
+        F: FnOnce(&mut stream::Recv) -> Result<(T, usize), ReadError>,
+    {
+        assert_ne!(id, StreamId(0), "cannot read an internal stream");
+        let (result, unconsumed) = {
+            let rs = self.streams.get_recv_mut(&id).unwrap();
+            let unconsumed = rs.unconsumed();
+            (f(rs), unconsumed)
+        };
+        let result = match result {
+            Ok((x, len)) => {
+                self.local_max_data += len as u64;
+                Ok(x)
+            }
+            Err(e @ ReadError::Reset { .. }) => {
+                self.reclaim_credit(id, unconsumed);
+                Err(e)
+            }
+            Err(e) => Err(e),
+        };
+        self.maybe_issue_credit(config, id);
+        result
+    }
+
+    /// Release connection-level credit for data on a reset stream that will never be read
+    ///
+    /// Without this, data the peer sent or skipped before resetting a stream would count against
+    /// the connection's flow control limit forever.
+    fn reclaim_credit(&mut self, id: StreamId, unconsumed: u64) {
+        trace!(self.log, "reclaiming credit from reset stream"; "stream" => id, "bytes" => unconsumed);
+        self.local_max_data += unconsumed;
+    }
+
+    /// Earliest time at which any of this connection's timers is armed to fire
+    pub fn next_timeout(&self) -> Option<u64> {
+        [
+            self.idle_time,
+            self.loss_detection_time,
+            self.delayed_ack_time,
+            self.close_time,
+            self.keep_alive_time,
+        ]
+        .iter()
+        .filter_map(|&x| x)
+        .min()
+    }
+
+    pub fn stats(&self) -> ConnectionStats {
+        ConnectionStats {
+            bytes_in_flight: self.bytes_in_flight,
+            congestion_window: self.congestion_window,
+            ssthresh: self.ssthresh,
+            smoothed_rtt: self.smoothed_rtt,
+            min_rtt: self.min_rtt,
+            unacked_packets: self
+                .spaces
+                .iter()
+                .map(|x| x.sent_packets.len() as u64)
+                .sum(),
+            lost_packets: self.lost_packets,
+            tlp_count: self.tlp_count,
+            rto_count: self.rto_count,
+            data_sent: self.data_sent,
+            data_recvd: self.data_recvd,
+            wire_bytes_sent: self.wire_bytes_sent,
+            wire_bytes_recvd: self.wire_bytes_recvd,
+            flow_control_blocked_writes: self.flow_control_blocked_writes,
         }
-        Ok((buf, len))
     }
 
-    pub fn read(&mut self, id: StreamId, buf: &mut [u8]) -> Result<usize, ReadError> {
+    pub fn peek(&mut self, id: StreamId, buf: &mut [u8]) -> Result<usize, ReadError> {
         assert_ne!(id, StreamId(0), "cannot read an internal stream");
+        self.streams.get_recv_mut(&id).unwrap().peek(buf)
+    }
+
+    /// Queue flow control updates once the peer has used up enough of its credit that it may soon
+    /// become blocked
+    ///
+    /// Advertising every increase would cost a frame per read.
+    fn maybe_issue_credit(&mut self, config: &Config, id: StreamId) {
+        if self.local_max_data - self.advertised_max_data >= config.receive_window as u64 / 2 {
+            self.pending.max_data = true;
+        }
         let rs = self.streams.get_recv_mut(&id).unwrap();
-        let len = rs.read(buf)?;
-        // TODO: Reduce granularity of flow control credit, while still avoiding stalls, to
-        // reduce overhead
-        self.local_max_data += len as u64;
-        self.pending.max_data = true;
-        if rs.receiving_unknown_size() {
+        if rs.receiving_unknown_size()
+            && rs.max_data - rs.advertised_max_data >= config.stream_receive_window as u64 / 2
+        {
             self.pending.max_stream_data.insert(id);
         }
-        Ok(len)
     }
 
-    pub fn stop_sending(&mut self, id: StreamId, error_code: u16) {
-        assert!(
-            id.directionality() == Directionality::Bi || id.initiator() != self.side,
-            "only streams supporting incoming data may be reset"
-        );
-        let stream = self
-            .streams
-            .streams
-            .get(&id)
-            .expect("stream must have begun sending to be stopped")
-            .recv()
-            .unwrap();
+    pub fn stop_sending(
+        &mut self,
+        id: StreamId,
+        error_code: u16,
+    ) -> Result<(), InvalidStreamError> {
+        // Only streams supporting incoming data may be stopped
+        if id.directionality() == Directionality::Uni && id.initiator() == self.side {
+            return Err(InvalidStreamError);
+        }
+        let stream = match self.streams.streams.get(&id) {
+            Some(x) => x.recv().unwrap(),
+            // Already closed
+            None => return Ok(()),
+        };
         // Only bother if there's data we haven't received yet
         if !stream.is_finished() {
             self.pending.stop_sending.push((id, error_code));
         }
+        Ok(())
     }
 
     fn congestion_blocked(&self) -> bool {
@@ -2155,27 +3492,43 @@ impl Connection {
 
     fn decrypt_packet(
         &mut self,
-        handshake: bool,
+        config: &Config,
+        space: Option<SpaceId>,
         packet: &mut Packet,
     ) -> Result<u64, Option<TransportError>> {
+        let space = match space {
+            Some(x) => x,
+            None => {
+                return Err(None);
+            }
+        };
         let (key_phase, number) = match packet.header {
             Header::Short {
                 key_phase, number, ..
-            }
-                if !handshake =>
-            {
-                (key_phase, number)
-            }
-            Header::Initial { number, .. } | Header::Long { number, .. } if handshake => {
-                (false, number)
-            }
+            } => (key_phase, number),
+            Header::Initial { number, .. } | Header::Long { number, .. } => (false, number),
             _ => {
                 return Err(None);
             }
         };
-        let number = number.expand(self.rx_packet);
+        let rx_packet = self.spaces[space as usize].rx_packet;
+        let number = number.expand(rx_packet);
+        if space != SpaceId::Data {
+            self.handshake_crypto
+                .decrypt(number, &packet.header_data, &mut packet.payload)
+                .map_err(|()| None)?;
+            return Ok(number);
+        }
         if key_phase != self.key_phase {
-            if number <= self.rx_packet {
+            if let Some((boundary, ref prev)) = self.prev_crypto {
+                if number < boundary {
+                    // Reordered from before the last update, or the peer hasn't yet seen ours
+                    prev.decrypt(number, &packet.header_data, &mut packet.payload)
+                        .map_err(|()| None)?;
+                    return Ok(number);
+                }
+            }
+            if number <= rx_packet {
                 // Illegal key update
                 return Err(Some(TransportError::PROTOCOL_VIOLATION));
             }
@@ -2185,41 +3538,172 @@ impl Connection {
 
             let old = mem::replace(self.crypto.as_mut().unwrap(), new);
             self.prev_crypto = Some((number, old));
+            self.prev_crypto_expiry = None;
+            self.prev_crypto_sent = self.spaces[SpaceId::Data as usize].largest_sent_packet;
             self.key_phase = !self.key_phase;
+            if let Some(ref observer) = config.observer {
+                observer.key_updated(self.handle);
+            }
             Ok(number)
         } else {
-            let crypto = match (handshake, &self.prev_crypto) {
-                (true, _) => &self.handshake_crypto,
-                (false, &Some((boundary, ref prev))) if number < boundary => prev,
-                _ => self.crypto.as_ref().unwrap(),
-            };
-            crypto
+            self.crypto
+                .as_ref()
+                .unwrap()
                 .decrypt(number, &packet.header_data, &mut packet.payload)
                 .map_err(|()| None)?;
+            if let Some((ref mut boundary, _)) = self.prev_crypto {
+                if *boundary == u64::max_value() {
+                    // First packet from the peer under keys we initiated
+                    *boundary = number;
+                }
+            }
             Ok(number)
         }
     }
 
+    /// Discard the previous generation of 1-RTT keys a PTO after the peer confirms an update
+    ///
+    /// Retaining them any longer would only serve to accept very old, possibly replayed packets.
+    /// Keys that still protect unacknowledged packets are kept until those are resolved.
+    fn expire_prev_crypto(&mut self, config: &Config, now: u64) {
+        match self.prev_crypto {
+            // Not yet confirmed by a packet in the new phase
+            Some((boundary, _)) if boundary != u64::max_value() => {}
+            _ => return,
+        }
+        if self.prev_crypto_expiry.is_none() {
+            self.prev_crypto_expiry = Some(now + self.rto(config));
+        }
+        self.retire_prev_crypto(now);
+    }
+
+    /// Switch to the next generation of 1-RTT keys
+    ///
+    /// Has no effect before the handshake completes, or while the peer has yet to respond to a
+    /// previous update.
+    pub fn force_key_update(&mut self, config: &Config) {
+        match *self.state.as_ref().unwrap() {
+            State::Established => {}
+            _ => return,
+        }
+        if let Some((boundary, _)) = self.prev_crypto {
+            if boundary == u64::max_value() {
+                trace!(self.log, "key update already in progress");
+                return;
+            }
+        }
+        let new = self.crypto.as_mut().unwrap().update(self.side);
+        let old = mem::replace(self.crypto.as_mut().unwrap(), new);
+        // Until the peer responds in the new phase, anything it sends in the old phase is current
+        self.prev_crypto = Some((u64::max_value(), old));
+        self.prev_crypto_expiry = None;
+        self.prev_crypto_sent = self.spaces[SpaceId::Data as usize].largest_sent_packet;
+        self.key_phase = !self.key_phase;
+        trace!(self.log, "initiated key update");
+        if let Some(ref observer) = config.observer {
+            observer.key_updated(self.handle);
+        }
+    }
+
     pub fn write(
         &mut self,
         ctx: &mut Context,
         stream: StreamId,
         data: &[u8],
+    ) -> Result<usize, WriteError> {
+        self.write_vectored(ctx, stream, &[data])
+    }
+
+    pub fn write_vectored(
+        &mut self,
+        ctx: &mut Context,
+        stream: StreamId,
+        bufs: &[&[u8]],
+    ) -> Result<usize, WriteError> {
+        let len = bufs.iter().map(|x| x.len() as u64).sum::<u64>();
+        let n = self.write_limit(ctx, stream, len)?;
+        let mut data = BytesMut::with_capacity(n);
+        for buf in bufs {
+            let remaining = n - data.len();
+            if remaining == 0 {
+                break;
+            }
+            data.extend_from_slice(&buf[0..remaining.min(buf.len())]);
+        }
+        self.transmit(stream, data.freeze());
+        ctx.dirty_conns.insert(self.handle);
+        trace!(self.log, "write"; "stream" => stream.0, "len" => n);
+        Ok(n)
+    }
+
+    pub fn write_bytes(
+        &mut self,
+        ctx: &mut Context,
+        stream: StreamId,
+        mut data: Bytes,
+    ) -> Result<Bytes, WriteError> {
+        let n = self.write_limit(ctx, stream, data.len() as u64)?;
+        let data = data.split_to(n);
+        self.transmit(stream, data.clone());
+        ctx.dirty_conns.insert(self.handle);
+        trace!(self.log, "write"; "stream" => stream.0, "len" => n);
+        Ok(data)
+    }
+
+    /// Queue an unreliable datagram for transmission
+    ///
+    /// The datagram is sent in a single DATAGRAM frame, and is not retransmitted if lost.
+    pub fn send_datagram(&mut self, ctx: &mut Context, data: Bytes) -> Result<(), DatagramError> {
+        if self.params.max_datagram_frame_size == 0 {
+            return Err(DatagramError::UnsupportedByPeer);
+        }
+        let size = frame::Datagram::size(data.len());
+        // Leave room for the largest possible short header
+        let space = (self.mtu as usize - AEAD_TAG_SIZE).saturating_sub(1 + self.rem_cid.len() + 4);
+        if size > self.params.max_datagram_frame_size as usize || size > space {
+            return Err(DatagramError::TooLarge);
+        }
+        if self.pending.datagrams.len() >= ctx.config.max_queued_datagrams as usize {
+            trace!(self.log, "datagram queue full, dropping the oldest");
+            self.pending.datagrams.pop_front();
+        }
+        trace!(self.log, "queued datagram"; "len" => data.len());
+        self.pending.datagrams.push_back(data);
+        ctx.dirty_conns.insert(self.handle);
+        Ok(())
+    }
+
+    /// How many of `len` bytes may be written to `stream` right now
+    fn write_limit(
+        &mut self,
+        ctx: &mut Context,
+        stream: StreamId,
+        len: u64,
     ) -> Result<usize, WriteError> {
         assert!(stream.directionality() == Directionality::Bi || stream.initiator() == self.side);
         if self.state.as_ref().unwrap().is_closed() {
-            trace!(self.log, "write blocked; connection draining"; "stream" => stream.0);
-            return Err(WriteError::Blocked);
+            trace!(self.log, "write rejected; connection closed"; "stream" => stream.0);
+            return Err(WriteError::ConnectionClosed);
         }
 
         if self.blocked() {
-            if self.congestion_blocked() {
+            let err = if self.congestion_blocked() {
                 trace!(self.log, "write blocked by congestion"; "stream" => stream.0);
+                WriteError::CongestionLimited
             } else {
                 trace!(self.log, "write blocked by connection-level flow control"; "stream" => stream.0);
+                WriteError::FlowControlLimited
+            };
+            if self.data_sent >= self.max_data {
+                self.flow_control_blocked_writes += 1;
+            }
+            if self.data_sent >= self.max_data && self.data_blocked_at != Some(self.max_data) {
+                self.data_blocked_at = Some(self.max_data);
+                self.pending.blocked = true;
+                ctx.dirty_conns.insert(self.handle);
             }
             self.blocked_streams.insert(stream);
-            return Err(WriteError::Blocked);
+            return Err(err);
         }
 
         let budget_res = {
@@ -2232,21 +3716,87 @@ impl Connection {
         let stream_budget = match budget_res {
             Ok(budget) => budget,
             Err(e @ WriteError::Stopped { .. }) => {
-                self.maybe_cleanup(&ctx.config, stream);
+                self.maybe_cleanup(stream);
                 return Err(e);
             }
-            Err(e @ WriteError::Blocked) => {
+            Err(e @ WriteError::FlowControlLimited) => {
                 trace!(self.log, "write blocked by flow control"; "stream" => stream.0);
+                let ss = self.streams.get_send_mut(&stream).unwrap();
+                if ss.blocked_at != Some(ss.max_data) {
+                    ss.blocked_at = Some(ss.max_data);
+                    self.pending.stream_blocked.insert(stream);
+                    ctx.dirty_conns.insert(self.handle);
+                }
                 return Err(e);
             }
         };
 
-        let conn_budget = self.max_data - self.data_sent;
-        let n = conn_budget.min(stream_budget).min(data.len() as u64) as usize;
-        self.transmit(stream, (&data[0..n]).into());
-        ctx.dirty_conns.insert(self.handle);
-        trace!(self.log, "write"; "stream" => stream.0, "len" => n);
-        Ok(n)
+        // Saturating in case the peer's limit is somehow below what we've already sent, e.g. if it
+        // has granted no credit at all
+        let conn_budget = self.max_data.saturating_sub(self.data_sent);
+        Ok(conn_budget.min(stream_budget).min(len) as usize)
+    }
+
+    pub fn stream_state(&self, id: StreamId) -> Option<stream::StreamState> {
+        let s = self.streams.streams.get(&id)?;
+        let send_budget = match s.send() {
+            Some(ss) if ss.state == stream::SendState::Ready => {
+                let conn_budget = self.max_data.saturating_sub(self.data_sent);
+                conn_budget.min(ss.max_data.saturating_sub(ss.offset))
+            }
+            _ => 0,
+        };
+        Some(stream::StreamState {
+            status: s.status(),
+            send: s.send().map(|x| x.state),
+            recv: s.recv().map(|x| x.state),
+            send_budget,
+        })
+    }
+
+    pub fn set_priority(
+        &mut self,
+        stream: StreamId,
+        priority: i32,
+    ) -> Result<(), InvalidStreamError> {
+        // Only streams supporting outgoing data have a priority
+        if stream.directionality() == Directionality::Uni && stream.initiator() != self.side {
+            return Err(InvalidStreamError);
+        }
+        // Closed streams have nothing left to send
+        if let Some(ss) = self.streams.get_send_mut(&stream) {
+            ss.priority = priority;
+        }
+        Ok(())
+    }
+
+    pub fn remote_stream_count(&self, directionality: Directionality) -> u64 {
+        let next = self.streams.next_remote(directionality);
+        self.streams
+            .streams
+            .keys()
+            .filter(|id| {
+                id.initiator() != self.side
+                    && id.directionality() == directionality
+                    && id.index() < next
+            }).count() as u64
+    }
+
+    pub fn open_stream_count(&self) -> (usize, usize) {
+        let mut counts = (0, 0);
+        for id in self.streams.streams.keys() {
+            if *id == StreamId(0) {
+                // Carries the handshake, and is never opened by the application
+                continue;
+            }
+            if id.initiator() == self.side {
+                counts.0 += 1;
+            } else if id.index() < self.streams.next_remote(id.directionality()) {
+                // Remote streams above `next_remote` are allocated ahead of time, but not yet open
+                counts.1 += 1;
+            }
+        }
+        counts
     }
 
     pub fn poll(&mut self) -> Option<Event> {
@@ -2266,6 +3816,58 @@ enum CryptoLevel {
     OneRtt,
 }
 
+impl CryptoLevel {
+    /// Whether `frame` may legally appear in a packet protected at this level
+    fn permits(&self, frame: &Frame) -> bool {
+        match *self {
+            CryptoLevel::Initial => match *frame {
+                Frame::Padding
+                | Frame::Ack(_)
+                | Frame::ConnectionClose(_)
+                | Frame::ApplicationClose(_)
+                | Frame::PathChallenge(_)
+                | Frame::PathResponse(_) => true,
+                Frame::Stream(ref x) => x.id == StreamId(0),
+                _ => false,
+            },
+            CryptoLevel::OneRtt => match *frame {
+                // The handshake stream can't be abandoned without abandoning the connection
+                Frame::RstStream(ref x) => x.id != StreamId(0),
+                Frame::StopSending { id, .. } => id != StreamId(0),
+                Frame::Padding
+                | Frame::ConnectionClose(_)
+                | Frame::ApplicationClose(_)
+                | Frame::MaxData(_)
+                | Frame::MaxStreamData { .. }
+                | Frame::MaxStreamId(_)
+                | Frame::Ping
+                | Frame::Blocked { .. }
+                | Frame::StreamBlocked { .. }
+                | Frame::StreamIdBlocked { .. }
+                | Frame::Ack(_)
+                | Frame::Stream(_)
+                | Frame::PathChallenge(_)
+                | Frame::PathResponse(_)
+                | Frame::NewConnectionId { .. }
+                | Frame::Datagram(_) => true,
+                // Reported as a frame encoding error by the caller
+                Frame::Invalid(_) => true,
+            },
+        }
+    }
+}
+
+/// Whether a flow control limit of `new`, received in packet `number`, lowers the limit `current`
+/// set by packet `current_pn`, or by the transport parameters if `None`
+///
+/// Reordering can deliver a stale limit, but only in a packet sent before the one that raised it.
+fn limit_lowered(current: u64, current_pn: Option<u64>, new: u64, number: u64) -> bool {
+    match current_pn {
+        None => new < current,
+        Some(pn) => (new < current && number > pn) || (new > current && number < pn),
+    }
+}
+
 /// Extract stream 0 data from an Initial or Retry packet payload
 fn parse_initial(log: &Logger, payload: Bytes) -> Result<Option<frame::Stream>, ()> {
     let mut result = None;
@@ -2297,6 +3899,7 @@ fn parse_initial(log: &Logger, payload: Bytes) -> Result<Option<frame::Stream>,
 
 pub fn handshake_close<R>(
     crypto: &Crypto,
+    version: u32,
     remote_id: &ConnectionId,
     local_id: &ConnectionId,
     packet_number: u8,
@@ -2309,6 +3912,7 @@ where
     let number = PacketNumber::U8(packet_number);
     let header = Header::Long {
         ty: LongType::Handshake,
+        version,
         dst_cid: *remote_id,
         src_cid: *local_id,
         number,
@@ -2333,7 +3937,8 @@ where
         }
     }
     set_payload_length(&mut buf, header_len, number.len());
-    crypto.encrypt(packet_number as u64, &mut buf, header_len);
+    // A close that fails to seal is indistinguishable from one lost in transit
+    let _ = crypto.encrypt(packet_number as u64, &mut buf, header_len);
     partial_encode.finish(&mut buf, crypto.pn_encrypt_key(), header_len);
     buf.into()
 }
@@ -2349,15 +3954,39 @@ struct Streams {
     // Remotely initiated
     max_remote_uni: u64,
     max_remote_bi: u64,
+    // One past the highest index of a remotely initiated stream the peer has used, implicitly
+    // opening all those below it
+    next_remote_uni: u64,
+    next_remote_bi: u64,
+    // Credit issued beyond the configured limits, bounded by `Config::remote_stream_headroom`.
+    // Never shrinks: `maybe_cleanup` returns the credit of every remote stream that closes, extra
+    // or not, so the headroom is re-granted as streams come and go, and only the number open at
+    // once is bounded.
+    extra_remote_uni: u64,
+    extra_remote_bi: u64,
+    // Locally initiated limits at which we most recently told the peer we were blocked
+    uni_blocked_at: Option<u64>,
+    bi_blocked_at: Option<u64>,
 
     finished: Vec<StreamId>,
 }
 
 impl Streams {
+    fn next_remote(&self, directionality: Directionality) -> u64 {
+        match directionality {
+            Directionality::Bi => self.next_remote_bi,
+            Directionality::Uni => self.next_remote_uni,
+        }
+    }
+
+    /// Look up a stream the peer may send on, opening it and any below it if it's remotely
+    /// initiated and new
     fn get_recv_stream(
         &mut self,
         side: Side,
         id: StreamId,
+        config: &Config,
+        params: &TransportParameters,
     ) -> Result<Option<&mut Stream>, TransportError> {
         if side == id.initiator() {
             match id.directionality() {
@@ -2377,6 +4006,26 @@ impl Streams {
             if id.index() >= limit {
                 return Err(TransportError::STREAM_ID_ERROR);
             }
+            let next = match id.directionality() {
+                Directionality::Bi => &mut self.next_remote_bi,
+                Directionality::Uni => &mut self.next_remote_uni,
+            };
+            for index in *next..id.index() + 1 {
+                let id = StreamId::new(!side, id.directionality(), index);
+                self.streams.entry(id).or_insert_with(|| {
+                    let window = u64::from(config.stream_receive_window);
+                    match id.directionality() {
+                        Directionality::Uni => stream::Recv::new(window).into(),
+                        Directionality::Bi => {
+                            let mut stream = Stream::new_bi(window);
+                            stream.send_mut().unwrap().max_data =
+                                params.initial_max_stream_data_bidi_local as u64;
+                            stream
+                        }
+                    }
+                });
+            }
+            *next = cmp::max(*next, id.index() + 1);
         }
         Ok(self.streams.get_mut(&id))
     }
@@ -2395,14 +4044,21 @@ pub struct Retransmits {
     pub max_data: bool,
     pub max_uni_stream_id: bool,
     pub max_bi_stream_id: bool,
+    pub uni_stream_id_blocked: bool,
+    pub bi_stream_id_blocked: bool,
+    pub blocked: bool,
     pub ping: bool,
     pub new_connection_id: Option<ConnectionId>,
     pub stream: VecDeque<frame::Stream>,
+    /// Stream data declared lost, sent ahead of anything in `stream` for the same stream
+    pub lost_stream: VecDeque<frame::Stream>,
     /// packet number, token
     pub path_response: Option<(u64, u64)>,
     pub rst_stream: Vec<(StreamId, u16)>,
     pub stop_sending: Vec<(StreamId, u16)>,
     pub max_stream_data: FnvHashSet<StreamId>,
+    pub stream_blocked: FnvHashSet<StreamId>,
+    pub datagrams: VecDeque<Bytes>,
 }
 
 impl Retransmits {
@@ -2410,13 +4066,19 @@ impl Retransmits {
         !self.max_data
             && !self.max_uni_stream_id
             && !self.max_bi_stream_id
+            && !self.uni_stream_id_blocked
+            && !self.bi_stream_id_blocked
+            && !self.blocked
             && !self.ping
             && self.new_connection_id.is_none()
             && self.stream.is_empty()
+            && self.lost_stream.is_empty()
             && self.path_response.is_none()
             && self.rst_stream.is_empty()
             && self.stop_sending.is_empty()
             && self.max_stream_data.is_empty()
+            && self.stream_blocked.is_empty()
+            && self.datagrams.is_empty()
     }
 
     pub fn path_challenge(&mut self, packet: u64, token: u64) {
@@ -2438,13 +4100,19 @@ impl Default for Retransmits {
             max_data: false,
             max_uni_stream_id: false,
             max_bi_stream_id: false,
+            uni_stream_id_blocked: false,
+            bi_stream_id_blocked: false,
+            blocked: false,
             ping: false,
             new_connection_id: None,
             stream: VecDeque::new(),
+            lost_stream: VecDeque::new(),
             path_response: None,
             rst_stream: Vec::new(),
             stop_sending: Vec::new(),
             max_stream_data: FnvHashSet::default(),
+            stream_blocked: FnvHashSet::default(),
+            datagrams: VecDeque::new(),
         }
     }
 }
@@ -2455,16 +4123,24 @@ impl ::std::ops::AddAssign for Retransmits {
         self.ping |= rhs.ping;
         self.max_uni_stream_id |= rhs.max_uni_stream_id;
         self.max_bi_stream_id |= rhs.max_bi_stream_id;
+        self.uni_stream_id_blocked |= rhs.uni_stream_id_blocked;
+        self.bi_stream_id_blocked |= rhs.bi_stream_id_blocked;
+        self.blocked |= rhs.blocked;
         if let Some(x) = rhs.new_connection_id {
             self.new_connection_id = Some(x);
         }
-        self.stream.extend(rhs.stream.into_iter());
+        // Everything being added was sent before, so it's recovered before fresh data is sent on
+        // the same stream
+        self.lost_stream.extend(rhs.lost_stream);
+        self.lost_stream.extend(rhs.stream);
         if let Some((packet, token)) = rhs.path_response {
             self.path_challenge(packet, token);
         }
         self.rst_stream.extend_from_slice(&rhs.rst_stream);
         self.stop_sending.extend_from_slice(&rhs.stop_sending);
         self.max_stream_data.extend(&rhs.max_stream_data);
+        self.stream_blocked.extend(&rhs.stream_blocked);
+        self.datagrams.extend(rhs.datagrams);
     }
 }
 
@@ -2502,6 +4178,9 @@ pub enum ConnectionError {
     /// The peer has become unreachable.
     #[fail(display = "timed out")]
     TimedOut,
+    /// The handshake completed locally, but the server never confirmed it.
+    #[fail(display = "handshake unconfirmed by peer")]
+    HandshakeUnconfirmed,
 }
 
 impl From<TransportError> for ConnectionError {
@@ -2515,6 +4194,9 @@ impl From<ConnectionError> for io::Error {
         use self::ConnectionError::*;
         match x {
             TimedOut => io::Error::new(io::ErrorKind::TimedOut, "timed out"),
+            HandshakeUnconfirmed => {
+                io::Error::new(io::ErrorKind::TimedOut, "handshake unconfirmed by peer")
+            }
             Reset => io::Error::new(io::ErrorKind::ConnectionReset, "reset by peer"),
             ApplicationClosed { reason } => io::Error::new(
                 io::ErrorKind::ConnectionAborted,
@@ -2549,6 +4231,17 @@ impl From<transport_parameters::Error> for ConnectionError {
     }
 }
 
+/// Reasons why a datagram can't be sent
+#[derive(Debug, Fail, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum DatagramError {
+    /// The peer doesn't accept datagrams, or the handshake has yet to complete.
+    #[fail(display = "datagrams not supported by peer")]
+    UnsupportedByPeer,
+    /// The datagram exceeds the peer's limit or wouldn't fit in a packet.
+    #[fail(display = "datagram too large")]
+    TooLarge,
+}
+
 pub enum State {
     Handshake(state::Handshake),
     Established,
@@ -2576,6 +4269,14 @@ impl State {
         })
     }
 
+    pub fn is_handshake(&self) -> bool {
+        if let State::Handshake(_) = *self {
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn is_closed(&self) -> bool {
         match *self {
             State::HandshakeFailed(_) => true,
@@ -2639,29 +4340,44 @@ pub mod state {
     }
 }
 
+/// Construct a TLS session for a new connection
+///
+/// For a client, `version` is the version the connection was initially attempted with; for a
+/// server, the version in use.
 pub fn make_tls(
-    ctx: &Context,
+    ctx: &mut Context,
     local_id: &ConnectionId,
+    version: u32,
     config: Option<&ClientConfig>,
 ) -> TlsSession {
+    let mut params = TransportParameters::new(&ctx.config);
+    if ctx.config.grease {
+        params.grease = Some(ctx.rng.gen::<u16>() & 0xf0f0 | 0x0a0a);
+    }
     match config {
         Some(&ClientConfig {
             ref tls_config,
             ref server_name,
-        }) => TlsSession::new_client(
-            tls_config,
-            server_name,
-            &TransportParameters::new(&ctx.config),
-        ).unwrap(),
+        }) => TlsSession::new_client(tls_config, server_name, version, &params).unwrap(),
         None => {
+            let mut supported = Vec::with_capacity(ctx.config.supported_versions.len() + 1);
+            if ctx.config.grease {
+                supported.push(grease_version(&mut ctx.rng));
+            }
+            supported.extend_from_slice(&ctx.config.supported_versions);
             let server_params = TransportParameters {
                 stateless_reset_token: Some(reset_token_for(
                     &ctx.listen_keys.as_ref().unwrap().reset,
                     &local_id,
                 )),
-                ..TransportParameters::new(&ctx.config)
+                ..params
             };
-            TlsSession::new_server(&ctx.config.tls_server_config, &server_params)
+            TlsSession::new_server(
+                &ctx.config.tls_server_config,
+                version,
+                &supported,
+                &server_params,
+            )
         }
     }
 }
@@ -2672,13 +4388,80 @@ pub struct ClientConfig {
     pub tls_config: Arc<crypto::ClientConfig>,
 }
 
+/// Identifies one of a connection's independent packet number spaces
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SpaceId {
+    /// Initial packets
+    Initial = 0,
+    /// Handshake packets
+    Handshake = 1,
+    /// 1-RTT packets
+    Data = 2,
+}
+
+impl SpaceId {
+    pub const VALUES: [SpaceId; 3] = [SpaceId::Initial, SpaceId::Handshake, SpaceId::Data];
+
+    /// The space a received packet with this header is numbered in, if any
+    fn of(header: &Header) -> Option<Self> {
+        match *header {
+            Header::Initial { .. } => Some(SpaceId::Initial),
+            Header::Long {
+                ty: LongType::Handshake,
+                ..
+            } => Some(SpaceId::Handshake),
+            Header::Short { .. } => Some(SpaceId::Data),
+            _ => None,
+        }
+    }
+}
+
+/// State tracked separately for each packet number space
+///
+/// Packet numbers in different spaces are unrelated, so each space is acknowledged and has its
+/// losses detected independently of the others.
+pub struct PacketSpace {
+    /// The packet number of the most recently sent packet.
+    pub largest_sent_packet: u64,
+    /// The largest packet number the remote peer acknowledged in an ACK frame.
+    pub largest_acked_packet: u64,
+    /// Transmitted but not acked
+    pub sent_packets: BTreeMap<u64, SentPacket>,
+    /// The time at which the next packet will be considered lost based on early transmit or
+    /// exceeding the reordering window in time.
+    pub loss_time: u64,
+    /// Received packets to be acknowledged
+    pub pending_acks: RangeSet,
+    /// Set iff we have received a non-ack frame since the last ack-only packet we sent
+    pub permit_ack_only: bool,
+    /// The largest packet number received
+    pub rx_packet: u64,
+    /// The time at which `rx_packet` was received
+    pub rx_packet_time: u64,
+}
+
+impl PacketSpace {
+    fn new() -> Self {
+        Self {
+            largest_sent_packet: 0,
+            largest_acked_packet: 0,
+            sent_packets: BTreeMap::new(),
+            loss_time: 0,
+            pending_acks: RangeSet::new(),
+            permit_ack_only: false,
+            rx_packet: 0,
+            rx_packet_time: 0,
+        }
+    }
+}
+
 /// Represents one or more packets subject to retransmission
 #[derive(Debug, Clone)]
 pub struct SentPacket {
     pub time: u64,
     /// 0 iff ack-only
     pub bytes: u16,
-    pub handshake: bool,
+    /// Packet numbers acknowledged by this packet's ACK frame, if it carried one
     pub acks: RangeSet,
     pub retransmits: Retransmits,
 }
@@ -2689,14 +4472,121 @@ impl SentPacket {
     }
 }
 
+/// An address the peer has sent from, which we're probing before migrating to it
+#[derive(Debug, Copy, Clone)]
+pub struct PathChallenge {
+    pub remote: SocketAddr,
+    pub token: u64,
+    /// When the challenge was last sent, if it has been
+    pub sent: Option<u64>,
+    /// Bytes received from `remote`, which bound what we may send to it
+    pub bytes_recvd: u64,
+    /// Bytes sent to `remote`
+    pub bytes_sent: u64,
+}
+
+impl PathChallenge {
+    pub fn new(remote: SocketAddr, token: u64) -> Self {
+        Self {
+            remote,
+            token,
+            sent: None,
+            bytes_recvd: 0,
+            bytes_sent: 0,
+        }
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
 pub struct ConnectionHandle(pub usize);
 
+/// Receives notifications of notable events on connections, e.g. to export metrics
+///
+/// Every method does nothing by default, so implementations need only override those they're
+/// interested in. Times are in microseconds.
+pub trait ConnectionObserver: Send + Sync {
+    /// A packet was sent. `bytes` is 0 for ack-only packets.
+    fn packet_sent(&self, _conn: ConnectionHandle, _number: u64, _bytes: u16) {}
+    /// A packet was acknowledged by the peer
+    fn packet_acked(&self, _conn: ConnectionHandle, _number: u64) {}
+    /// A packet was declared lost
+    fn packet_lost(&self, _conn: ConnectionHandle, _number: u64) {}
+    /// An acknowledgement yielded a new RTT sample
+    fn rtt_sampled(&self, _conn: ConnectionHandle, _latest_rtt: u64, _smoothed_rtt: u64) {}
+    /// Loss caused the congestion window to be reduced
+    fn congestion_event(&self, _conn: ConnectionHandle, _congestion_window: u64) {}
+    /// The 1-RTT keys were updated, at either peer's initiative
+    fn key_updated(&self, _conn: ConnectionHandle) {}
+}
+
+/// Snapshot of a connection's congestion, loss, and transfer state
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct ConnectionStats {
+    /// Bytes sent in packets that have been neither acknowledged nor declared lost
+    pub bytes_in_flight: u64,
+    /// Maximum number of bytes in flight that may be sent
+    pub congestion_window: u64,
+    /// Slow start threshold in bytes
+    pub ssthresh: u64,
+    /// Smoothed round-trip time estimate, in microseconds
+    pub smoothed_rtt: u64,
+    /// Minimum round-trip time observed, in microseconds
+    pub min_rtt: u64,
+    /// Number of packets sent but not yet acknowledged or declared lost
+    pub unacked_packets: u64,
+    /// Total number of packets declared lost
+    pub lost_packets: u64,
+    /// Tail loss probes sent since the last acknowledgement
+    pub tlp_count: u32,
+    /// Retransmission timeouts since the last acknowledgement
+    pub rto_count: u32,
+    /// Stream data sent, in bytes
+    pub data_sent: u64,
+    /// Stream data received, in bytes. Includes gaps, so it's an upper bound.
+    pub data_recvd: u64,
+    /// Total size of all UDP payloads sent
+    pub wire_bytes_sent: u64,
+    /// Total size of all UDP payloads received
+    pub wire_bytes_recvd: u64,
+    /// Number of writes rejected for lack of connection-level flow control credit
+    ///
+    /// A workload that frequently hits this limit may benefit from a larger `receive_window` on
+    /// the peer.
+    pub flow_control_blocked_writes: u64,
+}
+
 impl From<ConnectionHandle> for usize {
     fn from(x: ConnectionHandle) -> usize {
         x.0
     }
 }
 
-/// Ensures we can always fit all our ACKs in a single minimum-MTU packet with room to spare
-const MAX_ACK_BLOCKS: usize = 64;
+/// Largest amount of a stream's data sent at once while other streams of equal priority are also
+/// waiting to send
+const STREAM_CHUNK_SIZE: usize = 512;
+
+/// Smallest anti-amplification budget worth building a packet with: room for the largest long
+/// header and an AEAD tag, with some to spare
+const MIN_AMPLIFICATION_BUDGET: u64 = 128;
+
+/// Number of alternate CIDs we'll accept from a peer, bounding the reset tokens we track
+const MAX_REM_RESET_TOKENS: usize = 16;
+
+/// Number of past PATH_CHALLENGE tokens to which late responses are tolerated. A response to an
+/// older one is treated as a protocol violation, which would take a peer moving between this many
+/// addresses before answering.
+const MAX_PAST_PATH_TOKENS: usize = 4;
+
+/// Number of short-header packets to buffer while waiting for 1-RTT keys
+const MAX_EARLY_PACKETS: usize = 8;
+
+/// One in this many connections leaves the spin bit unset, so that endpoints which never spin
+/// don't stand out
+const SPIN_DISABLED_ONE_IN: u32 = 16;
+
+/// Furthest, in microseconds, that the time passed to a connection may lag the recorded send time
+/// of a packet due to coarse timers
+const MAX_CLOCK_LAG: u64 = 25_000;
+
+/// Number of streams of each directionality either side may open, as stream IDs are below 2^62
+const MAX_STREAM_COUNT: u64 = 1 << 60;
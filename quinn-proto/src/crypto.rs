@@ -1,6 +1,10 @@
-use std::net::SocketAddrV6;
+#[cfg(feature = "keylog")]
+use std::io::Write;
+use std::net::SocketAddr;
 use std::ops::{Deref, DerefMut};
 use std::sync::Arc;
+#[cfg(feature = "keylog")]
+use std::sync::Mutex;
 use std::{io, str};
 
 use aes_ctr::stream_cipher::generic_array::GenericArray;
@@ -13,6 +17,8 @@ use ring::digest;
 use ring::hkdf;
 use ring::hmac::{self, SigningKey};
 use rustls::quic::{ClientQuicExt, ServerQuicExt};
+#[cfg(feature = "keylog")]
+use rustls::KeyLog;
 pub use rustls::{Certificate, NoClientAuth, PrivateKey, TLSError};
 pub use rustls::{ClientConfig, ClientSession, ServerConfig, ServerSession, Session};
 use webpki::DNSNameRef;
@@ -20,7 +26,7 @@ use webpki::DNSNameRef;
 use endpoint::EndpointError;
 use packet::{ConnectionId, AEAD_TAG_SIZE};
 use transport_parameters::TransportParameters;
-use {Side, RESET_TOKEN_SIZE};
+use {ip_octets, Side, RESET_TOKEN_SIZE};
 
 pub enum TlsSession {
     Client(ClientSession),
@@ -31,6 +37,7 @@ impl TlsSession {
     pub fn new_client(
         config: &Arc<ClientConfig>,
         hostname: &str,
+        initial_version: u32,
         params: &TransportParameters,
     ) -> Result<TlsSession, EndpointError> {
         let pki_server_name = DNSNameRef::try_from_ascii_str(hostname)
@@ -38,14 +45,19 @@ impl TlsSession {
         Ok(TlsSession::Client(ClientSession::new_quic(
             &config,
             pki_server_name,
-            to_vec(Side::Client, params),
+            to_vec(Side::Client, initial_version, &[], params),
         )))
     }
 
-    pub fn new_server(config: &Arc<ServerConfig>, params: &TransportParameters) -> TlsSession {
+    pub fn new_server(
+        config: &Arc<ServerConfig>,
+        version: u32,
+        supported_versions: &[u32],
+        params: &TransportParameters,
+    ) -> TlsSession {
         TlsSession::Server(ServerSession::new_quic(
             config,
-            to_vec(Side::Server, params),
+            to_vec(Side::Server, version, supported_versions, params),
         ))
     }
 
@@ -58,7 +70,7 @@ impl TlsSession {
 }
 
 impl Deref for TlsSession {
-    type Target = dyn Session;
+    type Target = Session;
     fn deref(&self) -> &Self::Target {
         match *self {
             TlsSession::Client(ref session) => session,
@@ -68,7 +80,7 @@ impl Deref for TlsSession {
 }
 
 impl DerefMut for TlsSession {
-    fn deref_mut(&mut self) -> &mut (dyn Session + 'static) {
+    fn deref_mut(&mut self) -> &mut (Session + 'static) {
         match *self {
             TlsSession::Client(ref mut session) => session,
             TlsSession::Server(ref mut session) => session,
@@ -80,14 +92,12 @@ pub fn build_server_config() -> ServerConfig {
     ServerConfig::new(NoClientAuth::new())
 }
 
-fn to_vec(side: Side, params: &TransportParameters) -> Vec<u8> {
+fn to_vec(side: Side, version: u32, supported: &[u32], params: &TransportParameters) -> Vec<u8> {
     let mut bytes = Vec::new();
-    params.write(side, &mut bytes);
+    params.write(side, version, supported, &mut bytes);
     bytes
 }
 
-/// Value used in ACKs we transmit
-pub const ACK_DELAY_EXPONENT: u8 = 3;
 /// Magic value used to indicate 0-RTT support in NewSessionTicket
 //pub const TLS_MAX_EARLY_DATA: u32 = 0xffff_ffff;
 
@@ -99,50 +109,187 @@ pub fn reset_token_for(key: &SigningKey, id: &ConnectionId) -> [u8; RESET_TOKEN_
     result
 }
 
+/// Performs the AEAD operations that protect packet payloads
+///
+/// The default, `SoftwareProtection`, uses *ring*. Custom implementations allow the work to be
+/// batched or offloaded to dedicated hardware.
+pub trait PacketProtection: Send + Sync {
+    /// Prepare to seal outgoing packets with `cipher` under `key`
+    fn sealing_key(&self, cipher: Cipher, key: &[u8]) -> Box<PacketSealingKey>;
+    /// Prepare to open incoming packets with `cipher` under `key`
+    fn opening_key(&self, cipher: Cipher, key: &[u8]) -> Box<PacketOpeningKey>;
+}
+
+/// An AEAD algorithm negotiated for packet protection
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum Cipher {
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Identify the cipher corresponding to `alg`, if it's one QUIC can use
+    fn from_aead(alg: &aead::Algorithm) -> Option<Self> {
+        if alg == &aead::AES_128_GCM {
+            Some(Cipher::Aes128Gcm)
+        } else if alg == &aead::AES_256_GCM {
+            Some(Cipher::Aes256Gcm)
+        } else if alg == &aead::CHACHA20_POLY1305 {
+            Some(Cipher::ChaCha20Poly1305)
+        } else {
+            None
+        }
+    }
+
+    fn aead(self) -> &'static aead::Algorithm {
+        match self {
+            Cipher::Aes128Gcm => &aead::AES_128_GCM,
+            Cipher::Aes256Gcm => &aead::AES_256_GCM,
+            Cipher::ChaCha20Poly1305 => &aead::CHACHA20_POLY1305,
+        }
+    }
+}
+
+/// A key for sealing packets, constructed by a `PacketProtection` implementation
+pub trait PacketSealingKey: Send + Sync {
+    /// Encrypt `in_out` in place, except for its last `tag_len` bytes, which receive the tag
+    fn seal(&self, nonce: &[u8], header: &[u8], in_out: &mut [u8]) -> Result<(), ()>;
+}
+
+/// A key for opening packets, constructed by a `PacketProtection` implementation
+pub trait PacketOpeningKey: Send + Sync {
+    /// Authenticate and decrypt `in_out` in place, returning the length of the plaintext
+    fn open(&self, nonce: &[u8], header: &[u8], in_out: &mut [u8]) -> Result<usize, ()>;
+}
+
+/// Packet protection performed in software by *ring*
+pub struct SoftwareProtection;
+
+impl PacketProtection for SoftwareProtection {
+    fn sealing_key(&self, cipher: Cipher, key: &[u8]) -> Box<PacketSealingKey> {
+        Box::new(aead::SealingKey::new(cipher.aead(), key).unwrap())
+    }
+
+    fn opening_key(&self, cipher: Cipher, key: &[u8]) -> Box<PacketOpeningKey> {
+        Box::new(aead::OpeningKey::new(cipher.aead(), key).unwrap())
+    }
+}
+
+impl PacketSealingKey for aead::SealingKey {
+    fn seal(&self, nonce: &[u8], header: &[u8], in_out: &mut [u8]) -> Result<(), ()> {
+        let tag_len = self.algorithm().tag_len();
+        aead::seal_in_place(self, nonce, header, in_out, tag_len)
+            .map(|_| ())
+            .map_err(|_| ())
+    }
+}
+
+impl PacketOpeningKey for aead::OpeningKey {
+    fn open(&self, nonce: &[u8], header: &[u8], in_out: &mut [u8]) -> Result<usize, ()> {
+        aead::open_in_place(self, nonce, header, 0, in_out)
+            .map(|x| x.len())
+            .map_err(|_| ())
+    }
+}
+
+/// Logs TLS secrets to an arbitrary writer in the NSS key log format
+///
+/// Assign to the `key_log` field of a TLS configuration to allow protocol analyzers such as
+/// Wireshark to decrypt captured traffic. Anyone with access to the output can read and forge
+/// traffic on the logged connections, so this must never be enabled in production.
+#[cfg(feature = "keylog")]
+pub struct KeyLogWriter<W> {
+    writer: Mutex<W>,
+}
+
+#[cfg(feature = "keylog")]
+impl<W: Write + Send> KeyLogWriter<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+#[cfg(feature = "keylog")]
+impl<W: Write + Send> KeyLog for KeyLogWriter<W> {
+    fn log(&self, label: &str, client_random: &[u8], secret: &[u8]) {
+        let mut line = label.to_owned();
+        line.push(' ');
+        for byte in client_random {
+            line.push_str(&format!("{:02x}", byte));
+        }
+        line.push(' ');
+        for byte in secret {
+            line.push_str(&format!("{:02x}", byte));
+        }
+        line.push('\n');
+        // A failure to log secrets shouldn't interfere with the connection
+        let _ = self.writer.lock().unwrap().write_all(line.as_bytes());
+    }
+}
+
 pub struct Crypto {
     local_secret: Vec<u8>,
     local_iv: Vec<u8>,
     local_pn_key: PacketNumberKey,
-    sealing_key: aead::SealingKey,
+    sealing_key: Box<PacketSealingKey>,
     remote_secret: Vec<u8>,
     remote_iv: Vec<u8>,
     remote_pn_key: PacketNumberKey,
-    opening_key: aead::OpeningKey,
+    opening_key: Box<PacketOpeningKey>,
     digest: &'static digest::Algorithm,
+    cipher: Cipher,
+    protection: Arc<PacketProtection>,
 }
 
 impl Crypto {
-    pub fn new_initial(id: &ConnectionId, side: Side) -> Self {
-        let (digest, cipher) = (&digest::SHA256, &aead::AES_128_GCM);
+    pub fn new_initial(
+        protection: &Arc<PacketProtection>,
+        version: u32,
+        id: &ConnectionId,
+        side: Side,
+    ) -> Self {
+        let (digest, cipher) = (&digest::SHA256, Cipher::Aes128Gcm);
         let (local_label, remote_label) = if side == Side::Client {
             (b"client in", b"server in")
         } else {
             (b"server in", b"client in")
         };
-        let hs_secret = initial_secret(id);
+        let hs_secret = initial_secret(version, id);
         let (local_secret, remote_secret) = (
             expanded_initial_secret(&hs_secret, local_label),
             expanded_initial_secret(&hs_secret, remote_label),
         );
-        let (local_key, local_iv, local_pn_key) = Self::get_keys(digest, cipher, &local_secret);
-        let (remote_key, remote_iv, remote_pn_key) = Self::get_keys(digest, cipher, &remote_secret);
+        let (local_key, local_iv, local_pn_key) =
+            Self::get_keys(digest, cipher.aead(), &local_secret);
+        let (remote_key, remote_iv, remote_pn_key) =
+            Self::get_keys(digest, cipher.aead(), &remote_secret);
 
         Self {
             local_secret,
-            sealing_key: aead::SealingKey::new(cipher, &local_key).unwrap(),
+            sealing_key: protection.sealing_key(cipher, &local_key),
             local_pn_key,
             local_iv,
             remote_secret,
-            opening_key: aead::OpeningKey::new(cipher, &remote_key).unwrap(),
+            opening_key: protection.opening_key(cipher, &remote_key),
             remote_pn_key,
             remote_iv,
             digest,
+            cipher,
+            protection: protection.clone(),
         }
     }
 
-    pub fn new_1rtt(tls: &TlsSession, side: Side) -> Self {
+    /// Derive 1-RTT keys from a completed handshake, if the negotiated cipher is usable
+    pub fn new_1rtt(
+        protection: &Arc<PacketProtection>,
+        tls: &TlsSession,
+        side: Side,
+    ) -> Option<Self> {
         let suite = tls.get_negotiated_ciphersuite().unwrap();
-        let (cipher, digest) = (suite.get_aead_alg(), suite.get_hash());
+        let (cipher, digest) = (Cipher::from_aead(suite.get_aead_alg())?, suite.get_hash());
 
         const SERVER_LABEL: &[u8] = b"EXPORTER-QUIC server 1rtt";
         const CLIENT_LABEL: &[u8] = b"EXPORTER-QUIC client 1rtt";
@@ -160,7 +307,13 @@ impl Crypto {
         tls.export_keying_material(&mut remote_secret, remote_label, None)
             .unwrap();
 
-        Self::generate_1rtt(digest, cipher, local_secret, remote_secret)
+        Some(Self::generate_1rtt(
+            protection,
+            digest,
+            cipher,
+            local_secret,
+            remote_secret,
+        ))
     }
 
     pub fn write_nonce(&self, iv: &[u8], number: u64, out: &mut [u8]) {
@@ -185,12 +338,8 @@ impl Crypto {
         &self.local_pn_key
     }
 
-    pub fn encrypt(&self, packet: u64, buf: &mut Vec<u8>, header_len: usize) {
-        let (cipher, iv, key) = (
-            self.sealing_key.algorithm(),
-            &self.local_iv,
-            &self.sealing_key,
-        );
+    pub fn encrypt(&self, packet: u64, buf: &mut Vec<u8>, header_len: usize) -> Result<(), ()> {
+        let (cipher, iv, key) = (self.cipher.aead(), &self.local_iv, &self.sealing_key);
 
         let mut nonce_buf = [0u8; aead::MAX_TAG_LEN];
         let nonce = &mut nonce_buf[..cipher.nonce_len()];
@@ -199,7 +348,7 @@ impl Crypto {
         buf.extend(tag);
 
         let (header, payload) = buf.split_at_mut(header_len);
-        aead::seal_in_place(&key, &*nonce, header, payload, cipher.tag_len()).unwrap();
+        key.seal(&*nonce, header, payload)
     }
 
     pub fn decrypt(&self, packet: u64, header: &[u8], payload: &mut BytesMut) -> Result<(), ()> {
@@ -207,19 +356,14 @@ impl Crypto {
             return Err(());
         }
 
-        let (cipher, iv, key) = (
-            self.opening_key.algorithm(),
-            &self.remote_iv,
-            &self.opening_key,
-        );
+        let (cipher, iv, key) = (self.cipher.aead(), &self.remote_iv, &self.opening_key);
 
         let mut nonce_buf = [0u8; aead::MAX_TAG_LEN];
         let nonce = &mut nonce_buf[..cipher.nonce_len()];
         self.write_nonce(&iv, packet, nonce);
-        let payload_len = payload.len();
 
-        aead::open_in_place(&key, &*nonce, header, 0, payload.as_mut()).map_err(|_| ())?;
-        payload.split_off(payload_len - cipher.tag_len());
+        let len = key.open(&*nonce, header, payload.as_mut())?;
+        payload.split_off(len);
         Ok(())
     }
 
@@ -242,32 +386,38 @@ impl Crypto {
         qhkdf_expand(&remote_secret_key, &remote_label, &mut new_remote_secret);
 
         Self::generate_1rtt(
+            &self.protection,
             self.digest,
-            self.opening_key.algorithm(),
+            self.cipher,
             new_local_secret,
             new_remote_secret,
         )
     }
 
     fn generate_1rtt(
+        protection: &Arc<PacketProtection>,
         digest: &'static digest::Algorithm,
-        cipher: &'static aead::Algorithm,
+        cipher: Cipher,
         local_secret: Vec<u8>,
         remote_secret: Vec<u8>,
     ) -> Crypto {
-        let (local_key, local_iv, local_pn_key) = Self::get_keys(digest, cipher, &local_secret);
-        let (remote_key, remote_iv, remote_pn_key) = Self::get_keys(digest, cipher, &remote_secret);
+        let (local_key, local_iv, local_pn_key) =
+            Self::get_keys(digest, cipher.aead(), &local_secret);
+        let (remote_key, remote_iv, remote_pn_key) =
+            Self::get_keys(digest, cipher.aead(), &remote_secret);
 
         Crypto {
             local_secret,
-            sealing_key: aead::SealingKey::new(cipher, &local_key).unwrap(),
+            sealing_key: protection.sealing_key(cipher, &local_key),
             local_pn_key,
             local_iv,
             remote_secret,
-            opening_key: aead::OpeningKey::new(cipher, &remote_key).unwrap(),
+            opening_key: protection.opening_key(cipher, &remote_key),
             remote_pn_key,
             remote_iv,
             digest,
+            cipher,
+            protection: protection.clone(),
         }
     }
 
@@ -308,7 +458,7 @@ impl CookieFactory {
 
     fn generate_mac(&self, conn: &ConnectionInfo) -> [u8; COOKIE_MAC_BYTES] {
         let mut mac = Blake2b::new_keyed(&self.mac_key, COOKIE_MAC_BYTES);
-        mac.process(&conn.remote.ip().octets());
+        mac.process(&ip_octets(&conn.remote));
         {
             let mut buf = [0; 2];
             BigEndian::write_u16(&mut buf, conn.remote.port());
@@ -332,7 +482,7 @@ impl CookieFactory {
 #[derive(Clone)]
 pub struct ConnectionInfo {
     pub(crate) id: ConnectionId,
-    pub(crate) remote: SocketAddrV6,
+    pub(crate) remote: SocketAddr,
 }
 
 #[derive(Debug, Fail)]
@@ -341,6 +491,8 @@ pub enum ConnectError {
     MalformedSession,
     #[fail(display = "TLS error: {}", _0)]
     Tls(TLSError),
+    #[fail(display = "no unused connection ID of the configured length could be generated")]
+    ConnectionIdsExhausted,
 }
 
 impl From<TLSError> for ConnectError {
@@ -431,24 +583,38 @@ pub fn qhkdf_expand(key: &SigningKey, label: &[u8], out: &mut [u8]) {
     hkdf::expand(key, &info, out);
 }
 
-fn initial_secret(conn_id: &ConnectionId) -> SigningKey {
-    let key = SigningKey::new(&digest::SHA256, &INITIAL_SALT);
+fn initial_secret(version: u32, conn_id: &ConnectionId) -> SigningKey {
+    let key = SigningKey::new(&digest::SHA256, initial_salt(version));
     let mut buf = Vec::with_capacity(8);
     buf.put_slice(conn_id);
     hkdf::extract(&key, &buf)
 }
 
+/// Salt from which initial secrets are derived, which differs between draft versions
+fn initial_salt(version: u32) -> &'static [u8] {
+    match version {
+        // Drafts 17 through 20
+        0xff00_0011..=0xff00_0014 => &INITIAL_SALT_DRAFT17,
+        _ => &INITIAL_SALT,
+    }
+}
+
 const INITIAL_SALT: [u8; 20] = [
     0x9c, 0x10, 0x8f, 0x98, 0x52, 0x0a, 0x5c, 0x5c, 0x32, 0x96, 0x8e, 0x95, 0x0e, 0x8a, 0x2c, 0x5f,
     0xe0, 0x6d, 0x6c, 0x38,
 ];
 
+const INITIAL_SALT_DRAFT17: [u8; 20] = [
+    0xef, 0x4f, 0xb0, 0xab, 0xb4, 0x74, 0x70, 0xc4, 0x1b, 0xef, 0xcf, 0x80, 0x31, 0x33, 0x4f, 0xae,
+    0x48, 0x5e, 0x09, 0xa0,
+];
+
 #[cfg(test)]
 mod test {
     use super::*;
     use packet::PacketNumber;
     use rand;
-    use MAX_CID_SIZE;
+    use {MAX_CID_SIZE, VERSION};
 
     #[test]
     fn packet_number() {
@@ -465,11 +631,12 @@ mod test {
     #[test]
     fn handshake_crypto_roundtrip() {
         let conn = ConnectionId::random(&mut rand::thread_rng(), MAX_CID_SIZE);
-        let client = Crypto::new_initial(&conn, Side::Client);
-        let server = Crypto::new_initial(&conn, Side::Server);
+        let protection: Arc<PacketProtection> = Arc::new(SoftwareProtection);
+        let client = Crypto::new_initial(&protection, VERSION, &conn, Side::Client);
+        let server = Crypto::new_initial(&protection, VERSION, &conn, Side::Server);
 
         let mut buf = b"headerpayload".to_vec();
-        client.encrypt(0, &mut buf, 6);
+        client.encrypt(0, &mut buf, 6).unwrap();
 
         let mut header = BytesMut::from(buf);
         let mut payload = header.split_off(6);
@@ -477,12 +644,23 @@ mod test {
         assert_eq!(&*payload, b"payload");
     }
 
+    #[test]
+    fn cipher_from_aead() {
+        for &cipher in &[
+            Cipher::Aes128Gcm,
+            Cipher::Aes256Gcm,
+            Cipher::ChaCha20Poly1305,
+        ] {
+            assert_eq!(Cipher::from_aead(cipher.aead()), Some(cipher));
+        }
+    }
+
     #[test]
     fn key_derivation() {
         let id = ConnectionId::new(&[0x83, 0x94, 0xc8, 0xf0, 0x3e, 0x51, 0x57, 0x08]);
         let digest = &digest::SHA256;
         let cipher = &aead::AES_128_GCM;
-        let initial_secret = initial_secret(&id);
+        let initial_secret = initial_secret(VERSION, &id);
         let client_secret = expanded_initial_secret(&initial_secret, b"client in");
         assert_eq!(
             &client_secret[..],
@@ -1,5 +1,6 @@
+use std::any::Any;
 use std::collections::VecDeque;
-use std::net::SocketAddrV6;
+use std::net::SocketAddr;
 use std::sync::Arc;
 use std::{cmp, io};
 
@@ -11,19 +12,26 @@ use ring::hmac::SigningKey;
 use slab::Slab;
 use slog::{self, Logger};
 
+use cid_generator::ConnectionIdGenerator;
 use coding::BufMutExt;
 use connection::{
-    handshake_close, make_tls, ClientConfig, Connection, ConnectionError, ConnectionHandle, State,
+    handshake_close, make_tls, ClientConfig, Connection, ConnectionError, ConnectionHandle,
+    ConnectionObserver, ConnectionStats, DatagramError, State,
 };
-use crypto::{self, reset_token_for, ConnectError, Crypto, ServerConfig};
+use crypto::{self, reset_token_for, ConnectError, Crypto, PacketProtection, ServerConfig};
 use packet::{
     ConnectionId, Header, Packet, PacketDecodeError, PacketNumber, PartialDecode,
     PACKET_NUMBER_32_MASK,
 };
-use stream::{ReadError, WriteError};
+#[cfg(feature = "qlog")]
+use qlog::QlogSink;
+use stream::{FinishError, InvalidStreamError, OpenError, ReadError, StreamState, WriteError};
+use token;
+use token_bucket::TokenBucket;
+use transport_parameters::TransportParameters;
 use {
-    Directionality, Side, StreamId, TransportError, MAX_CID_SIZE, MIN_CID_SIZE, MIN_INITIAL_SIZE,
-    RESET_TOKEN_SIZE, VERSION,
+    grease_version, Directionality, Side, StreamId, TransportError, MAX_CID_SIZE, MAX_IDLE_TIMEOUT,
+    MIN_CID_SIZE, MIN_INITIAL_SIZE, MIN_MTU, RESET_TOKEN_SIZE, VERSION,
 };
 
 /// Parameters governing the core QUIC state machine.
@@ -32,11 +40,34 @@ pub struct Config {
     pub max_remote_bi_streams: u16,
     /// Maximum number of peer-initiated  unidirectional streams that may exist at one time.
     pub max_remote_uni_streams: u16,
+    /// Number of unopened peer-initiated streams of each directionality to try to keep available.
+    ///
+    /// Credit for a stream is normally only issued when an existing one closes, leaving a peer that
+    /// opens streams in bursts waiting a round trip for each. Extra credit is issued as the peer
+    /// opens streams, allowing at most this many more streams than `max_remote_bi_streams` or
+    /// `max_remote_uni_streams` to exist at once. 0 to only issue credit as streams close. A
+    /// directionality whose limit is 0 never receives extra credit.
+    pub remote_stream_headroom: u16,
     /// Maximum duration of inactivity to accept before timing out the connection (s).
     ///
-    /// Maximum value is 600 seconds. The actual value used is the minimum of this and the peer's
-    /// own idle timeout. 0 for none.
+    /// Maximum value is 600 seconds; larger values are treated as 600. The actual value used is
+    /// the minimum of this and the peer's own idle timeout, ignoring either if 0. 0 for none.
     pub idle_timeout: u16,
+    /// Period of inactivity after which a PING is sent to keep the connection alive (s). 0 to
+    /// disable.
+    ///
+    /// Restarted whenever an ack-eliciting packet is sent, so at most one PING is sent per period.
+    /// Must be shorter than `idle_timeout` if both are set, and has no effect unless also shorter
+    /// than the idle timeout negotiated with the peer.
+    pub keep_alive_interval: u16,
+    /// Minimum interval between PINGs sent to refresh the RTT estimate while only receiving (μs).
+    /// 0 to disable.
+    ///
+    /// A connection that never sends ack-eliciting packets takes no RTT samples, leaving a stale
+    /// estimate in place should it start sending. When set, receiving an ack-eliciting packet
+    /// queues a PING if nothing we sent is awaiting acknowledgement and no ack-eliciting packet has
+    /// been sent for this long. PINGs are subject to congestion control like any other frame.
+    pub rtt_probe_interval: u64,
     /// Maximum number of bytes the peer may transmit on any one stream before becoming blocked.
     ///
     /// This should be set to at least the expected connection latency multiplied by the maximum
@@ -57,9 +88,44 @@ pub struct Config {
     /// Calling `Endpoint::accept` removes a connection from the buffer, so this does not need to
     /// be large.
     pub accept_buffer: u32,
+    /// Maximum number of connections to maintain at once, whether handshaking, established, or
+    /// closing. 0 for no limit.
+    ///
+    /// Incoming connections beyond this are refused, bounding the memory an endpoint can be made to
+    /// commit to peers that complete handshakes faster than the application accepts them.
+    pub max_connections: u32,
+    /// Largest UDP payload to send, and to ask the peer to send, in bytes.
+    ///
+    /// Advertised to the peer as the `max_packet_size` transport parameter. Once the peer's
+    /// transport parameters are known, packets are sized to the smaller of this and the peer's own
+    /// limit, but never below the 1232 bytes every path is assumed to support. Only raise this if
+    /// paths are known to carry larger datagrams, as packets exceeding the path MTU will be lost.
+    pub max_packet_size: u16,
+    /// QUIC versions to accept, most preferred first. Must not be empty.
+    ///
+    /// Clients attempt connections with the first version listed. If the server doesn't support it,
+    /// the connection is retried with the first listed version the server offers.
+    pub supported_versions: Vec<u32>,
+    /// Whether to include reserved versions and transport parameters that peers must ignore.
+    ///
+    /// Exercises peers' tolerance of unknown values, so that future extensions remain deployable.
+    /// Off by default, as peers that mishandle these values will fail to connect.
+    pub grease: bool,
 
     /// Maximum number of tail loss probes before an RTO fires.
     pub max_tlps: u32,
+    /// Maximum number of times a client retransmits its final handshake flight without the server
+    /// confirming the handshake before the connection is abandoned. 0 for no limit.
+    ///
+    /// This fails a connection whose server has gone silent after the handshake completed locally
+    /// faster than the idle timeout would, and with a more specific error.
+    pub max_handshake_confirmation_retransmits: u32,
+    /// Maximum number of times handshake packets are retransmitted without any response before
+    /// the connection attempt is abandoned as timed out. 0 for no limit.
+    ///
+    /// The handshake retransmit timer doubles each time it fires, so this also bounds how long a
+    /// connection attempt to an unresponsive peer can take.
+    pub max_handshake_retransmits: u32,
     /// Maximum reordering in packet number space before FACK style loss detection considers a
     /// packet lost.
     pub reordering_threshold: u32,
@@ -74,8 +140,42 @@ pub struct Config {
     pub min_rto_timeout: u64,
     /// The length of the peer’s delayed ack timer (μs).
     pub delayed_ack_timeout: u64,
+    /// Number of retransmission timeouts a receive stream may go with a gap in its data before an
+    /// `Event::StreamStalled` is raised. 0 to disable.
+    ///
+    /// Useful for detecting ordered reads that are blocked indefinitely by data the peer will never
+    /// retransmit.
+    pub stream_stall_timeout: u32,
+    /// Maximum number of PATH_RESPONSE frames to send per round trip.
+    ///
+    /// PATH_CHALLENGE frames received in excess of this are ignored, so that a peer can't use them
+    /// to provoke an unbounded amount of response traffic.
+    pub max_path_responses_per_rtt: u32,
+    /// Whether to forbid the peer from migrating to a new address.
+    ///
+    /// Advertised to the peer as the `disable_migration` transport parameter. When set, an
+    /// authenticated packet arriving from any address other than the one a connection was
+    /// established on closes the connection with a PROTOCOL_VIOLATION. Otherwise, such packets
+    /// cause the new address to be validated with a PATH_CHALLENGE, and the connection moves to it
+    /// once the peer responds.
+    pub disable_migration: bool,
+    /// Maximum number of ranges of received packet numbers to track for acknowledgement.
+    ///
+    /// When exceeded, the oldest ranges are forgotten. ACK frames may carry fewer ranges still when
+    /// they would not otherwise fit in a packet.
+    pub max_ack_blocks: usize,
+    /// Exponent used to scale the delay field of ACKs we send.
+    ///
+    /// Larger values reduce the size of the encoded delay at the cost of precision. Maximum value
+    /// is 20.
+    pub ack_delay_exponent: u8,
     /// The default RTT used before an RTT sample is taken (μs)
     pub default_initial_rtt: u64,
+    /// Whether to raise `Event::RttUpdated` each time an RTT sample is taken.
+    ///
+    /// Samples are taken on most acknowledgements, so this is disabled by default to spare
+    /// applications that don't care about them.
+    pub rtt_events: bool,
 
     /// The default max packet size used for calculating default and minimum congestion windows.
     pub default_mss: u64,
@@ -87,12 +187,78 @@ pub struct Config {
     pub loss_reduction_factor: u16,
 
     pub tls_server_config: Arc<ServerConfig>,
+    /// Implementation of the AEAD operations protecting packet payloads.
+    ///
+    /// Replace to batch encryption and decryption or offload them to dedicated hardware.
+    pub packet_protection: Arc<PacketProtection>,
+    /// Receiver of notifications about connection events, e.g. for exporting metrics.
+    pub observer: Option<Arc<ConnectionObserver>>,
+    /// Destination for a log of connection events in the qlog format, for debugging.
+    #[cfg(feature = "qlog")]
+    pub qlog: Option<Arc<QlogSink>>,
 
     /// Length of connection IDs for the endpoint. This must be either 0 or between 4 and 18
     /// inclusive. The length of the local connection IDs constrains the amount of simultaneous
     /// connections the endpoint can maintain. The API user is responsible for making sure that
     /// the pool is large enough to cover the intended usage.
     pub local_cid_len: usize,
+    /// Maximum length of the token an Initial packet may carry (bytes).
+    ///
+    /// Initial packets with longer tokens are dropped without further processing.
+    pub max_token_length: usize,
+    /// Whether to require clients to prove they can receive at their address before committing
+    /// any state to them.
+    ///
+    /// Initial packets without a valid address validation token are answered with a Retry
+    /// carrying one, costing an extra round trip per connection but defeating handshakes from
+    /// spoofed addresses.
+    pub use_stateless_retry: bool,
+    /// Number of stateless resets that may be sent to any one address in a burst
+    ///
+    /// Stateless resets answer packets for unknown connections, so without a limit a peer that
+    /// spoofs a victim's address could have them sent there in bulk.
+    pub stateless_reset_burst: u32,
+    /// Rate at which stateless resets may be sent to any one address (per second).
+    pub stateless_reset_rate: u32,
+    /// Rate at which stateless resets may be sent to all addresses together (per second).
+    ///
+    /// Bursts of up to one second's worth are permitted.
+    pub global_stateless_reset_rate: u32,
+    /// Minimum average size of the non-padding frames in a received packet (bytes).
+    ///
+    /// Packets densely packed with tiny frames cost far more to process than their size suggests,
+    /// so a packet carrying more than its length divided by this many frames is treated as a
+    /// protocol violation. 0 to disable.
+    pub min_average_frame_size: usize,
+    /// Size to which 1-RTT packets are padded (bytes). 0 to disable.
+    ///
+    /// Sending uniformly sized packets hides the size of their contents from observers, at the
+    /// cost of bandwidth. Padding never exceeds the path MTU, and packets subject to congestion
+    /// control are only padded as far as the congestion window permits.
+    pub short_packet_padding: u16,
+    /// Maximum size of the DATAGRAM frames the peer may send (bytes). 0 to disable.
+    ///
+    /// Datagrams are delivered unreliably and unordered, outside of any stream, as
+    /// `Event::DatagramReceived`.
+    pub max_datagram_frame_size: u16,
+    /// Maximum number of outgoing datagrams queued on a connection. Must be nonzero.
+    ///
+    /// When the queue is full, `send_datagram` discards the oldest datagram in it to make room,
+    /// since datagrams that have waited longest are usually the least useful.
+    pub max_queued_datagrams: u32,
+    /// Whether to close connections on peer misbehavior that is otherwise tolerated.
+    ///
+    /// Some violations of the specification are harmless to us and ignored by default. Enabling
+    /// this reports them as transport errors instead, which is useful when testing other
+    /// implementations for conformance. Covers MAX_STREAM_ID naming a peer-initiated stream,
+    /// STREAM_BLOCKED on a send-only stream, BLOCKED beyond the advertised connection limit,
+    /// RST_STREAM with a final offset below data already received, and MAX_DATA or MAX_STREAM_DATA
+    /// lowering a limit. Only a lower limit sent after a higher one counts, since reordering alone
+    /// can make one stale.
+    ///
+    /// Frames not permitted at their encryption level and STREAM frames inconsistent with a known
+    /// final offset are rejected regardless.
+    pub strict: bool,
 }
 
 impl Default for Config {
@@ -105,19 +271,34 @@ impl Default for Config {
         Self {
             max_remote_bi_streams: 0,
             max_remote_uni_streams: 0,
+            remote_stream_headroom: 0,
             idle_timeout: 10,
+            keep_alive_interval: 0,
+            rtt_probe_interval: 0,
             stream_receive_window: STREAM_RWND,
             receive_window: 8 * STREAM_RWND,
             accept_buffer: 1024,
+            max_connections: 0,
+            max_packet_size: MIN_MTU,
+            supported_versions: vec![VERSION],
+            grease: false,
 
             max_tlps: 2,
+            max_handshake_confirmation_retransmits: 6,
+            max_handshake_retransmits: 6,
             reordering_threshold: 3,
             time_reordering_fraction: 0x2000, // 1/8
             using_time_loss_detection: false,
             min_tlp_timeout: 10 * 1000,
             min_rto_timeout: 200 * 1000,
             delayed_ack_timeout: 25 * 1000,
+            stream_stall_timeout: 0,
+            max_ack_blocks: 64,
+            max_path_responses_per_rtt: 2,
+            disable_migration: false,
+            ack_delay_exponent: 3,
             default_initial_rtt: EXPECTED_RTT as u64 * 1000,
+            rtt_events: false,
 
             default_mss: 1460,
             initial_window: 10 * 1460,
@@ -125,8 +306,22 @@ impl Default for Config {
             loss_reduction_factor: 0x8000, // 1/2
 
             tls_server_config: Arc::new(crypto::build_server_config()),
+            packet_protection: Arc::new(crypto::SoftwareProtection),
+            observer: None,
+            #[cfg(feature = "qlog")]
+            qlog: None,
 
             local_cid_len: 8,
+            max_token_length: 256,
+            use_stateless_retry: false,
+            stateless_reset_burst: 4,
+            stateless_reset_rate: 1,
+            global_stateless_reset_rate: 100,
+            min_average_frame_size: 2,
+            short_packet_padding: 0,
+            max_datagram_frame_size: 0,
+            max_queued_datagrams: 64,
+            strict: false,
         }
     }
 }
@@ -139,14 +334,30 @@ impl Default for Config {
 pub struct Endpoint {
     log: Logger,
     pub(crate) ctx: Context,
-    connection_ids_initial: FnvHashMap<ConnectionId, ConnectionHandle>,
+    pub(crate) connection_ids_initial: FnvHashMap<ConnectionId, ConnectionHandle>,
     connection_ids: FnvHashMap<ConnectionId, ConnectionHandle>,
-    connection_remotes: FnvHashMap<SocketAddrV6, ConnectionHandle>,
+    connection_remotes: FnvHashMap<SocketAddr, ConnectionHandle>,
     pub(crate) connections: Slab<Connection>,
+    /// Stateless reset allowances of recently reset addresses
+    pub(crate) reset_limits: FnvHashMap<SocketAddr, TokenBucket>,
+    global_reset_limit: TokenBucket,
+    /// Source of local connection IDs, if not drawn at random
+    cid_generator: Option<Box<ConnectionIdGenerator + Send>>,
 }
 
+/// Maximum number of addresses to track stateless reset allowances for
+///
+/// Addresses that have fully recovered their allowance are forgotten first, then those least
+/// recently reset.
+pub(crate) const MAX_RESET_LIMITED_ADDRESSES: usize = 1024;
+
+/// Number of times to generate a local connection ID before giving up on finding an unused one
+///
+/// Random IDs essentially never collide, so repeated collisions mean a generator has run out.
+const MAX_CID_GENERATION_ATTEMPTS: usize = 16;
+
 pub struct Context {
-    pub rng: OsRng,
+    pub rng: Box<RngCore + Send>,
     pub config: Arc<Config>,
     pub io: VecDeque<Io>,
     // pub session_ticket_buffer: SessionTicketBuffer,
@@ -212,12 +423,39 @@ impl Endpoint {
         config: Config,
         listen: Option<ListenKeys>,
     ) -> Result<Self, EndpointError> {
-        let rng = OsRng::new().unwrap();
-        let config = Arc::new(config);
+        Self::with_rng(log, config, listen, Box::new(OsRng::new().unwrap()))
+    }
+
+    /// Construct an endpoint drawing randomness from `rng` rather than the operating system
+    ///
+    /// Everything the endpoint randomizes, including connection IDs and stateless reset padding,
+    /// comes from `rng`, so a seeded generator makes packet captures and fuzzing reproducible. Such
+    /// a generator must not be used in production, as it makes connection IDs predictable.
+    pub fn with_rng(
+        log: Logger,
+        config: Config,
+        listen: Option<ListenKeys>,
+        rng: Box<RngCore + Send>,
+    ) -> Result<Self, EndpointError> {
+        let config = Arc::new(Config {
+            idle_timeout: cmp::min(config.idle_timeout, MAX_IDLE_TIMEOUT),
+            ..config
+        });
         assert!(
             (config.local_cid_len == 0 || config.local_cid_len >= MIN_CID_SIZE)
                 && config.local_cid_len <= MAX_CID_SIZE
         );
+        assert!(config.ack_delay_exponent <= 20);
+        assert!(
+            config.keep_alive_interval == 0
+                || config.idle_timeout == 0
+                || config.keep_alive_interval < config.idle_timeout,
+            "keep-alive interval must be shorter than the idle timeout"
+        );
+        assert!(config.max_queued_datagrams > 0);
+        // The transport parameters leave room for 62 versions alongside a GREASE one
+        assert!(!config.supported_versions.is_empty() && config.supported_versions.len() <= 62);
+        let rate = config.global_stateless_reset_rate;
         Ok(Self {
             ctx: Context {
                 rng,
@@ -236,9 +474,22 @@ impl Endpoint {
             connection_ids: FnvHashMap::default(),
             connection_remotes: FnvHashMap::default(),
             connections: Slab::new(),
+            reset_limits: FnvHashMap::default(),
+            global_reset_limit: TokenBucket::new(rate, rate, 0),
+            cid_generator: None,
         })
     }
 
+    /// Choose local connection IDs with `generator` rather than at random
+    ///
+    /// Only affects connections created afterwards.
+    pub fn set_connection_id_generator(
+        &mut self,
+        generator: Box<ConnectionIdGenerator + Send>,
+    ) {
+        self.cid_generator = Some(generator);
+    }
+
     fn listen(&self) -> bool {
         self.ctx.listen_keys.is_some()
     }
@@ -250,7 +501,8 @@ impl Endpoint {
         }
         loop {
             let &conn = self.ctx.readable_conns.iter().next()?;
-            if let Some(x) = self.connections[conn.0].poll() {
+            // Skip connections that have since been forgotten
+            if let Some(x) = self.connections.get_mut(conn.0).and_then(|c| c.poll()) {
                 return Some((conn, x));
             }
             self.ctx.readable_conns.remove(&conn);
@@ -265,16 +517,25 @@ impl Endpoint {
             }
             let &conn = self.ctx.dirty_conns.iter().next()?;
             // TODO: Only determine a single operation; only remove from dirty set if that fails
-            self.flush_pending(now, conn);
+            if self.connections.contains(conn.0) {
+                self.flush_pending(now, conn);
+            }
             self.ctx.dirty_conns.remove(&conn);
         }
     }
 
     /// Process an incoming UDP datagram
-    pub fn handle(&mut self, now: u64, remote: SocketAddrV6, mut data: BytesMut) {
+    ///
+    /// `remote` should be reported exactly as the socket saw it, whether IPv4 or IPv6.
+    pub fn handle<A: Into<SocketAddr>>(&mut self, now: u64, remote: A, mut data: BytesMut) {
+        let remote = remote.into();
         let datagram_len = data.len();
         while !data.is_empty() {
-            match PartialDecode::new(data, self.ctx.config.local_cid_len) {
+            match PartialDecode::new(
+                data,
+                self.ctx.config.local_cid_len,
+                &self.ctx.config.supported_versions,
+            ) {
                 Ok(partial_decode) => {
                     match self.handle_decode(now, remote, partial_decode, datagram_len) {
                         Some(rest) => {
@@ -301,8 +562,12 @@ impl Endpoint {
                         src_cid: destination,
                         dst_cid: source,
                     }.encode(&mut buf);
-                    buf.write::<u32>(0x0a1a_2a3a); // reserved version
-                    buf.write(VERSION); // supported version
+                    if self.ctx.config.grease {
+                        buf.write(grease_version(&mut self.ctx.rng));
+                    }
+                    for &version in &self.ctx.config.supported_versions {
+                        buf.write(version);
+                    }
                     self.ctx.io.push_back(Io::Transmit {
                         destination: remote,
                         packet: buf.into(),
@@ -320,7 +585,7 @@ impl Endpoint {
     fn handle_decode(
         &mut self,
         now: u64,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
         partial_decode: PartialDecode,
         datagram_len: usize,
     ) -> Option<BytesMut> {
@@ -340,12 +605,33 @@ impl Endpoint {
                 .cloned()
         };
         if let Some(conn) = conn {
-            return self.connections[conn.0].handle_decode(
+            let len = partial_decode.len();
+            let prev_remote = self.connections[conn.0].remote;
+            let was_handshake = self.connections[conn.0].state.as_ref().unwrap().is_handshake();
+            let rest = self.connections[conn.0].handle_decode(
                 &mut self.ctx,
                 now,
                 remote,
                 partial_decode,
             );
+            self.connections[conn.0].wire_bytes_recvd +=
+                (len - rest.as_ref().map_or(0, |x| x.len())) as u64;
+            if was_handshake && self.connections[conn.0].side == Side::Server {
+                if let Some(State::Established) = self.connections[conn.0].state {
+                    // The client has switched to our CID, so the initial one is no longer needed
+                    self.connection_ids_initial
+                        .remove(&self.connections[conn.0].init_cid);
+                }
+            }
+            let new_remote = self.connections[conn.0].remote;
+            if new_remote != prev_remote {
+                // The peer migrated
+                if self.connection_remotes.get(&prev_remote) == Some(&conn) {
+                    self.connection_remotes.remove(&prev_remote);
+                }
+                self.connection_remotes.insert(new_remote, conn);
+            }
+            return rest;
         }
 
         //
@@ -372,10 +658,21 @@ impl Endpoint {
                     return None;
                 }
 
-                let crypto = Crypto::new_initial(&partial_decode.dst_cid(), Side::Server);
-                return match partial_decode.finish(crypto.pn_decrypt_key()) {
+                let crypto = Crypto::new_initial(
+                    &self.ctx.config.packet_protection,
+                    partial_decode.version().unwrap(),
+                    &partial_decode.dst_cid(),
+                    Side::Server,
+                );
+                let len = partial_decode.len();
+                let max_token_length = self.ctx.config.max_token_length;
+                return match partial_decode.finish(crypto.pn_decrypt_key(), max_token_length) {
                     Ok((packet, rest)) => {
                         self.handle_initial(now, remote, packet, crypto);
+                        if let Some(&conn) = self.connection_ids_initial.get(&dst_cid) {
+                            self.connections[conn.0].wire_bytes_recvd +=
+                                (len - rest.as_ref().map_or(0, |x| x.len())) as u64;
+                        }
                         rest
                     }
                     Err(e) => {
@@ -399,6 +696,16 @@ impl Endpoint {
         //
 
         if !dst_cid.is_empty() {
+            if let Some(ref generator) = self.cid_generator {
+                if !generator.validate(&dst_cid) {
+                    trace!(self.log, "dropping packet for foreign connection ID");
+                    return None;
+                }
+            }
+            if !self.permit_stateless_reset(now, remote) {
+                trace!(self.log, "not sending stateless reset due to rate limit");
+                return None;
+            }
             debug!(self.log, "sending stateless reset");
             let mut buf = Vec::<u8>::new();
             // Bound padding size to at most 8 bytes larger than input to mitigate amplification
@@ -417,6 +724,7 @@ impl Endpoint {
                 dst_cid: ConnectionId::random(&mut self.ctx.rng, MAX_CID_SIZE),
                 number: PacketNumber::U32(number),
                 key_phase: false,
+                spin: self.ctx.rng.gen(),
             }.encode(&mut buf);
             {
                 let start = buf.len();
@@ -438,20 +746,27 @@ impl Endpoint {
     }
 
     /// Initiate a connection
-    pub fn connect(
+    ///
+    /// `remote` must be of an address family the socket in use can send to.
+    pub fn connect<A: Into<SocketAddr>>(
         &mut self,
-        remote: SocketAddrV6,
+        now: u64,
+        remote: A,
         config: &Arc<crypto::ClientConfig>,
         server_name: &str,
     ) -> Result<ConnectionHandle, ConnectError> {
-        let local_id = self.new_cid();
+        let remote = remote.into();
+        let local_id = self.new_cid().ok_or(ConnectError::ConnectionIdsExhausted)?;
         let remote_id = ConnectionId::random(&mut self.ctx.rng, MAX_CID_SIZE);
         trace!(self.log, "initial dcid"; "value" => %remote_id);
+        let version = self.ctx.config.supported_versions[0];
         let conn = self.add_connection(
+            now,
             remote_id,
             local_id,
             remote_id,
             remote,
+            version,
             Some(ClientConfig {
                 tls_config: config.clone(),
                 server_name: server_name.into(),
@@ -461,29 +776,83 @@ impl Endpoint {
         Ok(conn)
     }
 
-    fn new_cid(&mut self) -> ConnectionId {
-        loop {
-            let cid = ConnectionId::random(&mut self.ctx.rng, self.ctx.config.local_cid_len);
+    /// Choose a local connection ID not used by any other connection, if one can be found
+    fn new_cid(&mut self) -> Option<ConnectionId> {
+        if self.ctx.config.local_cid_len == 0 {
+            // Connections are distinguished by remote address alone, so there's nothing to collide
+            return Some(ConnectionId::new(&[]));
+        }
+        for _ in 0..MAX_CID_GENERATION_ATTEMPTS {
+            let cid = match self.cid_generator {
+                Some(ref mut generator) => generator.generate(),
+                None => ConnectionId::random(&mut self.ctx.rng, self.ctx.config.local_cid_len),
+            };
+            if cid.len() != self.ctx.config.local_cid_len {
+                // Retrying is unlikely to help a generator that ignores the configured length
+                warn!(self.log, "generated connection ID has the wrong length"; "len" => cid.len());
+                return None;
+            }
             if !self.connection_ids.contains_key(&cid) {
-                break cid;
+                return Some(cid);
+            }
+        }
+        warn!(self.log, "failed to generate an unused connection ID");
+        None
+    }
+
+    /// Whether a stateless reset may be sent to `remote` now, consuming an allowance if so
+    fn permit_stateless_reset(&mut self, now: u64, remote: SocketAddr) -> bool {
+        if !self.global_reset_limit.available(now) {
+            return false;
+        }
+        if !self.reset_limits.contains_key(&remote)
+            && self.reset_limits.len() >= MAX_RESET_LIMITED_ADDRESSES
+        {
+            // Addresses back at their full allowance are indistinguishable from new ones
+            self.reset_limits.retain(|_, x| !x.is_full(now));
+            if self.reset_limits.len() >= MAX_RESET_LIMITED_ADDRESSES {
+                let oldest = *self
+                    .reset_limits
+                    .iter()
+                    .min_by_key(|&(_, x)| x.last_used())
+                    .unwrap()
+                    .0;
+                self.reset_limits.remove(&oldest);
             }
-            assert!(self.ctx.config.local_cid_len > 0);
         }
+        let config = &self.ctx.config;
+        let permitted = self
+            .reset_limits
+            .entry(remote)
+            .or_insert_with(|| {
+                TokenBucket::new(
+                    config.stateless_reset_burst,
+                    config.stateless_reset_rate,
+                    now,
+                )
+            })
+            .take(now);
+        if permitted {
+            self.global_reset_limit.take(now);
+        }
+        permitted
     }
 
     fn add_connection(
         &mut self,
+        now: u64,
         initial_id: ConnectionId,
         local_id: ConnectionId,
         remote_id: ConnectionId,
-        remote: SocketAddrV6,
+        remote: SocketAddr,
+        version: u32,
         client_config: Option<ClientConfig>,
     ) -> ConnectionHandle {
-        debug_assert!(!local_id.is_empty());
+        debug_assert!(self.ctx.config.local_cid_len == 0 || !local_id.is_empty());
         let conn = {
             let entry = self.connections.vacant_entry();
             let conn = ConnectionHandle(entry.key());
-            let tls = make_tls(&self.ctx, &local_id, client_config.as_ref());
+            let tls = make_tls(&mut self.ctx, &local_id, version, client_config.as_ref());
 
             entry.insert(Connection::new(
                 self.log.new(o!("connection" => local_id)),
@@ -491,10 +860,12 @@ impl Endpoint {
                 local_id,
                 remote_id,
                 remote,
+                version,
                 client_config,
                 tls,
                 &mut self.ctx,
                 conn,
+                now,
             ));
             conn
         };
@@ -505,19 +876,20 @@ impl Endpoint {
         conn
     }
 
-    fn handle_initial(&mut self, now: u64, remote: SocketAddrV6, packet: Packet, crypto: Crypto) {
+    fn handle_initial(&mut self, now: u64, remote: SocketAddr, packet: Packet, crypto: Crypto) {
         let Packet {
             header,
             header_data,
             mut payload,
         } = packet;
-        let (src_cid, dst_cid, packet_number) = match header {
+        let (version, src_cid, dst_cid, token, packet_number) = match header {
             Header::Initial {
+                version,
                 src_cid,
                 dst_cid,
+                token,
                 number,
-                ..
-            } => (src_cid, dst_cid, number),
+            } => (version, src_cid, dst_cid, token, number),
             _ => panic!("non-initial packet in handle_initial()"),
         };
         let packet_number = packet_number.expand(0);
@@ -529,16 +901,65 @@ impl Endpoint {
             debug!(self.log, "failed to authenticate initial packet");
             return;
         };
-        let loc_cid = self.new_cid();
 
-        if self.ctx.incoming.len() + self.ctx.incoming_handshakes
+        let mut address_validated = false;
+        if !token.is_empty() {
+            let keys = self.ctx.listen_keys.as_ref().unwrap();
+            match token::validate(&keys.cookie, &remote, &token, now, token::LIFETIME) {
+                Ok(()) => {
+                    trace!(self.log, "address validated by token");
+                    address_validated = true;
+                }
+                Err(e) => debug!(self.log, "ignoring address validation token"; "reason" => %e),
+            }
+        }
+
+        let loc_cid = if let Some(x) = self.new_cid() {
+            x
+        } else {
+            debug!(self.log, "dropping initial for lack of a connection ID");
+            return;
+        };
+
+        if !address_validated && self.ctx.config.use_stateless_retry {
+            trace!(self.log, "sending retry");
+            let header = Header::Retry {
+                version,
+                src_cid: loc_cid,
+                dst_cid: src_cid,
+                orig_dst_cid: dst_cid,
+            };
+            let mut buf = Vec::new();
+            header.encode(&mut buf);
+            let keys = self.ctx.listen_keys.as_ref().unwrap();
+            buf.extend_from_slice(&token::generate(&keys.cookie, &remote, now));
+            self.ctx.io.push_back(Io::Transmit {
+                destination: remote,
+                packet: buf.into(),
+            });
+            return;
+        }
+
+        let busy = if self.ctx.incoming.len() + self.ctx.incoming_handshakes
             == self.ctx.config.accept_buffer as usize
         {
-            debug!(self.log, "rejecting connection due to full accept buffer");
+            Some("full accept buffer")
+        } else if self.ctx.config.max_connections != 0
+            && self.connections.len() >= self.ctx.config.max_connections as usize
+        {
+            Some("connection limit reached")
+        } else {
+            None
+        };
+        if let Some(reason) = busy {
+            debug!(self.log, "rejecting connection"; "reason" => reason);
+            // A single small packet in response to a padded Initial, so well within the
+            // anti-amplification limit despite having no connection to account for it
             self.ctx.io.push_back(Io::Transmit {
                 destination: remote,
                 packet: handshake_close(
                     &crypto,
+                    version,
                     &src_cid,
                     &loc_cid,
                     0,
@@ -549,8 +970,9 @@ impl Endpoint {
             return;
         }
 
-        let conn = self.add_connection(dst_cid, loc_cid, src_cid, remote, None);
+        let conn = self.add_connection(now, dst_cid, loc_cid, src_cid, remote, version, None);
         self.connection_ids_initial.insert(dst_cid, conn);
+        self.connections[conn.0].address_validated = address_validated;
         match self.connections[conn.0].handle_initial(
             &mut self.ctx,
             now,
@@ -560,17 +982,16 @@ impl Endpoint {
             Ok(()) => {}
             Err(e) => {
                 debug!(self.log, "handshake failed"; "reason" => %e);
-                self.ctx.io.push_back(Io::Transmit {
-                    destination: remote,
-                    packet: handshake_close(
-                        &crypto,
-                        &src_cid,
-                        &loc_cid,
-                        0,
-                        TransportError::TLS_HANDSHAKE_FAILED,
-                        None,
-                    ),
-                });
+                let packet = handshake_close(
+                    &crypto,
+                    version,
+                    &src_cid,
+                    &loc_cid,
+                    0,
+                    TransportError::TLS_HANDSHAKE_FAILED,
+                    None,
+                );
+                self.connections[conn.0].queue_datagram(&mut self.ctx, remote, packet);
             }
         }
     }
@@ -580,10 +1001,8 @@ impl Endpoint {
         while let Some(packet) =
             self.connections[conn.0].next_packet(&self.log, &self.ctx.config, now)
         {
-            self.ctx.io.push_back(Io::Transmit {
-                destination: self.connections[conn.0].remote,
-                packet: packet.into(),
-            });
+            let remote = self.connections[conn.0].remote;
+            self.connections[conn.0].queue_datagram(&mut self.ctx, remote, packet);
             sent = true;
         }
         if sent {
@@ -592,6 +1011,7 @@ impl Endpoint {
         {
             let c = &mut self.connections[conn.0];
             if let Some(setting) = c.set_idle.take() {
+                c.idle_time = setting;
                 if let Some(time) = setting {
                     self.ctx.io.push_back(Io::TimerStart {
                         connection: conn,
@@ -606,6 +1026,7 @@ impl Endpoint {
                 }
             }
             if let Some(setting) = c.set_loss_detection.take() {
+                c.loss_detection_time = setting;
                 if let Some(time) = setting {
                     self.ctx.io.push_back(Io::TimerStart {
                         connection: conn,
@@ -619,6 +1040,36 @@ impl Endpoint {
                     });
                 }
             }
+            if let Some(setting) = c.set_keep_alive.take() {
+                c.keep_alive_time = setting;
+                if let Some(time) = setting {
+                    self.ctx.io.push_back(Io::TimerStart {
+                        connection: conn,
+                        timer: Timer::KeepAlive,
+                        time,
+                    });
+                } else {
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::KeepAlive,
+                    });
+                }
+            }
+            if let Some(setting) = c.set_delayed_ack.take() {
+                c.delayed_ack_time = setting;
+                if let Some(time) = setting {
+                    self.ctx.io.push_back(Io::TimerStart {
+                        connection: conn,
+                        timer: Timer::DelayedAck,
+                        time,
+                    });
+                } else {
+                    self.ctx.io.push_back(Io::TimerStop {
+                        connection: conn,
+                        timer: Timer::DelayedAck,
+                    });
+                }
+            }
         }
     }
 
@@ -640,12 +1091,32 @@ impl Endpoint {
 
     /// Handle a timer expiring
     pub fn timeout(&mut self, now: u64, conn: ConnectionHandle, timer: Timer) {
+        if !self.connections.contains(conn.0) {
+            return;
+        }
         match timer {
             Timer::Close => {
+                // Ignore stale timers, e.g. one superseded by the peer's own close, or one outliving
+                // a connection that drained early, whose slot may since have been reused
+                {
+                    let c = &self.connections[conn.0];
+                    let state = c.state.as_ref().unwrap();
+                    if !c.close_time.map_or(false, |time| now >= time)
+                        || !state.is_closed()
+                        || state.is_drained()
+                    {
+                        return;
+                    }
+                }
                 self.ctx.io.push_back(Io::TimerStop {
                     connection: conn,
                     timer: Timer::Idle,
                 });
+                {
+                    let c = &mut self.connections[conn.0];
+                    c.close_time = None;
+                    c.idle_time = None;
+                }
                 self.ctx.events.push_back((conn, Event::ConnectionDrained));
                 if self.connections[conn.0].app_closed {
                     self.forget(conn);
@@ -654,6 +1125,7 @@ impl Endpoint {
                 }
             }
             Timer::Idle => {
+                self.connections[conn.0].idle_time = None;
                 self.connections[conn.0].close_common(&mut self.ctx, now);
                 self.connections[conn.0].state = Some(State::Draining);
                 self.ctx.events.push_back((
@@ -666,8 +1138,22 @@ impl Endpoint {
                                                    // goes through
             }
             Timer::LossDetection => {
+                self.connections[conn.0].loss_detection_time = None;
                 self.connections[conn.0].check_packet_loss(&mut self.ctx, now);
             }
+            Timer::KeepAlive => {
+                let c = &mut self.connections[conn.0];
+                c.keep_alive_time = None;
+                if !c.state.as_ref().unwrap().is_closed() {
+                    c.pending.ping = true;
+                    self.ctx.dirty_conns.insert(conn);
+                }
+            }
+            Timer::DelayedAck => {
+                self.connections[conn.0].delayed_ack_time = None;
+                self.connections[conn.0].ack_due = true;
+                self.ctx.dirty_conns.insert(conn);
+            }
         }
     }
 
@@ -686,16 +1172,50 @@ impl Endpoint {
         self.connections[conn.0].write(&mut self.ctx, stream, data)
     }
 
-    /// Indicate that no more data will be sent on a stream
+    /// Transmit the concatenation of `bufs` on a stream
     ///
-    /// All previously transmitted data will still be delivered. Incoming data on bidirectional
-    /// streams is unaffected.
+    /// Equivalent to a single `write` of the concatenated data, but without the caller having to
+    /// assemble it. Returns the total number of bytes written on success, which may be less than
+    /// the total length of `bufs`.
     ///
     /// # Panics
     /// - when applied to a stream that does not have an active outgoing channel
-    pub fn finish(&mut self, conn: ConnectionHandle, stream: StreamId) {
-        self.connections[conn.0].finish(stream);
+    pub fn write_vectored(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        bufs: &[&[u8]],
+    ) -> Result<usize, WriteError> {
+        self.connections[conn.0].write_vectored(&mut self.ctx, stream, bufs)
+    }
+
+    /// Transmit data on a stream without copying it
+    ///
+    /// Returns the prefix of `data` that was written on success, which may be shorter than `data`.
+    /// The returned `Bytes` shares storage with `data`.
+    ///
+    /// # Panics
+    /// - when applied to a stream that does not have an active outgoing channel
+    pub fn write_bytes(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        data: Bytes,
+    ) -> Result<Bytes, WriteError> {
+        self.connections[conn.0].write_bytes(&mut self.ctx, stream, data)
+    }
+
+    /// Indicate that no more data will be sent on a stream
+    ///
+    /// All previously transmitted data will still be delivered. Incoming data on bidirectional
+    /// streams is unaffected. Finishing a stream that has already been finished or reset has no
+    /// effect beyond returning `FinishError::Finished`.
+    ///
+    /// Fails with `FinishError::InvalidStream` if `stream` does not have an outgoing channel.
+    pub fn finish(&mut self, conn: ConnectionHandle, stream: StreamId) -> Result<(), FinishError> {
+        self.connections[conn.0].finish(stream)?;
         self.ctx.dirty_conns.insert(conn);
+        Ok(())
     }
 
     /// Read data from a stream
@@ -712,15 +1232,54 @@ impl Endpoint {
         buf: &mut [u8],
     ) -> Result<usize, ReadError> {
         self.ctx.dirty_conns.insert(conn); // May need to send flow control frames after reading
-        match self.connections[conn.0].read(stream, buf) {
+        match self.connections[conn.0].read(&self.ctx.config, stream, buf) {
             x @ Err(ReadError::Finished) | x @ Err(ReadError::Reset { .. }) => {
-                self.connections[conn.0].maybe_cleanup(&self.ctx.config, stream);
+                self.connections[conn.0].maybe_cleanup(stream);
                 x
             }
             x => x,
         }
     }
 
+    /// Read up to `max_len` bytes from a stream into a freshly allocated buffer
+    ///
+    /// Like `read`, but spares the caller from initializing a buffer up front, which is
+    /// significant when reading in large chunks.
+    ///
+    /// # Panics
+    /// - when applied to a stream that does not have an active incoming channel
+    pub fn read_bytes(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        max_len: usize,
+    ) -> Result<Bytes, ReadError> {
+        self.ctx.dirty_conns.insert(conn); // May need to send flow control frames after reading
+        match self.connections[conn.0].read_bytes(&self.ctx.config, stream, max_len) {
+            x @ Err(ReadError::Finished) | x @ Err(ReadError::Reset { .. }) => {
+                self.connections[conn.0].maybe_cleanup(stream);
+                x
+            }
+            x => x,
+        }
+    }
+
+    /// Copy data from a stream without consuming it
+    ///
+    /// Returns the same data a `read` with the same `buf` would, but leaves it to be returned again
+    /// by the next read. Peeked data isn't considered consumed for flow control purposes.
+    ///
+    /// # Panics
+    /// - when applied to a stream that does not have an active incoming channel
+    pub fn peek(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        buf: &mut [u8],
+    ) -> Result<usize, ReadError> {
+        self.connections[conn.0].peek(stream, buf)
+    }
+
     /// Read data from a stream out of order
     ///
     /// Unlike `read`, this interface is not subject to head-of-line blocking within the stream,
@@ -739,9 +1298,9 @@ impl Endpoint {
         stream: StreamId,
     ) -> Result<(Bytes, u64), ReadError> {
         self.ctx.dirty_conns.insert(conn); // May need to send flow control frames after reading
-        match self.connections[conn.0].read_unordered(stream) {
+        match self.connections[conn.0].read_unordered(&self.ctx.config, stream) {
             x @ Err(ReadError::Finished) | x @ Err(ReadError::Reset { .. }) => {
-                self.connections[conn.0].maybe_cleanup(&self.ctx.config, stream);
+                self.connections[conn.0].maybe_cleanup(stream);
                 x
             }
             x => x,
@@ -750,27 +1309,127 @@ impl Endpoint {
 
     /// Abandon transmitting data on a stream
     ///
-    /// # Panics
-    /// - when applied to a receive stream or an unopened send stream
-    pub fn reset(&mut self, conn: ConnectionHandle, stream: StreamId, error_code: u16) {
+    /// Fails if `stream` does not have an outgoing channel. Resetting a closed stream has no effect.
+    pub fn reset(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        error_code: u16,
+    ) -> Result<(), InvalidStreamError> {
         self.connections[conn.0].reset(&mut self.ctx, stream, error_code)
     }
 
     /// Instruct the peer to abandon transmitting data on a stream
     ///
-    /// # Panics
-    /// - when applied to a stream that has not begin receiving data
-    pub fn stop_sending(&mut self, conn: ConnectionHandle, stream: StreamId, error_code: u16) {
-        self.connections[conn.0].stop_sending(stream, error_code);
+    /// Fails if `stream` does not have an incoming channel. Stopping a closed stream has no effect.
+    pub fn stop_sending(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        error_code: u16,
+    ) -> Result<(), InvalidStreamError> {
+        self.connections[conn.0].stop_sending(stream, error_code)?;
         self.ctx.dirty_conns.insert(conn);
+        Ok(())
     }
 
     /// Create a new stream
     ///
-    /// Returns `None` if the maximum number of streams currently permitted by the remote endpoint
-    /// are already open.
-    pub fn open(&mut self, conn: ConnectionHandle, direction: Directionality) -> Option<StreamId> {
-        self.connections[conn.0].open(&self.ctx.config, direction)
+    /// Fails with `OpenError::LimitReached` if the maximum number of streams currently permitted
+    /// by the remote endpoint are already open, in which case the peer is notified that we'd like
+    /// more.
+    pub fn open(
+        &mut self,
+        conn: ConnectionHandle,
+        direction: Directionality,
+    ) -> Result<StreamId, OpenError> {
+        let result = self.connections[conn.0].open(&self.ctx.config, direction);
+        if let Err(OpenError::LimitReached) = result {
+            self.ctx.dirty_conns.insert(conn);
+        }
+        result
+    }
+
+    /// Permit the peer to open `additional` more streams of the given directionality on `conn`
+    ///
+    /// Raises the limit set by `Config::max_remote_bi_streams` or `Config::max_remote_uni_streams`
+    /// for this connection alone, up to the 2^60 streams that stream IDs can express. Resources for
+    /// each stream are allocated once the peer opens it.
+    pub fn grant_streams(
+        &mut self,
+        conn: ConnectionHandle,
+        directionality: Directionality,
+        additional: u64,
+    ) {
+        self.connections[conn.0].grant_streams(directionality, additional);
+        self.ctx.dirty_conns.insert(conn);
+    }
+
+    /// Look up the state of a stream, each of its halves, and how much may be written to it
+    ///
+    /// Returns `None` if the stream doesn't exist, either because it hasn't been opened yet or
+    /// because it has been closed and forgotten. This makes it safe to call before operating on a
+    /// stream that may no longer exist.
+    pub fn stream_state(&self, conn: ConnectionHandle, stream: StreamId) -> Option<StreamState> {
+        self.connections[conn.0].stream_state(stream)
+    }
+
+    /// When the loss detection timer for `conn` is next due to fire, if it's armed
+    ///
+    /// This is the same time most recently reported by `Io::TimerStart` for
+    /// `Timer::LossDetection`, allowing callers driving a virtual clock to advance it to exactly
+    /// the moment `timeout` should next be called for that timer.
+    pub fn next_loss_detection_time(&self, conn: ConnectionHandle) -> Option<u64> {
+        self.connections[conn.0].loss_detection_time
+    }
+
+    /// When `timeout` next needs to be called for any connection, if ever
+    ///
+    /// This is the earliest time among all timers armed by the `Io::TimerStart` operations
+    /// produced so far, so it should be consulted after draining `poll_io`. Useful for event loops
+    /// that would rather sleep until a single deadline than track every timer themselves.
+    pub fn next_timeout(&self) -> Option<u64> {
+        self.connections
+            .iter()
+            .filter_map(|(_, c)| c.next_timeout())
+            .min()
+    }
+
+    /// Set the priority of a stream's outgoing data
+    ///
+    /// Data is sent from the highest priority streams that have any to send before lower priority
+    /// streams are considered, including when retransmitting. Streams of equal priority share the
+    /// available bandwidth. Defaults to 0.
+    ///
+    /// Fails if `stream` does not have an outgoing channel. Setting the priority of a closed stream
+    /// has no effect.
+    pub fn set_priority(
+        &mut self,
+        conn: ConnectionHandle,
+        stream: StreamId,
+        priority: i32,
+    ) -> Result<(), InvalidStreamError> {
+        self.connections[conn.0].set_priority(stream, priority)
+    }
+
+    /// Number of live streams on `conn`, as `(locally initiated, remotely initiated)`
+    ///
+    /// Counts streams of both directionalities that have been opened and not yet fully closed.
+    /// Streams the peer is permitted to open but hasn't are not included.
+    pub fn open_stream_count(&self, conn: ConnectionHandle) -> (usize, usize) {
+        self.connections[conn.0].open_stream_count()
+    }
+
+    /// Number of streams of a given directionality the peer has opened on `conn` that are still live
+    ///
+    /// Useful for enforcing application-level concurrency limits that are tighter than those
+    /// enforced by the transport's stream ID limits.
+    pub fn remote_stream_count(
+        &self,
+        conn: ConnectionHandle,
+        directionality: Directionality,
+    ) -> u64 {
+        self.connections[conn.0].remote_stream_count(directionality)
     }
 
     /// Ping the remote endpoint
@@ -781,6 +1440,28 @@ impl Endpoint {
         self.ctx.dirty_conns.insert(conn);
     }
 
+    /// Send an unreliable datagram on `conn`
+    ///
+    /// Datagrams are subject to congestion control but are never retransmitted. Fails if the peer
+    /// doesn't accept datagrams or if `data` is too large to fit in a single packet. If
+    /// `Config::max_queued_datagrams` are already waiting to be sent, the oldest is dropped.
+    pub fn send_datagram(
+        &mut self,
+        conn: ConnectionHandle,
+        data: Bytes,
+    ) -> Result<(), DatagramError> {
+        self.connections[conn.0].send_datagram(&mut self.ctx, data)
+    }
+
+    /// Rotate the 1-RTT keys used to protect packets on `conn`
+    ///
+    /// Long-lived connections should do this periodically to stay within the confidentiality
+    /// limits of the AEAD. Ignored if the handshake is incomplete or a previous update has yet to be
+    /// acknowledged by the peer.
+    pub fn force_key_update(&mut self, conn: ConnectionHandle) {
+        self.connections[conn.0].force_key_update(&self.ctx.config);
+    }
+
     /// Close a connection immediately
     ///
     /// This does not ensure delivery of outstanding data. It is the application's responsibility
@@ -793,6 +1474,29 @@ impl Endpoint {
         self.connections[conn.0].close(&mut self.ctx, now, error_code, reason);
     }
 
+    /// Close every connection, e.g. to shut down the endpoint
+    ///
+    /// Connections that have already drained are left for the application to `close` as usual.
+    /// `is_drained` reports when the last `Event::ConnectionDrained` has been emitted.
+    pub fn close_all(&mut self, now: u64, error_code: u16, reason: Bytes) {
+        let conns = self
+            .connections
+            .iter()
+            .filter(|&(_, c)| !c.state.as_ref().unwrap().is_drained())
+            .map(|(i, _)| ConnectionHandle(i))
+            .collect::<Vec<_>>();
+        for conn in conns {
+            self.close(now, conn, error_code, reason.clone());
+        }
+    }
+
+    /// Whether every connection on the endpoint has drained
+    pub fn is_drained(&self) -> bool {
+        self.connections
+            .iter()
+            .all(|(_, c)| c.state.as_ref().unwrap().is_drained())
+    }
+
     /// Look up whether we're the client or server of `conn`.
     pub fn get_side(&self, conn: ConnectionHandle) -> Side {
         self.connections[conn.0].side
@@ -806,7 +1510,16 @@ impl Endpoint {
     pub fn get_remote_id(&self, conn: ConnectionHandle) -> ConnectionId {
         self.connections[conn.0].rem_cid
     }
-    pub fn get_remote_address(&self, conn: ConnectionHandle) -> &SocketAddrV6 {
+    /// The latency spin bit `conn` currently sends, or `None` if it doesn't take part in spinning
+    pub fn get_spin_bit(&self, conn: ConnectionHandle) -> Option<bool> {
+        let conn = &self.connections[conn.0];
+        if conn.spin_enabled {
+            Some(conn.spin)
+        } else {
+            None
+        }
+    }
+    pub fn get_remote_address(&self, conn: ConnectionHandle) -> &SocketAddr {
         &self.connections[conn.0].remote
     }
     pub fn get_protocol(&self, conn: ConnectionHandle) -> Option<&[u8]> {
@@ -821,12 +1534,61 @@ impl Endpoint {
         self.connections[conn.0].bytes_in_flight
     }
 
+    /// Total UDP payload bytes sent and received on `conn`, respectively
+    ///
+    /// Unlike application-level counters, this includes all protocol overhead such as headers,
+    /// acknowledgements, padding, and retransmissions.
+    pub fn wire_bytes(&self, conn: ConnectionHandle) -> (u64, u64) {
+        let conn = &self.connections[conn.0];
+        (conn.wire_bytes_sent, conn.wire_bytes_recvd)
+    }
+
+    /// Microseconds elapsed since `conn` was created
+    ///
+    /// Useful for enforcing a maximum connection lifetime, or for preferring newer connections
+    /// when evicting from a pool.
+    pub fn connection_age(&self, conn: ConnectionHandle, now: u64) -> u64 {
+        now.saturating_sub(self.connections[conn.0].created_at)
+    }
+
+    /// The time at which the handshake of `conn` completed, if it has
+    pub fn established_time(&self, conn: ConnectionHandle) -> Option<u64> {
+        self.connections[conn.0].established_at
+    }
+
+    /// The transport parameters declared by the peer of `conn`
+    ///
+    /// `None` until the handshake completes, since the peer's parameters aren't authenticated
+    /// before then.
+    pub fn get_transport_parameters(&self, conn: ConnectionHandle) -> Option<TransportParameters> {
+        let conn = &self.connections[conn.0];
+        conn.established_at.map(|_| conn.params)
+    }
+
+    /// Snapshot of the congestion, loss, and transfer state of `conn`
+    pub fn get_stats(&self, conn: ConnectionHandle) -> ConnectionStats {
+        self.connections[conn.0].stats()
+    }
+
     /// Number of bytes worth of non-ack-only packets that may be sent.
     pub fn get_congestion_state(&self, conn: ConnectionHandle) -> u64 {
         let c = &self.connections[conn.0];
         c.congestion_window.saturating_sub(c.bytes_in_flight)
     }
 
+    /// Number of bytes that may currently be written to `conn`, across all streams
+    ///
+    /// The lesser of the space in the congestion window and the connection-level flow control
+    /// credit extended by the peer. Individual streams may be further limited by their own flow
+    /// control.
+    pub fn writable_bytes(&self, conn: ConnectionHandle) -> u64 {
+        let c = &self.connections[conn.0];
+        cmp::min(
+            c.congestion_window.saturating_sub(c.bytes_in_flight),
+            c.max_data.saturating_sub(c.data_sent),
+        )
+    }
+
     /// The name a client supplied via SNI.
     ///
     /// None if no name was supplied or if this connection was locally-initiated.
@@ -834,6 +1596,29 @@ impl Endpoint {
         self.connections[conn.0].tls.get_sni_hostname()
     }
 
+    /// Associate arbitrary application data with `conn`, replacing any previous value
+    pub fn set_user_data<T: Any + Send>(&mut self, conn: ConnectionHandle, data: T) {
+        self.connections[conn.0].user_data = Some(Box::new(data));
+    }
+
+    /// Data previously associated with `conn` by `set_user_data`
+    ///
+    /// Returns `None` if no data has been set or it is not of type `T`.
+    pub fn get_user_data<T: Any + Send>(&self, conn: ConnectionHandle) -> Option<&T> {
+        self.connections[conn.0]
+            .user_data
+            .as_ref()
+            .and_then(|x| x.downcast_ref())
+    }
+
+    /// Mutable access to data previously associated with `conn` by `set_user_data`
+    pub fn get_user_data_mut<T: Any + Send>(&mut self, conn: ConnectionHandle) -> Option<&mut T> {
+        self.connections[conn.0]
+            .user_data
+            .as_mut()
+            .and_then(|x| x.downcast_mut())
+    }
+
     /// Whether a previous session was successfully resumed by `conn`.
     pub fn get_session_resumed(&self, _: ConnectionHandle) -> bool {
         false // TODO: fixme?
@@ -872,6 +1657,28 @@ pub enum Event {
     StreamFinished {
         stream: StreamId,
     },
+    /// The peer asked us to stop sending on `stream`, which has consequently been reset
+    ///
+    /// Further writes to the stream will fail with `WriteError::Stopped`.
+    StreamStopped {
+        stream: StreamId,
+        /// The error code supplied by the peer
+        error_code: u16,
+    },
+    /// `stream` has had a gap in its received data for longer than `Config::stream_stall_timeout`
+    ///
+    /// Ordered reads cannot proceed until the gap is filled. The application may wish to call
+    /// `stop_sending` rather than wait indefinitely. Raised at most once per stream.
+    StreamStalled {
+        stream: StreamId,
+    },
+    /// The peer has finished sending on the receive side of bidirectional `stream`
+    ///
+    /// All data up to the peer's final offset has arrived, though some may still be waiting to be
+    /// read by the application.
+    StreamRecvFinished {
+        stream: StreamId,
+    },
     /// At least one new stream of a certain directionality may be opened
     StreamAvailable {
         directionality: Directionality,
@@ -879,13 +1686,24 @@ pub enum Event {
     NewSessionTicket {
         ticket: Box<[u8]>,
     },
+    /// The peer sent an unreliable datagram
+    DatagramReceived {
+        data: Bytes,
+    },
+    /// A new RTT sample was taken. Only raised if `Config::rtt_events` is set.
+    RttUpdated {
+        /// The sample just taken (μs)
+        latest: u64,
+        /// The smoothed RTT estimate, incorporating `latest` (μs)
+        smoothed: u64,
+    },
 }
 
 /// I/O operations to be immediately executed the backend.
 #[derive(Debug)]
 pub enum Io {
     Transmit {
-        destination: SocketAddrV6,
+        destination: SocketAddr,
         packet: Box<[u8]>,
     },
     /// Start or reset a timer
@@ -906,6 +1724,8 @@ pub enum Timer {
     Close,
     LossDetection,
     Idle,
+    DelayedAck,
+    KeepAlive,
 }
 
 impl slog::Value for Timer {
@@ -101,6 +101,8 @@ frame_types!{
     ACK = 0x0d,
     PATH_CHALLENGE = 0x0e,
     PATH_RESPONSE = 0x0f,
+    DATAGRAM = 0x30,
+    DATAGRAM_LEN = 0x31,
 }
 
 #[derive(Debug)]
@@ -139,6 +141,7 @@ pub enum Frame {
         id: ConnectionId,
         reset_token: [u8; 16],
     },
+    Datagram(Datagram),
     Invalid(Type),
 }
 
@@ -172,6 +175,7 @@ impl Frame {
             PathChallenge(_) => Type::PATH_CHALLENGE,
             PathResponse(_) => Type::PATH_RESPONSE,
             NewConnectionId { .. } => Type::NEW_CONNECTION_ID,
+            Datagram(_) => Type::DATAGRAM_LEN,
             Invalid(ty) => ty,
         }
     }
@@ -276,23 +280,53 @@ impl<'a> IntoIterator for &'a Ack {
 }
 
 impl Ack {
-    pub fn encode<W: BufMut>(delay: u64, ranges: &RangeSet, buf: &mut W) {
+    /// Encode the newest of `ranges` that fit in `max_len` bytes, returning those encoded
+    ///
+    /// The newest range is always encoded, even if it alone exceeds `max_len`.
+    pub fn encode<W: BufMut>(
+        delay: u64,
+        ranges: &RangeSet,
+        max_len: usize,
+        buf: &mut W,
+    ) -> RangeSet {
         let mut rest = ranges.iter().rev();
         let first = rest.next().unwrap();
         let largest = first.end - 1;
         let first_size = first.end - first.start;
+        // The block count precedes the blocks, so count the blocks that fit before writing any
+        let mut len = 1
+            + varint::size(largest).unwrap()
+            + varint::size(delay).unwrap()
+            + varint::size(first_size - 1).unwrap();
+        let mut count = 0;
+        let mut prev = first.start;
+        for block in rest {
+            let block_len = varint::size(prev - block.end - 1).unwrap()
+                + varint::size(block.end - block.start - 1).unwrap();
+            if len + block_len + varint::size(count + 1).unwrap() > max_len {
+                break;
+            }
+            len += block_len;
+            count += 1;
+            prev = block.start;
+        }
+
         buf.write(Type::ACK);
         varint::write(largest, buf).unwrap();
         varint::write(delay, buf).unwrap();
-        varint::write(ranges.len() as u64 - 1, buf).unwrap();
+        varint::write(count, buf).unwrap();
         varint::write(first_size - 1, buf).unwrap();
         let mut prev = first.start;
-        for block in rest {
+        let mut encoded = RangeSet::new();
+        encoded.insert(first);
+        for block in ranges.iter().rev().skip(1).take(count as usize) {
             let size = block.end - block.start;
             varint::write(prev - block.end - 1, buf).unwrap();
             varint::write(size - 1, buf).unwrap();
             prev = block.start;
+            encoded.insert(block);
         }
+        encoded
     }
 
     pub fn iter(&self) -> AckIter {
@@ -335,6 +369,30 @@ where
     }
 }
 
+/// An unreliable datagram, delivered outside of any stream
+#[derive(Debug, Clone)]
+pub struct Datagram<T = Bytes> {
+    pub data: T,
+}
+
+impl Datagram {
+    /// Encoded size of a DATAGRAM frame carrying `len` bytes
+    pub fn size(len: usize) -> usize {
+        1 + varint::size(len as u64).unwrap() + len
+    }
+}
+
+impl<T> Datagram<T>
+where
+    T: AsRef<[u8]>,
+{
+    pub fn encode<W: BufMut>(&self, out: &mut W) {
+        out.write(Type::DATAGRAM_LEN);
+        varint::write(self.data.as_ref().len() as u64, out).unwrap();
+        out.put_slice(self.data.as_ref());
+    }
+}
+
 pub struct Iter {
     // TODO: ditch io::Cursor after bytes 0.5
     bytes: io::Cursor<Bytes>,
@@ -371,6 +429,13 @@ impl Iter {
         Ok(self.bytes.get_ref().slice(start, start + len as usize))
     }
 
+    fn take_remaining(&mut self) -> Bytes {
+        let mut x = mem::replace(self.bytes.get_mut(), Bytes::new());
+        x.advance(self.bytes.position() as usize);
+        self.bytes.set_position(0);
+        x
+    }
+
     fn try_next(&mut self) -> Result<Frame, IterErr> {
         let ty = self.bytes.get::<Type>()?;
         self.last_ty = Some(ty);
@@ -449,6 +514,12 @@ impl Iter {
                     reset_token,
                 }
             }
+            Type::DATAGRAM => Frame::Datagram(Datagram {
+                data: self.take_remaining(),
+            }),
+            Type::DATAGRAM_LEN => Frame::Datagram(Datagram {
+                data: self.take_len()?,
+            }),
             _ => match ty.stream() {
                 Some(s) => Frame::Stream(Stream {
                     id: self.bytes.get()?,
@@ -457,10 +528,7 @@ impl Iter {
                     data: if s.len() {
                         self.take_len()?
                     } else {
-                        let mut x = mem::replace(self.bytes.get_mut(), Bytes::new());
-                        x.advance(self.bytes.position() as usize);
-                        self.bytes.set_position(0);
-                        x
+                        self.take_remaining()
                     },
                 }),
                 None => {
@@ -557,7 +625,7 @@ mod test {
             ranges.insert(packet..packet + 1);
         }
         let mut buf = Vec::new();
-        Ack::encode(42, &ranges, &mut buf);
+        Ack::encode(42, &ranges, usize::max_value(), &mut buf);
         let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
         match frames[0] {
             Frame::Ack(ref ack) => {
@@ -568,4 +636,87 @@ mod test {
             ref x => panic!("incorrect frame {:?}", x),
         }
     }
+
+    #[test]
+    fn ack_truncated() {
+        let mut ranges = RangeSet::new();
+        for packet in (0..1000).step_by(2) {
+            ranges.insert_one(packet);
+        }
+        let mut buf = Vec::new();
+        let encoded = Ack::encode(42, &ranges, 100, &mut buf);
+        assert!(buf.len() <= 100);
+        // The caller's ranges are left intact
+        assert_eq!(ranges.len(), 500);
+        assert_eq!(encoded.max(), Some(998));
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        match frames[0] {
+            Frame::Ack(ref ack) => {
+                let packets = ack.iter().flat_map(|x| x).collect::<Vec<_>>();
+                // Only the newest ranges are acknowledged
+                assert_eq!(packets[0], 998);
+                assert!(packets.len() > 1 && packets.len() < 500);
+                assert_eq!(packets.len(), encoded.len());
+                for pair in packets.windows(2) {
+                    assert_eq!(pair[0], pair[1] + 2);
+                }
+            }
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+    }
+
+    #[test]
+    fn connection_close_unknown_code() {
+        let mut buf = Vec::new();
+        buf.write(Type::CONNECTION_CLOSE);
+        buf.write::<u16>(0x7f3a);
+        varint::write(3, &mut buf).unwrap();
+        buf.extend_from_slice(b"bye");
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 1);
+        match frames[0] {
+            Frame::ConnectionClose(ref close) => {
+                assert_eq!(u16::from(close.error_code), 0x7f3a);
+                assert_eq!(close.to_string(), "unknown error 0x7f3a: bye");
+            }
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+    }
+
+    #[test]
+    fn connection_close_unknown_code_round_trip() {
+        let close = ConnectionClose {
+            error_code: io::Cursor::new(&[0x7f, 0x3a][..])
+                .get::<TransportError>()
+                .unwrap(),
+            reason: Bytes::from(&b"bye"[..]),
+        };
+        let mut buf = Vec::new();
+        close.encode(&mut buf, 1200);
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 1);
+        match frames[0] {
+            Frame::ConnectionClose(ref decoded) => {
+                assert_eq!(decoded.error_code, close.error_code);
+                assert_eq!(decoded.reason, close.reason);
+            }
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+    }
+
+    #[test]
+    fn connection_close_truncated_reason() {
+        let mut buf = Vec::new();
+        buf.write(Type::CONNECTION_CLOSE);
+        buf.write::<u16>(0x7f3a);
+        // Claims a longer reason than follows
+        varint::write(4, &mut buf).unwrap();
+        buf.extend_from_slice(b"bye");
+        let frames = Iter::new(Bytes::from(buf)).collect::<Vec<_>>();
+        assert_eq!(frames.len(), 1);
+        match frames[0] {
+            Frame::Invalid(ty) => assert_eq!(ty, Type::CONNECTION_CLOSE),
+            ref x => panic!("incorrect frame {:?}", x),
+        }
+    }
 }
@@ -18,6 +18,9 @@ extern crate orion;
 extern crate rand;
 extern crate ring;
 extern crate rustls;
+#[cfg(feature = "qlog")]
+#[macro_use]
+extern crate serde_json;
 extern crate slab;
 #[macro_use]
 extern crate slog;
@@ -26,19 +29,29 @@ extern crate untrusted;
 extern crate webpki;
 
 use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+mod cid_generator;
+pub use cid_generator::ConnectionIdGenerator;
 
 mod coding;
 mod range_set;
 #[cfg(test)]
 mod tests;
-mod transport_parameters;
 mod varint;
 
 mod connection;
-pub use connection::{ConnectionError, ConnectionHandle};
+pub use connection::{
+    ConnectionError, ConnectionHandle, ConnectionObserver, ConnectionStats, DatagramError,
+};
 
 mod crypto;
-pub use crypto::{ClientConfig, ConnectError};
+#[cfg(feature = "keylog")]
+pub use crypto::KeyLogWriter;
+pub use crypto::{
+    Cipher, ClientConfig, ConnectError, PacketOpeningKey, PacketProtection, PacketSealingKey,
+    SoftwareProtection,
+};
 
 mod frame;
 use frame::Frame;
@@ -50,14 +63,33 @@ pub use endpoint::{Config, Endpoint, EndpointError, Event, Io, ListenKeys, Timer
 mod packet;
 pub use packet::ConnectionId;
 
+#[cfg(feature = "qlog")]
+mod qlog;
+#[cfg(feature = "qlog")]
+pub use qlog::QlogSink;
+
 mod stream;
-pub use stream::{ReadError, WriteError};
+pub use stream::{
+    FinishError, InvalidStreamError, OpenError, ReadError, RecvState, SendState, StreamState,
+    StreamStatus, WriteError,
+};
+
+mod token;
+mod token_bucket;
 
 mod transport_error;
 pub use transport_error::Error as TransportError;
 
+mod transport_parameters;
+pub use transport_parameters::{PreferredAddress, TransportParameters};
+
 /// The QUIC protocol version implemented
-pub const VERSION: u32 = 0xff00_000f;
+pub const VERSION: u32 = 0xff00_0010;
+
+/// Pick a random reserved version of the form `0x?a?a?a?a`, which no peer may implement
+fn grease_version<R: rand::Rng>(rng: &mut R) -> u32 {
+    rng.gen::<u32>() & 0xf0f0_f0f0 | 0x0a0a_0a0a
+}
 
 /// TLS ALPN value for HTTP over QUIC
 pub const ALPN_QUIC_HTTP: &[u8] = b"hq-11";
@@ -180,3 +212,16 @@ const MAX_CID_SIZE: usize = 18;
 const MIN_CID_SIZE: usize = 4;
 const MIN_INITIAL_SIZE: usize = 1200;
 const MIN_MTU: u16 = 1232;
+/// Largest permitted idle timeout (s)
+const MAX_IDLE_TIMEOUT: u16 = 600;
+
+/// The IP address of `addr`, with IPv4 addresses in v4-mapped form
+///
+/// Used wherever a peer's address is authenticated, so that the result doesn't depend on whether
+/// it was reported by an IPv4 socket or a dual-stack IPv6 socket.
+fn ip_octets(addr: &SocketAddr) -> [u8; 16] {
+    match addr.ip() {
+        IpAddr::V4(x) => x.to_ipv6_mapped().octets(),
+        IpAddr::V6(x) => x.octets(),
+    }
+}
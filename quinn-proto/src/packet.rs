@@ -7,7 +7,7 @@ use slog;
 use coding::{self, BufExt, BufMutExt, Codec};
 use crypto::PacketNumberKey;
 use varint;
-use {MAX_CID_SIZE, MIN_CID_SIZE, VERSION};
+use {MAX_CID_SIZE, MIN_CID_SIZE};
 
 // Due to packet number encryption, it is impossible to fully decode a header
 // (which includes a variable-length packet number) without crypto context.
@@ -25,15 +25,29 @@ pub struct PartialDecode {
 }
 
 impl PartialDecode {
-    pub fn new(bytes: BytesMut, local_cid_len: usize) -> Result<Self, PacketDecodeError> {
+    /// Decode the invariant header
+    ///
+    /// Long-header packets whose version isn't in `supported_versions` are rejected with
+    /// `UnsupportedVersion`, except for version negotiation packets.
+    pub fn new(
+        bytes: BytesMut,
+        local_cid_len: usize,
+        supported_versions: &[u32],
+    ) -> Result<Self, PacketDecodeError> {
         let mut buf = io::Cursor::new(bytes);
-        let invariant_header = InvariantHeader::decode(&mut buf, local_cid_len)?;
+        let invariant_header =
+            InvariantHeader::decode(&mut buf, local_cid_len, supported_versions)?;
         Ok(Self {
             invariant_header,
             buf,
         })
     }
 
+    /// Number of bytes remaining in the datagram, including this packet
+    pub fn len(&self) -> usize {
+        self.buf.get_ref().len()
+    }
+
     pub fn has_long_header(&self) -> bool {
         use self::InvariantHeader::*;
         match self.invariant_header {
@@ -45,26 +59,22 @@ impl PartialDecode {
     pub fn is_initial(&self) -> bool {
         use self::InvariantHeader::*;
         match self.invariant_header {
-            Long {
-                version: VERSION,
-                first,
-                ..
-            } => PacketType::from_byte(first) == Ok(PacketType::Initial),
+            Long { version, first, .. } if version != 0 => {
+                PacketType::from_byte(first) == Ok(PacketType::Initial)
+            }
             Long { .. } | Short { .. } => false,
         }
     }
 
     pub fn is_handshake(&self) -> bool {
         match self.invariant_header {
-            InvariantHeader::Long {
-                version: VERSION,
-                first,
-                ..
-            } => match PacketType::from_byte(first).unwrap() {
-                PacketType::Initial => true,
-                PacketType::Long(LongType::Handshake) => true,
-                _ => false,
-            },
+            InvariantHeader::Long { version, first, .. } if version != 0 => {
+                match PacketType::from_byte(first).unwrap() {
+                    PacketType::Initial => true,
+                    PacketType::Long(LongType::Handshake) => true,
+                    _ => false,
+                }
+            }
             InvariantHeader::Long { .. } => false,
             InvariantHeader::Short { .. } => false,
         }
@@ -74,9 +84,21 @@ impl PartialDecode {
         self.invariant_header.dst_cid()
     }
 
+    /// QUIC version of a long-header packet
+    pub fn version(&self) -> Option<u32> {
+        match self.invariant_header {
+            InvariantHeader::Long { version, .. } => Some(version),
+            InvariantHeader::Short { .. } => None,
+        }
+    }
+
+    /// Decrypt the packet number and decode the rest of the header
+    ///
+    /// Initial packets whose token exceeds `max_token_length` bytes are rejected.
     pub fn finish(
         self,
         pn_key: &PacketNumberKey,
+        max_token_length: usize,
     ) -> Result<(Packet, Option<BytesMut>), PacketDecodeError> {
         let Self {
             invariant_header,
@@ -85,6 +107,7 @@ impl PartialDecode {
         let (payload_len, header, allow_coalesced) = match invariant_header {
             InvariantHeader::Short { first, dst_cid } => {
                 let key_phase = first & KEY_PHASE_BIT != 0;
+                let spin = first & SPIN_BIT != 0;
                 if !buf.has_remaining() {
                     return Err(PacketDecodeError::InvalidHeader(
                         "header ends before packet number",
@@ -99,6 +122,7 @@ impl PartialDecode {
                         dst_cid,
                         number,
                         key_phase,
+                        spin,
                     },
                     false,
                 )
@@ -118,9 +142,10 @@ impl PartialDecode {
                 },
                 false,
             ),
+            // Any other version was checked against those supported by `InvariantHeader::decode`
             InvariantHeader::Long {
                 first,
-                version: VERSION,
+                version,
                 dst_cid,
                 src_cid,
             } => match PacketType::from_byte(first)? {
@@ -132,6 +157,7 @@ impl PartialDecode {
                     (
                         buf.remaining(),
                         Header::Retry {
+                            version,
                             src_cid,
                             dst_cid,
                             orig_dst_cid,
@@ -140,7 +166,14 @@ impl PartialDecode {
                     )
                 }
                 PacketType::Initial => {
-                    let token_length = buf.get_var()? as usize;
+                    let token_length = buf.get_var()?;
+                    if token_length > max_token_length as u64 {
+                        return Err(PacketDecodeError::InvalidHeader("token too long"));
+                    }
+                    let token_length = token_length as usize;
+                    if token_length > buf.remaining() {
+                        return Err(PacketDecodeError::InvalidHeader("token longer than packet"));
+                    }
                     let mut token = vec![0; token_length];
                     buf.copy_to_slice(&mut token);
 
@@ -156,6 +189,7 @@ impl PartialDecode {
                     (
                         (len as usize) - number.len(),
                         Header::Initial {
+                            version,
                             src_cid,
                             dst_cid,
                             token,
@@ -173,6 +207,7 @@ impl PartialDecode {
                         (len as usize) - number.len(),
                         Header::Long {
                             ty,
+                            version,
                             src_cid,
                             dst_cid,
                             number,
@@ -183,8 +218,6 @@ impl PartialDecode {
                 // InvariantHeader should be Short variant for Short packet type
                 PacketType::Short { .. } => unreachable!(),
             },
-            // InvariantHeader decode checks for unsupported versions
-            InvariantHeader::Long { .. } => unreachable!(),
         };
 
         let header_len = buf.position() as usize;
@@ -250,9 +283,23 @@ pub struct Packet {
     pub payload: BytesMut,
 }
 
+impl Packet {
+    /// Whether any header bits that must be zero are set
+    ///
+    /// Only meaningful once the packet has been authenticated, since the header is otherwise
+    /// attacker-controlled.
+    pub fn reserved_bits_set(&self) -> bool {
+        match self.header {
+            Header::Short { .. } => self.header_data[0] & SHORT_RESERVED_BITS != 0,
+            _ => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Header {
     Initial {
+        version: u32,
         src_cid: ConnectionId,
         dst_cid: ConnectionId,
         token: Vec<u8>,
@@ -260,11 +307,13 @@ pub enum Header {
     },
     Long {
         ty: LongType,
+        version: u32,
         src_cid: ConnectionId,
         dst_cid: ConnectionId,
         number: PacketNumber,
     },
     Retry {
+        version: u32,
         src_cid: ConnectionId,
         dst_cid: ConnectionId,
         orig_dst_cid: ConnectionId,
@@ -273,6 +322,8 @@ pub enum Header {
         dst_cid: ConnectionId,
         number: PacketNumber,
         key_phase: bool,
+        /// Latency spin bit
+        spin: bool,
     },
     VersionNegotiate {
         random: u8,
@@ -286,13 +337,14 @@ impl Header {
         use self::Header::*;
         match *self {
             Initial {
+                version,
                 ref src_cid,
                 ref dst_cid,
                 ref token,
                 number,
             } => {
                 w.write(u8::from(PacketType::Initial));
-                w.write(VERSION);
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
                 w.write_var(token.len() as u64);
                 w.put_slice(token);
@@ -310,12 +362,13 @@ impl Header {
             }
             Long {
                 ty,
+                version,
                 ref src_cid,
                 ref dst_cid,
                 number,
             } => {
                 w.write(u8::from(PacketType::Long(ty)));
-                w.write(VERSION);
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
                 w.write::<u16>(0); // Placeholder for payload length; see `set_payload_length`
                 number.encode(w);
@@ -326,12 +379,13 @@ impl Header {
                 }
             }
             Retry {
+                version,
                 ref src_cid,
                 ref dst_cid,
                 ref orig_dst_cid,
             } => {
                 w.write(u8::from(PacketType::Retry));
-                w.write(VERSION);
+                w.write(version);
                 Self::encode_cids(w, dst_cid, src_cid);
                 w.write(orig_dst_cid.len() as u8);
                 w.put_slice(orig_dst_cid);
@@ -344,8 +398,16 @@ impl Header {
                 ref dst_cid,
                 number,
                 key_phase,
+                spin,
             } => {
-                w.write(0x30 | if key_phase { KEY_PHASE_BIT } else { 0 });
+                let mut first = 0x30u8;
+                if key_phase {
+                    first |= KEY_PHASE_BIT;
+                }
+                if spin {
+                    first |= SPIN_BIT;
+                }
+                w.write(first);
                 w.put_slice(dst_cid);
                 number.encode(w);
                 PartialEncode {
@@ -465,7 +527,11 @@ impl InvariantHeader {
         }
     }
 
-    fn decode<R: Buf>(buf: &mut R, local_cid_len: usize) -> Result<Self, PacketDecodeError> {
+    fn decode<R: Buf>(
+        buf: &mut R,
+        local_cid_len: usize,
+        supported_versions: &[u32],
+    ) -> Result<Self, PacketDecodeError> {
         let first = buf.get::<u8>()?;
         if first & LONG_HEADER_FORM == 0 {
             if buf.remaining() < local_cid_len {
@@ -495,7 +561,7 @@ impl InvariantHeader {
             let dst_cid = Self::get_cid(buf, dcil);
             let src_cid = Self::get_cid(buf, scil);
 
-            if version > 0 && version != VERSION {
+            if version > 0 && !supported_versions.contains(&version) {
                 return Err(PacketDecodeError::UnsupportedVersion {
                     source: src_cid,
                     destination: dst_cid,
@@ -795,13 +861,18 @@ pub const PACKET_NUMBER_32_MASK: u32 = 0x3fffffff;
 
 const LONG_HEADER_FORM: u8 = 0x80;
 const KEY_PHASE_BIT: u8 = 0x40;
+const SPIN_BIT: u8 = 0x04;
+/// The Google QUIC demultiplexing bit, and the bits reserved for future use
+const SHORT_RESERVED_BITS: u8 = 0x0b;
 
 #[cfg(test)]
 mod tests {
     use super::{
-        ConnectionId, Header, PacketNumber, PacketNumberKey, PartialDecode, PartialEncode,
+        set_payload_length, ConnectionId, Header, Packet, PacketDecodeError, PacketNumber,
+        PacketNumberKey, PartialDecode, PartialEncode, AEAD_TAG_SIZE,
     };
     use std::io;
+    use VERSION;
 
     fn check_pn(typed: PacketNumber, encoded: &[u8]) {
         let mut buf = Vec::new();
@@ -833,8 +904,8 @@ mod tests {
             0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a, 0x20, 0x3f, 0xbe, 0x2e, 0x32, 0x17, 0xfc,
             0x5b, 0x88, 0x55,
         ];
-        let partial_decode = PartialDecode::new(received.into(), 0).unwrap();
-        let packet = partial_decode.finish(&key).unwrap().0;
+        let partial_decode = PartialDecode::new(received.into(), 0, &[VERSION]).unwrap();
+        let packet = partial_decode.finish(&key, 0).unwrap().0;
         match packet.header {
             Header::Short {
                 number: PacketNumber::U16(15034),
@@ -852,6 +923,7 @@ mod tests {
             dst_cid: ConnectionId::new(&[]),
             number: PacketNumber::U16(15034),
             key_phase: false,
+            spin: false,
         };
         PartialEncode {
             header: &header,
@@ -873,8 +945,8 @@ mod tests {
             0x3d, 0x7e, 0x11, 0x73, 0x93, 0x17, 0x2a, 0x20, 0x3f, 0xbe, 0x2e, 0x32, 0x17, 0xfc,
             0x5b, 0x88, 0x55,
         ];
-        let partial_decode = PartialDecode::new(received.into(), 0).unwrap();
-        let packet = partial_decode.finish(&key).unwrap().0;
+        let partial_decode = PartialDecode::new(received.into(), 0, &[VERSION]).unwrap();
+        let packet = partial_decode.finish(&key, 0).unwrap().0;
         match packet.header {
             Header::Short {
                 number: PacketNumber::U16(15034),
@@ -892,6 +964,7 @@ mod tests {
             dst_cid: ConnectionId::new(&[]),
             number: PacketNumber::U16(15034),
             key_phase: false,
+            spin: false,
         };
         PartialEncode {
             header: &header,
@@ -899,4 +972,76 @@ mod tests {
         }.finish(&mut sending, &key, 3);
         assert_eq!(&sending[1..3], [0xa9, 0x0e]);
     }
+
+    #[test]
+    fn token_length_limit() {
+        let key = PacketNumberKey::AesCtr128([0; 16]);
+        let header = Header::Initial {
+            version: VERSION,
+            src_cid: ConnectionId::new(&[]),
+            dst_cid: ConnectionId::new(&[0xab; 8]),
+            token: vec![0xcd; 64],
+            number: PacketNumber::U8(0),
+        };
+        let mut buf = Vec::new();
+        let partial_encode = header.encode(&mut buf);
+        let header_len = buf.len();
+        buf.resize(header_len + 128, 0);
+        set_payload_length(&mut buf, header_len, 1);
+        partial_encode.finish(&mut buf, &key, header_len);
+        buf.resize(header_len + 128 + AEAD_TAG_SIZE, 0);
+
+        let result = PartialDecode::new(buf[..].into(), 0, &[VERSION])
+            .unwrap()
+            .finish(&key, 63);
+        assert_matches!(
+            result,
+            Err(PacketDecodeError::InvalidHeader("token too long"))
+        );
+        let result = PartialDecode::new(buf[..].into(), 0, &[VERSION])
+            .unwrap()
+            .finish(&key, 64);
+        assert_matches!(result, Ok((Packet { header: Header::Initial { ref token, .. }, .. }, _)) if token.len() == 64);
+    }
+
+    #[test]
+    fn supported_versions() {
+        const OLD_VERSION: u32 = 0xff00_000e;
+        let key = PacketNumberKey::AesCtr128([0; 16]);
+        let header = Header::Initial {
+            version: OLD_VERSION,
+            src_cid: ConnectionId::new(&[]),
+            dst_cid: ConnectionId::new(&[0xab; 8]),
+            token: Vec::new(),
+            number: PacketNumber::U8(0),
+        };
+        let mut buf = Vec::new();
+        let partial_encode = header.encode(&mut buf);
+        let header_len = buf.len();
+        buf.resize(header_len + 128, 0);
+        set_payload_length(&mut buf, header_len, 1);
+        partial_encode.finish(&mut buf, &key, header_len);
+        buf.resize(header_len + 128 + AEAD_TAG_SIZE, 0);
+
+        assert_matches!(
+            PartialDecode::new(buf[..].into(), 0, &[VERSION]).err(),
+            Some(PacketDecodeError::UnsupportedVersion { .. })
+        );
+        let result = PartialDecode::new(buf[..].into(), 0, &[VERSION, OLD_VERSION])
+            .unwrap()
+            .finish(&key, 64);
+        assert_matches!(
+            result,
+            Ok((
+                Packet {
+                    header: Header::Initial {
+                        version: OLD_VERSION,
+                        ..
+                    },
+                    ..
+                },
+                _
+            ))
+        );
+    }
 }
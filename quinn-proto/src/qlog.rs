@@ -0,0 +1,81 @@
+//! Connection event output in the qlog format, for analysis with external tooling
+//!
+//! The output is a single JSON document following draft-01 of the qlog schema. Events from every
+//! connection on an endpoint share one trace, distinguished by their `group_id`, and are
+//! timestamped in microseconds. The document is completed when the sink is dropped.
+
+use std::io::Write;
+use std::sync::Mutex;
+
+use serde_json::{self, Value};
+
+use connection::{ConnectionHandle, SpaceId};
+
+const HEADER: &str = concat!(
+    "{\"qlog_version\":\"draft-01\",\"traces\":[{",
+    "\"configuration\":{\"time_units\":\"us\"},",
+    "\"event_fields\":[\"time\",\"group_id\",\"category\",\"event\",\"data\"],",
+    "\"events\":["
+);
+const TRAILER: &str = "\n]}]}\n";
+
+/// Destination for the qlog events of every connection on an endpoint
+pub struct QlogSink {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    writer: Box<Write + Send>,
+    /// Whether any events have been written, and so must be separated from the next
+    nonempty: bool,
+}
+
+impl QlogSink {
+    pub fn new<W: Write + Send + 'static>(writer: W) -> Self {
+        let mut writer = Box::new(writer);
+        // Diagnostics must never disrupt the endpoint, so write errors are ignored
+        let _ = writer.write_all(HEADER.as_bytes());
+        Self {
+            inner: Mutex::new(Inner {
+                writer,
+                nonempty: false,
+            }),
+        }
+    }
+
+    /// Write a single event
+    pub(crate) fn emit(
+        &self,
+        time: u64,
+        conn: ConnectionHandle,
+        category: &str,
+        event: &str,
+        data: Value,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let separator = if inner.nonempty { ",\n" } else { "\n" };
+        inner.nonempty = true;
+        let _ = inner.writer.write_all(separator.as_bytes());
+        let _ = serde_json::to_writer(
+            &mut inner.writer,
+            &json!([time, conn.0, category, event, data]),
+        );
+    }
+}
+
+impl Drop for QlogSink {
+    fn drop(&mut self) {
+        let inner = self.inner.get_mut().unwrap();
+        let _ = inner.writer.write_all(TRAILER.as_bytes());
+        let _ = inner.writer.flush();
+    }
+}
+
+/// The qlog name for packets sent in `space`
+pub(crate) fn packet_type(space: SpaceId) -> &'static str {
+    match space {
+        SpaceId::Initial => "initial",
+        SpaceId::Handshake => "handshake",
+        SpaceId::Data => "1RTT",
+    }
+}
@@ -52,6 +52,51 @@ impl Stream {
     pub fn is_closed(&self) -> bool {
         self.send().map_or(true, |x| x.is_closed()) && self.recv().map_or(true, |x| x.is_closed())
     }
+
+    pub fn status(&self) -> StreamStatus {
+        let send = self.send().map(|x| x.state);
+        let recv = self.recv().map(|x| &x.state);
+        if send.map_or(false, |x| x.was_reset()) {
+            return StreamStatus::Reset;
+        }
+        match recv {
+            Some(&RecvState::ResetRecvd { .. }) => StreamStatus::Reset,
+            Some(&RecvState::DataRecvd { .. }) | Some(&RecvState::Closed) => {
+                StreamStatus::RecvFinished
+            }
+            _ if send.map_or(false, |x| x.is_finished()) => StreamStatus::SendFinished,
+            _ => StreamStatus::Open,
+        }
+    }
+}
+
+/// State of a stream, as reported by `Endpoint::stream_state`
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct StreamState {
+    /// Summary of the stream as a whole
+    pub status: StreamStatus,
+    /// State of the sending half, if the stream has one
+    pub send: Option<SendState>,
+    /// State of the receiving half, if the stream has one
+    pub recv: Option<RecvState>,
+    /// Number of bytes flow control currently permits writing
+    ///
+    /// 0 if the stream has no sending half or has been finished or reset. Writes within the budget
+    /// may still fail with `WriteError::CongestionLimited`.
+    pub send_budget: u64,
+}
+
+/// Summary of the state of a stream
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum StreamStatus {
+    /// Data may still be exchanged in every direction the stream supports
+    Open,
+    /// The peer has sent all of its data. Takes precedence over `SendFinished`.
+    RecvFinished,
+    /// We have finished sending data
+    SendFinished,
+    /// Transmission was abandoned by either endpoint
+    Reset,
 }
 
 impl From<Send> for Stream {
@@ -69,9 +114,16 @@ impl From<Recv> for Stream {
 pub struct Send {
     pub offset: u64,
     pub max_data: u64,
+    /// Number of the packet whose MAX_STREAM_DATA set `max_data`, or `None` if the transport
+    /// parameters did
+    pub max_data_pn: Option<u64>,
     pub state: SendState,
     /// Number of bytes sent but unacked
     pub bytes_in_flight: u64,
+    /// Flow control limit at which we last told the peer we were blocked
+    pub blocked_at: Option<u64>,
+    /// Data from streams with higher priority is sent first
+    pub priority: i32,
 }
 
 impl Send {
@@ -79,8 +131,11 @@ impl Send {
         Self {
             offset: 0,
             max_data: 0,
+            max_data_pn: None,
             state: SendState::Ready,
             bytes_in_flight: 0,
+            blocked_at: None,
+            priority: 0,
         }
     }
 
@@ -100,9 +155,9 @@ impl Send {
             _ => {}
         };
 
-        let budget = self.max_data - self.offset;
+        let budget = self.max_data.saturating_sub(self.offset);
         if budget == 0 {
-            Err(WriteError::Blocked)
+            Err(WriteError::FlowControlLimited)
         } else {
             Ok(budget)
         }
@@ -120,9 +175,15 @@ impl Send {
 
 #[derive(Debug, Fail, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub enum WriteError {
-    /// The peer is not able to accept additional data, or the connection is congested.
-    #[fail(display = "unable to accept further writes")]
-    Blocked,
+    /// The peer is not able to accept additional data on this stream or connection.
+    #[fail(display = "blocked by flow control")]
+    FlowControlLimited,
+    /// The connection is congested.
+    #[fail(display = "blocked by congestion control")]
+    CongestionLimited,
+    /// The connection is closing or closed, so no more data will be sent.
+    #[fail(display = "connection closed")]
+    ConnectionClosed,
     /// The peer is no longer accepting data on this stream.
     #[fail(display = "stopped by peer: error {}", error_code)]
     Stopped { error_code: u16 },
@@ -135,9 +196,13 @@ pub struct Recv {
     pub buffered: VecDeque<(Bytes, u64)>,
     /// Upper limit dictated by the peer
     pub max_data: u64,
+    /// Value of `max_data` most recently sent to the peer
+    pub advertised_max_data: u64,
     /// Whether any unordered reads have been performed, making this stream unusable for ordered
     /// reads
     pub unordered: bool,
+    /// Ranges returned by unordered reads, which may overlap due to retransmissions
+    pub delivered: RangeSet,
     pub assembler: Assembler,
     /// Whether the application is aware of this stream yet
     pub fresh: bool,
@@ -150,13 +215,35 @@ impl Recv {
             recvd: RangeSet::new(),
             buffered: VecDeque::new(),
             max_data,
+            advertised_max_data: max_data,
             unordered: false,
+            delivered: RangeSet::new(),
             assembler: Assembler::new(),
             fresh: true,
         }
     }
 
     pub fn read(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        self.read_ordered(|x| {
+            let n = x.read(buf);
+            (n, n)
+        })
+    }
+
+    /// Like `read`, but returns the data in a freshly allocated buffer of at most `max_len` bytes
+    pub fn read_bytes(&mut self, max_len: usize) -> Result<Bytes, ReadError> {
+        self.read_ordered(|x| {
+            let data = x.read_bytes(max_len);
+            let n = data.len();
+            (data, n)
+        })
+    }
+
+    /// Consume data with `f`, which returns its result and the number of bytes consumed
+    fn read_ordered<T, F>(&mut self, f: F) -> Result<T, ReadError>
+    where
+        F: FnOnce(&mut Assembler) -> (T, usize),
+    {
         assert!(
             !self.unordered,
             "cannot perform ordered reads following unordered reads on a stream"
@@ -167,12 +254,12 @@ impl Recv {
         }
 
         if !self.assembler.blocked() {
-            let n = self.assembler.read(buf);
+            let (result, n) = f(&mut self.assembler);
             // Only bother issuing stream credit if the peer wants to send more
             if self.receiving_unknown_size() {
                 self.max_data += n as u64;
             }
-            Ok(n)
+            Ok(result)
         } else {
             match self.state {
                 RecvState::ResetRecvd { error_code, .. } => {
@@ -189,15 +276,40 @@ impl Recv {
         }
     }
 
+    /// Like `read`, but leaves the data to be returned again by the next read
+    pub fn peek(&mut self, buf: &mut [u8]) -> Result<usize, ReadError> {
+        assert!(
+            !self.unordered,
+            "cannot perform ordered reads following unordered reads on a stream"
+        );
+
+        for (data, offset) in self.buffered.drain(..) {
+            self.assembler.insert(offset, &data);
+        }
+
+        if !self.assembler.blocked() {
+            Ok(self.assembler.peek(buf))
+        } else {
+            match self.state {
+                RecvState::ResetRecvd { error_code, .. } => Err(ReadError::Reset { error_code }),
+                RecvState::Closed => unreachable!(),
+                RecvState::Recv { .. } => Err(ReadError::Blocked),
+                RecvState::DataRecvd { .. } => Err(ReadError::Finished),
+            }
+        }
+    }
+
     pub fn read_unordered(&mut self) -> Result<(Bytes, u64), ReadError> {
         self.unordered = true;
         // TODO: Drain rs.assembler to handle ordered-then-unordered reads reliably
 
         // Return data we already have buffered, regardless of state
         if let Some(x) = self.buffered.pop_front() {
+            let prev = self.delivered_offset();
+            self.delivered.insert(x.1..x.1 + x.0.len() as u64);
             // Only bother issuing stream credit if the peer wants to send more
             if self.receiving_unknown_size() {
-                self.max_data += x.0.len() as u64;
+                self.max_data += self.delivered_offset() - prev;
             }
             Ok(x)
         } else {
@@ -244,6 +356,28 @@ impl Recv {
         self.buffered.push_back((data, offset));
     }
 
+    /// Length of the contiguous prefix of the stream returned by unordered reads
+    ///
+    /// Only this prefix is considered consumed for flow control purposes, since bytes beyond a gap
+    /// may yet be delivered again.
+    pub fn delivered_offset(&self) -> u64 {
+        match self.delivered.iter().next() {
+            Some(ref x) if x.start == 0 => x.end,
+            _ => 0,
+        }
+    }
+
+    /// Amount of the stream's known final size not yet consumed by the application
+    pub fn unconsumed(&self) -> u64 {
+        let consumed = if self.unordered {
+            self.delivered_offset()
+        } else {
+            self.assembler.offset
+        };
+        self.final_offset()
+            .map_or(0, |x| x.saturating_sub(consumed))
+    }
+
     /// Offset after the largest byte received
     pub fn limit(&self) -> u64 {
         self.recvd.max().map_or(0, |x| x + 1)
@@ -272,12 +406,47 @@ pub enum ReadError {
     Finished,
 }
 
+/// The stream doesn't support the requested operation, e.g. finishing a receive-only stream
+#[derive(Debug, Fail, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[fail(display = "invalid stream for this operation")]
+pub struct InvalidStreamError;
+
+#[derive(Debug, Fail, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum FinishError {
+    /// The stream has already been finished or reset. Finishing it again has no effect.
+    #[fail(display = "already finished")]
+    Finished,
+    /// The stream has no outgoing channel.
+    #[fail(display = "invalid stream for this operation")]
+    InvalidStream,
+}
+
+#[derive(Debug, Fail, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
+pub enum OpenError {
+    /// The peer does not currently permit any more streams of this directionality.
+    ///
+    /// The peer is asked for more, and `Event::StreamAvailable` is raised once it grants them.
+    #[fail(display = "stream limit reached")]
+    LimitReached,
+    /// The connection is closing or closed.
+    #[fail(display = "connection closed")]
+    ConnectionClosed,
+}
+
+/// State of the sending half of a stream, as reported by `Endpoint::stream_state`
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum SendState {
+    /// More data may be written
     Ready,
+    /// The stream has been finished, but not all data has been acknowledged
     DataSent,
+    /// The stream has been reset, but the peer hasn't acknowledged the reset
+    ///
+    /// `stop_reason` holds the error code of a STOP_SENDING not yet reported to the application.
     ResetSent { stop_reason: Option<u16> },
+    /// All data has been acknowledged by the peer
     DataRecvd,
+    /// The peer has acknowledged the reset
     ResetRecvd { stop_reason: Option<u16> },
 }
 
@@ -289,13 +458,26 @@ impl SendState {
             _ => false,
         }
     }
+
+    /// Whether the application is done writing to the stream, whether by finishing or resetting it
+    pub fn is_finished(self) -> bool {
+        match self {
+            SendState::Ready => false,
+            _ => true,
+        }
+    }
 }
 
+/// State of the receiving half of a stream, as reported by `Endpoint::stream_state`
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum RecvState {
+    /// More data may arrive. `size` is the final size of the stream, if the peer has finished it.
     Recv { size: Option<u64> },
+    /// All data has arrived, but not necessarily been read
     DataRecvd { size: u64 },
+    /// The peer abandoned the stream with `error_code`, which has yet to be read
     ResetRecvd { size: u64, error_code: u16 },
+    /// The application has read the end of the stream or its reset
     Closed,
 }
 
@@ -338,20 +520,40 @@ impl Assembler {
         self.written.len()
     }
 
+    /// Copy out leading contiguous data without consuming it
+    pub fn peek(&self, buf: &mut [u8]) -> usize {
+        let (a, b) = self.data.as_slices();
+        let available = self.prefix_len();
+        let a_len = a.len().min(available);
+        let (a, b) = (&a[0..a_len], &b[0..(available - a_len)]);
+        let a_n = a.len().min(buf.len());
+        buf[0..a_n].copy_from_slice(&a[0..a_n]);
+        let b_n = b.len().min(buf.len().saturating_sub(a.len()));
+        buf[a_n..(a_n + b_n)].copy_from_slice(&b[0..b_n]);
+        a_n + b_n
+    }
+
     pub fn read(&mut self, buf: &mut [u8]) -> usize {
-        let n;
+        let n = self.peek(buf);
+        self.consume(n);
+        n
+    }
+
+    /// Take up to `max_len` bytes of leading contiguous data, without zero-filling a buffer first
+    pub fn read_bytes(&mut self, max_len: usize) -> Bytes {
+        let n = self.prefix_len().min(max_len);
+        let mut buf = Vec::with_capacity(n);
         {
             let (a, b) = self.data.as_slices();
-            let available = self.prefix_len();
-            let a_len = a.len().min(available);
-            let (a, b) = (&a[0..a_len], &b[0..(available - a_len)]);
-            let a_n = a.len().min(buf.len());
-            buf[0..a_n].copy_from_slice(&a[0..a_n]);
-            let b_n = b.len().min(buf.len().saturating_sub(a.len()));
-            buf[a_n..(a_n + b_n)].copy_from_slice(&b[0..b_n]);
-            n = a_n + b_n;
+            let a_n = a.len().min(n);
+            buf.extend_from_slice(&a[0..a_n]);
+            buf.extend_from_slice(&b[0..(n - a_n)]);
         }
+        self.consume(n);
+        buf.into()
+    }
 
+    fn consume(&mut self, n: usize) {
         self.offset += n as u64;
         self.data.drain(0..n);
         let q = n / 8;
@@ -359,8 +561,6 @@ impl Assembler {
         let carry = (self.written_offset as usize + r) / 8;
         self.written.drain(0..(q + carry));
         self.written_offset = (self.written_offset as usize + r - carry * 8) as u8;
-
-        n
     }
 
     #[cfg(test)]
@@ -404,6 +604,26 @@ impl Assembler {
 mod test {
     use super::*;
 
+    #[test]
+    fn unordered_credit() {
+        let mut x = Recv::new(100);
+        x.buffer((&b"12345"[..]).into(), 0);
+        x.buffer((&b"4567"[..]).into(), 3);
+        x.buffer((&b"34"[..]).into(), 2);
+        x.buffer((&b"9"[..]).into(), 8);
+        assert_matches!(x.read_unordered(), Ok((_, 0)));
+        assert_eq!(x.max_data, 105);
+        assert_matches!(x.read_unordered(), Ok((_, 3)));
+        assert_eq!(x.max_data, 107);
+        // Wholly duplicate data earns no credit
+        assert_matches!(x.read_unordered(), Ok((_, 2)));
+        assert_eq!(x.max_data, 107);
+        // Nor does data beyond a gap
+        assert_matches!(x.read_unordered(), Ok((_, 8)));
+        assert_eq!(x.max_data, 107);
+        assert_eq!(x.delivered_offset(), 7);
+    }
+
     #[test]
     fn assemble_ordered() {
         let mut x = Assembler::new();
@@ -418,6 +638,18 @@ mod test {
         assert_matches!(x.next(), None);
     }
 
+    #[test]
+    fn assemble_bytes() {
+        let mut x = Assembler::new();
+        x.insert(0, (&b"123"[..]).into());
+        x.insert(5, (&b"678"[..]).into());
+        assert_eq!(&x.read_bytes(2)[..], b"12");
+        assert_eq!(&x.read_bytes(10)[..], b"3");
+        assert!(x.read_bytes(10).is_empty());
+        x.insert(3, (&b"45"[..]).into());
+        assert_eq!(&x.read_bytes(10)[..], b"45678");
+    }
+
     #[test]
     fn assemble_unordered() {
         let mut x = Assembler::new();
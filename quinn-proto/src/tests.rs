@@ -1,14 +1,15 @@
 use std::collections::VecDeque;
 use std::io::{self, Read, Write};
-use std::net::{Ipv6Addr, SocketAddrV6, UdpSocket};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
 use std::ops::RangeFrom;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 use std::time::Duration;
 use std::{env, fmt, fs, str};
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
-use rand::RngCore;
+use rand::{rngs::StdRng, RngCore, SeedableRng};
 use ring::digest;
 use ring::hmac::SigningKey;
 use rustls::{internal::pemfile, KeyLogFile, ProtocolVersion};
@@ -16,6 +17,10 @@ use slog::{Drain, Logger, KV};
 use untrusted::Input;
 
 use super::*;
+use coding::BufMutExt;
+use connection::{PathChallenge, SpaceId};
+use endpoint::MAX_RESET_LIMITED_ADDRESSES;
+use packet::{Header, PacketNumber, PartialDecode};
 
 struct TestDrain;
 
@@ -125,6 +130,16 @@ fn client_config() -> Arc<ClientConfig> {
 
 impl Pair {
     fn new(server_config: Config, client_config: Config, listen_keys: ListenKeys) -> Self {
+        let localhost = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
+        Self::with_ip(server_config, client_config, listen_keys, localhost.into())
+    }
+
+    fn with_ip(
+        server_config: Config,
+        client_config: Config,
+        listen_keys: ListenKeys,
+        localhost: IpAddr,
+    ) -> Self {
         let log = logger();
         let server = Endpoint::new(
             log.new(o!("side" => "Server")),
@@ -133,19 +148,8 @@ impl Pair {
         ).unwrap();
         let client = Endpoint::new(log.new(o!("side" => "Client")), client_config, None).unwrap();
 
-        let localhost = Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1);
-        let server_addr = SocketAddrV6::new(
-            localhost,
-            SERVER_PORTS.lock().unwrap().next().unwrap(),
-            0,
-            0,
-        );
-        let client_addr = SocketAddrV6::new(
-            localhost,
-            CLIENT_PORTS.lock().unwrap().next().unwrap(),
-            0,
-            0,
-        );
+        let server_addr = SocketAddr::new(localhost, SERVER_PORTS.lock().unwrap().next().unwrap());
+        let client_addr = SocketAddr::new(localhost, CLIENT_PORTS.lock().unwrap().next().unwrap());
         Self {
             log,
             server: TestEndpoint::new(Side::Server, server, server_addr),
@@ -186,7 +190,15 @@ impl Pair {
     fn drive_client(&mut self) {
         trace!(self.log, "client running");
         self.client.drive(&self.log, self.time, self.server.addr);
-        for packet in self.client.outbound.drain(..) {
+        for (destination, packet) in self.client.outbound.drain(..) {
+            if destination != self.server.addr {
+                trace!(
+                    self.log,
+                    "dropping packet for unknown address {addr}",
+                    addr = destination
+                );
+                continue;
+            }
             if let Some(ref socket) = self.client.socket {
                 socket.send_to(&packet, self.server.addr).unwrap();
             }
@@ -199,7 +211,15 @@ impl Pair {
     fn drive_server(&mut self) {
         trace!(self.log, "server running");
         self.server.drive(&self.log, self.time, self.client.addr);
-        for packet in self.server.outbound.drain(..) {
+        for (destination, packet) in self.server.outbound.drain(..) {
+            if destination != self.client.addr {
+                trace!(
+                    self.log,
+                    "dropping packet for unknown address {addr}",
+                    addr = destination
+                );
+                continue;
+            }
             if let Some(ref socket) = self.server.socket {
                 socket.send_to(&packet, self.client.addr).unwrap();
             }
@@ -213,7 +233,7 @@ impl Pair {
         info!(self.log, "connecting");
         let client_conn = self
             .client
-            .connect(self.server.addr, &client_config(), "localhost")
+            .connect(self.time, self.server.addr, &client_config(), "localhost")
             .unwrap();
         self.drive();
         let server_conn = if let Some(c) = self.server.accept() {
@@ -229,18 +249,20 @@ impl Pair {
 struct TestEndpoint {
     side: Side,
     endpoint: Endpoint,
-    addr: SocketAddrV6,
+    addr: SocketAddr,
     socket: Option<UdpSocket>,
     idle: u64,
     loss: u64,
     close: u64,
+    delayed_ack: u64,
+    keep_alive: u64,
     conn: Option<ConnectionHandle>,
-    outbound: VecDeque<Box<[u8]>>,
+    outbound: VecDeque<(SocketAddr, Box<[u8]>)>,
     inbound: VecDeque<(u64, Box<[u8]>)>,
 }
 
 impl TestEndpoint {
-    fn new(side: Side, endpoint: Endpoint, addr: SocketAddrV6) -> Self {
+    fn new(side: Side, endpoint: Endpoint, addr: SocketAddr) -> Self {
         let socket = if env::var_os("SSLKEYLOGFILE").is_some() {
             let socket = UdpSocket::bind(addr).expect("failed to bind UDP socket");
             socket
@@ -258,13 +280,15 @@ impl TestEndpoint {
             idle: u64::max_value(),
             loss: u64::max_value(),
             close: u64::max_value(),
+            delayed_ack: u64::max_value(),
+            keep_alive: u64::max_value(),
             conn: None,
             outbound: VecDeque::new(),
             inbound: VecDeque::new(),
         }
     }
 
-    fn drive(&mut self, log: &Logger, now: u64, remote: SocketAddrV6) {
+    fn drive(&mut self, log: &Logger, now: u64, remote: SocketAddr) {
         if let Some(ref socket) = self.socket {
             loop {
                 let mut buf = [0; 8192];
@@ -304,6 +328,26 @@ impl TestEndpoint {
                 self.close = u64::max_value();
                 self.endpoint.timeout(now, conn, Timer::Close);
             }
+            if self.delayed_ack <= now {
+                trace!(
+                    log,
+                    "{side:?} {timer:?} timeout",
+                    side = self.side,
+                    timer = Timer::DelayedAck
+                );
+                self.delayed_ack = u64::max_value();
+                self.endpoint.timeout(now, conn, Timer::DelayedAck);
+            }
+            if self.keep_alive <= now {
+                trace!(
+                    log,
+                    "{side:?} {timer:?} timeout",
+                    side = self.side,
+                    timer = Timer::KeepAlive
+                );
+                self.keep_alive = u64::max_value();
+                self.endpoint.timeout(now, conn, Timer::KeepAlive);
+            }
         }
         while self.inbound.front().map_or(false, |x| x.0 <= now) {
             self.endpoint.handle(
@@ -314,8 +358,11 @@ impl TestEndpoint {
         }
         while let Some(x) = self.endpoint.poll_io(now) {
             match x {
-                Io::Transmit { packet, .. } => {
-                    self.outbound.push_back(packet);
+                Io::Transmit {
+                    destination,
+                    packet,
+                } => {
+                    self.outbound.push_back((destination, packet));
                 }
                 Io::TimerStart {
                     timer,
@@ -340,6 +387,12 @@ impl TestEndpoint {
                         Timer::Close => {
                             self.close = time;
                         }
+                        Timer::DelayedAck => {
+                            self.delayed_ack = time;
+                        }
+                        Timer::KeepAlive => {
+                            self.keep_alive = time;
+                        }
                     }
                 }
                 Io::TimerStop { timer, .. } => {
@@ -359,6 +412,12 @@ impl TestEndpoint {
                         Timer::Close => {
                             self.close = u64::max_value();
                         }
+                        Timer::DelayedAck => {
+                            self.delayed_ack = u64::max_value();
+                        }
+                        Timer::KeepAlive => {
+                            self.keep_alive = u64::max_value();
+                        }
                     }
                 }
             }
@@ -369,6 +428,7 @@ impl TestEndpoint {
         self.idle
             .min(self.loss)
             .min(self.close)
+            .min(self.delayed_ack)
             .min(self.inbound.front().map_or(u64::max_value(), |x| x.0))
     }
 }
@@ -386,10 +446,151 @@ impl ::std::ops::DerefMut for TestEndpoint {
     }
 }
 
+#[test]
+fn ipv4() {
+    let mut server_config = server_config();
+    server_config.max_remote_uni_streams = 1;
+    let mut pair = Pair::with_ip(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+        Ipv4Addr::new(127, 0, 0, 1).into(),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        *pair.client.get_remote_address(client_conn),
+        pair.server.addr
+    );
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+
+    const MSG: &[u8] = b"hello";
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn zero_length_cid() {
+    let log = logger();
+    let mut client = Endpoint::new(
+        log.new(o!("peer" => "client")),
+        Config {
+            local_cid_len: 0,
+            ..Config::default()
+        },
+        None,
+    ).unwrap();
+    let mut conns = Vec::new();
+    for port in 4433..4436 {
+        let server = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), port);
+        let conn = client
+            .connect(0, server, &client_config(), "localhost")
+            .unwrap();
+        assert!(client.connections[conn.0].loc_cid.is_empty());
+        assert!(!conns.contains(&conn));
+        conns.push(conn);
+    }
+}
+
+#[test]
+fn connection_id_generator() {
+    struct Prefixed(u32);
+    impl ConnectionIdGenerator for Prefixed {
+        fn generate(&mut self) -> ConnectionId {
+            let mut bytes = [0xab, 0xcd, 0, 0, 0, 0, 0, 0];
+            BigEndian::write_u32(&mut bytes[2..6], self.0);
+            self.0 += 1;
+            ConnectionId::new(&bytes)
+        }
+        fn validate(&self, cid: &ConnectionId) -> bool {
+            cid[..2] == [0xab, 0xcd]
+        }
+    }
+
+    let mut pair = Pair::default();
+    pair.server
+        .set_connection_id_generator(Box::new(Prefixed(0)));
+    let (client_conn, server_conn) = pair.connect();
+    let cid = pair.server.get_local_id(server_conn);
+    assert_eq!(cid[..2], [0xab, 0xcd]);
+    assert_eq!(pair.client.get_remote_id(client_conn), cid);
+}
+
+#[test]
+fn connection_id_generator_exhausted() {
+    struct Constant;
+    impl ConnectionIdGenerator for Constant {
+        fn generate(&mut self) -> ConnectionId {
+            ConnectionId::new(&[0xab; 8])
+        }
+        fn validate(&self, cid: &ConnectionId) -> bool {
+            cid[..] == [0xab; 8]
+        }
+    }
+
+    let mut pair = Pair::default();
+    pair.client.set_connection_id_generator(Box::new(Constant));
+    pair.client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    // The only ID the generator offers is taken
+    assert_matches!(
+        pair.client.connect(pair.time, pair.server.addr, &client_config(), "localhost"),
+        Err(ConnectError::ConnectionIdsExhausted)
+    );
+}
+
+#[test]
+fn connection_id_generator_wrong_length() {
+    struct Short;
+    impl ConnectionIdGenerator for Short {
+        fn generate(&mut self) -> ConnectionId {
+            ConnectionId::new(&[0xab; 4])
+        }
+        fn validate(&self, cid: &ConnectionId) -> bool {
+            cid[..] == [0xab; 4]
+        }
+    }
+
+    let mut pair = Pair::default();
+    pair.client.set_connection_id_generator(Box::new(Short));
+    assert_matches!(
+        pair.client.connect(pair.time, pair.server.addr, &client_config(), "localhost"),
+        Err(ConnectError::ConnectionIdsExhausted)
+    );
+    assert!(pair.client.connections.is_empty());
+}
+
+#[test]
+fn seeded_rng() {
+    let log = logger();
+    let server = SocketAddr::new(Ipv6Addr::LOCALHOST.into(), 4433);
+    let cids = |seed| {
+        let mut client = Endpoint::with_rng(
+            log.new(o!("peer" => "client")),
+            Config::default(),
+            None,
+            Box::new(StdRng::from_seed(seed)),
+        ).unwrap();
+        let conn = client
+            .connect(0, server, &client_config(), "localhost")
+            .unwrap();
+        let conn = &client.connections[conn.0];
+        (conn.loc_cid, conn.rem_cid)
+    };
+    assert_eq!(cids([1; 32]), cids([1; 32]));
+    assert_ne!(cids([1; 32]), cids([2; 32]));
+}
+
 #[test]
 fn version_negotiate() {
     let log = logger();
-    let client_addr = "[::2]:7890".parse().unwrap();
+    let client_addr: SocketAddr = "[::2]:7890".parse().unwrap();
     let config = server_config();
     let mut server = Endpoint::new(
         log.new(o!("peer" => "server")),
@@ -417,11 +618,176 @@ fn version_negotiate() {
                 .chunks(4)
                 .any(|x| BigEndian::read_u32(x) == VERSION)
         );
+        // Along with a reserved version the client must ignore
+        assert!(
+            packet[14..]
+                .chunks(4)
+                .any(|x| BigEndian::read_u32(x) & 0x0f0f_0f0f == 0x0a0a_0a0a)
+        );
     }
     assert_matches!(server.poll_io(0), None);
     assert_matches!(server.poll(), None);
 }
 
+#[test]
+fn version_negotiate_fallback() {
+    const OLD_VERSION: u32 = 0xff00_000e;
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![OLD_VERSION],
+            ..server_config()
+        },
+        Config {
+            supported_versions: vec![VERSION, OLD_VERSION],
+            ..Config::default()
+        },
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(pair.client.connections[client_conn.0].version, OLD_VERSION);
+    assert_eq!(pair.server.connections[server_conn.0].version, OLD_VERSION);
+}
+
+#[test]
+fn version_negotiate_new_initial_salt() {
+    // Initial keys for the first attempt and for the fallback are derived from different salts
+    const DRAFT17: u32 = 0xff00_0011;
+    const OLD_VERSION: u32 = 0xff00_000e;
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![OLD_VERSION],
+            ..server_config()
+        },
+        Config {
+            supported_versions: vec![DRAFT17, OLD_VERSION],
+            ..Config::default()
+        },
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(pair.client.connections[client_conn.0].version, OLD_VERSION);
+    assert_eq!(pair.server.connections[server_conn.0].version, OLD_VERSION);
+
+    const MSG: &[u8] = b"hello";
+    let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s).unwrap();
+    pair.drive();
+    let mut buf = [0; 16];
+    assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(MSG.len()));
+    assert_eq!(&buf[..MSG.len()], MSG);
+    pair.server.write(server_conn, s, MSG).unwrap();
+    pair.drive();
+    assert_eq!(pair.client.read(client_conn, s, &mut buf), Ok(MSG.len()));
+    assert_eq!(&buf[..MSG.len()], MSG);
+}
+
+#[test]
+fn version_negotiate_client_preference() {
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![0xff00_000e, VERSION],
+            ..server_config()
+        },
+        Config {
+            supported_versions: vec![0xff00_0011, VERSION, 0xff00_000e],
+            ..Config::default()
+        },
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    // The client's order decides among the versions both sides support
+    assert_eq!(pair.client.connections[client_conn.0].version, VERSION);
+    assert_eq!(pair.server.connections[server_conn.0].version, VERSION);
+}
+
+#[test]
+fn version_negotiate_no_common_version() {
+    let mut pair = Pair::new(
+        Config {
+            supported_versions: vec![0xff00_000e],
+            ..server_config()
+        },
+        Config::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let client_conn = pair
+        .client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::VersionMismatch })) if conn == client_conn);
+}
+
+#[test]
+fn grease() {
+    let mut pair = Pair::new(
+        Config {
+            grease: true,
+            ..server_config()
+        },
+        Config {
+            grease: true,
+            ..Config::default()
+        },
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    let client = &pair.client.connections[client_conn.0];
+    let server = &pair.server.connections[server_conn.0];
+
+    // The client's parameters follow its initial version
+    let from_client = server.tls.get_quic_transport_parameters().unwrap();
+    assert!(grease_param(&from_client[4..]).is_some());
+    // The server's follow the negotiated version and its supported versions
+    let from_server = client.tls.get_quic_transport_parameters().unwrap();
+    let supported_len = from_server[4] as usize;
+    assert!(
+        from_server[5..5 + supported_len]
+            .chunks(4)
+            .any(|x| BigEndian::read_u32(x) & 0x0f0f_0f0f == 0x0a0a_0a0a)
+    );
+    assert!(grease_param(&from_server[5 + supported_len..]).is_some());
+
+    // Both sides ignored what they didn't understand
+    assert_eq!(client.params.grease, None);
+    assert_eq!(server.params.grease, None);
+}
+
+#[test]
+fn no_grease_by_default() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let client = &pair.client.connections[client_conn.0];
+    let server = &pair.server.connections[server_conn.0];
+
+    let from_client = server.tls.get_quic_transport_parameters().unwrap();
+    assert_eq!(grease_param(&from_client[4..]), None);
+    let from_server = client.tls.get_quic_transport_parameters().unwrap();
+    let supported_len = from_server[4] as usize;
+    assert!(
+        !from_server[5..5 + supported_len]
+            .chunks(4)
+            .any(|x| BigEndian::read_u32(x) & 0x0f0f_0f0f == 0x0a0a_0a0a)
+    );
+    assert_eq!(grease_param(&from_server[5 + supported_len..]), None);
+}
+
+/// Find a reserved parameter of the form `0x?a?a` in encoded transport parameters
+fn grease_param(params: &[u8]) -> Option<u16> {
+    // Skip the length
+    let mut params = &params[2..];
+    while !params.is_empty() {
+        let id = BigEndian::read_u16(params);
+        let len = BigEndian::read_u16(&params[2..]) as usize;
+        if id & 0x0f0f == 0x0a0a {
+            return Some(id);
+        }
+        params = &params[4 + len..];
+    }
+    None
+}
+
 #[test]
 fn lifecycle() {
     let mut pair = Pair::default();
@@ -439,19 +805,104 @@ fn lifecycle() {
     assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionDrained)) if conn == client_conn);
 }
 
-/*
+#[test]
+fn handshake_timeout() {
+    let mut pair = Pair::default();
+    // A server that isn't listening silently drops every connection attempt
+    pair.server.endpoint = Endpoint::new(
+        pair.log.new(o!("peer" => "server")),
+        Config::default(),
+        None,
+    ).unwrap();
+    let client_conn = pair
+        .client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::TimedOut })) if conn == client_conn);
+    assert_eq!(
+        pair.client.connections[client_conn.0].handshake_count,
+        Config::default().max_handshake_retransmits
+    );
+}
+
+#[test]
+fn reordered_1rtt_during_handshake() {
+    let mut pair = Pair::default();
+    let client_conn = pair
+        .client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    // Run the handshake until the client is established, but the server has yet to see its final
+    // handshake packet
+    loop {
+        pair.drive_client();
+        if pair.client.connections[client_conn.0].crypto.is_some() {
+            break;
+        }
+        pair.drive_server();
+    }
+    let handshake_packets = pair.server.inbound.len();
+    assert!(handshake_packets > 0);
+
+    const MSG: &[u8] = b"hello";
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive_client();
+    // Deliver the 1-RTT packets ahead of the handshake packets
+    let mut early = pair.server.inbound.split_off(handshake_packets);
+    early.append(&mut pair.server.inbound);
+    pair.server.inbound = early;
+
+    pair.drive_server();
+    let server_conn = pair.server.accept().expect("server didn't connect");
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn initial_cid_forgotten() {
+    let mut pair = Pair::default();
+    pair.client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.time += pair.latency;
+    pair.drive_server();
+    assert_eq!(pair.server.connection_ids_initial.len(), 1);
+    pair.drive();
+    assert_matches!(pair.server.accept(), Some(_));
+    assert!(pair.server.connection_ids_initial.is_empty());
+    assert!(pair.client.connection_ids_initial.is_empty());
+}
+
 #[test]
 fn stateless_retry() {
+    let server_config = Config {
+        use_stateless_retry: true,
+        ..server_config()
+    };
     let mut pair = Pair::new(
-        Config {
-            use_stateless_retry: true,
-            ..Config::default()
-        },
-        Config::default(),
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
     );
-    pair.connect();
+    let client_conn = pair
+        .client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    // The server answers with a Retry rather than committing any state
+    assert!(pair.server.connections.is_empty());
+    assert_eq!(pair.client.inbound.len(), 1);
+    assert_eq!(pair.client.inbound[0].1[0], 0xfe);
+
+    pair.drive();
+    let server_conn = pair.server.accept().expect("server didn't connect");
+    assert!(pair.server.connections[server_conn.0].address_validated);
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
 }
-*/
 
 #[test]
 fn stateless_reset() {
@@ -485,241 +936,3469 @@ fn stateless_reset() {
 }
 
 #[test]
-fn finish_stream() {
-    let mut pair = Pair::default();
-    let (client_conn, server_conn) = pair.connect();
+fn stateless_reset_limit() {
+    let log = logger();
+    let mut server = Endpoint::new(
+        log.new(o!("peer" => "server")),
+        Config {
+            stateless_reset_burst: 3,
+            stateless_reset_rate: 1,
+            global_stateless_reset_rate: 5,
+            ..server_config()
+        },
+        Some(ListenKeys::new(&mut rand::thread_rng())),
+    ).unwrap();
 
-    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    // Count the resets sent in response to packets for unknown connections
+    let resets = |server: &mut Endpoint, now: u64, remote: SocketAddr, count: usize| {
+        let mut sent = 0;
+        for _ in 0..count {
+            let mut buf = Vec::new();
+            Header::Short {
+                dst_cid: ConnectionId::random(&mut rand::thread_rng(), 8),
+                number: PacketNumber::U8(0),
+                key_phase: false,
+                spin: false,
+            }.encode(&mut buf);
+            buf.resize(64, 0);
+            server.handle(now, remote, buf[..].into());
+            while let Some(io) = server.poll_io(now) {
+                if let Io::Transmit { .. } = io {
+                    sent += 1;
+                }
+            }
+        }
+        sent
+    };
 
-    const MSG: &[u8] = b"hello";
-    pair.client.write(client_conn, s, MSG).unwrap();
-    pair.client.finish(client_conn, s);
-    pair.drive();
+    let first: SocketAddr = "[::2]:7890".parse().unwrap();
+    let second: SocketAddr = "[::2]:7891".parse().unwrap();
+    let third: SocketAddr = "[::2]:7892".parse().unwrap();
+    // Each address may burst, subject to the global limit
+    assert_eq!(resets(&mut server, 0, first, 10), 3);
+    assert_eq!(resets(&mut server, 0, second, 10), 2);
+    assert_eq!(resets(&mut server, 0, third, 10), 0);
+    // Allowances recover over time
+    assert_eq!(resets(&mut server, 1_000_000, first, 10), 1);
+    assert_eq!(resets(&mut server, 1_000_000, third, 10), 3);
+}
 
-    assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_conn && stream == s);
-    assert_matches!(pair.client.poll(), None);
-    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
-    assert_matches!(pair.server.poll(), None);
-    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
-    assert_matches!(
-        pair.server.read_unordered(server_conn, s),
-        Err(ReadError::Finished)
-    );
+#[test]
+fn stateless_reset_limit_addresses() {
+    let log = logger();
+    let mut server = Endpoint::new(
+        log.new(o!("peer" => "server")),
+        Config {
+            global_stateless_reset_rate: 2 * MAX_RESET_LIMITED_ADDRESSES as u32,
+            ..server_config()
+        },
+        Some(ListenKeys::new(&mut rand::thread_rng())),
+    ).unwrap();
+
+    let addr =
+        |i: usize| SocketAddr::new(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 2, i as u16).into(), 7890);
+    for i in 0..MAX_RESET_LIMITED_ADDRESSES + 2 {
+        let mut buf = Vec::new();
+        Header::Short {
+            dst_cid: ConnectionId::random(&mut rand::thread_rng(), 8),
+            number: PacketNumber::U8(0),
+            key_phase: false,
+            spin: false,
+        }.encode(&mut buf);
+        buf.resize(64, 0);
+        // None of the allowances recover in the meantime
+        server.handle(i as u64, addr(i), buf[..].into());
+        while server.poll_io(i as u64).is_some() {}
+    }
+    assert_eq!(server.reset_limits.len(), MAX_RESET_LIMITED_ADDRESSES);
+    // The least recently reset addresses were evicted
+    assert!(!server.reset_limits.contains_key(&addr(0)));
+    assert!(!server.reset_limits.contains_key(&addr(1)));
+    assert!(server.reset_limits.contains_key(&addr(2)));
+    assert!(server
+        .reset_limits
+        .contains_key(&addr(MAX_RESET_LIMITED_ADDRESSES + 1)));
 }
 
 #[test]
-fn reset_stream() {
+fn stateless_reset_alternate_cid() {
     let mut pair = Pair::default();
     let (client_conn, server_conn) = pair.connect();
 
-    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
-
-    const MSG: &[u8] = b"hello";
-    pair.client.write(client_conn, s, MSG).unwrap();
+    let mut reset_token = [0; RESET_TOKEN_SIZE];
+    rand::thread_rng().fill_bytes(&mut reset_token);
+    let mut payload = Vec::new();
+    payload.write(frame::Type::NEW_CONNECTION_ID);
+    payload.write_var(1);
+    payload.write(8u8);
+    payload.extend_from_slice(&ConnectionId::random(&mut rand::thread_rng(), 8));
+    payload.extend_from_slice(&reset_token);
+    let packet = forge_packet(&mut pair.server.connections[server_conn.0], &payload);
+    pair.client.inbound.push_back((pair.time, packet));
     pair.drive();
 
-    info!(pair.log, "resetting stream");
-    const ERROR: u16 = 42;
-    pair.client.reset(client_conn, s, ERROR);
+    // A reset carrying the token for the alternate CID, rather than the primary one
+    let mut buf = Vec::new();
+    Header::Short {
+        dst_cid: pair.server.connections[server_conn.0].rem_cid,
+        number: PacketNumber::U8(0),
+        key_phase: false,
+        spin: false,
+    }.encode(&mut buf);
+    let start = buf.len();
+    buf.resize(start + 32, 0);
+    rand::thread_rng().fill_bytes(&mut buf[start..]);
+    buf.extend_from_slice(&reset_token);
+    info!(pair.log, "resetting");
+    pair.client.inbound.push_back((pair.time, buf.into()));
     pair.drive();
-
-    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
-    assert_matches!(pair.server.poll(), None);
-    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
-    assert_matches!(
-        pair.server.read_unordered(server_conn, s),
-        Err(ReadError::Reset { error_code: ERROR })
-    );
-    assert_matches!(pair.client.poll(), None);
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionLost { reason: ConnectionError::Reset })) if conn == client_conn);
 }
 
 #[test]
-fn stop_stream() {
+fn wire_bytes() {
     let mut pair = Pair::default();
     let (client_conn, server_conn) = pair.connect();
 
     let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
-    const MSG: &[u8] = b"hello";
-    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.client.finish(client_conn, s).unwrap();
     pair.drive();
 
-    info!(pair.log, "stopping stream");
-    const ERROR: u16 = 42;
-    pair.server.stop_sending(server_conn, s, ERROR);
+    let (client_sent, client_recvd) = pair.client.wire_bytes(client_conn);
+    let (server_sent, server_recvd) = pair.server.wire_bytes(server_conn);
+    assert!(client_sent >= MIN_INITIAL_SIZE as u64);
+    assert_eq!(client_sent, server_recvd);
+    assert_eq!(server_sent, client_recvd);
+}
+
+#[test]
+fn connection_stats() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[0; 3000]), Ok(3000));
+    pair.drive_client();
+    // Lose the packet carrying the start of the stream
+    pair.server.inbound.pop_front();
+    loop {
+        pair.drive_server();
+        if !pair.client.inbound.is_empty() {
+            break;
+        }
+        pair.time = pair.server.next_wakeup();
+    }
     pair.drive();
 
-    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
-    assert_matches!(pair.server.poll(), None);
-    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
-    assert_matches!(
-        pair.server.read_unordered(server_conn, s),
-        Err(ReadError::Reset { error_code: ERROR })
+    let stats = pair.client.get_stats(client_conn);
+    assert_eq!(stats.data_sent, 3000);
+    assert!(stats.lost_packets >= 1);
+    assert_eq!(
+        stats.bytes_in_flight,
+        pair.client.get_bytes_in_flight(client_conn)
     );
-
-    assert_matches!(
-        pair.client.write(client_conn, s, b"foo"),
-        Err(WriteError::Stopped { error_code: ERROR })
+    assert_eq!(
+        (stats.wire_bytes_sent, stats.wire_bytes_recvd),
+        pair.client.wire_bytes(client_conn)
     );
+    assert_eq!(pair.server.get_stats(server_conn).data_recvd, 3000);
 }
 
 #[test]
-fn reject_self_signed_cert() {
-    let mut client_config = ClientConfig::new();
-    client_config.versions = vec![ProtocolVersion::TLSv1_3];
-    client_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
-
-    let mut pair = Pair::default();
-    info!(pair.log, "connecting");
+fn transport_parameters() {
+    let server_config = Config {
+        max_remote_uni_streams: 3,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
     let client_conn = pair
         .client
-        .connect(pair.server.addr, &Arc::new(client_config), "localhost")
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
         .unwrap();
+    assert_eq!(pair.client.get_transport_parameters(client_conn), None);
     pair.drive();
-    assert_matches!(pair.client.poll(),
-                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
-                        error_code: TransportError::TLS_HANDSHAKE_FAILED
-                    }})) if conn == client_conn);
+    let server_conn = pair.server.accept().unwrap();
+
+    let params = pair.client.get_transport_parameters(client_conn).unwrap();
+    assert_eq!(params.initial_max_uni_streams, 3);
+    let params = pair.server.get_transport_parameters(server_conn).unwrap();
+    assert_eq!(params.initial_max_uni_streams, 0);
 }
 
 #[test]
-fn congestion() {
+fn connection_age() {
+    let mut pair = Pair::default();
+    pair.latency = 10_000;
+    let start = pair.time;
+    let (client_conn, server_conn) = pair.connect();
+
+    // The client completes the handshake after one round trip
+    let established = pair.client.established_time(client_conn).unwrap();
+    assert!(established >= start + 2 * pair.latency && established <= pair.time);
+    assert!(pair.server.established_time(server_conn).unwrap() > established);
+    assert_eq!(
+        pair.client.connection_age(client_conn, pair.time),
+        pair.time - start
+    );
+    pair.time += 1_000_000;
+    assert_eq!(
+        pair.client.connection_age(client_conn, pair.time),
+        pair.time - start
+    );
+}
+
+#[test]
+fn user_data() {
     let mut pair = Pair::default();
     let (client_conn, _) = pair.connect();
 
-    let initial_congestion_state = pair.client.get_congestion_state(client_conn);
-    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
-    loop {
-        match pair.client.write(client_conn, s, &[42; 1024]) {
-            Ok(n) => {
-                assert!(n <= 1024);
-                pair.drive_client();
-            }
-            Err(WriteError::Blocked) => {
-                break;
-            }
-            Err(e) => {
-                panic!("unexpected write error: {}", e);
-            }
-        }
-    }
-    pair.drive();
-    assert!(pair.client.get_congestion_state(client_conn) >= initial_congestion_state);
-    pair.client.write(client_conn, s, &[42; 1024]).unwrap();
+    assert_eq!(pair.client.get_user_data::<u32>(client_conn), None);
+    pair.client.set_user_data(client_conn, 42u32);
+    assert_eq!(pair.client.get_user_data::<u32>(client_conn), Some(&42));
+    assert_eq!(pair.client.get_user_data::<String>(client_conn), None);
+    *pair.client.get_user_data_mut::<u32>(client_conn).unwrap() += 1;
+    assert_eq!(pair.client.get_user_data::<u32>(client_conn), Some(&43));
 }
 
 #[test]
-fn high_latency_handshake() {
+fn finish_stream() {
     let mut pair = Pair::default();
-    pair.latency = 200 * 1000;
-    let client_conn = pair
-        .client
-        .connect(pair.server.addr, &client_config(), "localhost")
-        .unwrap();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s).unwrap();
     pair.drive();
-    let server_conn = if let Some(c) = pair.server.accept() {
-        c
-    } else {
-        panic!("server didn't connect");
-    };
-    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
-    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
-    assert_eq!(pair.server.get_bytes_in_flight(server_conn), 0);
+
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_conn && stream == s);
+    assert_matches!(pair.client.poll(), None);
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.poll(), None);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Finished)
+    );
 }
 
-/*
 #[test]
-fn zero_rtt() {
+fn peek() {
     let mut pair = Pair::default();
-    let (c, _) = pair.connect();
-    let ticket = match pair.client.poll() {
-        Some((conn, Event::NewSessionTicket { ref ticket })) if conn == c => ticket.clone(),
-        e => panic!("unexpected poll result: {:?}", e),
-    };
-    info!(pair.log, "closing"; "ticket size" => ticket.len());
-    pair.client.close(pair.time, c, 42, (&[][..]).into());
-    pair.drive();
-    info!(pair.log, "resuming");
-    let cc = pair
-        .client
-        .connect(
-            pair.server.addr,
-            "localhost",
-        )
-        .unwrap();
-    let s = pair.client.open(cc, Directionality::Uni).unwrap();
-    const MSG: &[u8] = b"Hello, 0-RTT!";
-    pair.client.write(cc, s, MSG).unwrap();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello world";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s).unwrap();
     pair.drive();
-    assert!(pair.client.get_session_resumed(c));
-    let sc = if let Some(c) = pair.server.accept() {
-        c
-    } else {
-        panic!("server didn't connect");
-    };
-    assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+
+    let mut buf = [0; 5];
+    for _ in 0..2 {
+        assert_eq!(pair.server.peek(server_conn, s, &mut buf), Ok(5));
+        assert_eq!(&buf, b"hello");
+    }
+    let mut buf = [0; 64];
+    assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(MSG.len()));
+    assert_eq!(&buf[..MSG.len()], MSG);
+    assert_eq!(
+        pair.server.peek(server_conn, s, &mut buf),
+        Err(ReadError::Finished)
+    );
+    assert_eq!(
+        pair.server.read(server_conn, s, &mut buf),
+        Err(ReadError::Finished)
+    );
 }
-*/
 
 #[test]
-fn close_during_handshake() {
+fn read_bytes() {
     let mut pair = Pair::default();
-    let c = pair
-        .client
-        .connect(pair.server.addr, &client_config(), "localhost")
-        .unwrap();
-    pair.client.close(pair.time, c, 0, Bytes::new());
-    // This never actually sends the client's Initial; we may want to behave better here.
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello world";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s).unwrap();
+    pair.drive();
+
+    assert_matches!(pair.server.read_bytes(server_conn, s, 5), Ok(ref x) if &x[..] == b"hello");
+    assert_matches!(pair.server.read_bytes(server_conn, s, 64), Ok(ref x) if &x[..] == b" world");
+    assert_matches!(
+        pair.server.read_bytes(server_conn, s, 64),
+        Err(ReadError::Finished)
+    );
 }
 
 #[test]
-fn stream_id_backpressure() {
-    let server_config = Config {
-        max_remote_uni_streams: 1,
-        ..server_config()
-    };
-    let mut pair = Pair::new(
-        server_config,
-        Default::default(),
-        ListenKeys::new(&mut rand::thread_rng()),
-    );
+fn finish_stream_twice() {
+    let mut pair = Pair::default();
     let (client_conn, server_conn) = pair.connect();
 
-    let s = pair
-        .client
-        .open(client_conn, Directionality::Uni)
-        .expect("couldn't open first stream");
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s).unwrap();
     assert_eq!(
-        pair.client.open(client_conn, Directionality::Uni),
-        None,
-        "only one stream is permitted at a time"
+        pair.client.finish(client_conn, s),
+        Err(FinishError::Finished)
+    );
+    pair.drive();
+    // By now the stream has been acknowledged and may have been forgotten
+    assert_eq!(
+        pair.client.finish(client_conn, s),
+        Err(FinishError::Finished)
     );
-    // Close the first stream to make room for the second
-    pair.client.finish(client_conn, s);
     pair.drive();
+
     assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_conn && stream == s);
     assert_matches!(pair.client.poll(), None);
     assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.poll(), None);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
     assert_matches!(
         pair.server.read_unordered(server_conn, s),
         Err(ReadError::Finished)
     );
-    // Server will only send MAX_STREAM_ID now that the application's been notified
-    pair.drive();
-    assert_matches!(pair.client.poll(), Some((conn, Event::StreamAvailable { directionality: Directionality::Uni })) if conn == client_conn);
-    assert_matches!(pair.client.poll(), None);
+}
 
-    // Try opening the second stream again, now that we've made room
-    let s = pair
-        .client
-        .open(client_conn, Directionality::Uni)
-        .expect("didn't get stream id budget");
-    pair.client.finish(client_conn, s);
+#[test]
+fn finish_bi_stream() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
+
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s).unwrap();
     pair.drive();
-    // Make sure the server actually processes data on the newly-available stream
+
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_conn && stream == s);
+    assert_matches!(pair.client.poll(), None);
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamRecvFinished { stream })) if conn == server_conn && stream == s);
     assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
     assert_matches!(pair.server.poll(), None);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
     assert_matches!(
         pair.server.read_unordered(server_conn, s),
         Err(ReadError::Finished)
     );
 }
+
+#[test]
+fn handshake_ack_after_established() {
+    let mut pair = Pair::default();
+    let server_addr = pair.server.addr;
+    let client_conn = pair
+        .client
+        .connect(pair.time, server_addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    pair.drive_client();
+
+    // Deliver the client's Finished without letting the server respond yet
+    let client_addr = pair.client.addr;
+    while let Some((_, packet)) = pair.server.inbound.pop_front() {
+        pair.server
+            .handle(pair.time, client_addr, Vec::from(packet).into());
+    }
+    let server_conn = pair.server.accept().expect("server didn't connect");
+    {
+        let conn = &pair.server.connections[server_conn.0];
+        assert!(!conn.awaiting_handshake);
+        // The ACK of the Finished must go out in a Handshake packet, despite the server having
+        // moved on to 1-RTT
+        assert!(conn.spaces[SpaceId::Handshake as usize].permit_ack_only);
+        assert!(conn.spaces[SpaceId::Data as usize].pending_acks.is_empty());
+    }
+
+    pair.drive_server();
+    assert!(
+        !pair.server.connections[server_conn.0].spaces[SpaceId::Handshake as usize].permit_ack_only
+    );
+    pair.drive_client();
+    let conn = &pair.client.connections[client_conn.0];
+    assert!(conn.spaces[SpaceId::Handshake as usize]
+        .sent_packets
+        .is_empty());
+    assert!(!conn.awaiting_handshake);
+}
+
+#[test]
+fn packet_number_spaces() {
+    let mut pair = Pair::default();
+    let server_addr = pair.server.addr;
+    let client_conn = pair
+        .client
+        .connect(pair.time, server_addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    {
+        let conn = &pair.client.connections[client_conn.0];
+        assert_eq!(
+            conn.spaces[SpaceId::Initial as usize].largest_sent_packet,
+            1
+        );
+        assert_eq!(
+            conn.spaces[SpaceId::Handshake as usize].largest_sent_packet,
+            0
+        );
+        assert_eq!(conn.spaces[SpaceId::Data as usize].largest_sent_packet, 0);
+    }
+
+    pair.drive_server();
+    {
+        let (_, server_conn) = pair.server.connections.iter().next().unwrap();
+        // The client's Initial is acknowledged in an Initial packet of the server's own
+        assert_eq!(
+            server_conn.spaces[SpaceId::Initial as usize].largest_sent_packet,
+            1
+        );
+        // Each space numbers its packets from 1, regardless of the other spaces or the peer
+        assert_eq!(
+            *server_conn.spaces[SpaceId::Handshake as usize]
+                .sent_packets
+                .keys()
+                .next()
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            server_conn.spaces[SpaceId::Data as usize].largest_sent_packet,
+            0
+        );
+    }
+
+    pair.drive_client();
+    let conn = &pair.client.connections[client_conn.0];
+    assert_eq!(
+        conn.spaces[SpaceId::Initial as usize].largest_acked_packet,
+        1
+    );
+}
+
+#[test]
+fn spin_bit() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    // Participation is randomized, so make sure both sides spin
+    pair.client.connections[client_conn.0].spin_enabled = true;
+    pair.server.connections[server_conn.0].spin_enabled = true;
+    for _ in 0..4 {
+        let spin = pair.client.get_spin_bit(client_conn).unwrap();
+        pair.client.ping(client_conn);
+        pair.drive();
+        // The server reflects the client's value, and the client inverts it once per round trip
+        assert_eq!(pair.server.get_spin_bit(server_conn), Some(spin));
+        assert_eq!(pair.client.get_spin_bit(client_conn), Some(!spin));
+    }
+}
+
+#[test]
+fn key_update() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    pair.client.force_key_update(client_conn);
+    assert!(pair.client.connections[client_conn.0].key_phase);
+
+    const MSG: &[u8] = b"hello";
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s).unwrap();
+    pair.drive();
+
+    assert!(pair.server.connections[server_conn.0].key_phase);
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+    // The server has responded under the new keys
+    assert_matches!(pair.client.connections[client_conn.0].prev_crypto, Some((boundary, _)) if boundary != u64::max_value());
+
+    let s = pair.server.open(server_conn, Directionality::Uni).unwrap();
+    pair.server.write(server_conn, s, MSG).unwrap();
+    pair.server.finish(server_conn, s).unwrap();
+    pair.drive();
+
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { .. })) if conn == client_conn);
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == client_conn && stream == s);
+    assert_matches!(pair.client.read_unordered(client_conn, s), Ok((ref data, 0)) if data == MSG);
+}
+
+#[test]
+fn key_update_discards_old_keys() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    pair.client.force_key_update(client_conn);
+    const MSG: &[u8] = b"hello";
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive();
+    assert!(pair.client.connections[client_conn.0].prev_crypto.is_some());
+
+    // Well past a PTO, but short of the idle timeout
+    pair.time += 1_000_000;
+    let s = pair.server.open(server_conn, Directionality::Uni).unwrap();
+    pair.server.write(server_conn, s, MSG).unwrap();
+    pair.drive();
+    assert!(pair.client.connections[client_conn.0].prev_crypto.is_none());
+    assert!(pair.server.connections[server_conn.0].prev_crypto.is_none());
+}
+
+/// Counts the notifications checked by the `observer` test
+#[derive(Default)]
+struct CountingObserver {
+    sent: AtomicUsize,
+    acked: AtomicUsize,
+    rtt_samples: AtomicUsize,
+    key_updates: AtomicUsize,
+}
+
+impl ConnectionObserver for CountingObserver {
+    fn packet_sent(&self, _: ConnectionHandle, _: u64, _: u16) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+    fn packet_acked(&self, _: ConnectionHandle, _: u64) {
+        self.acked.fetch_add(1, Ordering::Relaxed);
+    }
+    fn rtt_sampled(&self, _: ConnectionHandle, _: u64, _: u64) {
+        self.rtt_samples.fetch_add(1, Ordering::Relaxed);
+    }
+    fn key_updated(&self, _: ConnectionHandle) {
+        self.key_updates.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[test]
+fn observer() {
+    let observer = Arc::new(CountingObserver::default());
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        observer: Some(observer.clone()),
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, _) = pair.connect();
+
+    pair.client.force_key_update(client_conn);
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+
+    assert_ne!(observer.sent.load(Ordering::Relaxed), 0);
+    assert_ne!(observer.acked.load(Ordering::Relaxed), 0);
+    assert_ne!(observer.rtt_samples.load(Ordering::Relaxed), 0);
+    // The server followed the client's update
+    assert_eq!(observer.key_updates.load(Ordering::Relaxed), 1);
+}
+
+#[test]
+fn reset_stream() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive();
+
+    info!(pair.log, "resetting stream");
+    const ERROR: u16 = 42;
+    pair.client.reset(client_conn, s, ERROR).unwrap();
+    pair.drive();
+
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.poll(), None);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Reset { error_code: ERROR })
+    );
+    assert_matches!(pair.client.poll(), None);
+}
+
+#[test]
+fn finish_vs_reset() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    const MSG: &[u8] = b"hello";
+    let finished = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, finished, MSG).unwrap();
+    pair.client.finish(client_conn, finished).unwrap();
+    let reset = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, reset, MSG).unwrap();
+    pair.drive();
+    const ERROR: u16 = 7;
+    pair.client.reset(client_conn, reset, ERROR).unwrap();
+    pair.drive();
+
+    let mut buf = [0; 16];
+    for &s in &[finished, reset] {
+        assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(MSG.len()));
+        assert_eq!(&buf[..MSG.len()], MSG);
+    }
+    assert_eq!(
+        pair.server.read(server_conn, finished, &mut buf),
+        Err(ReadError::Finished)
+    );
+    assert_eq!(
+        pair.server.read(server_conn, reset, &mut buf),
+        Err(ReadError::Reset { error_code: ERROR })
+    );
+}
+
+#[test]
+fn invalid_stream_direction() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+
+    // The server can only receive on `s`, and the client only send
+    assert_eq!(
+        pair.server.reset(server_conn, s, 0),
+        Err(InvalidStreamError)
+    );
+    assert_eq!(
+        pair.server.finish(server_conn, s),
+        Err(FinishError::InvalidStream)
+    );
+    assert_eq!(
+        pair.client.stop_sending(client_conn, s, 0),
+        Err(InvalidStreamError)
+    );
+    pair.drive();
+    assert_matches!(pair.client.poll(), None);
+}
+
+#[test]
+fn stop_stream() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive();
+
+    info!(pair.log, "stopping stream");
+    const ERROR: u16 = 42;
+    pair.server.stop_sending(server_conn, s, ERROR).unwrap();
+    pair.drive_server();
+    // Deliver the STOP_SENDING twice, as if it had been retransmitted
+    let dup = pair.client.inbound.back().unwrap().clone();
+    pair.client.inbound.push_back(dup);
+    pair.drive();
+
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.poll(), None);
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data == MSG);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Reset { error_code: ERROR })
+    );
+
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamStopped { stream, error_code: ERROR })) if conn == client_conn && stream == s);
+    // Reported once, even though the STOP_SENDING arrived twice
+    assert_matches!(pair.client.poll(), None);
+
+    assert_matches!(
+        pair.client.write(client_conn, s, b"foo"),
+        Err(WriteError::Stopped { error_code: ERROR })
+    );
+}
+
+#[test]
+fn writable_bytes() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        receive_window: 1000,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, _) = pair.connect();
+    assert_eq!(pair.client.writable_bytes(client_conn), 1000);
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[0; 400]), Ok(400));
+    assert_eq!(pair.client.writable_bytes(client_conn), 600);
+    pair.drive();
+    assert_eq!(pair.client.writable_bytes(client_conn), 600);
+}
+
+#[test]
+fn write_vectored() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        receive_window: 1000,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let bufs: &[&[u8]] = &[b"hello", b" ", b"world", &[0; 2000]];
+    assert_eq!(pair.client.write_vectored(client_conn, s, bufs), Ok(1000));
+    pair.drive();
+
+    let mut data = Vec::new();
+    let mut buf = [0; 2000];
+    while let Ok(n) = pair.server.read(server_conn, s, &mut buf) {
+        data.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(data.len(), 1000);
+    assert_eq!(&data[..11], b"hello world");
+    assert!(data[11..].iter().all(|&x| x == 0));
+}
+
+#[test]
+fn write_bytes() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        receive_window: 1000,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let data = Bytes::from(vec![42; 1500]);
+    let sent = pair
+        .client
+        .write_bytes(client_conn, s, data.clone())
+        .unwrap();
+    assert_eq!(sent.len(), 1000);
+    assert_eq!(sent.as_ptr(), data.as_ptr(), "data was copied");
+    pair.drive();
+
+    let mut recvd = 0;
+    while let Ok((chunk, _)) = pair.server.read_unordered(server_conn, s) {
+        assert!(chunk.iter().all(|&x| x == 42));
+        recvd += chunk.len();
+    }
+    assert_eq!(recvd, 1000);
+}
+
+#[test]
+fn spurious_loss() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    for i in 0..5 {
+        pair.client.write(client_conn, s, &[i; 100]).unwrap();
+        pair.drive_client();
+    }
+    let delayed_pn = {
+        let conn = &pair.client.connections[client_conn.0];
+        *conn.spaces[SpaceId::Data as usize]
+            .sent_packets
+            .iter()
+            .find(|&(_, info)| info.bytes != 0)
+            .unwrap()
+            .0
+    };
+
+    // Hold back the first packet until the client considers it lost and retransmits its contents
+    let (_, delayed) = pair.server.inbound.pop_front().unwrap();
+    pair.drive();
+    let cwnd = {
+        let conn = &pair.client.connections[client_conn.0];
+        assert!(!conn.spaces[SpaceId::Data as usize]
+            .sent_packets
+            .contains_key(&delayed_pn));
+        assert_eq!(conn.bytes_in_flight, 0);
+        conn.congestion_window
+    };
+
+    // The original finally arrives and is acknowledged, which must not be accounted for again
+    let time = pair.time;
+    pair.server.inbound.push_back((time, delayed));
+    pair.drive();
+    let conn = &pair.client.connections[client_conn.0];
+    assert_eq!(conn.bytes_in_flight, 0);
+    assert_eq!(conn.congestion_window, cwnd);
+}
+
+#[test]
+fn stream_state() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(
+        pair.client.stream_state(client_conn, s).map(|x| x.status),
+        Some(StreamStatus::Open)
+    );
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.finish(client_conn, s).unwrap();
+    assert_eq!(
+        pair.client.stream_state(client_conn, s).map(|x| x.status),
+        Some(StreamStatus::SendFinished)
+    );
+    pair.drive();
+    // All data was acknowledged, so the stream has been forgotten
+    assert_eq!(pair.client.stream_state(client_conn, s), None);
+    assert_eq!(
+        pair.server.stream_state(server_conn, s).map(|x| x.status),
+        Some(StreamStatus::RecvFinished)
+    );
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.reset(client_conn, s, 42).unwrap();
+    assert_eq!(
+        pair.client.stream_state(client_conn, s).map(|x| x.status),
+        Some(StreamStatus::Reset)
+    );
+    pair.drive();
+    assert_eq!(
+        pair.server.stream_state(server_conn, s).map(|x| x.status),
+        Some(StreamStatus::Reset)
+    );
+}
+
+#[test]
+fn open_stream_count() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(pair.client.open_stream_count(client_conn), (0, 0));
+    assert_eq!(pair.server.open_stream_count(server_conn), (0, 0));
+
+    let uni = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let bi = pair.client.open(client_conn, Directionality::Bi).unwrap();
+    for &s in &[uni, bi] {
+        pair.client.write(client_conn, s, b"hello").unwrap();
+    }
+    pair.client.finish(client_conn, uni).unwrap();
+    pair.drive();
+    // The finished stream has been fully acknowledged and forgotten
+    assert_eq!(pair.client.open_stream_count(client_conn), (1, 0));
+    assert_eq!(pair.server.open_stream_count(server_conn), (0, 2));
+
+    let mut buf = [0; 16];
+    pair.server.read(server_conn, uni, &mut buf).unwrap();
+    assert_eq!(
+        pair.server.read(server_conn, uni, &mut buf),
+        Err(ReadError::Finished)
+    );
+    assert_eq!(pair.server.open_stream_count(server_conn), (0, 1));
+}
+
+#[test]
+fn stream_half_states() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let state = pair.client.stream_state(client_conn, s).unwrap();
+    assert_eq!(state.send, Some(SendState::Ready));
+    assert_eq!(state.recv, None);
+    assert_ne!(state.send_budget, 0);
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    assert_eq!(
+        pair.client
+            .stream_state(client_conn, s)
+            .unwrap()
+            .send_budget,
+        state.send_budget - MSG.len() as u64
+    );
+    pair.client.finish(client_conn, s).unwrap();
+    let state = pair.client.stream_state(client_conn, s).unwrap();
+    assert_eq!(state.send, Some(SendState::DataSent));
+    assert_eq!(state.send_budget, 0);
+    pair.drive();
+    let state = pair.server.stream_state(server_conn, s).unwrap();
+    assert_eq!(state.recv, Some(RecvState::DataRecvd { size: 5 }));
+    assert_eq!(state.send, None);
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.client.reset(client_conn, s, 42).unwrap();
+    assert_eq!(
+        pair.client.stream_state(client_conn, s).unwrap().send,
+        Some(SendState::ResetSent { stop_reason: None })
+    );
+    pair.drive();
+    assert_eq!(
+        pair.server.stream_state(server_conn, s).unwrap().recv,
+        Some(RecvState::ResetRecvd {
+            size: 5,
+            error_code: 42
+        })
+    );
+}
+
+#[test]
+fn reject_self_signed_cert() {
+    let mut client_config = ClientConfig::new();
+    client_config.versions = vec![ProtocolVersion::TLSv1_3];
+    client_config.set_protocols(&[str::from_utf8(ALPN_QUIC_HTTP).unwrap().into()]);
+
+    let mut pair = Pair::default();
+    info!(pair.log, "connecting");
+    let client_conn = pair
+        .client
+        .connect(
+            pair.time,
+            pair.server.addr,
+            &Arc::new(client_config),
+            "localhost",
+        )
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::TLS_HANDSHAKE_FAILED
+                    }})) if conn == client_conn);
+}
+
+#[test]
+fn congestion() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+
+    let initial_congestion_state = pair.client.get_congestion_state(client_conn);
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    loop {
+        match pair.client.write(client_conn, s, &[42; 1024]) {
+            Ok(n) => {
+                assert!(n <= 1024);
+                pair.drive_client();
+            }
+            Err(WriteError::CongestionLimited) => {
+                break;
+            }
+            Err(e) => {
+                panic!("unexpected write error: {}", e);
+            }
+        }
+    }
+    pair.drive();
+    assert!(pair.client.get_congestion_state(client_conn) >= initial_congestion_state);
+    pair.client.write(client_conn, s, &[42; 1024]).unwrap();
+}
+
+#[test]
+fn high_latency_handshake() {
+    let mut pair = Pair::default();
+    pair.latency = 200 * 1000;
+    let client_conn = pair
+        .client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    let server_conn = if let Some(c) = pair.server.accept() {
+        c
+    } else {
+        panic!("server didn't connect");
+    };
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+    assert_eq!(pair.client.get_bytes_in_flight(client_conn), 0);
+    assert_eq!(pair.server.get_bytes_in_flight(server_conn), 0);
+}
+
+/*
+#[test]
+fn zero_rtt() {
+    let mut pair = Pair::default();
+    let (c, _) = pair.connect();
+    let ticket = match pair.client.poll() {
+        Some((conn, Event::NewSessionTicket { ref ticket })) if conn == c => ticket.clone(),
+        e => panic!("unexpected poll result: {:?}", e),
+    };
+    info!(pair.log, "closing"; "ticket size" => ticket.len());
+    pair.client.close(pair.time, c, 42, (&[][..]).into());
+    pair.drive();
+    info!(pair.log, "resuming");
+    let cc = pair
+        .client
+        .connect(
+            pair.time,
+            pair.server.addr,
+            "localhost",
+        )
+        .unwrap();
+    let s = pair.client.open(cc, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"Hello, 0-RTT!";
+    pair.client.write(cc, s, MSG).unwrap();
+    pair.drive();
+    assert!(pair.client.get_session_resumed(c));
+    let sc = if let Some(c) = pair.server.accept() {
+        c
+    } else {
+        panic!("server didn't connect");
+    };
+    assert_matches!(pair.server.read_unordered(sc, s), Ok((ref data, 0)) if data == MSG);
+}
+*/
+
+#[test]
+fn close_during_handshake() {
+    let mut pair = Pair::default();
+    let c = pair
+        .client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.client.close(pair.time, c, 0, Bytes::new());
+    // This never actually sends the client's Initial; we may want to behave better here.
+}
+
+#[test]
+fn open_after_close() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    pair.client.close(pair.time, client_conn, 0, Bytes::new());
+    assert_eq!(
+        pair.client.open(client_conn, Directionality::Bi),
+        Err(OpenError::ConnectionClosed)
+    );
+}
+
+#[test]
+fn close_unknown_error_code() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let mut payload = Vec::new();
+    payload.write(frame::Type::CONNECTION_CLOSE);
+    payload.write(0x7f3au16);
+    payload.write_var(3);
+    payload.extend_from_slice(b"bye");
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    pair.server.inbound.push_back((pair.time, packet));
+    pair.drive();
+    assert_matches!(pair.server.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::ConnectionClosed {
+                        ref reason
+                    }})) if conn == server_conn
+                        && u16::from(reason.error_code) == 0x7f3a
+                        && reason.reason == &b"bye"[..]);
+}
+
+#[test]
+fn ack_delay_exponent() {
+    let server_config = Config {
+        ack_delay_exponent: 10,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.client.connections[client_conn.0]
+            .params
+            .ack_delay_exponent,
+        10
+    );
+    assert_eq!(
+        pair.server.connections[server_conn.0]
+            .params
+            .ack_delay_exponent,
+        3
+    );
+
+    // Have the server receive a ping, but only get around to acknowledging it some time later
+    const DELAY: u64 = 8 * 1024 + 500;
+    pair.client.ping(client_conn);
+    pair.drive_client();
+    let (_, packet) = pair.server.inbound.pop_front().unwrap();
+    let client_addr = pair.client.addr;
+    pair.server
+        .handle(pair.time, client_addr, Vec::from(packet).into());
+    pair.time += DELAY;
+    pair.drive();
+    // The delay is truncated to a multiple of 2^10 μs on the wire
+    assert_eq!(
+        pair.client.connections[client_conn.0].max_ack_delay,
+        8 * 1024
+    );
+}
+
+#[test]
+fn delayed_ack() {
+    const LATENCY: u64 = 1000;
+    let mut pair = Pair::default();
+    pair.latency = LATENCY;
+    let (client_conn, _) = pair.connect();
+
+    // The server has nothing to send, so it must acknowledge the ping once its delayed ACK timer
+    // expires
+    pair.client.ping(client_conn);
+    pair.drive();
+    let rtt = pair.client.connections[client_conn.0].latest_rtt;
+    let timeout = pair.server.ctx.config.delayed_ack_timeout;
+    assert!(rtt > 2 * LATENCY, "ACK was not delayed");
+    assert!(rtt <= 2 * LATENCY + timeout, "ACK was delayed too long");
+}
+
+#[test]
+fn immediate_ack() {
+    const LATENCY: u64 = 1000;
+    let mut pair = Pair::default();
+    pair.latency = LATENCY;
+    let (client_conn, _) = pair.connect();
+
+    // A second ack-eliciting packet is acknowledged without waiting for the delayed ACK timer
+    for _ in 0..2 {
+        pair.client.ping(client_conn);
+        pair.drive_client();
+    }
+    pair.drive();
+    let rtt = pair.client.connections[client_conn.0].latest_rtt;
+    let timeout = pair.server.ctx.config.delayed_ack_timeout;
+    assert!(timeout > 0);
+    assert!(rtt < 2 * LATENCY + timeout, "ACK was delayed");
+}
+
+#[test]
+fn rtt_probe() {
+    const INTERVAL: u64 = 100_000;
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        rtt_probe_interval: INTERVAL,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let last_sent = pair.server.connections[server_conn.0].time_of_last_sent_retransmittable_packet;
+
+    // The server has sent nothing ack-eliciting since the handshake, so receiving prompts a PING
+    pair.time += INTERVAL;
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+    let conn = &pair.server.connections[server_conn.0];
+    assert!(conn.time_of_last_sent_retransmittable_packet > last_sent);
+    assert!(!conn.pending.ping);
+    assert_eq!(conn.bytes_in_flight, 0);
+}
+
+#[test]
+fn flow_control_credit_batching() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        stream_receive_window: 2000,
+        receive_window: 2000,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+
+    // Consuming a small fraction of the window isn't worth advertising
+    assert_eq!(pair.client.write(client_conn, s, &[0; 500]), Ok(500));
+    pair.drive();
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 0)) if data.len() == 500);
+    {
+        let conn = &pair.server.connections[server_conn.0];
+        assert!(!conn.pending.max_data);
+        assert!(conn.pending.max_stream_data.is_empty());
+    }
+
+    // Once more than half the window is consumed, credit is issued
+    assert_eq!(pair.client.write(client_conn, s, &[0; 1000]), Ok(1000));
+    pair.drive();
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((ref data, 500)) if data.len() == 1000);
+    {
+        let conn = &pair.server.connections[server_conn.0];
+        assert!(conn.pending.max_data);
+        assert!(conn.pending.max_stream_data.contains(&s));
+    }
+    pair.drive();
+    assert_eq!(pair.client.connections[client_conn.0].max_data, 3500);
+    assert_eq!(pair.client.write(client_conn, s, &[0; 2000]), Ok(2000));
+}
+
+#[test]
+fn flow_control_blocked() {
+    let server_config = Config {
+        max_remote_uni_streams: 2,
+        stream_receive_window: 1000,
+        receive_window: 1500,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // Stream-level flow control
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[0; 1000]), Ok(1000));
+    for _ in 0..2 {
+        assert_eq!(
+            pair.client.write(client_conn, s, &[0; 100]),
+            Err(WriteError::FlowControlLimited)
+        );
+    }
+    assert!(pair.client.connections[client_conn.0]
+        .pending
+        .stream_blocked
+        .contains(&s));
+    pair.drive_client();
+    assert_eq!(
+        count_frames(&pair.server, server_conn, frame::Type::STREAM_BLOCKED),
+        1
+    );
+    pair.drive();
+    assert_eq!(
+        pair.client.write(client_conn, s, &[0; 100]),
+        Err(WriteError::FlowControlLimited)
+    );
+    // The peer has already been told about this limit
+    assert!(pair.client.connections[client_conn.0]
+        .pending
+        .stream_blocked
+        .is_empty());
+    pair.drive_client();
+    assert_eq!(
+        count_frames(&pair.server, server_conn, frame::Type::STREAM_BLOCKED),
+        0
+    );
+    pair.drive();
+    assert_eq!(
+        pair.client
+            .get_stats(client_conn)
+            .flow_control_blocked_writes,
+        0
+    );
+
+    // Connection-level flow control
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[0; 1000]), Ok(500));
+    for _ in 0..2 {
+        assert_eq!(
+            pair.client.write(client_conn, s, &[0; 100]),
+            Err(WriteError::FlowControlLimited)
+        );
+    }
+    assert!(pair.client.connections[client_conn.0].pending.blocked);
+    pair.drive_client();
+    assert_eq!(
+        count_frames(&pair.server, server_conn, frame::Type::BLOCKED),
+        1
+    );
+    pair.drive();
+    assert_eq!(
+        pair.client.write(client_conn, s, &[0; 100]),
+        Err(WriteError::FlowControlLimited)
+    );
+    assert!(!pair.client.connections[client_conn.0].pending.blocked);
+    pair.drive_client();
+    assert_eq!(
+        count_frames(&pair.server, server_conn, frame::Type::BLOCKED),
+        0
+    );
+    pair.drive();
+    assert_eq!(
+        pair.client
+            .get_stats(client_conn)
+            .flow_control_blocked_writes,
+        3
+    );
+}
+
+#[test]
+fn write_after_close() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Bi).unwrap();
+    pair.client.close(pair.time, client_conn, 0, Bytes::new());
+    assert_eq!(
+        pair.client.write(client_conn, s, b"hello"),
+        Err(WriteError::ConnectionClosed)
+    );
+}
+
+#[test]
+fn flow_control_wakeup() {
+    let server_config = Config {
+        max_remote_uni_streams: 4,
+        stream_receive_window: 1000,
+        receive_window: 4000,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // Exhaust every stream's credit, and with it the connection's
+    let streams = (0..4)
+        .map(|_| pair.client.open(client_conn, Directionality::Uni).unwrap())
+        .collect::<Vec<_>>();
+    for &s in &streams {
+        assert_eq!(pair.client.write(client_conn, s, &[0; 1000]), Ok(1000));
+    }
+    let (a, b, c) = (streams[0], streams[1], streams[2]);
+    assert_eq!(
+        pair.client.write(client_conn, a, &[0; 100]),
+        Err(WriteError::FlowControlLimited)
+    );
+    pair.drive();
+    while pair.client.poll().is_some() {}
+
+    // Stream-level credit alone doesn't make a connection-blocked stream writable
+    while pair.server.read_unordered(server_conn, a).is_ok() {}
+    pair.drive();
+    assert!(!writable_streams(&mut pair.client).contains(&a));
+
+    // Connection-level credit alone doesn't make a stream-blocked stream writable
+    assert_eq!(
+        pair.client.write(client_conn, b, &[0; 100]),
+        Err(WriteError::FlowControlLimited)
+    );
+    while pair.server.read_unordered(server_conn, c).is_ok() {}
+    pair.drive();
+    let writable = writable_streams(&mut pair.client);
+    assert!(writable.contains(&a));
+    assert!(!writable.contains(&b));
+}
+
+fn writable_streams(endpoint: &mut TestEndpoint) -> Vec<StreamId> {
+    let mut result = Vec::new();
+    while let Some((_, event)) = endpoint.poll() {
+        if let Event::StreamWritable { stream } = event {
+            result.push(stream);
+        }
+    }
+    result
+}
+
+#[test]
+fn zero_receive_window() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        receive_window: 0,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, _) = pair.connect();
+    assert_eq!(pair.client.writable_bytes(client_conn), 0);
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(
+        pair.client.write(client_conn, s, &[0; 100]),
+        Err(WriteError::FlowControlLimited)
+    );
+    assert!(pair.client.connections[client_conn.0].pending.blocked);
+    pair.drive();
+    assert_eq!(
+        pair.client.write(client_conn, s, &[0; 100]),
+        Err(WriteError::FlowControlLimited)
+    );
+}
+
+#[test]
+fn reset_stream_credit() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        receive_window: 2000,
+        stream_receive_window: 2000,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // Reset a stream whose data was never sent, so none of it can be read
+    let s = StreamId::new(Side::Client, Directionality::Uni, 0);
+    let mut payload = Vec::new();
+    payload.write(frame::Type::RST_STREAM);
+    payload.write(s);
+    payload.write(42u16);
+    payload.write_var(1500);
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    pair.server.inbound.push_back((pair.time, packet));
+    pair.drive();
+    assert_eq!(pair.server.connections[server_conn.0].data_recvd, 1500);
+
+    assert_matches!(
+        pair.server.read(server_conn, s, &mut [0; 100]),
+        Err(ReadError::Reset { error_code: 42 })
+    );
+    pair.drive();
+    assert_eq!(pair.server.connections[server_conn.0].local_max_data, 3500);
+    assert_eq!(pair.client.connections[client_conn.0].max_data, 3500);
+}
+
+#[test]
+fn unordered_read_credit() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // Deliver overlapping segments, as a retransmission might
+    let s = StreamId::new(Side::Client, Directionality::Uni, 0);
+    let mut payload = Vec::new();
+    for &(offset, data) in &[(0, &b"12345"[..]), (3, &b"4567"[..])] {
+        frame::Stream {
+            id: s,
+            offset,
+            fin: false,
+            data,
+        }.encode(true, &mut payload);
+    }
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    pair.server.inbound.push_back((pair.time, packet));
+    pair.drive();
+    let initial = pair.server.connections[server_conn.0].local_max_data;
+
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((_, 0)));
+    assert_matches!(pair.server.read_unordered(server_conn, s), Ok((_, 3)));
+    // Only the 7 distinct bytes earn connection-level credit
+    assert_eq!(
+        pair.server.connections[server_conn.0].local_max_data,
+        initial + 7
+    );
+}
+
+#[test]
+fn stream_id_blocked() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    pair.client.open(client_conn, Directionality::Uni).unwrap();
+    for _ in 0..2 {
+        assert_eq!(
+            pair.client.open(client_conn, Directionality::Uni),
+            Err(OpenError::LimitReached)
+        );
+    }
+    assert!(
+        pair.client.connections[client_conn.0]
+            .pending
+            .uni_stream_id_blocked
+    );
+    pair.drive_client();
+    assert_eq!(
+        count_frames(&pair.server, server_conn, frame::Type::STREAM_ID_BLOCKED),
+        1
+    );
+    pair.drive();
+    {
+        let conn = &pair.client.connections[client_conn.0];
+        assert!(!conn.pending.uni_stream_id_blocked);
+        assert!(!conn.pending.bi_stream_id_blocked);
+    }
+
+    // The peer already knows we're blocked at this limit
+    assert_eq!(
+        pair.client.open(client_conn, Directionality::Uni),
+        Err(OpenError::LimitReached)
+    );
+    assert!(
+        !pair.client.connections[client_conn.0]
+            .pending
+            .uni_stream_id_blocked
+    );
+    pair.drive_client();
+    assert_eq!(
+        count_frames(&pair.server, server_conn, frame::Type::STREAM_ID_BLOCKED),
+        0
+    );
+}
+
+#[test]
+fn stream_available() {
+    let mut pair = Pair::new(
+        server_config(),
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.client.open(client_conn, Directionality::Uni),
+        Err(OpenError::LimitReached)
+    );
+
+    // A repeated or stale limit isn't news
+    let mut payload = Vec::new();
+    for &index in &[3, 3, 1] {
+        payload.write(frame::Type::MAX_STREAM_ID);
+        payload.write(StreamId::new(Side::Client, Directionality::Uni, index));
+    }
+    payload.write(frame::Type::MAX_STREAM_ID);
+    payload.write(StreamId::new(Side::Client, Directionality::Bi, 0));
+    let packet = forge_packet(&mut pair.server.connections[server_conn.0], &payload);
+    pair.client
+        .handle(pair.time, pair.server.addr, packet[..].into());
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamAvailable { directionality: Directionality::Uni })) if conn == client_conn);
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamAvailable { directionality: Directionality::Bi })) if conn == client_conn);
+    assert_matches!(pair.client.poll(), None);
+    assert!(
+        !pair.client.connections[client_conn.0]
+            .pending
+            .uni_stream_id_blocked
+    );
+    pair.client.open(client_conn, Directionality::Uni).unwrap();
+}
+
+#[test]
+fn grant_streams() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(
+        pair.client.open(client_conn, Directionality::Uni),
+        Err(OpenError::LimitReached)
+    );
+
+    pair.server
+        .grant_streams(server_conn, Directionality::Uni, 2);
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamAvailable { directionality: Directionality::Uni })) if conn == client_conn);
+    for _ in 0..2 {
+        let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+        pair.client.write(client_conn, s, b"hello").unwrap();
+    }
+    assert_eq!(
+        pair.client.open(client_conn, Directionality::Uni),
+        Err(OpenError::LimitReached)
+    );
+    pair.drive();
+    assert_eq!(
+        pair.server
+            .remote_stream_count(server_conn, Directionality::Uni),
+        3
+    );
+}
+
+#[test]
+fn grant_streams_limit() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    // Far more streams than stream IDs can express, which mustn't be allocated up front
+    pair.server
+        .grant_streams(server_conn, Directionality::Uni, u64::max_value());
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamAvailable { directionality: Directionality::Uni })) if conn == client_conn);
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+    assert_eq!(
+        pair.server
+            .remote_stream_count(server_conn, Directionality::Uni),
+        1
+    );
+}
+
+#[test]
+fn remote_stream_headroom() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        remote_stream_headroom: 4,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // Credit arrives ahead of need, even though the server never reads
+    for _ in 0..5 {
+        let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+        pair.client.write(client_conn, s, b"hello").unwrap();
+        pair.client.finish(client_conn, s).unwrap();
+        pair.drive();
+    }
+    assert_eq!(
+        pair.client.open(client_conn, Directionality::Uni),
+        Err(OpenError::LimitReached)
+    );
+    assert_eq!(
+        pair.server
+            .remote_stream_count(server_conn, Directionality::Uni),
+        5
+    );
+}
+
+#[test]
+fn remote_stream_headroom_replenished() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        remote_stream_headroom: 4,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // Many more streams than the limit and headroom combined, closed as we go. The first one
+    // prompts the server to issue its headroom.
+    for round in 0..8 {
+        let count = if round == 0 { 1 } else { 4 };
+        let streams = (0..count)
+            .map(|_| {
+                let s = pair
+                    .client
+                    .open(client_conn, Directionality::Uni)
+                    .expect("ran out of stream credit");
+                pair.client.finish(client_conn, s).unwrap();
+                s
+            }).collect::<Vec<_>>();
+        pair.drive();
+        for s in streams {
+            assert_matches!(
+                pair.server.read_unordered(server_conn, s),
+                Err(ReadError::Finished)
+            );
+        }
+        pair.drive();
+    }
+}
+
+#[test]
+fn remote_stream_headroom_zero_limit() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        remote_stream_headroom: 4,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, _) = pair.connect();
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive();
+
+    // Bidirectional streams were disabled, and stay that way
+    assert_eq!(
+        pair.client.open(client_conn, Directionality::Bi),
+        Err(OpenError::LimitReached)
+    );
+}
+
+#[test]
+fn stream_id_backpressure() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair
+        .client
+        .open(client_conn, Directionality::Uni)
+        .expect("couldn't open first stream");
+    assert_eq!(
+        pair.client.open(client_conn, Directionality::Uni),
+        Err(OpenError::LimitReached),
+        "only one stream is permitted at a time"
+    );
+    // Close the first stream to make room for the second
+    pair.client.finish(client_conn, s).unwrap();
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamFinished { stream })) if conn == client_conn && stream == s);
+    assert_matches!(pair.client.poll(), None);
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Finished)
+    );
+    // Server will only send MAX_STREAM_ID now that the application's been notified
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::StreamAvailable { directionality: Directionality::Uni })) if conn == client_conn);
+    assert_matches!(pair.client.poll(), None);
+
+    // Try opening the second stream again, now that we've made room
+    let s = pair
+        .client
+        .open(client_conn, Directionality::Uni)
+        .expect("didn't get stream id budget");
+    pair.client.finish(client_conn, s).unwrap();
+    pair.drive();
+    // Make sure the server actually processes data on the newly-available stream
+    assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+    assert_matches!(pair.server.poll(), None);
+    assert_matches!(
+        pair.server.read_unordered(server_conn, s),
+        Err(ReadError::Finished)
+    );
+}
+
+#[test]
+fn stop_sending_stream_0() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    // The handshake stream can't be stopped
+    let mut payload = Vec::new();
+    payload.write(frame::Type::STOP_SENDING);
+    payload.write(StreamId(0));
+    payload.write(0u16);
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    pair.server
+        .handle(pair.time, pair.client.addr, packet[..].into());
+    assert_matches!(pair.server.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::PROTOCOL_VIOLATION
+                    }})) if conn == server_conn);
+}
+
+#[test]
+fn ack_unsent_packet() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    // Have the server acknowledge a packet number the client never used
+    pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize]
+        .pending_acks
+        .insert_one(1 << 40);
+    pair.server.ping(server_conn);
+    pair.drive();
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::PROTOCOL_VIOLATION
+                    }})) if conn == client_conn);
+}
+
+#[test]
+fn rtt_events() {
+    let server_config = Config {
+        rtt_events: true,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    pair.latency = 10_000;
+    let (client_conn, server_conn) = pair.connect();
+    while pair.server.poll().is_some() {}
+
+    pair.server.ping(server_conn);
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::RttUpdated { latest, smoothed })) if conn == server_conn && latest >= 2 * pair.latency && smoothed != 0);
+    assert_matches!(pair.server.poll(), None);
+
+    // Not raised unless requested
+    pair.client.ping(client_conn);
+    pair.drive();
+    assert_matches!(pair.client.poll(), None);
+}
+
+#[test]
+fn inflated_ack_delay() {
+    let mut pair = Pair::default();
+    pair.latency = 10_000;
+    let (client_conn, _) = pair.connect();
+
+    // Make every ack delay reported by the server appear vastly larger than the round trip
+    pair.client.connections[client_conn.0]
+        .params
+        .ack_delay_exponent = 20;
+    let start = pair.time;
+    pair.client.ping(client_conn);
+    pair.drive();
+    let conn = &pair.client.connections[client_conn.0];
+    assert!(conn.latest_rtt >= conn.min_rtt);
+    assert!(conn.smoothed_rtt <= pair.time - start);
+}
+
+#[test]
+fn ack_before_send_time() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+
+    pair.client.ping(client_conn);
+    pair.drive_client();
+    // Pretend the clock went backwards after the ping was sent
+    let future = pair.time + 1_000_000;
+    for info in pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .sent_packets
+        .values_mut()
+    {
+        info.time = future;
+    }
+    pair.drive();
+    assert!(pair.client.connections[client_conn.0].smoothed_rtt < 1_000_000);
+}
+
+#[test]
+fn next_loss_detection_time() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+    assert_eq!(pair.client.next_loss_detection_time(client_conn), None);
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive_client();
+    let time = pair
+        .client
+        .next_loss_detection_time(client_conn)
+        .expect("loss detection timer not armed");
+    assert!(time > pair.time);
+    assert_eq!(time, pair.client.loss);
+
+    // Once the data is acknowledged there's nothing left to detect the loss of
+    pair.drive();
+    assert_eq!(pair.client.next_loss_detection_time(client_conn), None);
+}
+
+#[test]
+fn next_timeout() {
+    let mut pair = Pair::default();
+    assert_eq!(pair.client.next_timeout(), None);
+    let (client_conn, _) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive_client();
+    let timers = [
+        pair.client.idle,
+        pair.client.loss,
+        pair.client.close,
+        pair.client.delayed_ack,
+    ];
+    let time = pair.client.next_timeout().expect("no timer armed");
+    assert!(time > pair.time);
+    assert_eq!(time, *timers.iter().min().unwrap());
+
+    // Nothing is left armed once the connection has drained
+    pair.client.close(pair.time, client_conn, 0, Bytes::new());
+    pair.drive();
+    assert_eq!(pair.client.next_timeout(), None);
+}
+
+#[test]
+fn idle_timeout_negotiation() {
+    // Server, client, and effective idle timeouts (s), with 0 meaning none
+    for &(server, client, expected) in &[
+        (0, 0, 0),
+        (0, 20, 20),
+        (20, 0, 20),
+        (10, 20, 10),
+        (20, 10, 10),
+    ] {
+        let server_config = Config {
+            idle_timeout: server,
+            ..server_config()
+        };
+        let client_config = Config {
+            idle_timeout: client,
+            ..Config::default()
+        };
+        let mut pair = Pair::new(
+            server_config,
+            client_config,
+            ListenKeys::new(&mut rand::thread_rng()),
+        );
+        let (client_conn, server_conn) = pair.connect();
+        pair.drive();
+        assert_eq!(pair.client.idle == u64::max_value(), expected == 0);
+        assert_eq!(pair.server.idle == u64::max_value(), expected == 0);
+
+        let now = pair.time;
+        check_idle_timeout(&mut pair.client, client_conn, now, expected);
+        check_idle_timeout(&mut pair.server, server_conn, now, expected);
+    }
+}
+
+fn check_idle_timeout(
+    endpoint: &mut TestEndpoint,
+    conn: ConnectionHandle,
+    now: u64,
+    expected: u64,
+) {
+    let config = endpoint.ctx.config.clone();
+    let conn = &mut endpoint.connections[conn.0];
+    conn.reset_idle_timeout(&config, now);
+    let expected = if expected == 0 {
+        None
+    } else {
+        Some(now + expected * 1_000_000)
+    };
+    assert_eq!(conn.set_idle, Some(expected));
+}
+
+#[test]
+fn max_idle_timeout() {
+    let server_config = Config {
+        idle_timeout: u16::max_value(),
+        ..server_config()
+    };
+    let client_config = Config {
+        idle_timeout: 0,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        client_config,
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(pair.server.ctx.config.idle_timeout, 600);
+    let now = pair.time;
+    check_idle_timeout(&mut pair.client, client_conn, now, 600);
+    check_idle_timeout(&mut pair.server, server_conn, now, 600);
+
+    // Timestamps in the distant future saturate rather than overflow
+    let config = pair.client.ctx.config.clone();
+    let conn = &mut pair.client.connections[client_conn.0];
+    conn.reset_idle_timeout(&config, u64::max_value() - 1);
+    assert_eq!(conn.set_idle, Some(Some(u64::max_value())));
+}
+
+#[test]
+fn keep_alive() {
+    let client_config = Config {
+        keep_alive_interval: 1,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(
+        server_config(),
+        client_config,
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    pair.connect();
+
+    // Sit idle for well over the 10 second idle timeout, waking only for keep-alives
+    let start = pair.time;
+    while pair.time - start < 30 * 1_000_000 {
+        assert!(pair.client.keep_alive > pair.time);
+        assert!(pair.client.keep_alive < pair.client.idle);
+        pair.time = pair.client.keep_alive;
+        pair.drive();
+    }
+    assert_matches!(pair.client.poll(), None);
+    assert_matches!(pair.server.poll(), None);
+}
+
+#[test]
+#[should_panic(expected = "keep-alive interval must be shorter than the idle timeout")]
+fn keep_alive_longer_than_idle_timeout() {
+    let _ = Endpoint::new(
+        logger(),
+        Config {
+            idle_timeout: 10,
+            keep_alive_interval: 10,
+            ..Config::default()
+        },
+        None,
+    );
+}
+
+/// Lose one of two pings, then deliver the ACK of the other at `skew` microseconds before the
+/// client believes the pings were sent
+fn ack_with_clock_skew(skew: u64) {
+    let mut pair = Pair::default();
+    pair.latency = 10_000;
+    let (client_conn, _) = pair.connect();
+
+    pair.client.ping(client_conn);
+    pair.drive_client();
+    let lost =
+        pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize].largest_sent_packet;
+    pair.server.inbound.clear();
+    pair.client.ping(client_conn);
+    pair.drive_client();
+
+    while pair.client.inbound.is_empty() {
+        pair.time = pair.server.next_wakeup().max(pair.time + pair.latency);
+        pair.drive_server();
+    }
+    let arrival = pair.client.inbound.front().unwrap().0;
+    for info in pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .sent_packets
+        .values_mut()
+    {
+        info.time = arrival + skew;
+    }
+    let cwnd = pair.client.connections[client_conn.0].congestion_window;
+    pair.time = arrival;
+    pair.drive_client();
+
+    let conn = &pair.client.connections[client_conn.0];
+    assert!(conn.spaces[SpaceId::Data as usize]
+        .sent_packets
+        .contains_key(&lost));
+    assert!(conn.congestion_window >= cwnd);
+}
+
+#[test]
+fn loss_detection_at_send_time() {
+    ack_with_clock_skew(0);
+}
+
+#[test]
+fn loss_detection_before_send_time() {
+    ack_with_clock_skew(1000);
+}
+
+#[test]
+fn stream_offset_overflow() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    // Queue a frame whose end lies past the largest representable stream offset
+    pair.client.connections[client_conn.0]
+        .pending
+        .stream
+        .push_back(frame::Stream {
+            id: s,
+            offset: 2u64.pow(62) - 2,
+            fin: false,
+            data: Bytes::from(&b"hello"[..]),
+        });
+    pair.client.ping(client_conn);
+    pair.drive();
+    assert_matches!(pair.server.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::PROTOCOL_VIOLATION
+                    }})) if conn == server_conn);
+}
+
+#[test]
+fn stream_flow_control_boundary() {
+    for &(len, ok) in &[(1000, true), (1001, false)] {
+        let server_config = Config {
+            max_remote_uni_streams: 1,
+            stream_receive_window: 1000,
+            ..server_config()
+        };
+        let mut pair = Pair::new(
+            server_config,
+            Default::default(),
+            ListenKeys::new(&mut rand::thread_rng()),
+        );
+        let (client_conn, server_conn) = pair.connect();
+
+        let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+        // Bypass the client's own flow control accounting
+        pair.client.connections[client_conn.0]
+            .pending
+            .stream
+            .push_back(frame::Stream {
+                id: s,
+                offset: 0,
+                fin: false,
+                data: vec![0; len].into(),
+            });
+        pair.client.ping(client_conn);
+        pair.drive();
+        if ok {
+            assert_matches!(pair.server.poll(), Some((conn, Event::StreamReadable { stream, fresh: true })) if conn == server_conn && stream == s);
+        } else {
+            assert_matches!(pair.server.poll(),
+                            Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                                error_code: TransportError::FLOW_CONTROL_ERROR
+                            }})) if conn == server_conn);
+        }
+    }
+}
+
+#[test]
+fn frame_density_limit() {
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        min_average_frame_size: 8,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // Pack a single packet with many redundant 5-byte RST_STREAM frames
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    for _ in 0..50 {
+        pair.client.connections[client_conn.0]
+            .pending
+            .rst_stream
+            .push((s, 0));
+    }
+    pair.client.ping(client_conn);
+    pair.drive();
+    // Frames before the limit are still processed, so the stream may be reported as readable first
+    let mut lost = false;
+    while let Some((conn, event)) = pair.server.poll() {
+        assert_eq!(conn, server_conn);
+        if let Event::ConnectionLost { reason } = event {
+            assert_matches!(reason, ConnectionError::TransportError {
+                error_code: TransportError::PROTOCOL_VIOLATION
+            });
+            lost = true;
+        }
+    }
+    assert!(lost);
+}
+
+#[test]
+fn short_packet_padding() {
+    let client_config = Config {
+        short_packet_padding: 1000,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(
+        Config {
+            max_remote_uni_streams: 1,
+            ..server_config()
+        },
+        client_config,
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, _) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, b"hello").unwrap();
+    pair.drive_client();
+    assert!(!pair.server.inbound.is_empty());
+    for &(_, ref packet) in &pair.server.inbound {
+        assert_eq!(packet.len(), 1000);
+    }
+}
+
+#[test]
+fn handshake_unconfirmed() {
+    let mut pair = Pair::default();
+    let client_conn = pair
+        .client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    // Let the client complete the handshake, then cut it off from the server
+    loop {
+        pair.drive_client();
+        if let Some(connection::State::Established) = pair.client.connections[client_conn.0].state {
+            break;
+        }
+        pair.drive_server();
+    }
+    while pair.step() {
+        pair.client.inbound.clear();
+    }
+    assert_matches!(pair.client.poll(), Some((conn, Event::Connected { .. })) if conn == client_conn);
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::HandshakeUnconfirmed }))
+                    if conn == client_conn);
+}
+
+#[test]
+fn ack_truncation() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    // Pretend the server received every other packet of a long run from the client, leaving too
+    // many gaps to acknowledge in one small packet
+    let first = pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .largest_sent_packet
+        + 1;
+    for _ in 0..200 {
+        pair.client.ping(client_conn);
+        pair.drive_client();
+    }
+    pair.server.inbound.clear();
+    let last =
+        pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize].largest_sent_packet;
+    {
+        let conn = &mut pair.server.connections[server_conn.0];
+        for pn in (first..last + 1).rev().step_by(2) {
+            conn.spaces[SpaceId::Data as usize]
+                .pending_acks
+                .insert_one(pn);
+        }
+        conn.mtu = 200;
+    }
+    let ranges = pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize]
+        .pending_acks
+        .len();
+
+    pair.server.ping(server_conn);
+    pair.drive_server();
+    assert!(!pair.client.inbound.is_empty());
+    for &(_, ref packet) in &pair.client.inbound {
+        assert!(packet.len() <= 200);
+    }
+    // Only the frame was truncated; the ranges left out are still awaiting acknowledgement
+    assert_eq!(
+        pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize]
+            .pending_acks
+            .len(),
+        ranges
+    );
+    pair.drive_client();
+    let conn = &pair.client.connections[client_conn.0];
+    assert!(!conn.spaces[SpaceId::Data as usize]
+        .sent_packets
+        .contains_key(&last));
+}
+
+#[test]
+fn acknowledged_acks_forgotten() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    // A large, fragmented set of packets awaiting acknowledgement
+    let first = pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .largest_sent_packet
+        + 1;
+    for _ in 0..200 {
+        pair.client.ping(client_conn);
+        pair.drive_client();
+    }
+    pair.server.inbound.clear();
+    let last =
+        pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize].largest_sent_packet;
+    for pn in (first..last + 1).step_by(2) {
+        pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize]
+            .pending_acks
+            .insert_one(pn);
+    }
+
+    pair.server.ping(server_conn);
+    pair.drive_server();
+    let pn =
+        pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize].largest_sent_packet;
+    assert_eq!(
+        pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize].sent_packets[&pn]
+            .acks
+            .max(),
+        Some(last)
+    );
+    pair.drive();
+    // Once the peer acknowledges the ACK, nothing it covered is acknowledged again
+    let conn = &pair.server.connections[server_conn.0];
+    assert!(!conn.spaces[SpaceId::Data as usize]
+        .sent_packets
+        .contains_key(&pn));
+    assert!(conn.spaces[SpaceId::Data as usize]
+        .pending_acks
+        .min()
+        .map_or(true, |x| x > last));
+}
+
+#[test]
+fn truncated_ack_acknowledged() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let first = pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .largest_sent_packet
+        + 1;
+    for _ in 0..200 {
+        pair.client.ping(client_conn);
+        pair.drive_client();
+    }
+    pair.server.inbound.clear();
+    let last =
+        pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize].largest_sent_packet;
+    {
+        let conn = &mut pair.server.connections[server_conn.0];
+        for pn in (first..last + 1).rev().step_by(2) {
+            conn.spaces[SpaceId::Data as usize]
+                .pending_acks
+                .insert_one(pn);
+        }
+        conn.mtu = 200;
+    }
+
+    pair.server.ping(server_conn);
+    pair.drive_server();
+    // A packet inside the range the ACK covered arrives late
+    pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize]
+        .pending_acks
+        .insert_one(last - 1);
+    pair.drive();
+    // Once the peer acknowledges the ACK, only what the frame actually encoded is forgotten
+    let acks = &pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize].pending_acks;
+    assert!(!acks.contains(last));
+    assert!(acks.contains(last - 1));
+    assert!(acks.contains(first));
+}
+
+#[test]
+fn stream_fairness() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+
+    let a = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let b = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, a, &[0; 4000]), Ok(4000));
+    assert_eq!(pair.client.write(client_conn, b, &[0; 4000]), Ok(4000));
+    pair.drive_client();
+
+    // Neither stream may monopolize a packet while the other is waiting
+    let packets = pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .sent_packets
+        .values()
+        .collect::<Vec<_>>();
+    assert!(packets.len() > 2);
+    for info in &packets[..packets.len() - 1] {
+        let ids = info
+            .retransmits
+            .stream
+            .iter()
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        assert!(ids.contains(&a) && ids.contains(&b));
+    }
+}
+
+#[test]
+fn remote_stream_count() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    assert_eq!(
+        pair.server
+            .remote_stream_count(server_conn, Directionality::Uni),
+        0
+    );
+
+    // Using the second stream implicitly opens the first
+    let a = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let b = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, b, b"hello").unwrap();
+    pair.drive();
+    assert_eq!(
+        pair.server
+            .remote_stream_count(server_conn, Directionality::Uni),
+        2
+    );
+    assert_eq!(
+        pair.server
+            .remote_stream_count(server_conn, Directionality::Bi),
+        0
+    );
+
+    // Streams no longer count once they're finished and fully read
+    pair.client.finish(client_conn, a).unwrap();
+    pair.drive();
+    assert_matches!(
+        pair.server.read_unordered(server_conn, a),
+        Err(ReadError::Finished)
+    );
+    assert_eq!(
+        pair.server
+            .remote_stream_count(server_conn, Directionality::Uni),
+        1
+    );
+}
+
+#[test]
+fn stream_priority() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    let low = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    let high = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.set_priority(client_conn, high, 1).unwrap();
+    assert_eq!(pair.client.write(client_conn, low, &[0; 3000]), Ok(3000));
+    assert_eq!(pair.client.write(client_conn, high, &[0; 3000]), Ok(3000));
+    pair.drive_client();
+
+    // sent_packets is ordered by packet number, so this is the order frames were sent in
+    let ids = pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .sent_packets
+        .values()
+        .flat_map(|info| info.retransmits.stream.iter().map(|x| x.id))
+        .collect::<Vec<_>>();
+    let first_low = ids.iter().position(|&x| x == low).unwrap();
+    let last_high = ids.iter().rposition(|&x| x == high).unwrap();
+    assert!(last_high < first_low);
+
+    // Receive-only streams have no outgoing data to prioritize
+    assert_eq!(
+        pair.server.set_priority(server_conn, high, 1),
+        Err(InvalidStreamError)
+    );
+}
+
+#[test]
+fn anti_amplification() {
+    let mut pair = Pair::default();
+    pair.client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    pair.drive_server();
+    {
+        // The server's first flight is larger than it may send in response to a single Initial
+        let (_, conn) = pair.server.connections.iter().next().unwrap();
+        assert!(conn.wire_bytes_sent <= 3 * conn.wire_bytes_recvd);
+        assert!(conn.wire_bytes_sent > 2 * conn.wire_bytes_recvd);
+    }
+
+    // The client's acknowledgements earn the server enough to finish the handshake
+    pair.drive();
+    let server_conn = pair.server.accept().expect("server didn't connect");
+    assert!(pair.server.connections[server_conn.0].address_validated);
+}
+
+#[test]
+fn anti_amplification_unanswered() {
+    let mut pair = Pair::default();
+    pair.client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive_client();
+    let recvd = pair
+        .server
+        .inbound
+        .iter()
+        .map(|&(_, ref x)| x.len())
+        .sum::<usize>();
+
+    // Nothing the server sends is answered, as if the client's address had been spoofed
+    let mut sent = 0;
+    let mut retransmits: u32 = 0;
+    loop {
+        pair.drive_server();
+        sent += pair
+            .client
+            .inbound
+            .drain(..)
+            .map(|(_, x)| x.len())
+            .sum::<usize>();
+        if let Some((_, conn)) = pair.server.connections.iter().next() {
+            retransmits = retransmits.max(conn.handshake_count);
+        }
+        let next = pair.server.loss.min(pair.server.close);
+        if next == u64::max_value() {
+            break;
+        }
+        pair.time = pair.time.max(next);
+    }
+    assert!(retransmits > 0);
+    assert!(sent <= 3 * recvd);
+}
+
+#[test]
+fn max_connections() {
+    let server_config = Config {
+        max_connections: 1,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    pair.connect();
+
+    // A second concurrent connection exceeds the limit
+    let client_conn = pair
+        .client
+        .connect(pair.time, pair.server.addr, &client_config(), "localhost")
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.server.accept(), None);
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::ConnectionClosed {
+                        reason: ConnectionClose { error_code: TransportError::SERVER_BUSY, .. }
+                    }})) if conn == client_conn);
+}
+
+#[test]
+fn close_all() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    pair.server.close_all(pair.time, 42, Bytes::new());
+    assert!(!pair.server.is_drained());
+    pair.drive();
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::ApplicationClosed {
+                        reason: ApplicationClose { error_code: 42, .. }
+                    }})) if conn == client_conn);
+    assert_matches!(pair.server.poll(), Some((conn, Event::ConnectionDrained)) if conn == server_conn);
+    assert!(pair.server.is_drained());
+    assert!(!pair.server.connections.contains(server_conn.0));
+}
+
+#[test]
+fn simultaneous_close() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    pair.client.close(pair.time, client_conn, 0, Bytes::new());
+    pair.server.close(pair.time, server_conn, 0, Bytes::new());
+    let start = pair.time;
+    pair.drive();
+    assert_matches!(pair.client.poll(), Some((conn, Event::ConnectionDrained)) if conn == client_conn);
+    assert_matches!(pair.server.poll(), Some((conn, Event::ConnectionDrained)) if conn == server_conn);
+    // Each side's close told the other there was nothing left to wait for
+    assert!(pair.time - start < pair.server.ctx.config.min_rto_timeout);
+}
+
+#[test]
+fn stale_close_timer() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    pair.client.close(pair.time, client_conn, 0, Bytes::new());
+    pair.server.close(pair.time, server_conn, 0, Bytes::new());
+    let deadline = pair.server.connections[server_conn.0].close_time.unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::ConnectionDrained)) if conn == server_conn);
+
+    // The drained connection's slot is reused
+    let (_, new_conn) = pair.connect();
+    assert_eq!(new_conn, server_conn);
+
+    // The timer the original close scheduled fires regardless
+    let now = pair.time.max(deadline);
+    pair.server.timeout(now, server_conn, Timer::Close);
+    assert_matches!(pair.server.poll(), None);
+    assert!(!pair.server.connections[new_conn.0]
+        .state
+        .as_ref()
+        .unwrap()
+        .is_closed());
+}
+
+#[test]
+fn poll_forgotten_connection() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+
+    // Closing and draining the connection causes the client to forget it
+    pair.client.close(pair.time, client_conn, 0, Bytes::new());
+    pair.drive();
+    assert!(!pair.client.connections.contains(client_conn.0));
+
+    // A handle that lingered after being forgotten must be skipped, not dereferenced
+    pair.client.ctx.readable_conns.insert(client_conn);
+    pair.client.ctx.dirty_conns.insert(client_conn);
+    while pair.client.poll().is_some() {}
+    while pair.client.poll_io(pair.time).is_some() {}
+    assert!(pair.client.ctx.readable_conns.is_empty());
+    assert!(pair.client.ctx.dirty_conns.is_empty());
+}
+
+#[test]
+fn retransmit_before_fresh_data() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, s, &[0; 3000]), Ok(3000));
+    pair.drive_client();
+    // Lose the packet carrying the start of the stream
+    pair.server.inbound.pop_front();
+    // Queue fresh data that won't be sent until after the loss is detected
+    assert_eq!(pair.client.write(client_conn, s, &[0; 1000]), Ok(1000));
+    loop {
+        pair.drive_server();
+        if !pair.client.inbound.is_empty() {
+            break;
+        }
+        pair.time = pair.server.next_wakeup();
+    }
+    let largest_sent =
+        pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize].largest_sent_packet;
+    pair.drive_client();
+
+    let offsets = pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .sent_packets
+        .range(largest_sent + 1..)
+        .flat_map(|(_, info)| info.retransmits.stream.iter().map(|x| x.offset))
+        .collect::<Vec<_>>();
+    let lost = offsets.iter().position(|&x| x == 0).unwrap();
+    let fresh = offsets.iter().position(|&x| x >= 3000).unwrap();
+    assert!(lost < fresh);
+}
+
+#[test]
+fn retransmit_shares_with_other_streams() {
+    let mut pair = Pair::default();
+    let (client_conn, _) = pair.connect();
+
+    let a = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, a, &[0; 3000]), Ok(3000));
+    let largest_sent =
+        pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize].largest_sent_packet;
+    pair.drive_client();
+    // Lose the packet carrying the start of the first stream
+    pair.server.inbound.pop_front();
+    let lost_end = pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .sent_packets[&(largest_sent + 1)]
+        .retransmits
+        .stream
+        .iter()
+        .map(|x| x.offset + x.data.len() as u64)
+        .max()
+        .unwrap();
+    // Queue fresh data on another stream, to be sent once the loss is detected
+    let b = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    assert_eq!(pair.client.write(client_conn, b, &[0; 1000]), Ok(1000));
+    loop {
+        pair.drive_server();
+        if !pair.client.inbound.is_empty() {
+            break;
+        }
+        pair.time = pair.server.next_wakeup();
+    }
+    let largest_sent =
+        pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize].largest_sent_packet;
+    pair.drive_client();
+
+    // The other stream's data isn't held back until all the lost data has been resent
+    let frames = pair.client.connections[client_conn.0].spaces[SpaceId::Data as usize]
+        .sent_packets
+        .range(largest_sent + 1..)
+        .flat_map(|(_, info)| info.retransmits.stream.iter().map(|x| (x.id, x.offset)))
+        .collect::<Vec<_>>();
+    let fresh = frames.iter().position(|&(id, _)| id == b).unwrap();
+    let lost = frames
+        .iter()
+        .rposition(|&(id, offset)| id == a && offset < lost_end)
+        .unwrap();
+    assert!(fresh < lost);
+}
+
+/// Encrypt a 1-RTT packet from `conn` carrying `payload`, bypassing the usual frame scheduling
+fn forge_packet(conn: &mut connection::Connection, payload: &[u8]) -> Box<[u8]> {
+    conn.spaces[SpaceId::Data as usize].largest_sent_packet += 1;
+    let number = conn.spaces[SpaceId::Data as usize].largest_sent_packet;
+    let mut buf = Vec::new();
+    let header = Header::Short {
+        dst_cid: conn.rem_cid,
+        number: PacketNumber::new(
+            number,
+            conn.spaces[SpaceId::Data as usize].largest_acked_packet,
+        ),
+        key_phase: conn.key_phase,
+        spin: conn.spin,
+    };
+    let partial_encode = header.encode(&mut buf);
+    let header_len = buf.len();
+    buf.extend_from_slice(payload);
+    let crypto = conn.crypto.as_ref().unwrap();
+    crypto.encrypt(number, &mut buf, header_len).unwrap();
+    partial_encode.finish(&mut buf, crypto.pn_encrypt_key(), header_len);
+    buf.into()
+}
+
+/// Count frames of type `ty` in the 1-RTT packets queued for delivery to `endpoint`'s `conn`
+fn count_frames(endpoint: &TestEndpoint, conn: ConnectionHandle, ty: frame::Type) -> usize {
+    let conn = &endpoint.connections[conn.0];
+    let crypto = conn.crypto.as_ref().unwrap();
+    let mut count = 0;
+    for &(_, ref datagram) in &endpoint.inbound {
+        // Long-header packets are rejected, as no versions are supported
+        let partial_decode = match PartialDecode::new(datagram[..].into(), conn.loc_cid.len(), &[])
+        {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let (mut packet, _) = partial_decode.finish(crypto.pn_decrypt_key(), 0).unwrap();
+        let number = match packet.header {
+            Header::Short { number, .. } => {
+                number.expand(conn.spaces[SpaceId::Data as usize].rx_packet)
+            }
+            _ => unreachable!(),
+        };
+        crypto
+            .decrypt(number, &packet.header_data, &mut packet.payload)
+            .unwrap();
+        count += frame::Iter::new(packet.payload.freeze())
+            .filter(|x| x.ty() == ty)
+            .count();
+    }
+    count
+}
+
+#[test]
+fn path_challenge_flood() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    for i in 0..5u64 {
+        let mut payload = Vec::new();
+        payload.write(frame::Type::PATH_CHALLENGE);
+        payload.write(i);
+        let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+        pair.server.inbound.push_back((pair.time, packet));
+        pair.drive_server();
+        // The client never sent any challenges, so it mustn't see the responses
+        pair.client.inbound.clear();
+    }
+    assert_eq!(
+        pair.server.connections[server_conn.0].path_responses_sent,
+        2
+    );
+
+    // The limit resets once a round trip has passed
+    pair.time += 1_000_000;
+    let mut payload = Vec::new();
+    payload.write(frame::Type::PATH_CHALLENGE);
+    payload.write(5u64);
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    pair.server.inbound.push_back((pair.time, packet));
+    pair.drive_server();
+    assert_eq!(
+        pair.server.connections[server_conn.0].path_responses_sent,
+        1
+    );
+}
+
+#[test]
+fn disable_migration() {
+    let mut pair = Pair::new(
+        Config {
+            disable_migration: true,
+            ..server_config()
+        },
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert!(
+        pair.client.connections[client_conn.0]
+            .params
+            .disable_migration
+    );
+
+    // A packet from elsewhere that fails authentication is dropped
+    let rx_packet = pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize].rx_packet;
+    let mut payload = Vec::new();
+    payload.write(frame::Type::PING);
+    let mut packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    let last = packet.len() - 1;
+    packet[last] ^= 0xff;
+    let spoofed: SocketAddr = "[::2]:9999".parse().unwrap();
+    pair.server.handle(pair.time, spoofed, packet[..].into());
+    assert_eq!(
+        pair.server.connections[server_conn.0].spaces[SpaceId::Data as usize].rx_packet,
+        rx_packet
+    );
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+    assert!(!pair.server.connections[server_conn.0]
+        .state
+        .as_ref()
+        .unwrap()
+        .is_closed());
+
+    // Only the server advertised the restriction, so the server may still migrate
+    let packet = forge_packet(&mut pair.server.connections[server_conn.0], &payload);
+    pair.client.handle(pair.time, spoofed, packet[..].into());
+    assert_matches!(pair.client.connections[client_conn.0].path_challenge, Some(ref x) if x.remote == spoofed);
+    assert!(!pair.client.connections[client_conn.0]
+        .state
+        .as_ref()
+        .unwrap()
+        .is_closed());
+}
+
+#[test]
+fn migration() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    // The client's address changes, e.g. through NAT rebinding
+    let old_addr = pair.client.addr;
+    let new_addr: SocketAddr = "[::2]:9999".parse().unwrap();
+    pair.client.addr = new_addr;
+    pair.client.ping(client_conn);
+    pair.drive_client();
+    pair.time += pair.latency;
+    pair.drive_server();
+    // The new address is probed rather than trusted
+    assert_eq!(*pair.server.get_remote_address(server_conn), old_addr);
+    assert_matches!(pair.server.connections[server_conn.0].path_challenge, Some(ref x) if x.remote == new_addr);
+
+    // Once the peer responds, the connection moves to the new address
+    pair.drive();
+    assert_eq!(*pair.server.get_remote_address(server_conn), new_addr);
+    assert!(pair.server.connections[server_conn.0]
+        .path_challenge
+        .is_none());
+}
+
+#[test]
+fn path_challenge_limits() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let spoofed: SocketAddr = "[::2]:9999".parse().unwrap();
+    let mut payload = Vec::new();
+    payload.write(frame::Type::PING);
+
+    let mut recvd = 0;
+    let mut challenges = 0;
+    let mut sent = 0;
+    for _ in 0..2 {
+        // However many packets arrive from the new address within a PTO, it's challenged once
+        for _ in 0..10 {
+            let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+            recvd += packet.len();
+            pair.server.handle(pair.time, spoofed, packet[..].into());
+        }
+        pair.server.drive(&pair.log, pair.time, pair.client.addr);
+        for &(destination, ref packet) in &pair.server.outbound {
+            if destination == spoofed {
+                challenges += 1;
+                sent += packet.len();
+            }
+        }
+        pair.drive_server();
+        // Well past a PTO
+        pair.time += 1000 * 1000;
+    }
+    assert_eq!(challenges, 2);
+    assert!(sent <= 3 * recvd);
+    // The address was never validated, since its challenges went nowhere
+    assert_matches!(pair.server.connections[server_conn.0].path_challenge, Some(ref x) if x.remote == spoofed);
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+}
+
+#[test]
+fn path_response() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    // A response matching our outstanding challenge validates the path it was issued for
+    const TOKEN: u64 = 0x0123_4567_89ab_cdef;
+    let new_addr: SocketAddr = "[::2]:9999".parse().unwrap();
+    pair.server.connections[server_conn.0].path_challenge =
+        Some(PathChallenge::new(new_addr, TOKEN));
+    let mut payload = Vec::new();
+    payload.write(frame::Type::PATH_RESPONSE);
+    payload.write(TOKEN);
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    pair.server
+        .handle(pair.time, pair.client.addr, packet[..].into());
+    assert_eq!(*pair.server.get_remote_address(server_conn), new_addr);
+    assert!(!pair.server.connections[server_conn.0]
+        .state
+        .as_ref()
+        .unwrap()
+        .is_closed());
+
+    // The client never issued a challenge, so any response is a protocol violation
+    let mut payload = Vec::new();
+    payload.write(frame::Type::PATH_RESPONSE);
+    payload.write(TOKEN);
+    let packet = forge_packet(&mut pair.server.connections[server_conn.0], &payload);
+    pair.client
+        .handle(pair.time, pair.server.addr, packet[..].into());
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::UNSOLICITED_PATH_RESPONSE
+                    }})) if conn == client_conn);
+}
+
+#[test]
+fn path_response_stale() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+    let mut ping = Vec::new();
+    ping.write(frame::Type::PING);
+
+    // The client appears at one new address, and then another before answering the challenge
+    let first: SocketAddr = "[::2]:9999".parse().unwrap();
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &ping);
+    pair.server.handle(pair.time, first, packet[..].into());
+    let token = pair.server.connections[server_conn.0]
+        .path_challenge
+        .unwrap()
+        .token;
+    let second: SocketAddr = "[::3]:9999".parse().unwrap();
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &ping);
+    pair.server.handle(pair.time, second, packet[..].into());
+
+    // A late response to the abandoned challenge is tolerated, but validates nothing
+    let mut payload = Vec::new();
+    payload.write(frame::Type::PATH_RESPONSE);
+    payload.write(token);
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    pair.server
+        .handle(pair.time, pair.client.addr, packet[..].into());
+    assert_eq!(
+        *pair.server.get_remote_address(server_conn),
+        pair.client.addr
+    );
+    assert_matches!(pair.server.connections[server_conn.0].path_challenge, Some(ref x) if x.remote == second);
+    assert_matches!(pair.server.poll(), None);
+
+    // A response matching no challenge we sent is a protocol violation
+    let mut payload = Vec::new();
+    payload.write(frame::Type::PATH_RESPONSE);
+    payload.write(!token);
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    pair.server
+        .handle(pair.time, pair.client.addr, packet[..].into());
+    assert_matches!(pair.server.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::PROTOCOL_VIOLATION
+                    }})) if conn == server_conn);
+}
+
+#[test]
+fn migration_despite_disable_migration() {
+    let mut pair = Pair::new(
+        Config {
+            disable_migration: true,
+            ..server_config()
+        },
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // The server asked not to be migrated away from, so an authenticated packet from the client on
+    // a new path is fatal
+    let mut payload = Vec::new();
+    payload.write(frame::Type::PING);
+    let packet = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    let new_addr: SocketAddr = "[::2]:9999".parse().unwrap();
+    pair.server.handle(pair.time, new_addr, packet[..].into());
+    assert_matches!(pair.server.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::PROTOCOL_VIOLATION
+                    }})) if conn == server_conn);
+
+    // The client hears why on its original path
+    pair.drive();
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::ConnectionClosed {
+                        reason: ConnectionClose { error_code: TransportError::PROTOCOL_VIOLATION, .. }
+                    }})) if conn == client_conn);
+}
+
+#[test]
+fn reserved_bits() {
+    let mut pair = Pair::default();
+    let (client_conn, server_conn) = pair.connect();
+
+    // Like `forge_packet`, but with a reserved bit set in the authenticated header
+    let packet = {
+        let conn = &mut pair.client.connections[client_conn.0];
+        conn.spaces[SpaceId::Data as usize].largest_sent_packet += 1;
+        let number = conn.spaces[SpaceId::Data as usize].largest_sent_packet;
+        let mut buf = Vec::new();
+        let header = Header::Short {
+            dst_cid: conn.rem_cid,
+            number: PacketNumber::new(
+                number,
+                conn.spaces[SpaceId::Data as usize].largest_acked_packet,
+            ),
+            key_phase: conn.key_phase,
+            spin: conn.spin,
+        };
+        let partial_encode = header.encode(&mut buf);
+        buf[0] |= 0x01;
+        let header_len = buf.len();
+        buf.write(frame::Type::PING);
+        let crypto = conn.crypto.as_ref().unwrap();
+        crypto.encrypt(number, &mut buf, header_len).unwrap();
+        partial_encode.finish(&mut buf, crypto.pn_encrypt_key(), header_len);
+        buf
+    };
+    pair.server.inbound.push_back((pair.time, packet.into()));
+    pair.drive_server();
+    assert_matches!(pair.server.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::PROTOCOL_VIOLATION
+                    }})) if conn == server_conn);
+}
+
+/// Deliver `payload` from the client, which a server should only reject in strict mode
+fn check_strict(payload: &[u8], error: TransportError) {
+    for &strict in &[false, true] {
+        let server_config = Config {
+            strict,
+            max_remote_uni_streams: 1,
+            max_remote_bi_streams: 1,
+            ..server_config()
+        };
+        let mut pair = Pair::new(
+            server_config,
+            Default::default(),
+            ListenKeys::new(&mut rand::thread_rng()),
+        );
+        let (client_conn, server_conn) = pair.connect();
+
+        let packet = forge_packet(&mut pair.client.connections[client_conn.0], payload);
+        pair.server.inbound.push_back((pair.time, packet));
+        pair.drive();
+        if strict {
+            assert_matches!(pair.server.poll(),
+                            Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                                error_code
+                            }})) if conn == server_conn && error_code == error);
+        } else {
+            assert!(!pair.server.connections[server_conn.0]
+                .state
+                .as_ref()
+                .unwrap()
+                .is_closed());
+        }
+    }
+}
+
+#[test]
+fn strict_stream_blocked() {
+    // STREAM_BLOCKED on a stream the server can only send on
+    let mut payload = Vec::new();
+    payload.write(frame::Type::STREAM_BLOCKED);
+    payload.write(StreamId::new(Side::Server, Directionality::Uni, 0));
+    payload.write_var(0);
+    check_strict(&payload, TransportError::PROTOCOL_VIOLATION);
+}
+
+#[test]
+fn strict_max_stream_id() {
+    // Only the server may raise the limit on streams the client initiates
+    let mut payload = Vec::new();
+    payload.write(frame::Type::MAX_STREAM_ID);
+    payload.write(StreamId::new(Side::Client, Directionality::Bi, 100));
+    check_strict(&payload, TransportError::STREAM_ID_ERROR);
+}
+
+#[test]
+fn strict_blocked() {
+    // Far beyond any limit the server advertised
+    let mut payload = Vec::new();
+    payload.write(frame::Type::BLOCKED);
+    payload.write_var(1 << 40);
+    check_strict(&payload, TransportError::PROTOCOL_VIOLATION);
+}
+
+#[test]
+fn strict_rst_stream_final_offset() {
+    // Reset a stream below the end of data already sent on it
+    let s = StreamId::new(Side::Client, Directionality::Uni, 0);
+    let mut payload = Vec::new();
+    frame::Stream {
+        id: s,
+        offset: 0,
+        fin: false,
+        data: &[0; 10][..],
+    }.encode(true, &mut payload);
+    payload.write(frame::Type::RST_STREAM);
+    payload.write(s);
+    payload.write(0u16);
+    payload.write_var(5);
+    check_strict(&payload, TransportError::FINAL_OFFSET_ERROR);
+}
+
+#[test]
+fn strict_max_data() {
+    // Below the limit set by the client's transport parameters
+    let mut payload = Vec::new();
+    payload.write(frame::Type::MAX_DATA);
+    payload.write_var(0);
+    check_strict(&payload, TransportError::PROTOCOL_VIOLATION);
+}
+
+#[test]
+fn strict_max_stream_data() {
+    let mut payload = Vec::new();
+    payload.write(frame::Type::MAX_STREAM_DATA);
+    payload.write(StreamId::new(Side::Client, Directionality::Bi, 0));
+    payload.write_var(0);
+    check_strict(&payload, TransportError::PROTOCOL_VIOLATION);
+}
+
+#[test]
+fn strict_max_data_reordered() {
+    let server_config = Config {
+        strict: true,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    let initial = pair.server.connections[server_conn.0].max_data;
+
+    let mut payload = Vec::new();
+    payload.write(frame::Type::MAX_DATA);
+    payload.write_var(initial + 1000);
+    let first = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+    let mut payload = Vec::new();
+    payload.write(frame::Type::MAX_DATA);
+    payload.write_var(initial + 2000);
+    let second = forge_packet(&mut pair.client.connections[client_conn.0], &payload);
+
+    // The older, lower limit arrives last, which isn't the peer's doing
+    pair.server
+        .handle(pair.time, pair.client.addr, second[..].into());
+    pair.server
+        .handle(pair.time, pair.client.addr, first[..].into());
+    assert!(!pair.server.connections[server_conn.0]
+        .state
+        .as_ref()
+        .unwrap()
+        .is_closed());
+    assert_eq!(
+        pair.server.connections[server_conn.0].max_data,
+        initial + 2000
+    );
+}
+
+#[test]
+fn max_packet_size() {
+    let mut pair = Pair::new(
+        Config {
+            max_packet_size: 1400,
+            max_remote_uni_streams: 1,
+            ..server_config()
+        },
+        Config {
+            max_packet_size: 1500,
+            ..Config::default()
+        },
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    // The smaller of the two limits wins
+    assert_eq!(pair.client.connections[client_conn.0].mtu, 1400);
+    assert_eq!(pair.server.connections[server_conn.0].mtu, 1400);
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, &[0; 4000]).unwrap();
+    pair.drive_client();
+    let largest = pair.server.inbound.iter().map(|x| x.1.len()).max();
+    assert_eq!(largest, Some(1400));
+}
+
+#[test]
+fn datagram() {
+    let server_config = Config {
+        max_datagram_frame_size: 1200,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    const MSG: &[u8] = b"hello";
+    pair.client
+        .send_datagram(client_conn, Bytes::from(MSG))
+        .unwrap();
+    pair.drive();
+    assert_matches!(pair.server.poll(), Some((conn, Event::DatagramReceived { ref data })) if conn == server_conn && data[..] == *MSG);
+
+    // Too large for the server's limit
+    assert_matches!(
+        pair.client
+            .send_datagram(client_conn, Bytes::from(vec![0; 1200])),
+        Err(DatagramError::TooLarge)
+    );
+    // The client didn't enable datagrams
+    assert_matches!(
+        pair.server.send_datagram(server_conn, Bytes::from(MSG)),
+        Err(DatagramError::UnsupportedByPeer)
+    );
+
+    // So receiving one anyway is a protocol violation
+    let mut payload = Vec::new();
+    frame::Datagram { data: MSG }.encode(&mut payload);
+    let packet = forge_packet(&mut pair.server.connections[server_conn.0], &payload);
+    pair.client.inbound.push_back((pair.time, packet));
+    pair.drive();
+    assert_matches!(pair.client.poll(),
+                    Some((conn, Event::ConnectionLost { reason: ConnectionError::TransportError {
+                        error_code: TransportError::PROTOCOL_VIOLATION
+                    }})) if conn == client_conn);
+}
+
+#[test]
+fn datagram_queue_limit() {
+    let server_config = Config {
+        max_datagram_frame_size: 1200,
+        ..server_config()
+    };
+    let client_config = Config {
+        max_queued_datagrams: 2,
+        ..Config::default()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        client_config,
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+
+    // The oldest datagram is dropped to make room for the newest
+    for &x in &[1, 2, 3] {
+        pair.client
+            .send_datagram(client_conn, Bytes::from(vec![x]))
+            .unwrap();
+    }
+    pair.drive();
+    let mut received = Vec::new();
+    while let Some((conn, event)) = pair.server.poll() {
+        if let Event::DatagramReceived { data } = event {
+            assert_eq!(conn, server_conn);
+            received.push(data[0]);
+        }
+    }
+    assert_eq!(received, [2, 3]);
+}
+
+#[test]
+fn larger_than_mtu() {
+    let server_config = Config {
+        max_datagram_frame_size: 1200,
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    let mtu = pair.client.connections[client_conn.0].mtu as usize;
+
+    // More stream data than fits in a packet, alongside the largest datagram that does
+    let msg = vec![0xab; 3 * mtu];
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    pair.client.write(client_conn, s, &msg).unwrap();
+    let datagram_len = (0..mtu)
+        .rev()
+        .find(|&len| {
+            pair.client
+                .send_datagram(client_conn, vec![0xcd; len].into())
+                .is_ok()
+        }).unwrap();
+
+    while pair.step() {
+        for &(_, ref packet) in pair.server.inbound.iter().chain(&pair.client.inbound) {
+            assert!(packet.len() <= mtu);
+        }
+    }
+    let mut datagram = None;
+    while let Some((conn, event)) = pair.server.poll() {
+        if let Event::DatagramReceived { data } = event {
+            assert_eq!(conn, server_conn);
+            datagram = Some(data);
+        }
+    }
+    assert_eq!(datagram.map(|x| x.len()), Some(datagram_len));
+    let mut buf = vec![0; msg.len()];
+    assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(msg.len()));
+    assert_eq!(buf, msg);
+}
+
+/// Delegates to `SoftwareProtection`, counting the keys constructed
+struct CountingProtection(AtomicUsize);
+
+impl PacketProtection for CountingProtection {
+    fn sealing_key(&self, cipher: Cipher, key: &[u8]) -> Box<PacketSealingKey> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        SoftwareProtection.sealing_key(cipher, key)
+    }
+
+    fn opening_key(&self, cipher: Cipher, key: &[u8]) -> Box<PacketOpeningKey> {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        SoftwareProtection.opening_key(cipher, key)
+    }
+}
+
+#[test]
+fn custom_packet_protection() {
+    let protection = Arc::new(CountingProtection(AtomicUsize::new(0)));
+    let server_config = Config {
+        max_remote_uni_streams: 1,
+        packet_protection: protection.clone(),
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    let (client_conn, server_conn) = pair.connect();
+    assert_ne!(protection.0.load(Ordering::Relaxed), 0);
+
+    let s = pair.client.open(client_conn, Directionality::Uni).unwrap();
+    const MSG: &[u8] = b"hello";
+    pair.client.write(client_conn, s, MSG).unwrap();
+    pair.drive();
+    let mut buf = [0; 16];
+    assert_eq!(pair.server.read(server_conn, s, &mut buf), Ok(MSG.len()));
+    assert_eq!(&buf[..MSG.len()], MSG);
+}
+
+/// In-memory log output that remains accessible after being handed off
+#[cfg(any(feature = "qlog", feature = "keylog"))]
+#[derive(Clone, Default)]
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+#[cfg(any(feature = "qlog", feature = "keylog"))]
+impl SharedBuffer {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+#[cfg(any(feature = "qlog", feature = "keylog"))]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(feature = "qlog")]
+#[test]
+fn qlog() {
+    let buffer = SharedBuffer::default();
+    let server_config = Config {
+        qlog: Some(Arc::new(QlogSink::new(buffer.clone()))),
+        ..server_config()
+    };
+    let mut pair = Pair::new(
+        server_config,
+        Default::default(),
+        ListenKeys::new(&mut rand::thread_rng()),
+    );
+    pair.connect();
+    // Dropping the sink completes the document
+    drop(pair);
+
+    let log: ::serde_json::Value = ::serde_json::from_str(&buffer.contents()).unwrap();
+    assert_eq!(log["qlog_version"], "draft-01");
+    let trace = &log["traces"][0];
+    assert_eq!(
+        trace["event_fields"],
+        json!(["time", "group_id", "category", "event", "data"])
+    );
+    let events = trace["events"].as_array().unwrap();
+    for &(category, event) in &[
+        ("transport", "packet_sent"),
+        ("transport", "packet_received"),
+        ("transport", "frame_received"),
+        ("recovery", "metrics_updated"),
+    ] {
+        assert!(events.iter().any(|x| x[2] == category && x[3] == event));
+    }
+    // The client's first flight
+    assert!(events
+        .iter()
+        .any(|x| x[3] == "packet_received" && x[4]["packet_type"] == "initial"));
+}
+
+#[cfg(feature = "keylog")]
+#[test]
+fn keylog() {
+    let buffer = SharedBuffer::default();
+    let mut tls_config = (*client_config()).clone();
+    tls_config.key_log = Arc::new(KeyLogWriter::new(buffer.clone()));
+    let tls_config = Arc::new(tls_config);
+    let mut pair = Pair::default();
+    pair.client
+        .connect(pair.time, pair.server.addr, &tls_config, "localhost")
+        .unwrap();
+    pair.drive();
+    assert!(pair.server.accept().is_some());
+
+    let log = buffer.contents();
+    assert!(!log.is_empty());
+    for line in log.lines() {
+        let fields = line.split(' ').collect::<Vec<_>>();
+        assert_eq!(fields.len(), 3);
+        // Hex-encoded 32-byte client random
+        assert_eq!(fields[1].len(), 64);
+    }
+}
@@ -0,0 +1,162 @@
+//! Address validation tokens
+//!
+//! A token binds a client's IP address to the time it was issued, authenticated with an HMAC keyed
+//! by `ListenKeys::cookie`. Clients echo tokens back in the Initial packets of later connection
+//! attempts, proving that they can receive traffic at the address they claim.
+
+use std::net::SocketAddr;
+
+use bytes::{BigEndian, ByteOrder};
+use constant_time_eq::constant_time_eq;
+use ring::digest;
+use ring::hmac::{self, SigningKey};
+
+use ip_octets;
+
+/// Format version of tokens we issue. Bump this when the layout or key derivation changes, so that
+/// tokens issued under the old scheme are rejected rather than misinterpreted.
+const VERSION: u8 = 1;
+const MAC_SIZE: usize = 32;
+const TOKEN_SIZE: usize = 1 + 8 + MAC_SIZE;
+
+/// How long a token remains valid after it's issued (μs)
+pub const LIFETIME: u64 = 10 * 60 * 1000 * 1000;
+
+/// Produce a token attesting that `remote` could be reached at time `now`
+pub fn generate(cookie: &[u8; 64], remote: &SocketAddr, now: u64) -> Box<[u8]> {
+    let mut buf = [0; TOKEN_SIZE];
+    buf[0] = VERSION;
+    BigEndian::write_u64(&mut buf[1..9], now);
+    let signature = sign(cookie, &buf[..9], remote);
+    buf[9..].copy_from_slice(signature.as_ref());
+    buf[..].into()
+}
+
+/// Check that `token` was issued by us to `remote` no more than `lifetime` μs before `now`
+pub fn validate(
+    cookie: &[u8; 64],
+    remote: &SocketAddr,
+    token: &[u8],
+    now: u64,
+    lifetime: u64,
+) -> Result<(), Error> {
+    if token.len() != TOKEN_SIZE {
+        return Err(Error::Malformed);
+    }
+    if token[0] != VERSION {
+        return Err(Error::UnknownVersion);
+    }
+    let signature = sign(cookie, &token[..9], remote);
+    if !constant_time_eq(signature.as_ref(), &token[9..]) {
+        return Err(Error::Invalid);
+    }
+    let issued = BigEndian::read_u64(&token[1..9]);
+    if issued > now || now - issued > lifetime {
+        return Err(Error::Expired);
+    }
+    Ok(())
+}
+
+fn sign(cookie: &[u8; 64], header: &[u8], remote: &SocketAddr) -> hmac::Signature {
+    let key = SigningKey::new(&digest::SHA256, cookie);
+    let mut ctx = hmac::SigningContext::with_key(&key);
+    ctx.update(header);
+    ctx.update(&ip_octets(remote));
+    ctx.sign()
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Fail)]
+pub enum Error {
+    #[fail(display = "malformed token")]
+    Malformed,
+    #[fail(display = "unsupported token version")]
+    UnknownVersion,
+    #[fail(display = "token failed authentication")]
+    Invalid,
+    #[fail(display = "token expired")]
+    Expired,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const COOKIE: [u8; 64] = [0xab; 64];
+
+    fn remote() -> SocketAddr {
+        "[::1]:4433".parse().unwrap()
+    }
+
+    #[test]
+    fn roundtrip() {
+        let token = generate(&COOKIE, &remote(), 1000);
+        assert_eq!(validate(&COOKIE, &remote(), &token, 2000, LIFETIME), Ok(()));
+    }
+
+    #[test]
+    fn forged() {
+        let mut token = generate(&COOKIE, &remote(), 1000);
+        let last = token.len() - 1;
+        token[last] ^= 1;
+        assert_eq!(
+            validate(&COOKIE, &remote(), &token, 2000, LIFETIME),
+            Err(Error::Invalid)
+        );
+
+        // Issue time is covered by the MAC
+        let mut token = generate(&COOKIE, &remote(), 1000);
+        token[8] ^= 1;
+        assert_eq!(
+            validate(&COOKIE, &remote(), &token, 2000, LIFETIME),
+            Err(Error::Invalid)
+        );
+
+        let other_key = [0xcd; 64];
+        let token = generate(&other_key, &remote(), 1000);
+        assert_eq!(
+            validate(&COOKIE, &remote(), &token, 2000, LIFETIME),
+            Err(Error::Invalid)
+        );
+    }
+
+    #[test]
+    fn wrong_address() {
+        let token = generate(&COOKIE, &remote(), 1000);
+        let other = "[::2]:4433".parse().unwrap();
+        assert_eq!(
+            validate(&COOKIE, &other, &token, 2000, LIFETIME),
+            Err(Error::Invalid)
+        );
+    }
+
+    #[test]
+    fn expired() {
+        let token = generate(&COOKIE, &remote(), 1000);
+        assert_eq!(
+            validate(&COOKIE, &remote(), &token, 1000 + LIFETIME, LIFETIME),
+            Ok(())
+        );
+        assert_eq!(
+            validate(&COOKIE, &remote(), &token, 1001 + LIFETIME, LIFETIME),
+            Err(Error::Expired)
+        );
+        assert_eq!(
+            validate(&COOKIE, &remote(), &token, 999, LIFETIME),
+            Err(Error::Expired)
+        );
+    }
+
+    #[test]
+    fn versioned() {
+        let mut token = generate(&COOKIE, &remote(), 1000);
+        token[0] = VERSION + 1;
+        assert_eq!(
+            validate(&COOKIE, &remote(), &token, 2000, LIFETIME),
+            Err(Error::UnknownVersion)
+        );
+        assert_eq!(
+            validate(&COOKIE, &remote(), &token[..10], 2000, LIFETIME),
+            Err(Error::Malformed)
+        );
+    }
+}
@@ -0,0 +1,85 @@
+use std::cmp;
+
+/// Limits the rate of an event, while permitting bursts up to a fixed size
+#[derive(Debug, Copy, Clone)]
+pub struct TokenBucket {
+    /// Maximum number of events permitted in a burst
+    capacity: u32,
+    /// Events permitted per second, sustained
+    rate: u32,
+    /// Available events, in millionths
+    tokens: u64,
+    /// Time `tokens` was last brought up to date (μs)
+    updated: u64,
+}
+
+impl TokenBucket {
+    /// Construct a bucket that is initially full
+    pub fn new(capacity: u32, rate: u32, now: u64) -> Self {
+        Self {
+            capacity,
+            rate,
+            tokens: u64::from(capacity) * 1_000_000,
+            updated: now,
+        }
+    }
+
+    /// Consume a token if one is available, returning whether the event may proceed
+    pub fn take(&mut self, now: u64) -> bool {
+        self.refill(now);
+        if self.tokens < 1_000_000 {
+            return false;
+        }
+        self.tokens -= 1_000_000;
+        true
+    }
+
+    /// Whether a token is available, without consuming it
+    pub fn available(&mut self, now: u64) -> bool {
+        self.refill(now);
+        self.tokens >= 1_000_000
+    }
+
+    /// Whether the bucket has refilled completely, i.e. is indistinguishable from a new one
+    pub fn is_full(&self, now: u64) -> bool {
+        self.tokens_at(now) == u64::from(self.capacity) * 1_000_000
+    }
+
+    /// Time the bucket was last consulted by `take` or `available` (μs)
+    pub fn last_used(&self) -> u64 {
+        self.updated
+    }
+
+    fn refill(&mut self, now: u64) {
+        self.tokens = self.tokens_at(now);
+        self.updated = cmp::max(self.updated, now);
+    }
+
+    fn tokens_at(&self, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.updated);
+        cmp::min(
+            self.tokens
+                .saturating_add(elapsed.saturating_mul(u64::from(self.rate))),
+            u64::from(self.capacity) * 1_000_000,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn burst_then_rate() {
+        let mut bucket = TokenBucket::new(3, 2, 0);
+        for _ in 0..3 {
+            assert!(bucket.take(0));
+        }
+        assert!(!bucket.take(0));
+        assert!(!bucket.take(499_999));
+        assert!(bucket.take(500_000));
+        assert!(!bucket.take(500_000));
+        assert!(!bucket.is_full(1_500_000));
+        assert!(bucket.is_full(2_000_000));
+    }
+}
@@ -52,7 +52,8 @@ macro_rules! errors {
                 }
                 let x = match self.0 {
                     $($val => $desc,)*
-                    _ => "unknown error"
+                    // Codes from future versions or extensions must still be reported sensibly
+                    _ => return write!(f, "unknown error {:#06x}", self.0),
                 };
                 f.write_str(x)
             }
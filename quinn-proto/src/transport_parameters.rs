@@ -1,5 +1,5 @@
 use std::{
-    mem,
+    cmp, mem,
     net::{IpAddr, SocketAddr},
 };
 
@@ -8,7 +8,7 @@ use bytes::{Buf, BufMut};
 use coding::{BufExt, BufMutExt};
 use endpoint::Config;
 use packet::ConnectionId;
-use {Side, TransportError, MAX_CID_SIZE, MIN_CID_SIZE, VERSION};
+use {Side, TransportError, MAX_CID_SIZE, MIN_CID_SIZE};
 
 // Apply a given macro to a list of all the transport parameters having simple integer types, along with their codes and
 // default values. Using this helps us avoid error-prone duplication of the contained information across decoding,
@@ -30,17 +30,22 @@ macro_rules! apply_params {
             max_packet_size(0x0005): u16 = 65527,
             ack_delay_exponent(0x0007): u8 = 3,
             max_ack_delay(0x000c): u8 = 25,
+            max_datagram_frame_size(0x0020): u16 = 0,
         }
     };
 }
 
 macro_rules! make_struct {
     {$($name:ident ($code:expr) : $ty:ty = $default:expr,)*} => {
+        /// Limits and settings an endpoint declares to its peer during the handshake
         #[derive(Debug, Copy, Clone, Eq, PartialEq)]
         pub struct TransportParameters {
             $(pub $name : $ty,)*
 
             pub disable_migration: bool,
+            /// Reserved parameter ID of the form `0x?a?a` to send with an empty value, which the
+            /// peer must ignore. Never set on decoded parameters.
+            pub grease: Option<u16>,
 
             // Server-only
             pub original_connection_id: Option<ConnectionId>,
@@ -55,6 +60,7 @@ macro_rules! make_struct {
                     $($name: $default,)*
 
                     disable_migration: false,
+                    grease: None,
 
                     original_connection_id: None,
                     stateless_reset_token: None,
@@ -77,12 +83,17 @@ impl TransportParameters {
             initial_max_stream_data_bidi_remote: config.stream_receive_window,
             initial_max_stream_data_uni: config.stream_receive_window,
             idle_timeout: config.idle_timeout,
-            max_ack_delay: 0, // Unimplemented
+            ack_delay_exponent: config.ack_delay_exponent,
+            max_ack_delay: cmp::min((config.delayed_ack_timeout + 999) / 1000, 255) as u8,
+            max_datagram_frame_size: config.max_datagram_frame_size,
+            max_packet_size: config.max_packet_size,
+            disable_migration: config.disable_migration,
             ..Self::default()
         }
     }
 }
 
+/// An address a server would prefer clients migrate to after the handshake
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct PreferredAddress {
     address: SocketAddr,
@@ -188,14 +199,20 @@ impl From<Error> for TransportError {
 }
 
 impl TransportParameters {
-    pub fn write<W: BufMut>(&self, side: Side, w: &mut W) {
+    /// Encode the parameters along with version negotiation information
+    ///
+    /// A server writes `version` as the negotiated version, followed by `supported`, which should
+    /// include a reserved version. A client writes `version` as the version it initially requested
+    /// and ignores `supported`.
+    pub fn write<W: BufMut>(&self, side: Side, version: u32, supported: &[u32], w: &mut W) {
         if side == Side::Server {
-            w.write::<u32>(VERSION); // Negotiated version
-            w.write::<u8>(8); // Bytes of supported versions
-            w.write::<u32>(0x0a1a_2a3a); // Reserved version
-            w.write::<u32>(VERSION); // Real supported version
+            w.write::<u32>(version); // Negotiated version
+            w.write::<u8>(4 * supported.len() as u8); // Bytes of supported versions
+            for &x in supported {
+                w.write::<u32>(x);
+            }
         } else {
-            w.write::<u32>(VERSION); // Initially requested version
+            w.write::<u32>(version); // Initially requested version
         }
 
         let mut buf = Vec::new();
@@ -236,23 +253,40 @@ impl TransportParameters {
             buf.write::<u16>(0);
         }
 
+        if let Some(x) = self.grease {
+            buf.write::<u16>(x);
+            buf.write::<u16>(0);
+        }
+
         w.write::<u16>(buf.len() as u16);
         w.put_slice(&buf);
     }
 
-    pub fn read<R: Buf>(side: Side, r: &mut R) -> Result<Self, Error> {
+    /// Decode the parameters sent by the peer of a `side` endpoint
+    ///
+    /// `version` is the version in use on the connection. A server additionally uses `supported` to
+    /// detect a downgrade forced by tampering with version negotiation.
+    pub fn read<R: Buf>(
+        side: Side,
+        version: u32,
+        supported: &[u32],
+        r: &mut R,
+    ) -> Result<Self, Error> {
         if side == Side::Server {
             if r.remaining() < 26 {
                 return Err(Error::Malformed);
             }
-            // We only support one version, so there is no validation to do here.
-            r.get::<u32>().unwrap();
+            let initial = r.get::<u32>().unwrap();
+            if initial != version && supported.contains(&initial) {
+                // The client would have used its initial version had it not been told otherwise
+                return Err(Error::VersionNegotiation);
+            }
         } else {
             if r.remaining() < 31 {
                 return Err(Error::Malformed);
             }
             let negotiated = r.get::<u32>().unwrap();
-            if negotiated != VERSION {
+            if negotiated != version {
                 return Err(Error::VersionNegotiation);
             }
             let supported_bytes = r.get::<u8>().unwrap();
@@ -367,6 +401,7 @@ impl TransportParameters {
 mod test {
     use super::*;
     use bytes::IntoBuf;
+    use VERSION;
 
     #[test]
     fn coding() {
@@ -383,10 +418,30 @@ mod test {
             }),
             ..TransportParameters::default()
         };
-        params.write(Side::Server, &mut buf);
+        params.write(Side::Server, VERSION, &[VERSION], &mut buf);
         assert_eq!(
-            TransportParameters::read(Side::Client, &mut buf.into_buf()).unwrap(),
+            TransportParameters::read(Side::Client, VERSION, &[], &mut buf.into_buf()).unwrap(),
             params
         );
     }
+
+    #[test]
+    fn grease_ignored() {
+        let mut buf = Vec::new();
+        let params = TransportParameters {
+            initial_max_bidi_streams: 16,
+            initial_max_uni_streams: 16,
+            initial_max_data: 1024,
+            grease: Some(0x3a7a),
+            ..TransportParameters::default()
+        };
+        params.write(Side::Server, VERSION, &[0x4a5a_6a7a, VERSION], &mut buf);
+        assert_eq!(
+            TransportParameters::read(Side::Client, VERSION, &[], &mut buf.into_buf()).unwrap(),
+            TransportParameters {
+                grease: None,
+                ..params
+            }
+        );
+    }
 }
@@ -68,7 +68,11 @@ extern crate webpki_roots;
 use std::borrow::Cow;
 use std::cell::RefCell;
 use std::collections::{hash_map, VecDeque};
+#[cfg(feature = "keylog")]
+use std::fs::OpenOptions;
 use std::net::{SocketAddr, SocketAddrV6, ToSocketAddrs};
+#[cfg(feature = "keylog")]
+use std::path::Path;
 use std::rc::Rc;
 use std::str;
 use std::sync::Arc;
@@ -82,7 +86,9 @@ use futures::task::{self, Task};
 use futures::unsync::oneshot;
 use futures::Stream as FuturesStream;
 use futures::{Async, Future, Poll, Sink};
-use rustls::{Certificate, KeyLogFile, PrivateKey, ProtocolVersion, TLSError};
+use rustls::{Certificate, PrivateKey, ProtocolVersion, TLSError};
+#[cfg(feature = "keylog")]
+use rustls::{KeyLog, KeyLogFile};
 use slog::Logger;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_timer::Delay;
@@ -137,7 +143,9 @@ struct EndpointInner {
     log: Logger,
     socket: UdpSocket,
     inner: quinn::Endpoint,
-    outgoing: VecDeque<(SocketAddrV6, Box<[u8]>)>,
+    outgoing: VecDeque<(SocketAddr, Box<[u8]>)>,
+    /// Whether `socket` is an IPv6 socket, which can only reach IPv4 peers via mapped addresses
+    ipv6: bool,
     epoch: Instant,
     pending: FnvHashMap<ConnectionHandle, Pending>,
     // TODO: Replace this with something custom that avoids using oneshots to cancel
@@ -163,6 +171,8 @@ struct Pending {
     bi_opening: VecDeque<oneshot::Sender<Result<StreamId, ConnectionError>>>,
     cancel_loss_detect: Option<oneshot::Sender<()>>,
     cancel_idle: Option<oneshot::Sender<()>>,
+    cancel_delayed_ack: Option<oneshot::Sender<()>>,
+    cancel_keep_alive: Option<oneshot::Sender<()>>,
     incoming_streams: VecDeque<StreamId>,
     incoming_streams_reader: Option<Task>,
     finishing: FnvHashMap<StreamId, oneshot::Sender<Option<ConnectionError>>>,
@@ -183,6 +193,8 @@ impl Pending {
             bi_opening: VecDeque::new(),
             cancel_loss_detect: None,
             cancel_idle: None,
+            cancel_delayed_ack: None,
+            cancel_keep_alive: None,
             incoming_streams: VecDeque::new(),
             incoming_streams_reader: None,
             finishing: FnvHashMap::default(),
@@ -284,10 +296,28 @@ impl<'a> EndpointBuilder<'a> {
     /// Enable NSS-compatible cryptographic key logging to the `SSLKEYLOGFILE` environment variable.
     ///
     /// Useful for debugging encrypted communications with protocol analyzers such as Wireshark.
+    #[cfg(feature = "keylog")]
     pub fn enable_keylog(&mut self) -> &mut Self {
+        self.set_keylog(Arc::new(KeyLogFile::new()))
+    }
+
+    /// Enable NSS-compatible cryptographic key logging, appending to the file at `path`.
+    #[cfg(feature = "keylog")]
+    pub fn enable_keylog_to<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::Keylog)?;
+        Ok(self.set_keylog(Arc::new(quinn::KeyLogWriter::new(file))))
+    }
+
+    /// Log cryptographic secrets negotiated by incoming connections to `key_log`.
+    #[cfg(feature = "keylog")]
+    pub fn set_keylog(&mut self, key_log: Arc<KeyLog>) -> &mut Self {
         {
             let tls_server_config = Arc::get_mut(&mut self.config.tls_server_config).unwrap();
-            tls_server_config.key_log = Arc::new(KeyLogFile::new());
+            tls_server_config.key_log = key_log;
         }
         self
     }
@@ -337,6 +367,7 @@ impl<'a> EndpointBuilder<'a> {
         } else {
             Cow::Owned(tokio_reactor::Handle::current())
         };
+        let ipv6 = socket.local_addr().map_err(Error::Socket)?.is_ipv6();
         let socket = UdpSocket::from_std(socket, &reactor).map_err(Error::Socket)?;
         let (send, recv) = futures::sync::mpsc::channel(4);
         let rc = Rc::new(RefCell::new(EndpointInner {
@@ -344,6 +375,7 @@ impl<'a> EndpointBuilder<'a> {
             socket,
             inner: quinn::Endpoint::new(self.logger, self.config, self.listen)?,
             outgoing: VecDeque::new(),
+            ipv6,
             epoch: Instant::now(),
             pending: FnvHashMap::default(),
             timers: FuturesUnordered::new(),
@@ -409,8 +441,26 @@ impl ClientConfigBuilder {
     /// Enable NSS-compatible cryptographic key logging to the `SSLKEYLOGFILE` environment variable.
     ///
     /// Useful for debugging encrypted communications with protocol analyzers such as Wireshark.
+    #[cfg(feature = "keylog")]
     pub fn enable_keylog(&mut self) -> &mut Self {
-        self.config.key_log = Arc::new(KeyLogFile::new());
+        self.set_keylog(Arc::new(KeyLogFile::new()))
+    }
+
+    /// Enable NSS-compatible cryptographic key logging, appending to the file at `path`.
+    #[cfg(feature = "keylog")]
+    pub fn enable_keylog_to<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(Error::Keylog)?;
+        Ok(self.set_keylog(Arc::new(quinn::KeyLogWriter::new(file))))
+    }
+
+    /// Log cryptographic secrets negotiated by outgoing connections to `key_log`.
+    #[cfg(feature = "keylog")]
+    pub fn set_keylog(&mut self, key_log: Arc<KeyLog>) -> &mut Self {
+        self.config.key_log = key_log;
         self
     }
 
@@ -577,9 +627,13 @@ impl Endpoint {
         let (send, recv) = oneshot::channel();
         let handle = {
             let mut endpoint = self.inner.borrow_mut();
-            let handle = endpoint
-                .inner
-                .connect(normalize(*addr), config, server_name)?;
+            let addr = if endpoint.ipv6 {
+                SocketAddr::V6(ensure_ipv6(*addr))
+            } else {
+                *addr
+            };
+            let now = micros_from(endpoint.epoch.elapsed());
+            let handle = endpoint.inner.connect(now, addr, config, server_name)?;
             endpoint.pending.insert(handle, Pending::new(Some(send)));
             handle
         };
@@ -648,9 +702,7 @@ impl Future for Driver {
             loop {
                 match endpoint.socket.poll_recv_from(&mut buf) {
                     Ok(Async::Ready((n, addr))) => {
-                        endpoint
-                            .inner
-                            .handle(now, normalize(addr), (&buf[0..n]).into());
+                        endpoint.inner.handle(now, addr, (&buf[0..n]).into());
                     }
                     Ok(Async::NotReady) => {
                         break;
@@ -715,6 +767,27 @@ impl Future for Driver {
                             }
                         }
                     }
+                    StreamStopped { stream, .. } => {
+                        // Let any blocked writer observe the failure
+                        if let Some(writer) = endpoint
+                            .pending
+                            .get_mut(&connection)
+                            .unwrap()
+                            .blocked_writers
+                            .remove(&stream)
+                        {
+                            writer.notify();
+                        }
+                    }
+                    StreamStalled { stream } => {
+                        debug!(endpoint.log, "receive stream stalled"; "stream" => ?stream);
+                    }
+                    StreamRecvFinished { stream } => {
+                        let pending = endpoint.pending.get_mut(&connection).unwrap();
+                        if let Some(reader) = pending.blocked_readers.remove(&stream) {
+                            reader.notify();
+                        }
+                    }
                     StreamAvailable { directionality } => {
                         let pending = endpoint.pending.get_mut(&connection).unwrap();
                         let queue = match directionality {
@@ -722,7 +795,7 @@ impl Future for Driver {
                             Directionality::Bi => &mut pending.bi_opening,
                         };
                         while let Some(ch) = queue.pop_front() {
-                            if let Some(id) = endpoint.inner.open(connection, directionality) {
+                            if let Ok(id) = endpoint.inner.open(connection, directionality) {
                                 let _ = ch.send(Ok(id));
                             } else {
                                 queue.push_front(ch);
@@ -751,13 +824,20 @@ impl Future for Driver {
                             x.notify();
                         }
                     }
+                    DatagramReceived { data } => {
+                        // Unreliable datagrams aren't yet exposed by this API
+                        debug!(endpoint.log, "dropping datagram"; "len" => data.len());
+                    }
+                    RttUpdated { .. } => {
+                        // Not requested by this API
+                    }
                 }
             }
             let mut blocked = false;
             while !endpoint.outgoing.is_empty() {
                 {
                     let front = endpoint.outgoing.front().unwrap();
-                    match endpoint.socket.poll_send_to(&front.1, &front.0.into()) {
+                    match endpoint.socket.poll_send_to(&front.1, &front.0) {
                         Ok(Async::Ready(_)) => {}
                         Ok(Async::NotReady) => {
                             blocked = true;
@@ -782,7 +862,7 @@ impl Future for Driver {
                         packet,
                     } => {
                         if !blocked {
-                            match endpoint.socket.poll_send_to(&packet, &destination.into()) {
+                            match endpoint.socket.poll_send_to(&packet, &destination) {
                                 Ok(Async::Ready(_)) => {}
                                 Ok(Async::NotReady) => {
                                     blocked = true;
@@ -826,6 +906,8 @@ impl Future for Driver {
                         let mut cancel = match timer {
                             LossDetection => &mut pending.cancel_loss_detect,
                             Idle => &mut pending.cancel_idle,
+                            DelayedAck => &mut pending.cancel_delayed_ack,
+                            KeepAlive => &mut pending.cancel_keep_alive,
                             Close => unreachable!(),
                         };
                         let instant = endpoint.epoch + duration_micros(time);
@@ -856,6 +938,12 @@ impl Future for Driver {
                                 Idle => {
                                     pending.cancel_idle.take().map(|x| x.send(()));
                                 }
+                                DelayedAck => {
+                                    pending.cancel_delayed_ack.take().map(|x| x.send(()));
+                                }
+                                KeepAlive => {
+                                    pending.cancel_keep_alive.take().map(|x| x.send(()));
+                                }
                                 Close => {} // Arises from stateless reset
                             }
                         }
@@ -906,7 +994,7 @@ fn micros_from(x: Duration) -> u64 {
     x.as_secs() * 1000 * 1000 + x.subsec_micros() as u64
 }
 
-fn normalize(x: SocketAddr) -> SocketAddrV6 {
+fn ensure_ipv6(x: SocketAddr) -> SocketAddrV6 {
     match x {
         SocketAddr::V6(x) => x,
         SocketAddr::V4(x) => SocketAddrV6::new(x.ip().to_ipv6_mapped(), x.port(), 0, 0),
@@ -934,12 +1022,16 @@ impl Connection {
         let (send, recv) = oneshot::channel();
         {
             let mut endpoint = self.0.endpoint.borrow_mut();
-            if let Some(x) = endpoint.inner.open(self.0.conn, Directionality::Uni) {
+            if let Ok(x) = endpoint.inner.open(self.0.conn, Directionality::Uni) {
                 let _ = send.send(Ok(x));
             } else {
                 let pending = endpoint.pending.get_mut(&self.0.conn).unwrap();
-                pending.uni_opening.push_back(send);
-                // We don't notify the driver here because there's no way to ask the peer for more streams
+                if let Some(ref x) = pending.error {
+                    let _ = send.send(Err(x.clone()));
+                } else {
+                    pending.uni_opening.push_back(send);
+                    // We don't notify the driver here because there's no way to ask the peer for more streams
+                }
             }
         }
         let conn = self.0.clone();
@@ -953,12 +1045,16 @@ impl Connection {
         let (send, recv) = oneshot::channel();
         {
             let mut endpoint = self.0.endpoint.borrow_mut();
-            if let Some(x) = endpoint.inner.open(self.0.conn, Directionality::Bi) {
+            if let Ok(x) = endpoint.inner.open(self.0.conn, Directionality::Bi) {
                 let _ = send.send(Ok(x));
             } else {
                 let pending = endpoint.pending.get_mut(&self.0.conn).unwrap();
-                pending.bi_opening.push_back(send);
-                // We don't notify the driver here because there's no way to ask the peer for more streams
+                if let Some(ref x) = pending.error {
+                    let _ = send.send(Err(x.clone()));
+                } else {
+                    pending.bi_opening.push_back(send);
+                    // We don't notify the driver here because there's no way to ask the peer for more streams
+                }
             }
         }
         let conn = self.0.clone();
@@ -1009,12 +1105,12 @@ impl Connection {
 
     /// The peer's UDP address.
     pub fn remote_address(&self) -> SocketAddr {
-        (*self
+        *self
             .0
             .endpoint
             .borrow()
             .inner
-            .get_remote_address(self.0.conn)).into()
+            .get_remote_address(self.0.conn)
     }
 
     /// The `ConnectionId` used for `conn` locally.
@@ -1157,7 +1253,7 @@ impl Write for BiStream {
         use quinn::WriteError::*;
         let n = match endpoint.inner.write(self.conn.conn, self.stream, buf) {
             Ok(n) => n,
-            Err(Blocked) => {
+            Err(FlowControlLimited) | Err(CongestionLimited) | Err(ConnectionClosed) => {
                 let pending = endpoint.pending.get_mut(&self.conn.conn).unwrap();
                 if let Some(ref x) = pending.error {
                     return Err(WriteError::ConnectionClosed(x.clone()));
@@ -1176,7 +1272,17 @@ impl Write for BiStream {
     fn poll_finish(&mut self) -> Poll<(), ConnectionError> {
         let mut endpoint = self.conn.endpoint.borrow_mut();
         if self.finishing.is_none() {
-            endpoint.inner.finish(self.conn.conn, self.stream);
+            match endpoint.inner.finish(self.conn.conn, self.stream) {
+                Ok(()) => {}
+                // Already finished or reset, so there's nothing left to wait for
+                Err(quinn::FinishError::Finished) => {
+                    self.finished = true;
+                    return Ok(Async::Ready(()));
+                }
+                Err(quinn::FinishError::InvalidStream) => {
+                    unreachable!("writable streams always have an outgoing channel")
+                }
+            }
             let (send, recv) = oneshot::channel();
             self.finishing = Some(recv);
             endpoint
@@ -1202,7 +1308,8 @@ impl Write for BiStream {
         let endpoint = &mut *self.conn.endpoint.borrow_mut();
         endpoint
             .inner
-            .reset(self.conn.conn, self.stream, error_code);
+            .reset(self.conn.conn, self.stream, error_code)
+            .unwrap();
         endpoint.notify();
     }
 }
@@ -1260,7 +1367,8 @@ impl Read for BiStream {
         let endpoint = &mut *self.conn.endpoint.borrow_mut();
         endpoint
             .inner
-            .stop_sending(self.conn.conn, self.stream, error_code);
+            .stop_sending(self.conn.conn, self.stream, error_code)
+            .unwrap();
         endpoint.notify();
         self.recvd = true;
     }
@@ -1307,10 +1415,16 @@ impl Drop for BiStream {
             Directionality::Uni => (ours, !ours),
         };
         if send && !self.finished {
-            endpoint.inner.reset(self.conn.conn, self.stream, 0);
+            endpoint
+                .inner
+                .reset(self.conn.conn, self.stream, 0)
+                .unwrap();
         }
         if recv && !self.recvd {
-            endpoint.inner.stop_sending(self.conn.conn, self.stream, 0);
+            endpoint
+                .inner
+                .stop_sending(self.conn.conn, self.stream, 0)
+                .unwrap();
         }
         endpoint.notify();
     }